@@ -0,0 +1,367 @@
+//soak test of a server and its clients on localhost, every client sends messages for the duration
+//and the server checks what arrived. exits with 1 if the run failed
+//
+//  cargo run --release --example soak -- --scenario lossy --clients 20 --duration 30
+//
+//the scenario picks the defaults, the other options override them
+use std::{
+    collections::HashSet,
+    env,
+    net::SocketAddr,
+    process,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use game_networking::{
+    Client, ConnectionStats, SendType, Server, ServerConfig, ServerEvent, FRAGMENT_SIZE,
+    MAX_FRAGMENT_SIZE,
+};
+use rand::Rng;
+
+//the client index and the message index in front of every message
+const MESSAGE_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+struct Options {
+    clients: u32,
+    min_size: usize,
+    max_size: usize,
+    //messages per second of every client
+    rate: u32,
+    duration: Duration,
+    //unreliable messages can be lost without failing the run
+    reliable: bool,
+    latency: Duration,
+    jitter: Duration,
+    loss: f32,
+    //how long the server keeps reading after the clients stopped sending
+    drain: Duration,
+    port: u16,
+    //threads processing the connections on the server
+    workers: usize,
+}
+
+impl Options {
+    fn scenario(name: &str) -> Option<Options> {
+        let baseline = Options {
+            clients: 10,
+            min_size: 10,
+            max_size: 1000,
+            rate: 30,
+            duration: Duration::from_secs(10),
+            reliable: true,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            drain: Duration::from_secs(10),
+            port: 9500,
+            workers: 1,
+        };
+
+        match name {
+            "baseline" => Some(baseline),
+            //every message is split into fragments, larger bursts can be tried with --max-size
+            "fragments" => Some(Options {
+                min_size: FRAGMENT_SIZE + 1,
+                max_size: FRAGMENT_SIZE * 16,
+                rate: 10,
+                ..baseline
+            }),
+            "lossy" => Some(Options {
+                latency: Duration::from_millis(50),
+                jitter: Duration::from_millis(10),
+                loss: 0.05,
+                ..baseline
+            }),
+            "unreliable" => Some(Options {
+                reliable: false,
+                loss: 0.05,
+                ..baseline
+            }),
+            _ => None,
+        }
+    }
+
+    fn parse(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut args: Vec<String> = args.collect();
+        let scenario = match args.iter().position(|arg| arg == "--scenario") {
+            Some(index) if index + 1 < args.len() => {
+                let name = args.remove(index + 1);
+                args.remove(index);
+                name
+            }
+            Some(_) => return Err("--scenario needs a value".to_owned()),
+            None => "baseline".to_owned(),
+        };
+        let mut options =
+            Options::scenario(&scenario).ok_or_else(|| format!("unknown scenario {scenario}"))?;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            match arg.as_str() {
+                "--clients" => options.clients = parse_value(&arg, &value)?,
+                "--min-size" => options.min_size = parse_value(&arg, &value)?,
+                "--max-size" => options.max_size = parse_value(&arg, &value)?,
+                "--rate" => options.rate = parse_value(&arg, &value)?,
+                "--duration" => options.duration = Duration::from_secs(parse_value(&arg, &value)?),
+                "--latency" => options.latency = Duration::from_millis(parse_value(&arg, &value)?),
+                "--jitter" => options.jitter = Duration::from_millis(parse_value(&arg, &value)?),
+                "--loss" => options.loss = parse_value(&arg, &value)?,
+                "--drain" => options.drain = Duration::from_secs(parse_value(&arg, &value)?),
+                "--port" => options.port = parse_value(&arg, &value)?,
+                "--workers" => options.workers = parse_value(&arg, &value)?,
+                "--send-type" => {
+                    options.reliable = match value.as_str() {
+                        "reliable" => true,
+                        "unreliable" => false,
+                        _ => return Err(format!("invalid value for {arg}: {value}")),
+                    }
+                }
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+
+        if options.clients == 0 || options.rate == 0 {
+            return Err("--clients and --rate have to be above 0".to_owned());
+        }
+        if client_port(options.port, options.clients - 1).is_none() {
+            return Err("--port leaves no room for the ports of the clients".to_owned());
+        }
+        if !(0.0..=1.0).contains(&options.loss) {
+            return Err("--loss has to be between 0.0 and 1.0".to_owned());
+        }
+        options.min_size = options.min_size.max(MESSAGE_HEADER_SIZE);
+        if options.min_size > options.max_size || options.max_size > MAX_FRAGMENT_SIZE {
+            return Err(format!(
+                "the sizes have to be between {MESSAGE_HEADER_SIZE} and {MAX_FRAGMENT_SIZE}"
+            ));
+        }
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {arg}: {value}"))
+}
+
+//the content is derived from the header so the server can check it without knowing what was sent
+fn write_message(client_index: u32, message_index: u32, length: usize) -> Vec<u8> {
+    let mut message = Vec::with_capacity(length);
+    message.extend_from_slice(&client_index.to_le_bytes());
+    message.extend_from_slice(&message_index.to_le_bytes());
+    message.extend((MESSAGE_HEADER_SIZE..length).map(|i| (i as u32 ^ message_index) as u8));
+    message
+}
+
+//the client index and the message index if the content matches them
+fn read_message(message: &[u8]) -> Option<(u32, u32)> {
+    if message.len() < MESSAGE_HEADER_SIZE {
+        return None;
+    }
+    let client_index = u32::from_le_bytes(message[..4].try_into().unwrap());
+    let message_index = u32::from_le_bytes(message[4..8].try_into().unwrap());
+    (message == write_message(client_index, message_index, message.len()))
+        .then_some((client_index, message_index))
+}
+
+//the clients bind the ports after the one of the server, None past the last port
+fn client_port(server_port: u16, client_index: u32) -> Option<u16> {
+    let index = u16::try_from(client_index).ok()?;
+    server_port.checked_add(1)?.checked_add(index)
+}
+
+//sends at the rate for the duration, returns how many messages and bytes were sent
+fn run_client(
+    options: &Options,
+    client_index: u32,
+    server_addr: SocketAddr,
+) -> anyhow::Result<(u32, u64, ConnectionStats)> {
+    let port = client_port(options.port, client_index).context("no port left for the client")?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let client = Client::connect(addr, server_addr)?;
+    let interval = Duration::from_secs(1) / options.rate;
+
+    let start = Instant::now();
+    let mut next_send = start;
+    let mut sent = 0;
+    let mut bytes = 0;
+    while start.elapsed() < options.duration {
+        let length = rand::thread_rng().gen_range(options.min_size..=options.max_size);
+        let send_type = if options.reliable {
+            SendType::Reliable
+        } else {
+            SendType::Unreliable
+        };
+        client.send(&write_message(client_index, sent, length), send_type)?;
+        sent += 1;
+        bytes += length as u64;
+
+        next_send += interval;
+        thread::sleep(next_send.saturating_duration_since(Instant::now()));
+    }
+
+    //the client keeps resending until the server read everything
+    thread::sleep(options.drain);
+    let stats = client.stats();
+    client.disconnect()?;
+    Ok((sent, bytes, stats))
+}
+
+#[derive(Default)]
+struct Report {
+    connected: u32,
+    sent: u64,
+    sent_bytes: u64,
+    received: u64,
+    received_bytes: u64,
+    duplicates: u64,
+    corrupted: u64,
+    lost_connections: u32,
+    client_errors: u32,
+}
+
+fn main() {
+    let _ = env_logger::try_init();
+
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "usage: soak [--scenario baseline|fragments|lossy|unreliable] [--clients N] \
+                 [--min-size BYTES] [--max-size BYTES] [--rate PER_SECOND] [--duration SECONDS] \
+                 [--send-type reliable|unreliable] [--latency MS] [--jitter MS] [--loss 0.0-1.0] \
+                 [--drain SECONDS] [--port PORT] [--workers N]"
+            );
+            process::exit(2);
+        }
+    };
+    println!("{options:#?}");
+
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], options.port));
+    let config = ServerConfig {
+        max_clients: options.clients as usize,
+        workers: options.workers,
+        ..Default::default()
+    };
+    let server = match Server::start_with_config(server_addr, config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("failed starting the server: {e}");
+            process::exit(1);
+        }
+    };
+
+    let clients: Vec<_> = (0..options.clients)
+        .map(|client_index| {
+            let options = options.clone();
+            thread::spawn(move || run_client(&options, client_index, server_addr))
+        })
+        .collect();
+
+    let mut report = Report::default();
+    let mut received = HashSet::new();
+    let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+    let deadline = Instant::now() + options.duration + options.drain;
+    while Instant::now() < deadline {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match server.read(&mut buf, timeout) {
+            Ok(Some(ServerEvent::NewConnection(connection_id))) => {
+                report.connected += 1;
+                if options.latency > Duration::ZERO
+                    || options.jitter > Duration::ZERO
+                    || options.loss > 0.0
+                {
+                    if let Err(e) = server.set_debug_conditions(
+                        connection_id,
+                        options.latency,
+                        options.jitter,
+                        options.loss,
+                    ) {
+                        eprintln!("failed setting the debug conditions: {e}");
+                    }
+                }
+            }
+            Ok(Some(ServerEvent::Receive(_, message, _))) => match read_message(message) {
+                Some(key) if received.insert(key) => {
+                    report.received += 1;
+                    report.received_bytes += message.len() as u64;
+                }
+                Some(_) => report.duplicates += 1,
+                None => report.corrupted += 1,
+            },
+            Ok(Some(ServerEvent::ConnectionLost(connection_id, reason))) => {
+                eprintln!("connection {connection_id} lost: {reason:?}");
+                report.lost_connections += 1;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("failed reading from the server: {e}");
+                break;
+            }
+        }
+    }
+
+    let mut fragments = ConnectionStats::default().fragments;
+    for client in clients {
+        match client.join() {
+            Ok(Ok((sent, bytes, stats))) => {
+                report.sent += sent as u64;
+                report.sent_bytes += bytes;
+                fragments = fragments + stats.fragments;
+            }
+            Ok(Err(e)) => {
+                eprintln!("client failed: {e}");
+                report.client_errors += 1;
+            }
+            Err(_) => report.client_errors += 1,
+        }
+    }
+
+    let missing = report.sent.saturating_sub(report.received);
+    //over the time the clients were sending, the drain only picks up the resends
+    let seconds = options.duration.as_secs_f64();
+    println!(
+        "connected clients:  {}/{}",
+        report.connected, options.clients
+    );
+    println!(
+        "sent:               {} ({} bytes)",
+        report.sent, report.sent_bytes
+    );
+    println!(
+        "received:           {} ({} bytes)",
+        report.received, report.received_bytes
+    );
+    println!(
+        "missing:            {missing} ({:.2}%)",
+        missing as f64 * 100.0 / report.sent.max(1) as f64
+    );
+    println!("duplicates:         {}", report.duplicates);
+    println!("corrupted:          {}", report.corrupted);
+    println!("lost connections:   {}", report.lost_connections);
+    println!("client errors:      {}", report.client_errors);
+    println!("client fragments:   {fragments:?}");
+    println!(
+        "throughput:         {:.0} messages/s, {:.0} bytes/s",
+        report.received as f64 / seconds,
+        report.received_bytes as f64 / seconds
+    );
+
+    let passed = report.connected == options.clients
+        && report.duplicates == 0
+        && report.corrupted == 0
+        && report.lost_connections == 0
+        && report.client_errors == 0
+        && (!options.reliable || missing == 0);
+    println!("{}", if passed { "PASSED" } else { "FAILED" });
+    if !passed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use game_networking::fuzzing::parse_incoming;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_incoming(data);
+});
@@ -0,0 +1,38 @@
+#![no_main]
+
+use std::net::SocketAddr;
+
+use game_networking::fuzzing::ServerHarness;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let stranger: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+    let mut harness = ServerHarness::new(2);
+    let session_key = harness.connect(addr).unwrap();
+
+    //every packet is prefixed by a length byte and a flag byte selecting the sender
+    let mut rest = data;
+    while let [len, flags, tail @ ..] = rest {
+        let len = (*len as usize).min(tail.len());
+        let (packet, tail) = tail.split_at(len);
+        rest = tail;
+
+        let mut packet = packet.to_vec();
+        if flags & 1 == 1 {
+            let _ = harness.feed(stranger, &packet);
+            continue;
+        }
+
+        //patch in the session key so the fuzzer gets past the session check
+        if flags & 2 == 2 && packet.len() >= 11 {
+            packet[3..11].copy_from_slice(&session_key.to_le_bytes());
+        }
+        let _ = harness.feed(addr, &packet);
+
+        if flags & 4 == 4 {
+            harness.update();
+        }
+    }
+});
@@ -0,0 +1,158 @@
+//generates the client stub and the server wrapper of an rpc trait, the runtime side is
+//game_networking::rpc and the generated code only goes through it
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Error, FnArg, Ident, ItemTrait, Pat, ReturnType, TraitItem,
+    Type,
+};
+
+struct RpcMethod {
+    name: Ident,
+    args: Vec<(Ident, Type)>,
+    output: Type,
+}
+
+//for `trait Lobby` it adds `LobbyClient` with a method returning an RpcResponse per trait method
+//and `LobbyServer<T: Lobby>` which is registered with an RpcServer
+#[proc_macro_attribute]
+pub fn net_rpc(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(
+            proc_macro2::Span::call_site(),
+            "net_rpc doesn't take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let item_trait = parse_macro_input!(item as ItemTrait);
+    match expand(&item_trait) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn parse_methods(item_trait: &ItemTrait) -> syn::Result<Vec<RpcMethod>> {
+    let mut methods = Vec::new();
+
+    for item in &item_trait.items {
+        let TraitItem::Fn(method) = item else {
+            return Err(Error::new_spanned(
+                item,
+                "rpc traits can only contain methods",
+            ));
+        };
+        let sig = &method.sig;
+        if !sig.generics.params.is_empty() || sig.asyncness.is_some() {
+            return Err(Error::new_spanned(
+                sig,
+                "rpc methods can't be generic or async",
+            ));
+        }
+
+        let mut inputs = sig.inputs.iter();
+        match inputs.next() {
+            Some(FnArg::Receiver(receiver))
+                if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+            _ => return Err(Error::new_spanned(sig, "rpc methods have to take &self")),
+        }
+
+        let mut args = Vec::new();
+        for input in inputs {
+            let FnArg::Typed(arg) = input else {
+                return Err(Error::new_spanned(input, "unexpected receiver"));
+            };
+            let Pat::Ident(pat) = arg.pat.as_ref() else {
+                return Err(Error::new_spanned(
+                    &arg.pat,
+                    "rpc arguments have to be plain names",
+                ));
+            };
+            args.push((pat.ident.clone(), arg.ty.as_ref().clone()));
+        }
+
+        let output = match &sig.output {
+            ReturnType::Default => parse_quote!(()),
+            ReturnType::Type(_, ty) => ty.as_ref().clone(),
+        };
+
+        methods.push(RpcMethod {
+            name: sig.ident.clone(),
+            args,
+            output,
+        });
+    }
+
+    Ok(methods)
+}
+
+fn expand(item_trait: &ItemTrait) -> syn::Result<TokenStream2> {
+    let methods = parse_methods(item_trait)?;
+    let vis = &item_trait.vis;
+    let name = &item_trait.ident;
+    let service = name.to_string();
+    let client = format_ident!("{}Client", name);
+    let server = format_ident!("{}Server", name);
+
+    let calls = methods.iter().map(|method| {
+        let method_name = &method.name;
+        let method_str = method_name.to_string();
+        let output = &method.output;
+        let (arg_names, arg_types): (Vec<_>, Vec<_>) = method.args.iter().cloned().unzip();
+
+        quote! {
+            #vis fn #method_name(
+                &self,
+                #(#arg_names: #arg_types),*
+            ) -> ::game_networking::rpc::Result<::game_networking::rpc::RpcResponse<#output>> {
+                ::game_networking::rpc::call(self.client, #service, #method_str, &(#(#arg_names,)*))
+            }
+        }
+    });
+
+    let dispatch_arms = methods.iter().map(|method| {
+        let method_name = &method.name;
+        let method_str = method_name.to_string();
+        let (arg_names, arg_types): (Vec<_>, Vec<_>) = method.args.iter().cloned().unzip();
+
+        quote! {
+            #method_str => {
+                let (#(#arg_names,)*): (#(#arg_types,)*) = ::game_networking::rpc::decode(args)?;
+                ::game_networking::rpc::encode(&self.0.#method_name(#(#arg_names),*))
+            }
+        }
+    });
+
+    Ok(quote! {
+        #item_trait
+
+        #vis struct #client<'a> {
+            client: &'a ::game_networking::Client,
+        }
+
+        impl<'a> #client<'a> {
+            #vis fn new(client: &'a ::game_networking::Client) -> Self {
+                Self { client }
+            }
+
+            #(#calls)*
+        }
+
+        #vis struct #server<T>(pub T);
+
+        impl<T: #name> ::game_networking::rpc::RpcService for #server<T> {
+            fn name(&self) -> &'static str {
+                #service
+            }
+
+            fn dispatch(&self, method: &str, args: &[u8]) -> ::game_networking::rpc::Result<::std::vec::Vec<u8>> {
+                match method {
+                    #(#dispatch_arms)*
+                    _ => ::game_networking::rpc::unknown_method(#service, method),
+                }
+            }
+        }
+    })
+}
@@ -1,132 +1,3004 @@
 #![allow(unused)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+extern crate alloc;
+//the code generated by the rpc attribute refers to the crate by its name
+#[cfg(feature = "rpc")]
+extern crate self as game_networking;
+
+#[cfg(feature = "std")]
 use net::Bytes;
+#[cfg(feature = "std")]
 use rand::Rng;
 
+#[cfg(feature = "std")]
 mod net;
+pub mod proto;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+#[cfg(feature = "std")]
+pub mod transfer;
+
+//the public api, everything else in net is internal to the crate
+#[cfg(feature = "alloc-counters")]
+pub use net::CountingAllocator;
+#[cfg(feature = "std")]
+pub use net::{
+    allocation_stats, fetch_server_list, query_server_info, wire_format, Action, AllocationStats,
+    ChannelConfig, ChannelDebugState, Client, ClientConfig, ClientEvent, ConnectEvent,
+    ConnectFailure, ConnectionId, ConnectionQuality, ConnectionStats, DebugConditions, Direction,
+    DisconnectCode, DisconnectReason, DuplicatePolicy, EventReceiver, Field, FlagInfo,
+    FragmentGroupState, FragmentStats, HandshakeBackoff, HandshakeConfig, HandshakeFailure,
+    HandshakeStage, HandshakeStep, HeldReadLimits, HeldReadPolicy, IdSpace, InvalidPacketStats,
+    InvalidSessionToken, InvalidSource, Layout, Limit, MasterServer, MessageLimits,
+    MiddlewareChain, NetError, OutboundLimits, OutboundPolicy, OutstandingPacket, PacketContext,
+    PacketTypeInfo, PayloadValidator, PendingClient, Percentiles, PortFallback, ProcessProfile,
+    ProtocolId, QualityThresholds, RandomSource, RecentEvent, RecentEventKind, RedundantReceiver,
+    RedundantSender, RequestHandle, ResponseHandle, RetransmitTimeout, RttConfig, ScheduleHandle,
+    SendHandle, SendQueueStats, SendRateCallback, SendRateConfig, SendType, Server, ServerCluster,
+    ServerConfig, ServerConfigUpdate, ServerEvent, ServerInfo, ServerListEntry, SessionToken,
+    SocketConfig, SocketError, SocketRecovery, Verdict, WireFormat, FRAGMENT_SIZE,
+    MAX_FRAGMENT_COUNT, MAX_FRAGMENT_SIZE, MAX_INFO_PAYLOAD_SIZE, MAX_INVALID_SOURCES,
+    MAX_UNCONNECTED_SIZE,
+};
+//checks another implementation of the protocol against this one
+#[cfg(all(feature = "std", any(test, feature = "conformance")))]
+pub use net::{run_conformance, CheckResult, EchoServer, PeerSession, ScriptedPeer};
+
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub use net::fuzzing;
+
+//the types most applications need, `use game_networking::prelude::*`. the events hand out the
+//payloads, the packets and the send queue behind them stay internal
+#[cfg(feature = "std")]
+pub mod prelude {
+    //the endpoints and what they read
+    pub use crate::{
+        Client, ClientEvent, ConnectEvent, EventReceiver, PendingClient, SendType, Server,
+        ServerEvent,
+    };
+    //ids and handles that come with the events or are returned by the sends
+    pub use crate::{ConnectionId, RequestHandle, ResponseHandle, ScheduleHandle};
+    //why something failed or ended
+    pub use crate::{
+        ChannelConfig, ClientConfig, MessageLimits, ProtocolId, ServerConfig, ServerConfigUpdate,
+        SocketConfig,
+    };
+    pub use crate::{
+        ConnectFailure, DisconnectCode, DisconnectReason, HandshakeFailure, Limit, NetError,
+        SocketError, Verdict,
+    };
+    pub use crate::{ConnectionQuality, ConnectionStats, SendQueueStats};
+}
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use std::{
-        env,
+        env, io,
+        sync::Arc,
         thread::{self, sleep},
-        time::Duration,
+        time::{Duration, Instant},
     };
 
-    use crate::net::{Client, SendType, Server, ServerEvent, FRAGMENT_SIZE, MAX_FRAGMENT_SIZE};
+    use std::net::UdpSocket;
+
+    use crate::net::{
+        fetch_server_list, query_server_info, Client, MasterServer, PacketType, SendType, Server,
+        ServerConfig, ServerEvent, FRAGMENT_SIZE, MAX_FRAGMENT_SIZE, MAX_UNCONNECTED_SIZE,
+        PROTOCOL_ID_SIZE,
+    };
+
+    use crate::transfer::{IncomingTransfer, OutgoingTransfer, TransferMessage, TransferState};
 
     use super::*;
 
+    #[cfg(feature = "alloc-counters")]
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
     #[test]
     fn it_works() {
         env::set_var("RUST_LOG", "DEBUG");
-        env_logger::init();
+        let _ = env_logger::try_init();
 
         let client_addr = "127.0.0.1:9091".parse().unwrap();
         let server_addr = "127.0.0.1:9090".parse().unwrap();
 
-        let mut server = Server::start(server_addr, 64).unwrap();
-        let mut client = Client::connect(client_addr, server_addr).unwrap();
+        let server = Server::start(server_addr, 64).unwrap();
+        let client = Client::connect(client_addr, server_addr).unwrap();
 
         let mut read_buf: [u8; 65536] = [0_u8; 1 << 16];
 
         let data = generate_random_u8_vector(1160);
 
         assert!(client.send(&data, SendType::Reliable).is_ok());
-        thread::sleep(Duration::from_secs(120));
-        /*match server.read(&mut read_buf).unwrap() {
-            ServerEvent::Receive(1, d) => assert_eq!(data, d),
-            _ => panic!(""),
-        }*/
+        loop {
+            match server.read(&mut read_buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::NewConnection(_)) => {}
+                Some(ServerEvent::Receive(_, d, _)) => break assert_eq!(data, d),
+                event => panic!("expected the message of the client, got {event:?}"),
+            }
+        }
     }
 
     #[test]
-    fn soak_test() {
-        env::set_var("RUST_LOG", "INFO");
-        env_logger::init();
+    fn unconnected_packets() {
+        let _ = env_logger::try_init();
 
-        const MESSAGE_COUNT: usize = 20;
-        const CLIENT_COUNT: usize = 10;
-        let read_timeout = Duration::from_secs(20);
-        //start up the server
-        let server_addr = "127.0.0.1:9090".parse().unwrap();
-        let mut server = Server::start(server_addr, 64).unwrap();
-        let mut read_buf: [u8; MAX_FRAGMENT_SIZE] = [0_u8; MAX_FRAGMENT_SIZE];
-
-        //connect 10 clients to it
-        let mut clients = Vec::with_capacity(CLIENT_COUNT);
-        for client_index in 0..CLIENT_COUNT as u32 {
-            let client_addr = format!("127.0.0.1:{}", 9091 + client_index)
-                .parse()
+        let server_addr = "127.0.0.1:9300".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        server
+            .set_unconnected_handler(|_, payload| {
+                let mut response = b"pong:".to_vec();
+                response.extend_from_slice(payload);
+                Some(response)
+            })
+            .unwrap();
+
+        //a plain socket that never goes through the handshake
+        let socket = UdpSocket::bind("127.0.0.1:9301").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        let mut request = ProtocolId::DEFAULT.0.to_vec();
+        request.push(PacketType::Unconnected as u8);
+        request.extend_from_slice(b"ping");
+
+        //the handler is set on the server thread, the requests before it go unanswered
+        let mut buf = [0_u8; 64];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let len = loop {
+            assert!(
+                Instant::now() < deadline,
+                "expected the response of the handler"
+            );
+            socket.send_to(&request, server_addr).unwrap();
+            if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                break len;
+            }
+        };
+        assert!(ProtocolId::DEFAULT.matches(&buf[..len]));
+        assert_eq!(buf[PROTOCOL_ID_SIZE], PacketType::Unconnected as u8);
+        assert_eq!(&buf[PROTOCOL_ID_SIZE + 1..len], b"pong:ping");
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        //packets can also be pushed without a request
+        server
+            .send_unconnected(socket.local_addr().unwrap(), b"info")
+            .unwrap();
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[PROTOCOL_ID_SIZE + 1..len], b"info");
+
+        assert!(server
+            .send_unconnected(server_addr, &[0; MAX_UNCONNECTED_SIZE + 1])
+            .is_err());
+    }
+
+    #[test]
+    fn read_until_gathers_events() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9330".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9331".parse().unwrap(), server_addr).unwrap();
+
+        client.send(&[1, 2], SendType::Reliable).unwrap();
+        client.send(&[3], SendType::Reliable).unwrap();
+        let large: Vec<u8> = (0..FRAGMENT_SIZE * 2).map(|i| i as u8).collect();
+        client.send(&large, SendType::Reliable).unwrap();
+
+        let mut buf = Vec::new();
+        let mut events = Vec::new();
+        server
+            .read_until(
+                Instant::now() + Duration::from_secs(1),
+                &mut buf,
+                &mut events,
+            )
+            .unwrap();
+
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[0], ServerEvent::NewConnection(_)));
+        assert!(matches!(events[1], ServerEvent::Receive(_, [1, 2], _)));
+        assert!(matches!(events[2], ServerEvent::Receive(_, [3], _)));
+        assert!(matches!(events[3], ServerEvent::Receive(_, data, _) if data == large));
+
+        //a deadline in the past only drains what is already there
+        let mut buf = Vec::new();
+        let mut events = Vec::new();
+        server
+            .read_until(Instant::now(), &mut buf, &mut events)
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn max_message_size() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9332".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9333".parse().unwrap(), server_addr).unwrap();
+
+        let max = client.max_message_size();
+        assert_eq!(max, server.max_message_size());
+        assert_eq!(max, MAX_FRAGMENT_SIZE);
+
+        assert!(client.send(&vec![0; max + 1], SendType::Reliable).is_err());
+        client.send(&vec![7; max], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; max];
+        let mut read = None;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read.is_none() && Instant::now() < deadline {
+            if let Ok(Some(ServerEvent::Receive(_, data, _))) =
+                server.read(&mut buf, Duration::from_millis(100))
+            {
+                read = Some(data.len());
+            }
+        }
+        assert_eq!(read, Some(max));
+    }
+
+    #[test]
+    fn kicked_client_gets_the_reason() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9334".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9335".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)) {
+            Ok(Some(ServerEvent::NewConnection(connection_id))) => connection_id,
+            event => panic!("expected a new connection, got {event:?}"),
+        };
+
+        let reason = DisconnectReason::with_message(DisconnectCode::Kicked, "afk");
+        server.disconnect(connection_id, reason.clone()).unwrap();
+
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::ConnectionLost(connection_id, reason.clone()))
+        );
+        assert_eq!(
+            client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+            ClientEvent::Disconnected(reason)
+        );
+    }
+
+    #[test]
+    fn draining_disconnect_delivers_reliable_messages() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9336".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9337".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)) {
+            Ok(Some(ServerEvent::NewConnection(connection_id))) => connection_id,
+            event => panic!("expected a new connection, got {event:?}"),
+        };
+        //lost packets have to be resent before the disconnect can go out
+        server
+            .set_debug_conditions(
+                connection_id,
+                Duration::from_millis(20),
+                Duration::ZERO,
+                0.3,
+            )
+            .unwrap();
+        //the commands are handled in order, the conditions are set once the dump is answered
+        server.debug_dump(connection_id).unwrap();
+
+        for i in 0..5 {
+            client.send(&[i], SendType::Reliable).unwrap();
+        }
+        client
+            .disconnect_draining(DisconnectReason::default(), Duration::from_secs(3))
+            .unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)) {
+                Ok(Some(ServerEvent::Receive(_, data, _))) => received.push(data[0]),
+                Ok(Some(ServerEvent::ConnectionLost(_, reason))) => {
+                    assert_eq!(reason, DisconnectReason::default());
+                    break;
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        received.sort();
+        assert_eq!(received, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn start_with_existing_sockets() {
+        let _ = env_logger::try_init();
+
+        let server_socket = UdpSocket::bind("127.0.0.1:9338").unwrap();
+        server_socket.set_ttl(32).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let server = Server::start_with_socket(server_socket, ServerConfig::default()).unwrap();
+
+        //the client can be handed a socket bound to any free port
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client =
+            Client::connect_with_socket(client_socket, server_addr, ClientConfig::default())
                 .unwrap();
+        client.send(&[1, 2, 3], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1, 2, 3], _)))
+        ));
+    }
+
+    #[test]
+    fn prelude_covers_a_client_and_a_server() {
+        use crate::prelude::*;
+
+        let server_config = ServerConfig {
+            channel: ChannelConfig {
+                flow_control: true,
+                ..Default::default()
+            },
+            message_limits: MessageLimits {
+                max_message_size: Some(1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server =
+            Server::start_with_config("127.0.0.1:9413".parse().unwrap(), server_config).unwrap();
+        let client = Client::connect_with_config(
+            "127.0.0.1:9414".parse().unwrap(),
+            "127.0.0.1:9413".parse().unwrap(),
+            ClientConfig {
+                channel: ChannelConfig {
+                    flow_control: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 16];
+        let connection_id: ConnectionId = match server.read(&mut buf, Duration::from_secs(5)) {
+            Ok(Some(ServerEvent::NewConnection(connection_id))) => connection_id,
+            _ => panic!("expected a new connection"),
+        };
+        let stats: Option<ConnectionStats> = server.connection_stats(connection_id);
+        assert!(stats.is_some());
+        let queue: SendQueueStats = server.send_queue_stats();
+        assert_eq!(queue.expired, 0);
+    }
+
+    #[test]
+    fn compact_headers_are_negotiated() {
+        let _ = env_logger::try_init();
+
+        let channel = ChannelConfig {
+            compact_header: true,
+            ..Default::default()
+        };
+        let server_addr = "127.0.0.1:9346".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                channel: channel.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let client_addr = "127.0.0.1:9347".parse().unwrap();
+        let client = Client::connect_with_config(
+            client_addr,
+            server_addr,
+            ClientConfig {
+                channel,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send(&[1, 2, 3], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1, 2, 3], _)))
+        ));
+
+        server
+            .send(client_addr, &[4, 5], SendType::Reliable)
+            .unwrap();
+        assert_eq!(
+            client.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            [4, 5]
+        );
+    }
+
+    #[test]
+    fn fragment_stats_are_published() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9348".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9349".parse().unwrap(), server_addr).unwrap();
+
+        let data = generate_random_u8_vector(FRAGMENT_SIZE * 3);
+        client.send(&data, SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; FRAGMENT_SIZE * 4];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, received, _))) if received == data
+        ));
+        //published on the next update
+        wait_until(Duration::from_secs(5), || {
+            client.stats().fragments.fragments_sent == 3
+                && server
+                    .connection_stats(connection_id)
+                    .is_some_and(|stats| stats.fragments.groups_completed == 1)
+        });
+
+        let stats = server.connection_stats(connection_id).unwrap();
+        assert_eq!(stats.fragments.fragments_received, 3);
+        assert_eq!(stats.fragments.groups_completed, 1);
+        assert!(stats.fragments.average_reassembly_latency().is_some());
+        //an id of the same slot from another generation
+        let stale_id = ConnectionId {
+            generation: connection_id.generation.wrapping_add(1),
+            ..connection_id
+        };
+        assert!(server.connection_stats(stale_id).is_none());
+    }
+
+    #[test]
+    fn receive_progress_is_reported() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9350".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                receive_progress: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = Client::connect("127.0.0.1:9351".parse().unwrap(), server_addr).unwrap();
+
+        let data = generate_random_u8_vector(FRAGMENT_SIZE * 3);
+        client.send(&data, SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; FRAGMENT_SIZE * 4];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        for expected in 1..3 {
+            assert!(matches!(
+                server.read(&mut buf, Duration::from_secs(5)),
+                Ok(Some(ServerEvent::ReceiveProgress(_, _, received, 3))) if received == expected
+            ));
+        }
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, received, _))) if received == data
+        ));
+    }
+
+    #[test]
+    fn handshake_events_are_reported() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9384".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                max_clients: 1,
+                handshake_events: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client_addr = "127.0.0.1:9385".parse().unwrap();
+        let _client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 16];
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::Handshaking(client_addr))
+        );
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+
+        //the only slot is taken
+        let rejected_addr = "127.0.0.1:9386".parse().unwrap();
+        assert!(Client::connect(rejected_addr, server_addr).is_err());
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::HandshakeFailed(
+                rejected_addr,
+                HandshakeFailure::ServerFull
+            ))
+        );
+    }
+
+    #[test]
+    fn connect_stages_are_reported() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9387".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let mut pending = Client::start_connect(
+            "127.0.0.1:9388".parse().unwrap(),
+            server_addr,
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut stages = Vec::new();
+        while let Some(stage) = pending.poll(Duration::from_secs(5)).unwrap() {
+            stages.push(stage);
+        }
+        assert!(matches!(
+            stages[..],
+            [
+                ConnectEvent::Connecting,
+                ConnectEvent::ChallengeReceived,
+                ConnectEvent::Accepted(_)
+            ]
+        ));
+
+        let client = pending.into_client().unwrap();
+        client.send(&[1, 2, 3], SendType::Reliable).unwrap();
+        let mut buf = vec![0; 16];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, &[1, 2, 3], _)))
+        ));
+
+        //nothing listens on the port
+        let mut pending = Client::start_connect(
+            "127.0.0.1:9389".parse().unwrap(),
+            "127.0.0.1:9390".parse().unwrap(),
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            pending.poll(Duration::from_secs(5)).unwrap(),
+            Some(ConnectEvent::Connecting)
+        );
+        assert_eq!(
+            pending.poll(Duration::from_secs(5)).unwrap(),
+            Some(ConnectEvent::Denied(ConnectFailure::Refused))
+        );
+        assert!(pending.into_client().is_none());
+    }
+
+    #[test]
+    fn handshake_retries_are_configured() {
+        let _ = env_logger::try_init();
+
+        //bound but never replies
+        let silent_addr = "127.0.0.1:9393".parse().unwrap();
+        let _silent = std::net::UdpSocket::bind(silent_addr).unwrap();
+        let config = ClientConfig {
+            handshake: HandshakeConfig {
+                reply_timeout: Duration::from_millis(20),
+                attempts: 3,
+                rounds: 2,
+                backoff: HandshakeBackoff::Exponential {
+                    max_timeout: Duration::from_millis(40),
+                },
+            },
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let mut pending =
+            Client::start_connect("127.0.0.1:9394".parse().unwrap(), silent_addr, config).unwrap();
+        let mut denied = None;
+        while let Some(stage) = pending.poll(Duration::from_secs(5)).unwrap() {
+            if let ConnectEvent::Denied(failure) = stage {
+                denied = Some(failure);
+            }
+        }
+
+        //20 + 40 + 40 ms for each of the 2 rounds
+        assert_eq!(
+            denied,
+            Some(ConnectFailure::NoResponse(HandshakeStage::Challenge, 6))
+        );
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let e = Client::connect_with_config(
+            "127.0.0.1:9395".parse().unwrap(),
+            silent_addr,
+            ClientConfig {
+                handshake: HandshakeConfig {
+                    reply_timeout: Duration::from_millis(10),
+                    attempts: 1,
+                    rounds: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("no challenge received, attempts: 1"));
+    }
+
+    #[test]
+    fn connect_any_keeps_the_first_accepted() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9396".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        //the first candidate never replies, the second is started after its head start
+        let silent_addr = "127.0.0.1:9397".parse().unwrap();
+        let _silent = std::net::UdpSocket::bind(silent_addr).unwrap();
+
+        let client = Client::connect_any(&[silent_addr, server_addr], Default::default()).unwrap();
+        client.send(&[1, 2, 3], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 16];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, &[1, 2, 3], _)))
+        ));
+
+        let e = Client::connect_any(&[], Default::default()).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn server_listens_on_several_ports() {
+        let _ = env_logger::try_init();
+
+        let first_addr = "127.0.0.1:9398".parse().unwrap();
+        let second_addr = "127.0.0.1:9399".parse().unwrap();
+        let server = Server::start_multi(&[first_addr, second_addr], 2).unwrap();
+
+        //the clients are connected to the port, a reply from the other one wouldn't reach them
+        let first = Client::connect("127.0.0.1:9400".parse().unwrap(), first_addr).unwrap();
+        let second_client_addr = "127.0.0.1:9401".parse().unwrap();
+        let second = Client::connect(second_client_addr, second_addr).unwrap();
+        first.send(&[1], SendType::Reliable).unwrap();
+        second.send(&[2], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 16];
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(_, data, _)) => received.push(data[0]),
+                Some(_) => {}
+                None => panic!("timed out waiting for the messages"),
+            }
+        }
+        received.sort();
+        assert_eq!(received, [1, 2]);
+
+        server
+            .send(second_client_addr, &[3], SendType::Reliable)
+            .unwrap();
+        assert_eq!(second.read(&mut buf, Duration::from_secs(5)).unwrap(), [3]);
+        assert!(Server::start_multi(&[], 1).is_err());
+    }
 
-            let client = Client::connect(client_addr, server_addr);
-            assert!(client.is_ok());
-            let client = client.unwrap();
+    #[test]
+    fn config_is_updated_without_dropping_clients() {
+        let _ = env_logger::try_init();
 
-            let read_result = server.read(&mut read_buf, read_timeout);
-            assert!(read_result.is_ok());
+        let server_addr = "127.0.0.1:9402".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let first = Client::connect("127.0.0.1:9403".parse().unwrap(), server_addr).unwrap();
+        assert!(Client::connect("127.0.0.1:9404".parse().unwrap(), server_addr).is_err());
 
-            if let Ok(Some(ServerEvent::NewConnection(connection_id))) = read_result {
-                assert_eq!(connection_id, client_index + 1);
-            } else {
-                panic!("expected new connection, got: {:?}", read_result.unwrap());
+        server
+            .update_config(ServerConfigUpdate {
+                max_clients: Some(2),
+                keep_alive_interval: Some(Duration::from_millis(200)),
+                ..Default::default()
+            })
+            .unwrap();
+        //applied at the next update of the server, until then the server is still full
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let _second = loop {
+            match Client::connect("127.0.0.1:9404".parse().unwrap(), server_addr) {
+                Ok(client) => break client,
+                Err(_) if Instant::now() < deadline => {}
+                Err(e) => panic!("the second client wasn't accepted: {e}"),
             }
+        };
 
-            clients.push(client);
+        first.send(&[1], SendType::Reliable).unwrap();
+        let mut buf = vec![0; 16];
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(_, [1], _)) => break,
+                Some(ServerEvent::ConnectionLost(..)) => panic!("the first client was dropped"),
+                Some(_) => {}
+                None => panic!("timed out waiting for the message"),
+            }
         }
 
-        for i in 0..=0 {
-            let client_index = rand::thread_rng().gen_range(0..clients.len());
-            let mut client = &mut clients[client_index];
+        assert!(server
+            .update_config(ServerConfigUpdate {
+                max_clients: Some(1 << 17),
+                ..Default::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn connection_from_a_connected_ip_is_replaced() {
+        let _ = env_logger::try_init();
 
-            //start sending reliable messages
-            let mut data_list = Vec::with_capacity(MESSAGE_COUNT);
-            for i in 0..MESSAGE_COUNT {
-                let length = rand::thread_rng().gen_range(if i % 2 == 0 {
-                    10..FRAGMENT_SIZE
-                    //500..501
-                } else {
-                    FRAGMENT_SIZE + 1..MAX_FRAGMENT_SIZE
-                    //1200..1201
-                });
-                let data = generate_random_u8_vector(length);
-                assert!(client.send(&data, SendType::Reliable).is_ok());
-                data_list.push(data);
+        let server_addr = "127.0.0.1:9426".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                max_clients: 1,
+                duplicate_policy: DuplicatePolicy::ReplaceExisting,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let _first = Client::connect("127.0.0.1:9427".parse().unwrap(), server_addr).unwrap();
+        //the server is full but the restarted client takes the slot of the old connection
+        let second = Client::connect("127.0.0.1:9428".parse().unwrap(), server_addr).unwrap();
 
-                thread::sleep(Duration::from_millis(50));
+        let mut buf = vec![0; 16];
+        let mut connected = Vec::new();
+        let mut replaced = None;
+        while connected.len() < 2 || replaced.is_none() {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::NewConnection(connection_id)) => connected.push(connection_id),
+                Some(ServerEvent::ConnectionLost(connection_id, reason)) => {
+                    assert_eq!(reason.code, DisconnectCode::Kicked);
+                    replaced = Some(connection_id);
+                }
+                Some(_) => {}
+                None => panic!("timed out waiting for the replacement"),
             }
+        }
+        assert_eq!(replaced, Some(connected[0]));
 
-            //receive the data
-            for i in 0..MESSAGE_COUNT {
-                let ev = server.read(&mut read_buf, read_timeout);
-                if let Ok(Some(ServerEvent::Receive(connection_id, data))) = ev {
-                    assert_eq!(connection_id, client_index as u32 + 1);
-                    assert!(data_list.iter().any(|f| f == data))
-                } else {
-                    panic!("unexpected server read reading event ({i}) {:?}", ev);
+        second.send(&[1], SendType::Reliable).unwrap();
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(connection_id, [1], _)) => {
+                    assert_eq!(connection_id, connected[1]);
+                    break;
                 }
+                Some(_) => {}
+                None => panic!("timed out waiting for the message"),
             }
+        }
+    }
+
+    #[test]
+    fn payloads_are_validated_before_they_are_received() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9405".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                payload_validator: Some(PayloadValidator::new(|_, payload| match payload[0] {
+                    0 => Verdict::Reject,
+                    1 => Verdict::Flag,
+                    2 => Verdict::Disconnect,
+                    _ => Verdict::Accept,
+                })),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = Client::connect("127.0.0.1:9406".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 16];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+
+        //one at a time, reliable messages can overtake each other
+        client.send(&[0], SendType::Reliable).unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::PayloadFlagged(connection_id, Verdict::Reject))
+        );
+        client.send(&[1], SendType::Reliable).unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::PayloadFlagged(connection_id, Verdict::Flag))
+        );
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1], _)))
+        ));
+        client.send(&[2], SendType::Reliable).unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::PayloadFlagged(
+                connection_id,
+                Verdict::Disconnect
+            ))
+        );
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::ConnectionLost(_, reason))) if reason.code == DisconnectCode::Kicked
+        ));
+    }
+
+    #[test]
+    fn messages_over_the_limits_are_dropped() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9407".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                message_limits: MessageLimits {
+                    max_message_size: Some(4),
+                    violation_limit: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = Client::connect("127.0.0.1:9408".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 16];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+
+        //one at a time, reliable messages can overtake each other
+        client.send(&[0; 5], SendType::Reliable).unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::LimitExceeded(
+                connection_id,
+                Limit::MessageSize,
+                1
+            ))
+        );
+        client.send(&[1], SendType::Reliable).unwrap();
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1], _)))
+        ));
+        client.send(&[0; 5], SendType::Reliable).unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::LimitExceeded(
+                connection_id,
+                Limit::MessageSize,
+                2
+            ))
+        );
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::ConnectionLost(_, reason))) if reason.code == DisconnectCode::Kicked
+        ));
+    }
+
+    #[test]
+    fn only_the_latest_state_is_kept() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9409".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9410".parse().unwrap(), server_addr).unwrap();
 
-            thread::sleep(Duration::from_secs(1));
+        let mut buf = vec![0; 16];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        assert_eq!(client.latest(1), None);
+
+        server.set_state(connection_id, 1, &[1]).unwrap();
+        server.set_state(connection_id, 1, &[2]).unwrap();
+        server.set_state(connection_id, 2, &[3]).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (client.latest(1) != Some(vec![2]) || client.latest(2).is_none())
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(client.latest(1), Some(vec![2]));
+        assert_eq!(client.latest(2), Some(vec![3]));
 
-        //disconnect the clients and expect lost connections
-        for client in &clients {
-            assert!(client.disconnect().is_ok());
+        //the values aren't received as messages
+        client.send(&[4], SendType::Reliable).unwrap();
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [4], _)))
+        ));
+        server
+            .send("127.0.0.1:9410".parse().unwrap(), &[5], SendType::Reliable)
+            .unwrap();
+        assert_eq!(client.read(&mut buf, Duration::from_secs(5)).unwrap(), [5]);
+    }
+
+    #[test]
+    fn a_slow_client_slows_the_reliable_stream() {
+        let _ = env_logger::try_init();
+
+        let channel = ChannelConfig {
+            flow_control: true,
+            receive_window: 8,
+            ..Default::default()
+        };
+        let server_addr = "127.0.0.1:9411".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                channel: channel.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client_addr = "127.0.0.1:9412".parse().unwrap();
+        let client = Client::connect_with_config(
+            client_addr,
+            server_addr,
+            ClientConfig {
+                channel,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 16];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+
+        for i in 0..40 {
+            server.send(client_addr, &[i], SendType::Reliable).unwrap();
+        }
+        //the client doesn't read, the server holds back what doesn't fit its window
+        wait_until(Duration::from_secs(5), || {
+            server
+                .connection_stats(connection_id)
+                .is_some_and(|stats| stats.queued_reliable > 0)
+        });
+
+        let mut received: Vec<u8> = (0..40)
+            .map(|_| client.read(&mut buf, Duration::from_secs(5)).unwrap()[0])
+            .collect();
+        received.sort();
+        assert_eq!(received, (0..40).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn tagged_connections_are_read_by_tag() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9415".parse().unwrap();
+        let server = Server::start(server_addr, 2).unwrap();
+        let mut buf = vec![0; 16];
+
+        let lobby = Client::connect("127.0.0.1:9416".parse().unwrap(), server_addr).unwrap();
+        let Ok(Some(ServerEvent::NewConnection(lobby_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        let other = Client::connect("127.0.0.1:9417".parse().unwrap(), server_addr).unwrap();
+        let Ok(Some(ServerEvent::NewConnection(other_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+
+        server.tag(lobby_id, "lobby:12").unwrap();
+        //the dump goes through the process after the tag, it's applied once it returns
+        server.debug_dump(lobby_id).unwrap();
+        lobby.send(&[1], SendType::Reliable).unwrap();
+        other.send(&[2], SendType::Reliable).unwrap();
+
+        assert!(matches!(
+            server.read_tagged("lobby:12", &mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(id, [1], _))) if id == lobby_id
+        ));
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(id, [2], _))) if id == other_id
+        ));
+
+        //untagged the events go to read again
+        server.untag(lobby_id).unwrap();
+        server.debug_dump(lobby_id).unwrap();
+        lobby.send(&[3], SendType::Reliable).unwrap();
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(id, [3], _))) if id == lobby_id
+        ));
+        assert!(matches!(
+            server.read_tagged("lobby:12", &mut buf, Duration::ZERO),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn subscribers_get_every_event() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9418".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                workers: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let metrics = server.subscribe().unwrap();
+        let chat = server.subscribe().unwrap();
+        //the workers handle their commands before their reads, the subscriptions reach them
+        //before the handshake of the client is done
+
+        let client = Client::connect("127.0.0.1:9419".parse().unwrap(), server_addr).unwrap();
+        client.send(&[1, 2], SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 16];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1, 2], _)))
+        ));
+        for subscriber in [&metrics, &chat] {
+            assert!(matches!(
+                subscriber.read(&mut buf, Duration::from_secs(5)),
+                Ok(Some(ServerEvent::NewConnection(id))) if id == connection_id
+            ));
+            assert!(matches!(
+                subscriber.read(&mut buf, Duration::from_secs(5)),
+                Ok(Some(ServerEvent::Receive(id, [1, 2], _))) if id == connection_id
+            ));
+        }
+    }
+
+    #[test]
+    fn wakeup_is_called_when_events_arrive() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9420".parse().unwrap();
+        let server = Server::start(server_addr, 4).unwrap();
+        let (wakeups_tx, wakeups) = std::sync::mpsc::channel();
+        server.set_wakeup(move || {
+            let _ = wakeups_tx.send(());
+        });
+
+        let client = Client::connect("127.0.0.1:9421".parse().unwrap(), server_addr).unwrap();
+        //an external loop sleeps on its own handle and only reads without waiting
+        let mut buf = vec![0; 16];
+        let connected = loop {
+            wakeups.recv_timeout(Duration::from_secs(5)).unwrap();
+            match server.read(&mut buf, Duration::ZERO) {
+                Ok(Some(ServerEvent::NewConnection(_))) => break true,
+                Ok(_) => {}
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert!(connected);
+
+        let (client_tx, client_wakeups) = std::sync::mpsc::channel();
+        client.set_wakeup(move || {
+            let _ = client_tx.send(());
+        });
+        server
+            .send("127.0.0.1:9421".parse().unwrap(), &[7], SendType::Reliable)
+            .unwrap();
+        client_wakeups.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(client.read(&mut buf, Duration::ZERO).unwrap(), [7]);
+
+        server.clear_wakeup();
+        client.clear_wakeup();
+    }
+
+    #[test]
+    fn jitter_buffer_delays_unreliable_messages() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9422".parse().unwrap();
+        let client_addr = "127.0.0.1:9423".parse().unwrap();
+        let server = Server::start(server_addr, 4).unwrap();
+        let delay = Duration::from_millis(100);
+        let client = Client::connect_with_config(
+            client_addr,
+            server_addr,
+            ClientConfig {
+                channel: ChannelConfig {
+                    jitter_delay: Some(delay),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let sent_at = Instant::now();
+        for i in 1..=3 {
+            server
+                .send(client_addr, &[i], SendType::Unreliable)
+                .unwrap();
+        }
+        let mut buf = vec![0; 16];
+        //released by the update of the client without another packet arriving
+        for i in 1..=3 {
+            assert_eq!(client.read(&mut buf, Duration::from_secs(5)).unwrap(), [i]);
         }
-        thread::sleep(Duration::from_secs(2));
+        assert!(sent_at.elapsed() >= delay / 2);
+        assert_eq!(client.stats().late_unreliable, 0);
+    }
+
+    #[test]
+    fn scripted_peer_passes_the_conformance_checks() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9424".parse().unwrap();
+        let peer_addr = "127.0.0.1:9425".parse().unwrap();
+        let _server = EchoServer::start(server_addr, peer_addr).unwrap();
+
+        let results = run_conformance(peer_addr, server_addr, Duration::from_secs(5));
+        assert_eq!(results.len(), 6);
+        for result in &results {
+            assert!(result.passed(), "{}: {:?}", result.name, result.error);
+        }
+    }
+
+    #[test]
+    fn both_sides_have_the_same_session_token() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9429".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9430".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(connection_id)) => connection_id,
+            _ => panic!("expected the new connection"),
+        };
+        //there before the first update of either side
+        let token = client.session_token();
+        assert_eq!(server.session_token(connection_id), Some(token));
+        assert_eq!(token.to_string().parse(), Ok(token));
+        assert_eq!(client.stats().session_token, token);
+    }
+
+    #[test]
+    fn sends_over_the_outbound_limits_are_refused() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9431".parse().unwrap();
+        let client_addr = "127.0.0.1:9432".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                outbound_limits: OutboundLimits {
+                    max_packets: Some(4),
+                    max_bytes: None,
+                    policy: OutboundPolicy::Error,
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let _client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(connection_id)) => connection_id,
+            _ => panic!("expected the new connection"),
+        };
+        //the reliable sends are held while paused, like for a client that stopped taking them
+        server.pause(connection_id).unwrap();
+        for i in 0..6 {
+            server.send(client_addr, &[i], SendType::Reliable).unwrap();
+        }
+        for expected in 1..=2 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::OutboundLimitExceeded(id, count)) => {
+                    assert_eq!((id, count), (connection_id, expected))
+                }
+                _ => panic!("expected the refused send"),
+            }
+        }
+
+        server
+            .update_config(ServerConfigUpdate {
+                outbound_limits: Some(OutboundLimits {
+                    max_packets: Some(4),
+                    max_bytes: None,
+                    policy: OutboundPolicy::Disconnect,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        //applied at the next update of the server, the sends before it are refused
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(
+                Instant::now() < deadline,
+                "expected the connection to be kicked"
+            );
+            server.send(client_addr, &[6], SendType::Reliable).unwrap();
+            match server.read(&mut buf, Duration::from_millis(50)).unwrap() {
+                Some(ServerEvent::ConnectionLost(id, reason)) => {
+                    assert_eq!(id, connection_id);
+                    assert_eq!(reason.code, DisconnectCode::Kicked);
+                    break;
+                }
+                Some(ServerEvent::OutboundLimitExceeded(..)) | None => {}
+                _ => panic!("expected the connection to be kicked"),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_to_picks_the_local_port() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9433".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let local_addr = client.local_addr();
+        assert_eq!(local_addr.ip(), server_addr.ip());
+        assert_ne!(local_addr.port(), 0);
+
+        let mut buf = vec![0; 16];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+        server.send(local_addr, &[1], SendType::Reliable).unwrap();
+        assert_eq!(client.read(&mut buf, Duration::from_secs(5)).unwrap(), [1]);
+    }
+
+    #[test]
+    fn server_falls_back_to_a_free_port() {
+        let _ = env_logger::try_init();
+
+        let server_addr: std::net::SocketAddr = "127.0.0.1:9434".parse().unwrap();
+        let _taken = UdpSocket::bind(server_addr).unwrap();
+        assert!(Server::start(server_addr, 1).is_err());
+
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                port_fallback: PortFallback::Nearby(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(server.local_addr(), "127.0.0.1:9435".parse().unwrap());
+        let ephemeral = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                port_fallback: PortFallback::NearbyThenEphemeral(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_ne!(ephemeral.local_addr().port(), server_addr.port());
+
+        let client = Client::connect_to(server.local_addr()).unwrap();
+        let mut buf = vec![0; 16];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+        client.send(&[1], SendType::Reliable).unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, [1]),
+            _ => panic!("expected the message"),
+        }
+    }
+
+    #[test]
+    fn cluster_instances_share_the_connection_ids() {
+        let _ = env_logger::try_init();
+
+        let cluster = ServerCluster::start(
+            "127.0.0.1:9436".parse().unwrap(),
+            2,
+            ServerConfig {
+                max_clients: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let addrs = cluster.local_addrs();
+        assert_eq!(addrs[1].port(), 9437);
+        let clients: Vec<_> = addrs
+            .iter()
+            .map(|addr| Client::connect_to(*addr).unwrap())
+            .collect();
+
+        let mut buf = vec![0; 16];
+        let mut connections = Vec::new();
+        while connections.len() < 2 {
+            match cluster.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some((instance, ServerEvent::NewConnection(connection_id))) => {
+                    assert_eq!(cluster.instance_of(connection_id), instance);
+                    connections.push(connection_id);
+                }
+                Some(_) => {}
+                None => panic!("expected the new connections"),
+            }
+        }
+        assert_ne!(connections[0].index, connections[1].index);
+
+        for (i, client) in clients.iter().enumerate() {
+            client.send(&[i as u8], SendType::Reliable).unwrap();
+        }
+        for _ in 0..2 {
+            match cluster.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some((instance, ServerEvent::Receive(connection_id, data, _))) => {
+                    assert_eq!(data, [instance as u8]);
+                    assert_eq!(cluster.instance_of(connection_id), instance);
+                    assert!(cluster
+                        .server_of(connection_id)
+                        .connection_stats(connection_id)
+                        .is_some());
+                }
+                _ => panic!("expected the messages"),
+            }
+        }
+    }
 
-        for _ in 0..CLIENT_COUNT {
-            let read_result = server.read(&mut read_buf, read_timeout);
-            assert!(read_result.is_ok());
+    #[test]
+    fn server_profiles_its_phases() {
+        let _ = env_logger::try_init();
 
-            if let Ok(Some(ServerEvent::ConnectionLost(connection_id))) = read_result {
-                assert!((1..=10).contains(&connection_id));
-            } else {
-                panic!("expected lost connection, got: {:?}", read_result.unwrap());
+        let server_addr = "127.0.0.1:9438".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let mut buf = vec![0; 16];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+        for i in 0..20 {
+            client.send(&[i], SendType::Reliable).unwrap();
+            server
+                .send(client.local_addr(), &[i], SendType::Reliable)
+                .unwrap();
+        }
+        for _ in 0..20 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(..)) => {}
+                event => panic!("expected the messages of the client, got {event:?}"),
             }
         }
+        //the samples of the phases are published on the next update
+        wait_until(Duration::from_secs(5), || {
+            let profile = server.process_profiles()[0];
+            profile.ticks > 0
+                && profile.poll.max > Duration::ZERO
+                && profile.reads.max > Duration::ZERO
+                && profile.sends.max > Duration::ZERO
+        });
+
+        let profiles = server.process_profiles();
+        assert_eq!(profiles.len(), 1);
+        let profile = profiles[0];
+        assert!(profile.busy.max >= profile.updates.max);
+        assert!(profile.busy.p50 <= profile.busy.p99);
+    }
+
+    #[test]
+    fn cancelled_sends_never_arrive_in_part() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9440".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+
+        assert!(client.send_cancellable(&[1; 16]).is_err());
+        let snapshot = vec![1; FRAGMENT_SIZE * 20];
+        let handle = client.send_cancellable(&snapshot).unwrap();
+        client.cancel_send(handle).unwrap();
+        client.send(&[2; 16], SendType::Reliable).unwrap();
+
+        //the snapshot can be delivered before the cancel reaches the server, but never in part
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(_, data, _)) if data == [2; 16] => break,
+                Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, snapshot),
+                event => panic!("expected the messages, got {event:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn superseding_sends_end_with_the_latest() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9441".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let mut buf = vec![0; 16];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+
+        assert!(client
+            .send_superseding(1, &vec![0; FRAGMENT_SIZE * 2])
+            .is_err());
+        for i in 0..10 {
+            client.send_superseding(1, &[i]).unwrap();
+        }
+
+        //the replaced ones can arrive too when they went out before their replacement
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(_, [9], _)) => break,
+                Some(ServerEvent::Receive(_, [_], _)) => {}
+                event => panic!("expected the latest value, got {event:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn reconnects_wait_for_the_cooldown() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9442".parse().unwrap();
+        let cooldown = Duration::from_millis(300);
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                reconnect_cooldown: cooldown,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut peer = ScriptedPeer::bind("127.0.0.1:9443".parse().unwrap(), server_addr).unwrap();
+        let mut buf = vec![0; 16];
+
+        peer.handshake(0, Duration::from_secs(1)).unwrap();
+        for _ in 0..3 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::NewConnection(_)) => {}
+                event => panic!("expected the new connection, got {event:?}"),
+            }
+            peer.send_disconnect(&DisconnectReason::new(DisconnectCode::UserQuit))
+                .unwrap();
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::ConnectionLost(..)) => {}
+                event => panic!("expected the lost connection, got {event:?}"),
+            }
+
+            //the answers to the challenge are ignored until the cooldown is over
+            let closed_at = Instant::now();
+            while peer.handshake(0, Duration::from_millis(50)).is_err() {
+                assert!(closed_at.elapsed() < Duration::from_secs(2));
+            }
+            assert!(closed_at.elapsed() >= cooldown - Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn unacked_sends_show_up_in_the_recent_events() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9444".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let peer_addr = "127.0.0.1:9445".parse().unwrap();
+        let mut peer = ScriptedPeer::bind(peer_addr, server_addr).unwrap();
+        peer.handshake(0, Duration::from_secs(1)).unwrap();
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(connection_id)) => connection_id,
+            event => panic!("expected the new connection, got {event:?}"),
+        };
+
+        //the peer never acks the message
+        server
+            .send(peer_addr, &[1, 2, 3], SendType::Reliable)
+            .unwrap();
+        let mut events = Vec::new();
+        wait_until(Duration::from_secs(5), || {
+            events = server.recent_events(connection_id).unwrap();
+            events
+                .iter()
+                .any(|event| event.kind == RecentEventKind::Resend(0))
+        });
+        assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+
+        let unknown = ConnectionId {
+            index: 7,
+            generation: connection_id.generation,
+        };
+        assert!(server.recent_events(unknown).is_err());
+    }
+
+    #[test]
+    fn clients_connect_to_hosts() {
+        let _ = env_logger::try_init();
+
+        let server = Server::start("127.0.0.1:9453".parse().unwrap(), 2).unwrap();
+        let mut buf = vec![0; 16];
+        //localhost can resolve to ::1 first, the addresses are raced
+        let _client = Client::connect_to_host("localhost:9453", Default::default()).unwrap();
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+
+        let mut pending = Client::start_connect_to_host("127.0.0.1:9453", Default::default());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !matches!(
+            pending.poll(Duration::from_millis(100)).unwrap(),
+            Some(ConnectEvent::Accepted(_))
+        ) {
+            assert!(Instant::now() < deadline);
+        }
+        assert!(pending.into_client().is_some());
+
+        let mut unresolved = Client::start_connect_to_host("no port", Default::default());
+        assert_eq!(
+            unresolved.poll(Duration::from_secs(5)).unwrap(),
+            Some(ConnectEvent::Denied(ConnectFailure::Unresolved))
+        );
+        assert!(Client::connect_to_host("no port", Default::default()).is_err());
+    }
+
+    #[test]
+    fn handshakes_ignore_datagrams_that_arent_the_reply() {
+        use crate::proto::challenge::{ChallengeScheme, SipHashChallenge};
+
+        let _ = env_logger::try_init();
+
+        let control = |packet_type: PacketType, fields: &[&[u8]]| {
+            let mut packet = ProtocolId::DEFAULT.0.to_vec();
+            packet.push(packet_type as u8);
+            fields
+                .iter()
+                .for_each(|field| packet.extend_from_slice(field));
+            packet
+        };
+
+        //a server that sends junk and stale packets before every reply
+        let server = UdpSocket::bind("127.0.0.1:9454").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let scripted = thread::spawn(move || {
+            let mut buf = vec![0; 64];
+            let (len, client_addr) = server.recv_from(&mut buf).unwrap();
+            let request = &buf[PROTOCOL_ID_SIZE..len];
+            assert_eq!(request[0], PacketType::ConnectionRequest as u8);
+            let client_salt = u64::from_le_bytes(request[1..9].try_into().unwrap());
+
+            let mut junk = ProtocolId::DEFAULT.0.to_vec();
+            junk.push(0xff);
+            let challenge_of = |client_salt: u64| {
+                let client_tag = SipHashChallenge.client_tag(client_salt);
+                control(
+                    PacketType::Challenge,
+                    &[&client_tag.to_le_bytes(), &11_u64.to_le_bytes()],
+                )
+            };
+            let challenge = challenge_of(client_salt);
+            for packet in [&junk, &challenge_of(client_salt + 1), &challenge] {
+                server.send_to(packet, client_addr).unwrap();
+            }
+
+            server.recv(&mut buf).unwrap();
+            assert_eq!(buf[PROTOCOL_ID_SIZE], PacketType::ChallengeResponse as u8);
+            let accepted = control(
+                PacketType::ConnectionAccepted,
+                &[&5_u32.to_le_bytes(), &[0]],
+            );
+            for packet in [&challenge, &junk, &accepted] {
+                server.send_to(packet, client_addr).unwrap();
+            }
+        });
+
+        //a single attempt, the ignored datagrams can't have cost one
+        let config = ClientConfig {
+            handshake: HandshakeConfig {
+                reply_timeout: Duration::from_secs(2),
+                attempts: 1,
+                rounds: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Client::connect_to_host("127.0.0.1:9454", config).is_ok());
+        scripted.join().unwrap();
+    }
+
+    #[test]
+    fn keep_alive_payloads_have_their_own_event() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9455".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_with_config(
+            "127.0.0.1:9456".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                channel: ChannelConfig {
+                    keep_alive_interval: Duration::from_millis(20),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.set_keepalive_payload(&[3, 4]).unwrap();
+
+        let mut buf = vec![0; 16];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::KeepAlivePayload(_, [3, 4], _)) => break,
+                Some(ServerEvent::Receive(..)) => panic!("the keep alive payload was received"),
+                _ => assert!(Instant::now() < deadline),
+            }
+        }
+    }
+
+    #[test]
+    fn commands_over_the_budget_wait_for_the_next_update() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9457".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_with_config(
+            "127.0.0.1:9458".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                max_commands_per_tick: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for i in 0..20 {
+            client.send(&[i], SendType::Reliable).unwrap();
+        }
+
+        let mut buf = vec![0; 16];
+        let mut received = Vec::new();
+        while received.len() < 20 {
+            match server.read(&mut buf, Duration::from_secs(5)) {
+                Ok(Some(ServerEvent::Receive(_, data, _))) => received.push(data[0]),
+                Ok(Some(ServerEvent::NewConnection(_))) => {}
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shutdown_disconnects_the_clients() {
+        let _ = env_logger::try_init();
+
+        for (workers, server_port, client_port) in [(1, 9459, 9460), (2, 9461, 9462)] {
+            let server_addr = std::net::SocketAddr::from(([127, 0, 0, 1], server_port));
+            let server = Server::start_with_config(
+                server_addr,
+                ServerConfig {
+                    workers,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let client =
+                Client::connect(([127, 0, 0, 1], client_port).into(), server_addr).unwrap();
+            let mut buf = vec![0; 16];
+            assert!(matches!(
+                server.read(&mut buf, Duration::from_secs(5)),
+                Ok(Some(ServerEvent::NewConnection(_)))
+            ));
+
+            server.shutdown(Duration::from_secs(5)).unwrap();
+            assert_eq!(
+                client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+                ClientEvent::Disconnected(DisconnectReason::new(DisconnectCode::Shutdown))
+            );
+            //the port was released
+            Server::start(server_addr, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn silent_connections_are_closed_as_idle() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9463".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                channel: ChannelConfig {
+                    idle_timeout: Some(Duration::from_millis(300)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        //the client doesn't send its keep alives in time
+        let client = Client::connect_with_config(
+            "127.0.0.1:9464".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                channel: ChannelConfig {
+                    keep_alive_interval: Duration::from_secs(5),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)) {
+            Ok(Some(ServerEvent::NewConnection(connection_id))) => connection_id,
+            event => panic!("expected a new connection, got {event:?}"),
+        };
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::ConnectionLost(
+                connection_id,
+                DisconnectReason::new(DisconnectCode::Idle)
+            ))
+        );
+        assert_eq!(
+            client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+            ClientEvent::Disconnected(DisconnectReason::new(DisconnectCode::Idle))
+        );
+    }
+
+    #[test]
+    fn start_errors_are_returned() {
+        let _ = env_logger::try_init();
+
+        let taken_addr: std::net::SocketAddr = "127.0.0.1:9451".parse().unwrap();
+        let _taken = std::net::UdpSocket::bind(taken_addr).unwrap();
+        let e = Server::start(taken_addr, 1).err().unwrap();
+        assert_eq!(
+            e.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+        assert!(format!("{e:#}").contains("127.0.0.1:9451"));
+
+        //the socket options are applied on the thread of the process
+        let started = Instant::now();
+        let e = Server::start_with_config(
+            "127.0.0.1:0".parse().unwrap(),
+            ServerConfig {
+                socket: SocketConfig {
+                    dscp: Some(64),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{e:#}").contains("DSCP"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let server_addr = "127.0.0.1:9452".parse().unwrap();
+        let e = Client::connect(taken_addr, server_addr).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(e.to_string().contains("127.0.0.1:9451"));
+    }
+
+    #[test]
+    fn shared_sends_reach_every_connection() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9450".parse().unwrap();
+        let server = Server::start(server_addr, 2).unwrap();
+        let clients = [
+            Client::connect_to(server_addr).unwrap(),
+            Client::connect_to(server_addr).unwrap(),
+        ];
+        let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+        for _ in &clients {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::NewConnection(_)) => {}
+                event => panic!("expected the new connection, got {event:?}"),
+            }
+        }
+
+        let snapshot: Arc<[u8]> = (0..FRAGMENT_SIZE * 2).map(|i| i as u8).collect();
+        for client in &clients {
+            server
+                .send_shared(client.local_addr(), snapshot.clone(), SendType::Reliable)
+                .unwrap();
+        }
+        for client in &clients {
+            assert_eq!(
+                client.read(&mut buf, Duration::from_secs(5)).unwrap(),
+                &snapshot[..]
+            );
+        }
+
+        clients[0]
+            .send_shared(Arc::from(&[7][..]), SendType::Reliable)
+            .unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, [7]),
+            event => panic!("expected the message, got {event:?}"),
+        }
+    }
+
+    #[test]
+    fn packets_over_the_budget_arrive_on_later_ticks() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9449".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                max_reads_per_tick: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let mut buf = vec![0; 16];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            event => panic!("expected the new connection, got {event:?}"),
+        }
+
+        for i in 0..20u8 {
+            client.send(&[i], SendType::Reliable).unwrap();
+        }
+        for i in 0..20u8 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, [i]),
+                event => panic!("expected message {i}, got {event:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn connections_follow_their_client_to_another_port() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9446".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                address_migration: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let peer_addr = "127.0.0.1:9447".parse().unwrap();
+        let mut peer = ScriptedPeer::bind(peer_addr, server_addr).unwrap();
+        peer.handshake(0, Duration::from_secs(1)).unwrap();
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(connection_id)) => connection_id,
+            event => panic!("expected the new connection, got {event:?}"),
+        };
+        peer.send_reliable(&[1]).unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, [1]),
+            event => panic!("expected the message, got {event:?}"),
+        }
+
+        let rebound = "127.0.0.1:9448".parse().unwrap();
+        peer.rebind(rebound).unwrap();
+        let seq = peer.send_reliable(&[2]).unwrap();
+        //the connection moves once the message was read
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(id, data, _)) => {
+                assert_eq!((id, data), (connection_id, &[2][..]))
+            }
+            event => panic!("expected the message, got {event:?}"),
+        }
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::AddressChanged(id, old, new)) => {
+                assert_eq!((id, old, new), (connection_id, peer_addr, rebound))
+            }
+            event => panic!("expected the address change, got {event:?}"),
+        }
+
+        //the acks and the sends go to the new address
+        peer.wait_for_ack(seq, Duration::from_secs(1)).unwrap();
+        server.send(rebound, &[3], SendType::Reliable).unwrap();
+        assert_eq!(peer.recv_message(Duration::from_secs(1)).unwrap(), [3][..]);
+    }
+
+    #[test]
+    fn connections_follow_their_client_across_workers() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9469".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                address_migration: true,
+                workers: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut peer_addr = "127.0.0.1:9470".parse().unwrap();
+        let mut peer = ScriptedPeer::bind(peer_addr, server_addr).unwrap();
+        peer.handshake(0, Duration::from_secs(1)).unwrap();
+        let mut buf = vec![0; 16];
+        let connection_id = match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(connection_id)) => connection_id,
+            event => panic!("expected the new connection, got {event:?}"),
+        };
+        peer.send_reliable(&[1]).unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, [1]),
+            event => panic!("expected the message, got {event:?}"),
+        }
+
+        //some of the ports are split to the other worker
+        for port in 9471..9477u16 {
+            let rebound = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            peer.rebind(rebound).unwrap();
+            let seq = peer.send_reliable(&[port as u8]).unwrap();
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(id, data, _)) => {
+                    assert_eq!((id, data), (connection_id, &[port as u8][..]))
+                }
+                event => panic!("expected the message, got {event:?}"),
+            }
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::AddressChanged(id, old, new)) => {
+                    assert_eq!((id, old, new), (connection_id, peer_addr, rebound))
+                }
+                event => panic!("expected the address change, got {event:?}"),
+            }
+            peer.wait_for_ack(seq, Duration::from_secs(1)).unwrap();
+            server.send(rebound, &[3], SendType::Reliable).unwrap();
+            assert_eq!(peer.recv_message(Duration::from_secs(1)).unwrap(), [3][..]);
+            peer_addr = rebound;
+        }
+    }
+
+    #[cfg(feature = "alloc-counters")]
+    #[test]
+    fn packet_paths_count_their_allocations() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9439".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_to(server_addr).unwrap();
+        let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::NewConnection(_)) => {}
+            _ => panic!("expected the new connection"),
+        }
+
+        let before = allocation_stats();
+        let message = vec![7; FRAGMENT_SIZE * 3];
+        client.send(&message, SendType::Reliable).unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, message),
+            _ => panic!("expected the message"),
+        }
+        let after = allocation_stats();
+        assert!(after.send > before.send);
+        assert!(after.receive > before.receive);
+        assert!(after.reassembly > before.reassembly);
+    }
+
+    #[test]
+    fn send_queue_is_tracked() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9391".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9392".parse().unwrap(), server_addr).unwrap();
+
+        for _ in 0..10 {
+            client.send(&[1, 2, 3], SendType::Reliable).unwrap();
+        }
+        let mut buf = vec![0; 16];
+        let mut received = 0;
+        while received < 10 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Receive(..)) => received += 1,
+                Some(_) => {}
+                None => panic!("timed out waiting for the messages"),
+            }
+        }
+
+        //everything went out, only the high watermark remains
+        let stats = client.send_queue_stats();
+        assert!(stats.max_depth >= 1);
+        assert_eq!(stats.expired, 0);
+        assert_eq!(server.send_queue_stats().expired, 0);
+    }
+
+    #[test]
+    fn file_is_transferred_to_the_server() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9352".parse().unwrap();
+        let client_addr = "127.0.0.1:9353".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect(client_addr, server_addr).unwrap();
+
+        //small chunks so the parallel tests don't overflow the socket buffers
+        let file = generate_random_u8_vector(50_000);
+        let mut outgoing = OutgoingTransfer::new(1, "map.bin", file.clone(), 4000).unwrap();
+        client
+            .send(&outgoing.offer().write(), SendType::Reliable)
+            .unwrap();
+
+        let receiver = thread::spawn(move || {
+            let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+            let mut incoming = None;
+            loop {
+                let data = match server.read(&mut buf, Duration::from_secs(5)) {
+                    Ok(Some(ServerEvent::Receive(_, data, _))) => data,
+                    Ok(Some(ServerEvent::NewConnection(_) | ServerEvent::QualityChanged(..))) => {
+                        continue
+                    }
+                    event => panic!("unexpected event {event:?}"),
+                };
+                let reply = match TransferMessage::read(data).unwrap() {
+                    TransferMessage::Offer(offer) => {
+                        let transfer = incoming.insert(IncomingTransfer::new(offer));
+                        transfer.accept()
+                    }
+                    message => match incoming.as_mut().unwrap().handle(message).unwrap() {
+                        Some(reply) => reply,
+                        None => continue,
+                    },
+                };
+                server
+                    .send(client_addr, &reply.write(), SendType::Reliable)
+                    .unwrap();
+
+                //the server is kept alive until the client read the completion
+                let transfer = incoming.as_ref().unwrap();
+                if transfer.state() == TransferState::Completed {
+                    return (server, incoming.unwrap().into_data());
+                }
+            }
+        });
+
+        let mut buf = vec![0; MAX_FRAGMENT_SIZE];
+        while outgoing.state() != TransferState::Completed {
+            let message = client.read(&mut buf, Duration::from_secs(5)).unwrap();
+            outgoing
+                .handle(TransferMessage::read(message).unwrap())
+                .unwrap();
+            while let Some(chunk) = outgoing.next_chunk() {
+                client.send(&chunk.write(), SendType::Reliable).unwrap();
+            }
+        }
+
+        assert_eq!(outgoing.progress(), (50_000, 50_000));
+        assert_eq!(receiver.join().unwrap().1, file);
+    }
+
+    #[test]
+    fn requests_are_replied_to() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9354".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9355".parse().unwrap(), server_addr).unwrap();
+
+        let large = generate_random_u8_vector(FRAGMENT_SIZE * 2);
+        let login = client.request(b"login").unwrap();
+        let inventory = client.request(&large).unwrap();
+        //a message of the game is never taken for a request, whatever it starts with
+        client.send(b"RQST\x01\0\0\0", SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; FRAGMENT_SIZE * 4];
+        let mut requests = Vec::new();
+        let mut messages = Vec::new();
+        while requests.len() + messages.len() < 3 {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::Request(handle, data, _)) => {
+                    requests.push(data.to_vec());
+                    server.reply(handle, &[data.len() as u8]).unwrap();
+                }
+                Some(ServerEvent::Receive(_, data, _)) => messages.push(data.to_vec()),
+                _ => {}
+            }
+        }
+
+        requests.sort_by_key(|request| request.len());
+        assert_eq!(requests, [b"login".to_vec(), large]);
+        assert_eq!(messages, [b"RQST\x01\0\0\0".to_vec()]);
+        assert_eq!(login.wait(Duration::from_secs(5)).unwrap(), Some(vec![5]));
+        assert_eq!(
+            inventory.wait(Duration::from_secs(5)).unwrap(),
+            Some(vec![(FRAGMENT_SIZE * 2) as u8])
+        );
+        assert_ne!(login.request_id(), inventory.request_id());
+    }
+
+    #[test]
+    fn unanswered_requests_expire() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9465".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect_with_config(
+            "127.0.0.1:9466".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                request_timeout: Duration::from_millis(200),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let handle = client.request(b"ping").unwrap();
+        let mut buf = vec![0; 64];
+        let request = loop {
+            if let Some(ServerEvent::Request(request, ..)) =
+                server.read(&mut buf, Duration::from_secs(5)).unwrap()
+            {
+                break request;
+            }
+        };
+
+        //the client forgot the request, the late reply is dropped
+        assert!(handle.wait(Duration::from_secs(5)).is_err());
+        server.reply(request, b"pong").unwrap();
+    }
+
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn rpc_calls_are_answered() {
+        use crate::rpc::{net_rpc, RpcServer};
+
+        #[net_rpc]
+        trait Scores {
+            fn add(&self, a: u32, b: u32) -> u32;
+            fn name(&self) -> String;
+        }
+
+        struct ScoreService;
+
+        impl Scores for ScoreService {
+            fn add(&self, a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            fn name(&self) -> String {
+                "scores".to_owned()
+            }
+        }
+
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9356".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9357".parse().unwrap(), server_addr).unwrap();
+
+        let scores = ScoresClient::new(&client);
+        let sum = scores.add(2, 3).unwrap();
+        let name = scores.name().unwrap();
+
+        let mut rpc = RpcServer::new();
+        rpc.register(ScoresServer(ScoreService));
+        let mut buf = vec![0; 256];
+        let mut handled = 0;
+        while handled < 2 {
+            if let Some(ServerEvent::Request(handle, call, _)) =
+                server.read(&mut buf, Duration::from_secs(5)).unwrap()
+            {
+                rpc.handle(&server, handle, call).unwrap();
+                handled += 1;
+            }
+        }
+
+        assert_eq!(sum.wait(Duration::from_secs(5)).unwrap(), Some(5));
+        assert_eq!(
+            name.wait(Duration::from_secs(5)).unwrap().as_deref(),
+            Some("scores")
+        );
+    }
+
+    #[test]
+    fn broadcasts_are_filtered_by_interest() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9358".parse().unwrap();
+        let server = Server::start(server_addr, 2).unwrap();
+        let near = Client::connect("127.0.0.1:9359".parse().unwrap(), server_addr).unwrap();
+        let far = Client::connect("127.0.0.1:9360".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let mut connection_ids = Vec::new();
+        while connection_ids.len() < 2 {
+            if let Some(ServerEvent::NewConnection(connection_id)) =
+                server.read(&mut buf, Duration::from_secs(5)).unwrap()
+            {
+                connection_ids.push(connection_id);
+            }
+        }
+        server.set_interest(connection_ids[0], [1, 2]).unwrap();
+        server.set_interest(connection_ids[1], [2]).unwrap();
+
+        server
+            .broadcast_filtered(1, &[1], SendType::Reliable)
+            .unwrap();
+        server
+            .broadcast_filtered(2, &[2], SendType::Reliable)
+            .unwrap();
+        server
+            .broadcast_filtered(3, &[3], SendType::Reliable)
+            .unwrap();
+        server
+            .broadcast_filtered(2, &[4], SendType::Reliable)
+            .unwrap();
+
+        //the reliable messages can arrive in any order
+        let mut received = |client: &Client, count| {
+            let mut messages: Vec<u8> = (0..count)
+                .map(|_| client.read(&mut buf, Duration::from_secs(5)).unwrap()[0])
+                .collect();
+            messages.sort();
+            messages
+        };
+        assert_eq!(received(&near, 3), [1, 2, 4]);
+        assert_eq!(received(&far, 2), [2, 4]);
+    }
+
+    #[test]
+    fn repeated_sends_are_scheduled() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9361".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9362".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        assert!(server
+            .send_repeated(connection_id, &[1], Duration::ZERO, SendType::Unreliable)
+            .is_err());
+        let beacon = server
+            .send_repeated(
+                connection_id,
+                &[7, 7],
+                Duration::from_millis(20),
+                SendType::Unreliable,
+            )
+            .unwrap();
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(
+                client.read(&mut buf, Duration::from_secs(5)).unwrap(),
+                [7, 7]
+            );
+        }
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        server.cancel_repeated(beacon).unwrap();
+    }
+
+    #[test]
+    fn paused_connections_hold_payloads() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9363".parse().unwrap();
+        let client_addr = "127.0.0.1:9364".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        server.pause(connection_id).unwrap();
+        //the dump goes through the process after the pause, it's applied once it returns
+        server.debug_dump(connection_id).unwrap();
+
+        client.send(&[1], SendType::Reliable).unwrap();
+        server.send(client_addr, &[2], SendType::Reliable).unwrap();
+        server
+            .send(client_addr, &[3], SendType::Unreliable)
+            .unwrap();
+        assert_eq!(
+            server.read(&mut buf, Duration::from_millis(300)).unwrap(),
+            None
+        );
+
+        server.resume(connection_id).unwrap();
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::Receive(_, [1], _)))
+        ));
+        //the unreliable send was dropped while paused
+        assert_eq!(client.read(&mut buf, Duration::from_secs(5)).unwrap(), [2]);
+        server.send(client_addr, &[4], SendType::Reliable).unwrap();
+        assert_eq!(client.read(&mut buf, Duration::from_secs(5)).unwrap(), [4]);
+    }
+
+    #[test]
+    fn payloads_over_the_held_read_limits_are_dropped() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9467".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                held_read_limits: HeldReadLimits {
+                    max_messages: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = Client::connect("127.0.0.1:9468".parse().unwrap(), server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        server.pause(connection_id).unwrap();
+        //the dump goes through the process after the pause, it's applied once it returns
+        server.debug_dump(connection_id).unwrap();
+
+        for value in 1..=3 {
+            client.send(&[value], SendType::Reliable).unwrap();
+        }
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::LimitExceeded(id, Limit::HeldReads, 1))) if id == connection_id
+        ));
+
+        server.resume(connection_id).unwrap();
+        let mut received = Vec::new();
+        while let Some(event) = server.read(&mut buf, Duration::from_millis(300)).unwrap() {
+            if let ServerEvent::Receive(_, data, _) = event {
+                received.push(data[0]);
+            }
+        }
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[test]
+    fn quality_changes_are_reported() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9365".parse().unwrap();
+        let client_addr = "127.0.0.1:9366".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                channel: ChannelConfig {
+                    quality: QualityThresholds {
+                        interval: Duration::from_millis(100),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let _client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        server
+            .set_debug_conditions(connection_id, Duration::ZERO, Duration::ZERO, 0.5)
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let quality = loop {
+            assert!(Instant::now() < deadline, "expected a quality change");
+            server.send(client_addr, &[1], SendType::Reliable).unwrap();
+            if let Ok(Some(ServerEvent::QualityChanged(id, quality))) =
+                server.read(&mut buf, Duration::from_millis(20))
+            {
+                assert_eq!(id, connection_id);
+                break quality;
+            }
+        };
+        assert_ne!(quality, ConnectionQuality::Good);
+    }
+
+    #[test]
+    fn send_rate_changes_are_reported() {
+        let _ = env_logger::try_init();
+
+        let (rate_tx, rate_rx) = crossbeam_channel::unbounded();
+        let server_addr = "127.0.0.1:9367".parse().unwrap();
+        let client_addr = "127.0.0.1:9368".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                channel: ChannelConfig {
+                    quality: QualityThresholds {
+                        interval: Duration::from_millis(100),
+                        ..Default::default()
+                    },
+                    send_rate: SendRateConfig {
+                        on_change: Some(Arc::new(move |addr, rate| {
+                            rate_tx.send((addr, rate)).unwrap();
+                        })),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let _client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        //published on the next update
+        wait_until(Duration::from_secs(5), || {
+            server.recommended_send_rate(connection_id).is_some()
+        });
+        assert_eq!(server.recommended_send_rate(connection_id), Some(60));
+        server
+            .set_debug_conditions(connection_id, Duration::ZERO, Duration::ZERO, 0.5)
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (addr, rate) = loop {
+            assert!(Instant::now() < deadline, "expected a send rate change");
+            server.send(client_addr, &[1], SendType::Reliable).unwrap();
+            if let Ok(change) = rate_rx.recv_timeout(Duration::from_millis(20)) {
+                break change;
+            }
+        };
+        assert_eq!(addr, client_addr);
+        assert!(rate < 60);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn stuck_reliable_packets_are_dumped() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9369".parse().unwrap();
+        let client_addr = "127.0.0.1:9370".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let _client = Client::connect(client_addr, server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+        //nothing gets through so the message is never acked
+        server
+            .set_debug_conditions(connection_id, Duration::ZERO, Duration::ZERO, 1.0)
+            .unwrap();
+        server
+            .send(client_addr, &[1, 2, 3], SendType::Reliable)
+            .unwrap();
+
+        let state = server.debug_dump(connection_id).unwrap();
+        assert_eq!(state.outstanding.len(), 1);
+        assert_eq!(state.outstanding[0].seq, state.local_seq.wrapping_sub(1));
+        assert!(state.reliable_fragments.is_empty());
+
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["local_seq"], state.local_seq);
+        assert_eq!(json["outstanding"][0]["seq"], state.outstanding[0].seq);
+
+        let stale_id = ConnectionId {
+            generation: connection_id.generation.wrapping_add(1),
+            ..connection_id
+        };
+        assert!(server.debug_dump(stale_id).is_err());
+    }
+
+    #[test]
+    fn refused_connection_is_disconnected() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9371".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9372".parse().unwrap(), server_addr).unwrap();
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        //nothing listens on the address anymore once the process of the server stopped
+        drop(server);
+        wait_until(Duration::from_secs(5), || {
+            UdpSocket::bind(server_addr).is_ok()
+        });
+
+        client.send(&[1], SendType::Reliable).unwrap();
+        let Ok(ClientEvent::SocketError(e)) = client.read_event(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a socket error");
+        };
+        assert_eq!(e.kind, io::ErrorKind::ConnectionRefused);
+        assert_eq!(e.recovery, SocketRecovery::Dropped);
+        assert_eq!(
+            client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+            ClientEvent::Disconnected(DisconnectReason::new(DisconnectCode::Refused))
+        );
+    }
+
+    #[test]
+    fn restarted_server_disconnects_its_old_clients() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9375".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        let client = Client::connect("127.0.0.1:9376".parse().unwrap(), server_addr).unwrap();
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        //the process of the old server releases the port shortly after the drop
+        drop(server);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let _server = loop {
+            match Server::start(server_addr, 1) {
+                Ok(server) => break server,
+                Err(_) if Instant::now() < deadline => sleep(Duration::from_millis(10)),
+                Err(e) => panic!("failed restarting the server: {e}"),
+            }
+        };
+
+        //the new server doesn't know the session key of the client
+        client.send(&[1], SendType::Reliable).unwrap();
+        assert_eq!(
+            client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+            ClientEvent::Disconnected(DisconnectReason::new(DisconnectCode::ServerRestarted))
+        );
+    }
+
+    #[test]
+    fn malformed_packets_kick_the_connection() {
+        use crate::proto::{
+            challenge::{ChallengeScheme, SipHashChallenge},
+            header::Header,
+        };
+
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9377".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                malformed_packet_limit: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        //a raw handshake, so packets of another session can be sent from the connected address
+        let socket = UdpSocket::bind("127.0.0.1:9378").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client_salt = 7_u64;
+        let mut request = ProtocolId::DEFAULT.0.to_vec();
+        request.push(PacketType::ConnectionRequest as u8);
+        request.extend_from_slice(&client_salt.to_le_bytes());
+        request.push(0);
+        socket.send_to(&request, server_addr).unwrap();
+
+        let mut buf = vec![0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        let challenge = &buf[PROTOCOL_ID_SIZE..len];
+        assert_eq!(challenge[0], PacketType::Challenge as u8);
+        let server_salt = u64::from_le_bytes(challenge[9..17].try_into().unwrap());
+        let session_key = SipHashChallenge.session_key(client_salt, server_salt);
+        let mut response = ProtocolId::DEFAULT.0.to_vec();
+        response.push(PacketType::ChallengeResponse as u8);
+        response.extend_from_slice(&SipHashChallenge.response(session_key).to_le_bytes());
+        socket.send_to(&response, server_addr).unwrap();
+
+        let Ok(Some(ServerEvent::NewConnection(connection_id))) =
+            server.read(&mut buf, Duration::from_secs(5))
+        else {
+            panic!("expected a new connection");
+        };
+
+        for count in 1..=3 {
+            let mut packet = ProtocolId::DEFAULT.0.to_vec();
+            Header::new_keep_alive(count as u16, session_key + 1).write_into(&mut packet);
+            socket.send_to(&packet, server_addr).unwrap();
+            assert_eq!(
+                server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+                Some(ServerEvent::MalformedPacket(connection_id, count))
+            );
+        }
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::ConnectionLost(
+                connection_id,
+                DisconnectReason::with_message(
+                    DisconnectCode::Kicked,
+                    "too many malformed packets"
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn workers_split_the_connections() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9379".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                max_clients: 4,
+                workers: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                let addr = format!("127.0.0.1:{}", 9380 + i).parse().unwrap();
+                Client::connect(addr, server_addr).unwrap()
+            })
+            .collect();
+        for (i, client) in clients.iter().enumerate() {
+            client.send(&[i as u8], SendType::Reliable).unwrap();
+        }
+
+        let mut buf = vec![0; 64];
+        let mut connections = Vec::new();
+        let mut received = Vec::new();
+        while received.len() < clients.len() {
+            match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+                Some(ServerEvent::NewConnection(connection_id)) => connections.push(connection_id),
+                Some(ServerEvent::Receive(connection_id, data, _)) => {
+                    received.push((connection_id, data[0]))
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        connections.sort();
+        connections.dedup();
+        assert_eq!(connections.len(), clients.len());
+
+        //commands by connection id reach the worker that has the connection
+        for (connection_id, i) in received {
+            server
+                .disconnect(
+                    connection_id,
+                    DisconnectReason::with_message(DisconnectCode::Kicked, &i.to_string()),
+                )
+                .unwrap();
+        }
+        for (i, client) in clients.iter().enumerate() {
+            assert_eq!(
+                client.read_event(&mut buf, Duration::from_secs(5)).unwrap(),
+                ClientEvent::Disconnected(DisconnectReason::with_message(
+                    DisconnectCode::Kicked,
+                    &i.to_string()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn connecting_to_a_closed_port_fails_fast() {
+        let _ = env_logger::try_init();
+
+        let start = Instant::now();
+        let result = Client::connect(
+            "127.0.0.1:9374".parse().unwrap(),
+            "127.0.0.1:9373".parse().unwrap(),
+        );
+
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+        //the handshake gives up after the first refusal instead of retrying
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn commands_wake_the_process() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9339".parse().unwrap();
+        let server = Server::start(server_addr, 1).unwrap();
+        //without the wakeup the send would wait for the next update of the client
+        let client = Client::connect_with_config(
+            "127.0.0.1:9340".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                channel: ChannelConfig {
+                    update_interval: Duration::from_secs(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)),
+            Ok(Some(ServerEvent::NewConnection(_)))
+        ));
+        //the client waits in its poll for the next update meanwhile
+        assert_eq!(
+            server.read(&mut buf, Duration::from_millis(100)).unwrap(),
+            None
+        );
+
+        let sent_at = Instant::now();
+        client.send(&[1], SendType::Reliable).unwrap();
+        match server.read(&mut buf, Duration::from_secs(5)) {
+            Ok(Some(ServerEvent::Receive(_, [1], received_at))) => {
+                assert!(received_at - sent_at < Duration::from_millis(500));
+            }
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_packets_are_counted() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9341".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                protocol_error_interval: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:9342").unwrap();
+        for packet in [&b"GET / HTTP/1.1"[..], &[1, 2], &[0; 32]] {
+            socket.send_to(packet, server_addr).unwrap();
+        }
+
+        let mut buf = vec![0; 64];
+        assert_eq!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::ProtocolError(socket.local_addr().unwrap(), 1))
+        );
+        //the other two are within the interval
+        assert_eq!(
+            server.read(&mut buf, Duration::from_millis(200)).unwrap(),
+            None
+        );
+
+        let stats = server.invalid_packet_stats();
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.sources.len(), 1);
+        assert_eq!(stats.sources[0].count, 3);
+    }
+
+    #[test]
+    fn other_protocol_ids_are_ignored() {
+        let _ = env_logger::try_init();
+
+        let protocol_id = ProtocolId::from_game("arena", "1.0");
+        let server_addr = "127.0.0.1:9343".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                protocol_id,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        //a packet of another game is dropped before it's parsed
+        let socket = UdpSocket::bind("127.0.0.1:9344").unwrap();
+        let mut request = ProtocolId::DEFAULT.0.to_vec();
+        request.push(PacketType::ServerInfoRequest as u8);
+        request.resize(request.len() + 127, 0);
+        socket.send_to(&request, server_addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(socket.recv_from(&mut [0; 64]).is_err());
+        assert_eq!(server.invalid_packet_stats().total, 1);
+
+        let client = Client::connect_with_config(
+            "127.0.0.1:9345".parse().unwrap(),
+            server_addr,
+            ClientConfig {
+                protocol_id,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send(b"hello", SendType::Reliable).unwrap();
+
+        let mut buf = vec![0; 64];
+        assert!(matches!(
+            server.read(&mut buf, Duration::from_secs(5)).unwrap(),
+            Some(ServerEvent::NewConnection(_))
+        ));
+        match server.read(&mut buf, Duration::from_secs(5)).unwrap() {
+            Some(ServerEvent::Receive(_, data, _)) => assert_eq!(data, b"hello"),
+            _ => panic!("expected the message of the client"),
+        }
+    }
+
+    #[test]
+    fn server_info_query() {
+        let _ = env_logger::try_init();
+
+        let server_addr = "127.0.0.1:9310".parse().unwrap();
+        let server = Server::start(server_addr, 8).unwrap();
+        server.set_server_info_payload(b"arena").unwrap();
+
+        //the payload is set on the server thread before the handshake of the client is done
+        let _client = Client::connect("127.0.0.1:9311".parse().unwrap(), server_addr).unwrap();
+
+        let info = query_server_info(
+            "127.0.0.1:9312".parse().unwrap(),
+            server_addr,
+            ProtocolId::DEFAULT,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(info.players, 1);
+        assert_eq!(info.max_players, 8);
+        assert_eq!(info.payload, b"arena");
+    }
+
+    #[test]
+    fn master_server_list() {
+        let _ = env_logger::try_init();
+
+        let mut master = MasterServer::start(
+            "127.0.0.1:9320".parse().unwrap(),
+            ProtocolId::DEFAULT,
+            Duration::from_secs(10),
+            16,
+        )
+        .unwrap();
+
+        let server_addr = "127.0.0.1:9321".parse().unwrap();
+        let server = Server::start_with_config(
+            server_addr,
+            ServerConfig {
+                max_clients: 4,
+                master_server: Some(master.addr),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        server.set_server_info_payload(b"lobby").unwrap();
+        //the first heartbeat is sent on the next update, the list is empty until it arrives
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let list = loop {
+            let list = fetch_server_list(
+                "127.0.0.1:9322".parse().unwrap(),
+                master.addr,
+                ProtocolId::DEFAULT,
+                Duration::from_secs(5),
+            )
+            .unwrap();
+            if !list.is_empty() || Instant::now() >= deadline {
+                break list;
+            }
+            sleep(Duration::from_millis(50));
+        };
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].addr, server_addr);
+        assert_eq!(list[0].info.max_players, 4);
+
+        master.stop();
+        assert!(fetch_server_list(
+            "127.0.0.1:9322".parse().unwrap(),
+            master.addr,
+            ProtocolId::DEFAULT,
+            Duration::from_millis(200),
+        )
+        .is_err());
+    }
+
+    //for the state the process publishes on its updates, there is no event to wait for
+    fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + timeout;
+        while !condition() {
+            assert!(
+                Instant::now() < deadline,
+                "the condition didn't hold in {timeout:?}"
+            );
+            sleep(Duration::from_millis(10));
+        }
     }
 
     fn generate_random_u8_vector(length: usize) -> Bytes {
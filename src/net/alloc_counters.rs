@@ -0,0 +1,136 @@
+//counts the allocations made on the packet paths, so a test can catch a change that makes the
+//sends or the receives allocate more. the counting needs the alloc-counters feature and the
+//CountingAllocator installed as the global allocator of the binary, without them the paths only
+//mark their scope and nothing is counted
+#[cfg(feature = "alloc-counters")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPath {
+    //the message turned into packets, from construct_send_event to the send queue
+    Send,
+    //a datagram read from the socket and processed by its channel
+    Receive,
+    //the fragments of a message held and put back together, counted apart from Receive
+    Reassembly,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationStats {
+    pub send: u64,
+    pub receive: u64,
+    pub reassembly: u64,
+}
+
+#[cfg(feature = "alloc-counters")]
+thread_local! {
+    static PATH: Cell<Option<AllocPath>> = const { Cell::new(None) };
+}
+
+#[cfg(feature = "alloc-counters")]
+static COUNTS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+//the allocations of the thread count to the path until it's dropped, then the path it interrupted
+//is back
+#[must_use]
+pub struct PathGuard {
+    #[cfg(feature = "alloc-counters")]
+    previous: Option<AllocPath>,
+}
+
+pub fn enter(path: AllocPath) -> PathGuard {
+    #[cfg(feature = "alloc-counters")]
+    return PathGuard {
+        previous: PATH.with(|current| current.replace(Some(path))),
+    };
+    #[cfg(not(feature = "alloc-counters"))]
+    PathGuard {}
+}
+
+#[cfg(feature = "alloc-counters")]
+impl Drop for PathGuard {
+    fn drop(&mut self) {
+        PATH.with(|current| current.set(self.previous));
+    }
+}
+
+//the allocations of every thread of the process since it started, zero without the feature
+pub fn allocation_stats() -> AllocationStats {
+    #[cfg(feature = "alloc-counters")]
+    return AllocationStats {
+        send: COUNTS[AllocPath::Send as usize].load(Ordering::Relaxed),
+        receive: COUNTS[AllocPath::Receive as usize].load(Ordering::Relaxed),
+        reassembly: COUNTS[AllocPath::Reassembly as usize].load(Ordering::Relaxed),
+    };
+    #[cfg(not(feature = "alloc-counters"))]
+    AllocationStats::default()
+}
+
+//the system allocator counting the allocations made in a path, installed by the binary with
+//#[global_allocator]
+#[cfg(feature = "alloc-counters")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-counters")]
+impl CountingAllocator {
+    fn count(&self) {
+        //the thread local is gone while the thread is torn down
+        if let Ok(Some(path)) = PATH.try_with(Cell::get) {
+            COUNTS[path as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "alloc-counters")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(all(test, feature = "alloc-counters"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_count_to_the_innermost_path() {
+        let before = allocation_stats();
+        let mut buffers = Vec::new();
+        {
+            let _send = enter(AllocPath::Send);
+            buffers.push(vec![0u8; 64]);
+            {
+                let _reassembly = enter(AllocPath::Reassembly);
+                buffers.push(vec![0u8; 64]);
+            }
+            buffers.push(vec![0u8; 64]);
+        }
+        buffers.push(vec![0u8; 64]);
+
+        let after = allocation_stats();
+        //the other tests of the binary allocate on their paths at the same time
+        assert!(after.send - before.send >= 2);
+        assert!(after.reassembly > before.reassembly);
+        assert_eq!(PATH.with(Cell::get), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    cell::RefCell,
+    sync::{Arc, Mutex},
+};
+
+use super::{checksum::CHECKSUM_SIZE, Bytes, ProtocolId, PROTOCOL_ID_SIZE};
+
+//buffers kept around per thread, the process threads build and send their packets on the same thread
+const MAX_POOLED_BUFFERS: usize = 256;
+//larger buffers aren't kept so a burst of big packets doesn't pin the memory
+const MAX_POOLED_CAPACITY: usize = 2048;
+
+thread_local! {
+    static POOL: RefCell<BufferPool> = RefCell::new(BufferPool::new(MAX_POOLED_BUFFERS));
+}
+
+pub struct BufferPool {
+    buffers: Vec<Bytes>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Vec::with_capacity(max_buffers),
+            max_buffers,
+        }
+    }
+
+    //an empty buffer with just the default protocol id, the rest is appended so nothing is zero filled
+    pub fn take(&mut self, capacity: usize) -> Bytes {
+        //room for the protocol id and the optional checksum
+        let capacity = capacity + PROTOCOL_ID_SIZE + CHECKSUM_SIZE;
+        let mut buffer = match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => Vec::with_capacity(capacity),
+        };
+        buffer.extend_from_slice(&ProtocolId::DEFAULT.0);
+        buffer
+    }
+
+    pub fn recycle(&mut self, mut buffer: Bytes) {
+        if self.buffers.len() < self.max_buffers && buffer.capacity() <= MAX_POOLED_CAPACITY {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
+//a pool owned by a single connection instead of the thread. it only holds the heads of the
+//packets, the protocol id and the header of every packet plus the whole of the control packets.
+//the payloads are shared with the send buffer and freed with it, they are never pooled. the heads
+//come back once they are sent so a busy connection can't drain the pool the others rely on, with
+//several workers the io thread sends them which is what the mutex is for
+#[derive(Clone)]
+pub struct ConnectionPool(Arc<Mutex<BufferPool>>);
+
+impl ConnectionPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self(Arc::new(Mutex::new(BufferPool::new(max_buffers))))
+    }
+
+    pub fn take(&self, capacity: usize) -> Bytes {
+        self.lock().take(capacity)
+    }
+
+    pub fn recycle(&self, buffer: Bytes) {
+        self.lock().recycle(buffer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    //only the process thread uses the pool, a poisoned lock still holds valid buffers
+    fn lock(&self) -> std::sync::MutexGuard<'_, BufferPool> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+//takes a buffer from the pool of the current thread
+pub fn take_buffer(capacity: usize) -> Bytes {
+    POOL.with(|pool| pool.borrow_mut().take(capacity))
+}
+
+//hands a buffer that was sent back to the pool of the current thread
+pub fn recycle_buffer(buffer: Bytes) {
+    POOL.with(|pool| pool.borrow_mut().recycle(buffer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let mut pool = BufferPool::new(2);
+
+        let mut buffer = pool.take(10);
+        assert_eq!(buffer, ProtocolId::DEFAULT.0);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let ptr = buffer.as_ptr();
+
+        pool.recycle(buffer);
+        assert_eq!(pool.len(), 1);
+
+        //the same allocation comes back without the old data
+        let buffer = pool.take(10);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(buffer, ProtocolId::DEFAULT.0);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn pool_is_bounded() {
+        let mut pool = BufferPool::new(1);
+
+        pool.recycle(Vec::with_capacity(8));
+        pool.recycle(Vec::with_capacity(8));
+        assert_eq!(pool.len(), 1);
+
+        let mut pool = BufferPool::new(1);
+        pool.recycle(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn connection_pool_is_shared_by_clones() {
+        let pool = ConnectionPool::new(4);
+        let buffer = pool.take(10);
+        let ptr = buffer.as_ptr();
+
+        pool.clone().recycle(buffer);
+        assert_eq!(pool.len(), 1);
+        let buffer = pool.take(10);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(pool.is_empty());
+    }
+}
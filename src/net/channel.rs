@@ -1,12 +1,12 @@
 use std::{
     borrow::BorrowMut,
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::VecDeque,
     net::SocketAddr,
     ops::{Deref, DerefMut},
     rc::Rc,
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
@@ -14,16 +14,33 @@ use bit_field::BitField;
 use crossbeam_channel::Sender;
 use log::{debug, info};
 
+use crate::proto::ack::generate_ack_bits;
+
 use super::{
+    alloc_counters::{self, AllocPath},
+    buffer_pool::{self, ConnectionPool},
     bytes, bytes_with_header,
+    checksum::verify_checksum,
+    config::ChannelConfig,
+    debug_state::ChannelDebugState,
+    disconnect::DisconnectReason,
+    event_log::{EventLog, RecentEvent, RecentEventKind},
     fragmentation_manager::FragmentationManager,
     header::{Header, SendType, HEADER_SIZE},
     int_buffer::{self, IntBuffer},
-    packets::SendEvent,
+    jitter_buffer::JitterBuffer,
+    middleware::{Action, Direction, PacketContext},
+    packets::{Payload, SendEvent},
+    quality::{ConnectionQuality, QualityMonitor},
+    random::RandomSource,
+    reorder_buffer::ReorderBuffer,
+    request::split_request_id,
     send_buffer::{SendBufferManager, SendPayload},
-    sequence::{Sequence, SequenceBuffer, WindowSequenceBuffer},
-    socket::UdpSendEvent,
-    Bytes, PacketType, BUFFER_SIZE, BUFFER_WINDOW_SIZE, MAGIC_NUMBER_HEADER,
+    sequence::{ReplayWindow, Sequence, SequenceBuffer, WindowSequenceBuffer},
+    socket::{Datagram, UdpSendEvent},
+    stats::ConnectionStats,
+    Bytes, MessageKind, NetError, PacketType, SessionToken, BUFFER_SIZE, BUFFER_WINDOW_SIZE,
+    PROTOCOL_ID_SIZE,
 };
 
 #[derive(PartialEq, Eq)]
@@ -32,44 +49,250 @@ pub enum ChannelType {
     Server,
 }
 
+//the ack delay is sent in units of this after the header
+pub const ACK_DELAY_UNIT: Duration = Duration::from_micros(10);
+pub const ACK_DELAY_SIZE: usize = 2;
+//the flow control window is sent after the ack delay
+pub const WINDOW_SIZE: usize = 2;
+
 pub enum ReadPayload {
     Single(Bytes),
     Parts(Vec<Bytes>),
-    Disconnect,
+    //a fragment of an incomplete message, the group with the fragments received and the total
+    Progress(u16, u8, u8),
+    //a request of the remote and its id, the fragments are joined
+    Request(u32, Bytes),
+    //the response to a request and the id of the request
+    Response(u32, Bytes),
+    //the payload the remote attached to its keep alive
+    KeepAlive(Bytes),
+    Disconnect(DisconnectReason),
+    DisconnectAck,
     None,
 }
 
+//a reliable packet held back until the window of the remote has room for it
+struct PendingReliable {
+    payload: Payload,
+    kind: MessageKind,
+    frag: bool,
+    fragment_group_id: u16,
+    fragment_id: u8,
+    fragment_size: u8,
+    //the key of a superseding send
+    key: Option<u64>,
+}
+
+//the reliable packets waiting for the window by message, the fragments of a message together
+#[derive(Default)]
+struct PendingQueue {
+    messages: VecDeque<VecDeque<PendingReliable>>,
+    len: usize,
+    bytes: usize,
+}
+
+impl PendingQueue {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    //a fragment joins the message of the previous fragment of its group
+    fn push(&mut self, packet: PendingReliable) {
+        self.len += 1;
+        self.bytes += packet.payload.len();
+        if let Some(message) = self.messages.back_mut().filter(|message| {
+            packet.frag
+                && message.back().is_some_and(|last| {
+                    last.frag && last.fragment_group_id == packet.fragment_group_id
+                })
+        }) {
+            message.push_back(packet);
+            return;
+        }
+        self.messages.push_back(VecDeque::from([packet]));
+    }
+
+    //the next packet of the oldest message, or with interleave of the message whose turn it is
+    fn pop(&mut self, interleave: bool) -> Option<PendingReliable> {
+        let mut message = self.messages.pop_front()?;
+        let packet = message.pop_front()?;
+        self.len -= 1;
+        self.bytes -= packet.payload.len();
+        if !message.is_empty() {
+            if interleave {
+                self.messages.push_back(message);
+            } else {
+                self.messages.push_front(message);
+            }
+        }
+        Some(packet)
+    }
+
+    fn remove_group(&mut self, group_id: u16) {
+        for message in &mut self.messages {
+            message.retain(|packet| {
+                let keep = !packet.frag || packet.fragment_group_id != group_id;
+                if !keep {
+                    self.len -= 1;
+                    self.bytes -= packet.payload.len();
+                }
+                keep
+            });
+        }
+        self.messages.retain(|message| !message.is_empty());
+    }
+
+    fn remove_key(&mut self, key: u64) {
+        self.messages.retain(|message| {
+            let keep = message[0].key != Some(key);
+            if !keep {
+                self.len -= 1;
+                self.bytes -= message[0].payload.len();
+            }
+            keep
+        });
+    }
+}
+
+//the cancellable sends a channel remembers, a cancel of an older one does nothing
+const MAX_CANCELLABLE: usize = 32;
+
+//a reliable message in fragments that can be taken back until it's forgotten
+struct CancellableSend {
+    send_id: u32,
+    group_id: u16,
+    //the sequences the fragments got so far, the ones waiting for the window don't have one. they
+    //aren't consecutive when the fragments are interleaved
+    seqs: Vec<u16>,
+}
+
 pub struct Channel {
     pub mode: ChannelType,
+    pub config: ChannelConfig,
     pub session_key: u64,
     pub addr: SocketAddr,
+    //the socket of a server the datagrams leave from, see the ingress of the identity
+    pub socket: Option<usize>,
     pub unreliable_seq: u16,
     pub local_seq: u16,
     pub remote_seq: u16,
+    //when the packet of the remote seq was read, the ack delay is measured from it
+    remote_seq_received_at: Option<Instant>,
+    //the latest packet of the session, for the idle timeout
+    last_received: Instant,
     pub send_ack: bool,
+    //packets dropped because of a checksum mismatch
+    pub corrupted_packets: u64,
+    //packets from the remote address with another session key or a header that doesn't decode
+    pub malformed_packets: u64,
+    //unreliable packets and keep alives received before or behind the replay window
+    pub replayed_packets: u64,
+    //user data attached to every keep alive packet
+    pub keep_alive_payload: Option<Bytes>,
+    //messages read but not taken by the game yet, they count against the advertised window
+    pub receive_backlog: usize,
+    last_sent: Instant,
     //buffer of sent packets
     pub send_buffer: SendBufferManager,
     //tracking received packets for preventing emitting duplicate packets and generating acks
     received_packets: WindowSequenceBuffer<()>,
+    //the sequences of the unreliable packets, the reliable ones are deduplicated by received_packets
+    unreliable_window: ReplayWindow,
     //fragmentation
     reliable_fragmentation: FragmentationManager,
     unreliable_fragmentation: FragmentationManager,
+    //the fragment groups expire relative to this
+    created_at: Instant,
+    //packet heads of this channel only, the pool of the process thread is used if not set
+    pool: Option<ConnectionPool>,
+    quality: QualityMonitor,
+    //reliable messages held back until the ones sent before them arrived
+    reorder_buffer: ReorderBuffer,
+    //unreliable messages held to smooth their arrival, only set with a jitter delay
+    jitter_buffer: Option<JitterBuffer>,
+    released: VecDeque<ReadPayload>,
+    //the last reliable seq the remote takes with flow control, the ones after it wait in the queue
+    send_window_end: u16,
+    pending_reliable: PendingQueue,
+    cancellable: VecDeque<CancellableSend>,
+    //the window sent with the last packet, a reopened window is advertised right away
+    advertised_window: Cell<u16>,
+    events: EventLog,
+    //whether the last update left packets waiting for the window
+    window_stalled: bool,
+    //of both fragmentation managers, the ones since the last update are logged
+    fragment_timeouts: u64,
 }
 
 impl Channel {
-    pub fn new(addr: SocketAddr, session_key: u64, mode: ChannelType) -> Self {
+    //the first fragment group id is drawn from the source so it can't be guessed from the start of
+    //the session, the unreliable fragments take their group ids from the reliable manager too
+    pub fn start_groups(&mut self, random: &RandomSource) {
+        self.reliable_fragmentation
+            .start_groups_at(random.next_u64() as u16);
+    }
+
+    pub fn new(
+        addr: SocketAddr,
+        session_key: u64,
+        mode: ChannelType,
+        mut config: ChannelConfig,
+    ) -> Self {
+        config.receive_window = config.receive_window.clamp(1, BUFFER_WINDOW_SIZE);
+        let pool = (config.connection_pool_size > 0)
+            .then(|| ConnectionPool::new(config.connection_pool_size));
+        let quality = QualityMonitor::new(config.quality.clone(), Instant::now());
+        let unreliable_fragmentation = FragmentationManager::with_limits(
+            config.unreliable_fragment_timeout,
+            Some(config.max_unreliable_fragment_groups),
+        );
+        let send_buffer = SendBufferManager::with_rtt(config.rtt.clone());
+        let jitter_buffer = config.jitter_delay.map(JitterBuffer::new);
+        let events = EventLog::new(config.event_log_size);
+        //nothing was acked yet, the seqs from 0 up to the window are taken
+        let config_window = config.receive_window;
+        let send_window_end = config_window.saturating_sub(1);
+
         Self {
             mode,
+            config,
             session_key,
             addr,
+            socket: None,
             unreliable_seq: 0,
             local_seq: 0,
             remote_seq: 0,
+            remote_seq_received_at: None,
+            last_received: Instant::now(),
             send_ack: false,
-            send_buffer: SendBufferManager::new(),
+            corrupted_packets: 0,
+            malformed_packets: 0,
+            replayed_packets: 0,
+            keep_alive_payload: None,
+            receive_backlog: 0,
+            last_sent: Instant::now(),
+            send_buffer,
             received_packets: WindowSequenceBuffer::with_size(BUFFER_SIZE, BUFFER_WINDOW_SIZE),
+            unreliable_window: ReplayWindow::new(),
             reliable_fragmentation: FragmentationManager::new(),
-            unreliable_fragmentation: FragmentationManager::new(),
+            unreliable_fragmentation,
+            created_at: Instant::now(),
+            pool,
+            quality,
+            reorder_buffer: ReorderBuffer::new(),
+            jitter_buffer,
+            released: VecDeque::new(),
+            send_window_end,
+            pending_reliable: PendingQueue::default(),
+            cancellable: VecDeque::new(),
+            advertised_window: Cell::new(config_window),
+            events,
+            window_stalled: false,
+            fragment_timeouts: 0,
         }
     }
 
@@ -78,89 +301,432 @@ impl Channel {
         send_event: SendEvent,
         send_queue: &mut VecDeque<UdpSendEvent>,
     ) -> anyhow::Result<()> {
+        self.send_message(MessageKind::Data, send_event, send_queue)
+    }
+
+    //requests and responses are sent reliably in packets of their own type
+    pub fn send_message(
+        &mut self,
+        kind: MessageKind,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        let _path = alloc_counters::enter(AllocPath::Send);
+        if kind != MessageKind::Data
+            && !matches!(
+                send_event,
+                SendEvent::Single(_, true) | SendEvent::Fragmented(_, true)
+            )
+        {
+            bail!("requests and responses are only sent reliably");
+        }
         match send_event {
-            SendEvent::Single(mut buffer, reliable) => {
+            SendEvent::Single(payload, reliable) => {
                 if reliable {
-                    let seq: u16 = self.create_send_buffer(&mut buffer, false, 0, 0, 0)?;
-                    self.send_tracking(seq, buffer, send_queue);
+                    self.send_reliable(
+                        PendingReliable {
+                            payload,
+                            kind,
+                            frag: false,
+                            fragment_group_id: 0,
+                            fragment_id: 0,
+                            fragment_size: 0,
+                            key: None,
+                        },
+                        send_queue,
+                    )?;
                 } else {
-                    self.create_unreliable_packet(&mut buffer, false, 0, 0, 0);
-                    self.send_non_tracking(buffer, send_queue);
+                    if let Some(datagram) = self.create_unreliable_packet(payload, false, 0, 0, 0) {
+                        self.send_non_tracking(datagram, send_queue);
+                    }
                 }
             }
-            SendEvent::Fragmented(mut fragments, reliable) => {
+            SendEvent::Fragmented(fragments, reliable) => {
                 let fragments = self.reliable_fragmentation.split_fragments(fragments)?;
-                for mut chunk in fragments.chunks {
+                //a group id that came around again is the new message's, only a cancellable send
+                //that didn't start yet can be it
+                self.cancellable
+                    .retain(|send| send.group_id != fragments.group_id || send.seqs.is_empty());
+                //the fragments of a message are queued together or not at all
+                if reliable {
+                    self.check_queue_space(fragments.chunks.len())?;
+                }
+                for chunk in fragments.chunks {
                     if reliable {
-                        let seq: u16 = self.create_send_buffer(
-                            &mut chunk.buffer,
-                            true,
-                            fragments.group_id,
-                            chunk.fragment_id,
-                            fragments.chunk_count,
+                        self.send_reliable(
+                            PendingReliable {
+                                payload: chunk.buffer,
+                                kind,
+                                frag: true,
+                                fragment_group_id: fragments.group_id,
+                                fragment_id: chunk.fragment_id,
+                                fragment_size: fragments.chunk_count,
+                                key: None,
+                            },
+                            send_queue,
                         )?;
-                        self.send_tracking(seq, chunk.buffer, send_queue);
                     } else {
-                        self.create_unreliable_packet(
-                            &mut chunk.buffer,
+                        if let Some(datagram) = self.create_unreliable_packet(
+                            chunk.buffer,
                             true,
                             fragments.group_id,
                             chunk.fragment_id,
                             fragments.chunk_count,
-                        );
-                        self.send_non_tracking(chunk.buffer, send_queue);
+                        ) {
+                            self.send_non_tracking(datagram, send_queue);
+                        }
                     }
                 }
             }
-            SendEvent::Disconnect => {
-                //send three disconnect packets
-                for _ in 0..3 {
-                    let mut header = Header::new_disconnect(self.unreliable_seq, self.session_key);
-                    let mut buffer = bytes_with_header!(HEADER_SIZE);
+            //resent by the linger of the caller until it's acked
+            SendEvent::Disconnect(reason) => {
+                let header = Header::new_disconnect(self.unreliable_seq, self.session_key);
+                let mut buffer = self.take_buffer(HEADER_SIZE + reason.size());
+                self.write_header(&header, &mut buffer);
+                reason.write_into(&mut buffer);
 
-                    let mut int_buffer = IntBuffer::new_at(4);
-                    header.write(&mut buffer, &mut int_buffer)?;
+                Sequence::increment(&mut self.unreliable_seq);
 
-                    Sequence::increment(&mut self.unreliable_seq);
-
-                    self.send_non_tracking(buffer, send_queue);
-                }
+                self.send_non_tracking(self.datagram(buffer, None), send_queue);
             }
         };
 
         Ok(())
     }
 
-    pub fn send_empty_ack(
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = ConnectionStats::default();
+        self.write_stats(&mut stats);
+        stats
+    }
+
+    //overwrites a published snapshot in place, the samples reuse its allocation
+    pub fn write_stats(&self, stats: &mut ConnectionStats) {
+        let tracker = &self.send_buffer.trr_tracker;
+        stats.session_token = SessionToken::of(self.session_key);
+        stats.fragments = self.reliable_fragmentation.stats + self.unreliable_fragmentation.stats;
+        stats.corrupted_packets = self.corrupted_packets;
+        stats.malformed_packets = self.malformed_packets;
+        stats.replayed_packets = self.replayed_packets;
+        stats.late_unreliable = self.jitter_buffer.as_ref().map_or(0, |buffer| buffer.late);
+        stats.queued_reliable = self.pending_reliable.len();
+        stats.recommended_send_rate = self.recommended_send_rate();
+        stats.smoothed_rtt = tracker.smoothed_rtt();
+        stats.rtt_variance = tracker.rtt_variance();
+        stats.retransmit_timeout = tracker.recommended_max_rtt();
+        stats.rtt_samples.clear();
+        stats.rtt_samples.extend(tracker.samples());
+    }
+
+    pub fn debug_state(&self) -> ChannelDebugState {
+        let now = Instant::now();
+        let since_created = now.saturating_duration_since(self.created_at);
+        let received_in_window = (0..BUFFER_WINDOW_SIZE)
+            .filter(|i| {
+                self.received_packets
+                    .is_some(self.remote_seq.wrapping_sub(*i))
+            })
+            .count() as u16;
+        let rtt = &self.send_buffer.trr_tracker;
+
+        ChannelDebugState {
+            local_seq: self.local_seq,
+            unreliable_seq: self.unreliable_seq,
+            remote_seq: self.remote_seq,
+            ack_bits: self.generate_ack_field(),
+            received_in_window,
+            window_size: BUFFER_WINDOW_SIZE,
+            outstanding: self.send_buffer.outstanding(self.local_seq, now),
+            average_rtt: rtt.average_rtt(),
+            rtt_variance: rtt.rtt_variance(),
+            reliable_fragments: self.reliable_fragmentation.pending_groups(since_created),
+            unreliable_fragments: self.unreliable_fragmentation.pending_groups(since_created),
+        }
+    }
+
+    //reliable packets that weren't acked yet, the ones waiting for the window included
+    pub fn has_pending_reliable(&self) -> bool {
+        !self.pending_reliable.is_empty() || self.send_buffer.has_pending(self.local_seq)
+    }
+
+    //a reliable message in fragments that cancel_send can take back while it's one of the latest
+    pub fn send_cancellable(
+        &mut self,
+        send_id: u32,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        if !matches!(send_event, SendEvent::Fragmented(_, true)) {
+            bail!("only reliable messages sent in fragments can be cancelled");
+        }
+        if self.cancellable.len() == MAX_CANCELLABLE {
+            self.cancellable.pop_front();
+        }
+        self.cancellable.push_back(CancellableSend {
+            send_id,
+            group_id: self.reliable_fragmentation.next_group_id(),
+            seqs: Vec::new(),
+        });
+        let result = self.send_event(send_event, send_queue);
+        if result.is_err() {
+            self.cancellable.pop_back();
+        }
+        result
+    }
+
+    //the fragments waiting for the window are dropped and the sent ones aren't resent anymore. the
+    //remote is told to drop the fragments it has, with ordered_reliable it doesn't wait for them.
+    //false if the send was forgotten
+    pub fn cancel_send(
+        &mut self,
+        send_id: u32,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<bool> {
+        let Some(index) = self
+            .cancellable
+            .iter()
+            .position(|send| send.send_id == send_id)
+        else {
+            return Ok(false);
+        };
+        let send = self.cancellable.remove(index).unwrap();
+        self.pending_reliable.remove_group(send.group_id);
+        let Some(&first_seq) = send.seqs.first() else {
+            return Ok(true);
+        };
+
+        for &seq in &send.seqs {
+            self.send_buffer.give_up(seq);
+        }
+        //the remote only skips the range with ordered_reliable, which never interleaves the
+        //fragments, so they are consecutive there
+        let mut range = first_seq.to_le_bytes().to_vec();
+        range.extend_from_slice(&(send.seqs.len() as u16).to_le_bytes());
+        //a group has at least one fragment, a size of 0 marks the cancel
+        self.send_reliable(
+            PendingReliable {
+                payload: Payload::new(&range),
+                kind: MessageKind::Data,
+                frag: true,
+                fragment_group_id: send.group_id,
+                fragment_id: 0,
+                fragment_size: 0,
+                key: None,
+            },
+            send_queue,
+        )?;
+        Ok(true)
+    }
+
+    //a reliable message of one packet replacing the previous one with the key, which isn't sent or
+    //resent anymore. a resend of the previous one that was already on its way can still arrive
+    //after it
+    pub fn send_superseding(
+        &mut self,
+        key: u64,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        let SendEvent::Single(payload, true) = send_event else {
+            bail!("only reliable messages of one packet can supersede another");
+        };
+        //the remote would wait for the superseded message until the gap times out
+        if self.config.ordered_reliable {
+            bail!("superseding sends can't be used with ordered_reliable");
+        }
+
+        let _path = alloc_counters::enter(AllocPath::Send);
+        self.pending_reliable.remove_key(key);
+        self.send_reliable(
+            PendingReliable {
+                payload,
+                kind: MessageKind::Data,
+                frag: false,
+                fragment_group_id: 0,
+                fragment_id: 0,
+                fragment_size: 0,
+                key: Some(key),
+            },
+            send_queue,
+        )
+    }
+
+    //the fragments of the cancelled message that arrived are dropped, the order moves past the
+    //sequences the message and the cancel took
+    fn read_cancel(&mut self, header: &Header, payload: &[u8], received_at: &Instant) {
+        self.reliable_fragmentation
+            .discard(header.fragment_group_id);
+        if !self.config.ordered_reliable {
+            return;
+        }
+        if let [a, b, c, d, ..] = *payload {
+            let first_seq = u16::from_le_bytes([a, b]);
+            let sent = u16::from_le_bytes([c, d]);
+            self.reorder_buffer.insert(
+                first_seq,
+                sent,
+                ReadPayload::None,
+                *received_at,
+                &mut self.released,
+            );
+        }
+        self.reorder_buffer.insert(
+            header.seq,
+            1,
+            ReadPayload::None,
+            *received_at,
+            &mut self.released,
+        );
+    }
+
+    //the packets waiting for room in the send window and their bytes
+    pub fn queued_reliable(&self) -> (usize, usize) {
+        (self.pending_reliable.len(), self.pending_reliable.bytes)
+    }
+
+    //queued behind the earlier ones so the fragments of a message keep consecutive sequences, unless
+    //the fragments are interleaved
+    fn send_reliable(
+        &mut self,
+        packet: PendingReliable,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        if !self.pending_reliable.is_empty() || !self.send_window_open() {
+            self.check_queue_space(1)?;
+            self.pending_reliable.push(packet);
+            return Ok(());
+        }
+
+        self.send_pending(packet, send_queue);
+        Ok(())
+    }
+
+    fn send_pending(&mut self, packet: PendingReliable, send_queue: &mut VecDeque<UdpSendEvent>) {
+        let (seq, datagram) = self.create_send_buffer(
+            packet.payload,
+            packet.kind,
+            packet.frag,
+            packet.fragment_group_id,
+            packet.fragment_id,
+            packet.fragment_size,
+        );
+        if let Some(key) = packet.key {
+            self.send_buffer.supersede(key, seq);
+        }
+        self.send_tracking(seq, datagram, send_queue);
+    }
+
+    fn check_queue_space(&self, packets: usize) -> anyhow::Result<()> {
+        if let Some(max) = self.config.max_queued_reliable {
+            if self.pending_reliable.len() + packets > max {
+                bail!(NetError::WindowFull);
+            }
+        }
+        Ok(())
+    }
+
+    //the send buffer can't hold more than the window, and with flow control the remote can't take more
+    fn send_window_open(&mut self) -> bool {
+        if self.send_buffer.in_flight(self.local_seq) >= BUFFER_WINDOW_SIZE {
+            return false;
+        }
+        !self.config.flow_control || !Sequence::is_less_than(self.send_window_end, self.local_seq)
+    }
+
+    //the remote takes the reliable packets up to the ones it acked plus its window
+    fn update_send_window(&mut self, ack: u16, window: Option<u16>) {
+        if let Some(window) = window {
+            self.send_window_end = ack.wrapping_add(window);
+        }
+    }
+
+    //the reliable packets the remote can still send, shrinks while messages wait to be taken
+    pub fn receive_window(&self) -> u16 {
+        let held = self.reorder_buffer.len() + self.released.len() + self.receive_backlog;
+        self.config
+            .receive_window
+            .saturating_sub(held.min(u16::MAX as usize) as u16)
+    }
+
+    //sent for every disconnect that is read, the first one can be lost too
+    pub fn send_disconnect_ack(&mut self, send_queue: &mut VecDeque<UdpSendEvent>) {
+        let header = Header::new_disconnect_ack(self.unreliable_seq, self.session_key);
+        let mut buffer = self.take_buffer(HEADER_SIZE);
+        self.write_header(&header, &mut buffer);
+
+        Sequence::increment(&mut self.unreliable_seq);
+
+        self.send_non_tracking(self.datagram(buffer, None), send_queue);
+    }
+
+    //the one packet sent when there is nothing else to send, it carries the acks and keeps the
+    //connection alive. the keep alive payload is only attached when the keep alive is due
+    //so acking doesn't repeat it
+    pub fn send_idle(
         &mut self,
+        keep_alive: bool,
         send_queue: &mut VecDeque<UdpSendEvent>,
     ) -> anyhow::Result<()> {
-        let mut int_buffer = IntBuffer::new_at(4);
-        let mut buffer = bytes_with_header!(HEADER_SIZE);
+        let payload = self.keep_alive_payload.as_ref().filter(|_| keep_alive);
+        let payload_len = payload.map_or(0, |p| p.len());
 
-        self.create_unreliable_packet(&mut buffer, false, 0, 0, 0);
+        let mut header = Header::new_keep_alive(self.unreliable_seq, self.session_key);
+        self.write_header_ack_fields(&mut header);
+
+        let mut buffer = self.take_buffer(HEADER_SIZE + payload_len);
+        self.write_header(&header, &mut buffer);
+        if let Some(payload) = payload {
+            buffer.extend_from_slice(payload);
+        }
 
-        self.send_non_tracking(buffer, send_queue);
+        Sequence::increment(&mut self.unreliable_seq);
+
+        self.send_non_tracking(self.datagram(buffer, None), send_queue);
 
         Ok(())
     }
 
-    fn send_tracking(&mut self, seq: u16, buffer: Bytes, send_queue: &mut VecDeque<UdpSendEvent>) {
-        let header = Header::read(&buffer[4..]).unwrap();
+    fn send_tracking(
+        &mut self,
+        seq: u16,
+        datagram: Option<Datagram>,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) {
+        //dropped by the middleware, it counts as sent so it's resent like a lost packet
+        let Some(mut datagram) = datagram else {
+            self.send_buffer.mark_sent(seq, Instant::now());
+            return;
+        };
+
+        if self.config.checksum {
+            datagram.append_checksum();
+        }
+        datagram.socket = self.socket;
 
         send_queue.push_front(match self.mode {
-            ChannelType::Client => UdpSendEvent::ClientTracking(buffer, seq),
-            ChannelType::Server => UdpSendEvent::ServerTracking(buffer, self.addr, seq),
+            ChannelType::Client => UdpSendEvent::ClientTracking(datagram, seq),
+            ChannelType::Server => UdpSendEvent::ServerTracking(datagram, self.addr, seq),
         });
         self.send_ack = false;
+        self.last_sent = Instant::now();
     }
 
-    fn send_non_tracking(&mut self, buffer: Bytes, send_queue: &mut VecDeque<UdpSendEvent>) {
+    fn send_non_tracking(
+        &mut self,
+        mut datagram: Datagram,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) {
+        if self.config.checksum {
+            datagram.append_checksum();
+        }
+        datagram.socket = self.socket;
+
         send_queue.push_front(match self.mode {
-            ChannelType::Client => UdpSendEvent::Client(buffer),
-            ChannelType::Server => UdpSendEvent::Server(buffer, self.addr),
+            ChannelType::Client => UdpSendEvent::Client(datagram),
+            ChannelType::Server => UdpSendEvent::Server(datagram, self.addr),
         });
         self.send_ack = false;
+        self.last_sent = Instant::now();
     }
 
     pub fn read(
@@ -168,29 +734,72 @@ impl Channel {
         mut buffer: Bytes,
         received_at: &Instant,
     ) -> anyhow::Result<ReadPayload> {
-        let header = Header::read(&buffer)?;
-
-        //validate session key
-        if header.session_key != self.session_key {
-            bail!("incorrect session key");
+        let _path = alloc_counters::enter(AllocPath::Receive);
+        if self.config.checksum {
+            match verify_checksum(&buffer) {
+                Some(data_len) => buffer.truncate(data_len),
+                None => {
+                    self.corrupted_packets += 1;
+                    debug!("dropped corrupted packet from {}", self.addr);
+                    return Ok(ReadPayload::None);
+                }
+            }
         }
 
-        //client requested a disconnect
-        if header.packet_type == PacketType::Disconnect {
-            return Ok(ReadPayload::Disconnect);
+        let (header, header_size, ack_delay, window) = match self.read_header(&buffer) {
+            Ok(header) => header,
+            Err(e) => {
+                self.malformed_packets += 1;
+                self.log(RecentEventKind::Malformed);
+                return Err(e);
+            }
+        };
+        self.last_received = *received_at;
+
+        //the other side closed the connection or confirmed that we did
+        match header.packet_type {
+            PacketType::Disconnect => {
+                return Ok(ReadPayload::Disconnect(DisconnectReason::read(
+                    &buffer[header_size..],
+                )))
+            }
+            PacketType::DisconnectAck => return Ok(ReadPayload::DisconnectAck),
+            _ => {}
         }
 
         //remove the header data from the buffer
-        _ = buffer.drain(0..header.get_header_size());
+        _ = buffer.drain(0..header_size);
+
+        let action = self.config.middleware.run(&mut PacketContext {
+            addr: self.addr,
+            direction: Direction::Inbound,
+            header: &header,
+            payload: &mut buffer,
+        });
+        if action == Action::Drop {
+            return Ok(ReadPayload::None);
+        }
+
+        let now = received_at.saturating_duration_since(self.created_at);
+        //the time the remote held the ack doesn't count towards the rtt
+        let acked_at = received_at.checked_sub(ack_delay).unwrap_or(*received_at);
+
+        //every packet read gives a lost message the chance to be skipped, keep alives included
+        if self.config.ordered_reliable {
+            self.reorder_buffer
+                .release(*received_at, &mut self.released);
+        }
+        self.release_jittered(*received_at);
 
         match header.packet_type {
-            PacketType::PayloadReliable | PacketType::PayloadReliableFrag => {
+            packet_type if packet_type.is_reliable_variant() => {
                 //always send ack even if its a duplicate
                 self.send_ack = true;
                 let mut new_packet = false;
 
                 //always mark the acks
-                self.mark_acked_packets(header.ack, header.ack_bits, received_at);
+                self.mark_acked_packets(header.ack, header.ack_bits, &acked_at);
+                self.update_send_window(header.ack, window);
 
                 //if the sequence was not registered yet its a new packet
                 if self.update_remote_seq(header.seq) || self.received_packets.is_none(header.seq) {
@@ -200,44 +809,94 @@ impl Channel {
 
                 if new_packet {
                     self.received_packets.insert(header.seq, ());
+                    //the first packet has the initial remote seq without updating it
+                    if header.seq == self.remote_seq {
+                        self.remote_seq_received_at = Some(*received_at);
+                    }
 
                     if !buffer.is_empty() {
+                        if header.packet_type.is_frag_variant() && header.fragment_size == 0 {
+                            self.read_cancel(&header, &buffer, received_at);
+                            return Ok(ReadPayload::None);
+                        }
                         if header.packet_type.is_frag_variant() {
+                            let _path = alloc_counters::enter(AllocPath::Reassembly);
+                            let received = self.reliable_fragmentation.stats.fragments_received;
                             if self
                                 .reliable_fragmentation
-                                .insert_fragment(&header, buffer)?
+                                .insert_fragment(&header, buffer, now)?
                             {
                                 info!(
                                     "finished constructing new fragment with id {}",
                                     header.fragment_group_id
                                 );
-                                return Ok(ReadPayload::Parts(
-                                    self.reliable_fragmentation
-                                        .assemble(header.fragment_group_id)?,
+                                let parts = self
+                                    .reliable_fragmentation
+                                    .assemble(header.fragment_group_id, now)?;
+                                return Ok(self.deliver_reliable(
+                                    &header,
+                                    ReadPayload::Parts(parts),
+                                    received_at,
                                 ));
                             }
+                            return Ok(progress_payload(
+                                &self.reliable_fragmentation,
+                                header.fragment_group_id,
+                                received,
+                            ));
                         } else {
-                            return Ok(ReadPayload::Single(buffer));
+                            return Ok(self.deliver_reliable(
+                                &header,
+                                ReadPayload::Single(buffer),
+                                received_at,
+                            ));
                         }
                     }
                 }
             }
-            PacketType::PayloadUnreliable | PacketType::PayloadUnreliableFrag => {
-                self.mark_acked_packets(header.ack, header.ack_bits, received_at);
+            PacketType::PayloadUnreliable
+            | PacketType::PayloadUnreliableFrag
+            | PacketType::KeepAlive => {
+                //a captured packet sent again has a valid session key, only its sequence gives it away
+                if !self.unreliable_window.accept(header.seq) {
+                    self.replayed_packets += 1;
+                    debug!("dropped replayed packet {} from {}", header.seq, self.addr);
+                    return Ok(ReadPayload::None);
+                }
+                self.mark_acked_packets(header.ack, header.ack_bits, &acked_at);
+                self.update_send_window(header.ack, window);
 
                 if !buffer.is_empty() {
+                    if header.packet_type == PacketType::KeepAlive {
+                        return Ok(ReadPayload::KeepAlive(buffer));
+                    }
                     if header.packet_type.is_frag_variant() {
+                        let _path = alloc_counters::enter(AllocPath::Reassembly);
+                        let received = self.unreliable_fragmentation.stats.fragments_received;
                         if self
                             .unreliable_fragmentation
-                            .insert_fragment(&header, buffer)?
+                            .insert_fragment(&header, buffer, now)?
                         {
-                            return Ok(ReadPayload::Parts(
-                                self.unreliable_fragmentation
-                                    .assemble(header.fragment_group_id)?,
+                            let parts = self
+                                .unreliable_fragmentation
+                                .assemble(header.fragment_group_id, now)?;
+                            return Ok(self.deliver_unreliable(
+                                &header,
+                                ReadPayload::Parts(parts),
+                                received_at,
                             ));
                         }
+                        return Ok(progress_payload(
+                            &self.unreliable_fragmentation,
+                            header.fragment_group_id,
+                            received,
+                        ));
                     } else {
-                        return Ok(ReadPayload::Single(buffer));
+                        return Ok(self.deliver_unreliable(
+                            &header,
+                            ReadPayload::Single(buffer),
+                            received_at,
+                        ));
                     }
                 }
             }
@@ -247,34 +906,200 @@ impl Channel {
         Ok(ReadPayload::None)
     }
 
+    //a completed reliable message, returned right away unless the order is kept. held messages
+    //are released through take_released
+    fn deliver_reliable(
+        &mut self,
+        header: &Header,
+        payload: ReadPayload,
+        received_at: &Instant,
+    ) -> ReadPayload {
+        let payload = self.with_message_kind(header.packet_type, payload);
+        if !self.config.ordered_reliable {
+            return payload;
+        }
+
+        let (index, len) = if header.packet_type.is_frag_variant() {
+            (
+                ReorderBuffer::message_index(header.seq, header.fragment_id),
+                header.fragment_size as u16,
+            )
+        } else {
+            (header.seq, 1)
+        };
+        self.reorder_buffer
+            .insert(index, len, payload, *received_at, &mut self.released);
+        ReadPayload::None
+    }
+
+    //requests and responses are returned with the id they start with, without one the message
+    //is malformed but still takes its place in the order
+    fn with_message_kind(&mut self, packet_type: PacketType, payload: ReadPayload) -> ReadPayload {
+        let kind = packet_type.message_kind();
+        if kind == Some(MessageKind::Data) {
+            return payload;
+        }
+        let message = match payload {
+            ReadPayload::Single(message) => message,
+            ReadPayload::Parts(parts) => parts.concat(),
+            payload => return payload,
+        };
+
+        match (kind, split_request_id(message)) {
+            (Some(MessageKind::Request), Some((request_id, data))) => {
+                ReadPayload::Request(request_id, data)
+            }
+            (Some(MessageKind::Response), Some((request_id, data))) => {
+                ReadPayload::Response(request_id, data)
+            }
+            _ => {
+                self.malformed_packets += 1;
+                self.log(RecentEventKind::Malformed);
+                ReadPayload::None
+            }
+        }
+    }
+
+    //a completed unreliable message, returned right away unless there is a jitter buffer
+    fn deliver_unreliable(
+        &mut self,
+        header: &Header,
+        payload: ReadPayload,
+        received_at: &Instant,
+    ) -> ReadPayload {
+        let Some(jitter_buffer) = &mut self.jitter_buffer else {
+            return payload;
+        };
+
+        let index = if header.packet_type.is_frag_variant() {
+            ReorderBuffer::message_index(header.seq, header.fragment_id)
+        } else {
+            header.seq
+        };
+        jitter_buffer.insert(index, payload, *received_at);
+        jitter_buffer.release(*received_at, &mut self.released);
+        ReadPayload::None
+    }
+
+    //moves the unreliable messages that are due to the released ones, the processes check it on
+    //every update so they don't wait for the next packet
+    pub fn release_jittered(&mut self, now: Instant) -> bool {
+        if let Some(jitter_buffer) = &mut self.jitter_buffer {
+            jitter_buffer.release(now, &mut self.released);
+        }
+        !self.released.is_empty()
+    }
+
+    //messages that are next in order or due in the jitter buffer, checked after every read
+    pub fn take_released(&mut self) -> Option<ReadPayload> {
+        self.released.pop_front()
+    }
+
     pub fn update(
         &mut self,
         marked_packets: &mut Vec<Rc<SendPayload>>,
         send_queue: &mut VecDeque<UdpSendEvent>,
     ) -> anyhow::Result<()> {
+        while self.send_window_open() {
+            let Some(packet) = self.pending_reliable.pop(self.config.interleave_fragments) else {
+                break;
+            };
+            self.send_pending(packet, send_queue);
+        }
+        let stalled = !self.pending_reliable.is_empty();
+        if stalled != self.window_stalled {
+            self.window_stalled = stalled;
+            self.log(match stalled {
+                true => RecentEventKind::WindowStalled(self.pending_reliable.len()),
+                false => RecentEventKind::WindowReopened,
+            });
+        }
+
+        //the remote holds back once the window is used up, it learns that it reopened from the ack
+        if self.config.flow_control
+            && self.advertised_window.get() < self.config.receive_window / 2
+            && self.receive_window() > self.advertised_window.get()
+        {
+            self.send_ack = true;
+        }
+
         self.send_buffer
             .get_redelivery_packet(self.local_seq, marked_packets);
 
+        for seq in std::mem::take(&mut self.send_buffer.timed_out) {
+            self.log(RecentEventKind::SendTimedOut(seq));
+        }
+
         while let Some(packet) = marked_packets.pop() {
             let mut header = packet.original_header;
+            self.log(RecentEventKind::Resend(header.seq));
             self.write_header_ack_fields(&mut header);
 
-            let mut int_buffer = IntBuffer::new_at(4);
-            let mut buffer = bytes_with_header!(header.get_header_size() + packet.buffer.len());
+            let mut buffer = self.take_buffer(header.get_header_size());
+            self.write_header(&header, &mut buffer);
 
-            header.write(&mut buffer, &mut int_buffer)?;
-            int_buffer.write_slice(&packet.buffer, &mut buffer);
+            let datagram = self
+                .filter_outbound(&header, packet.data.clone())
+                .map(|payload| self.datagram(buffer, Some(payload)));
+            self.send_tracking(header.seq, datagram, send_queue);
+        }
 
-            self.send_tracking(header.seq, buffer, send_queue);
+        //anything sent above already carried the acks and counts as keeping the connection alive
+        let keep_alive = self.last_sent.elapsed() >= self.config.keep_alive_interval;
+        if self.send_ack || keep_alive {
+            self.send_idle(keep_alive, send_queue)?;
         }
 
-        if self.send_ack {
-            self.send_empty_ack(send_queue)?;
+        let now = Instant::now().saturating_duration_since(self.created_at);
+        self.reliable_fragmentation.expire_groups(now);
+        self.unreliable_fragmentation.expire_groups(now);
+        let fragment_timeouts = self.reliable_fragmentation.stats.groups_timed_out
+            + self.unreliable_fragmentation.stats.groups_timed_out;
+        if fragment_timeouts > self.fragment_timeouts {
+            self.log(RecentEventKind::FragmentsTimedOut(
+                fragment_timeouts - self.fragment_timeouts,
+            ));
+            self.fragment_timeouts = fragment_timeouts;
         }
 
         Ok(())
     }
 
+    fn log(&mut self, kind: RecentEventKind) {
+        let at = Instant::now().saturating_duration_since(self.created_at);
+        self.events.push(at, kind);
+    }
+
+    //the latest notable events, oldest first
+    pub fn recent_events(&self) -> Vec<RecentEvent> {
+        self.events.events()
+    }
+
+    //the new quality of the connection if it changed since the last evaluation, the send rate
+    //callback is called if the recommendation changed with it
+    pub fn poll_quality(&mut self, now: Instant) -> Option<ConnectionQuality> {
+        let previous_rate = self.recommended_send_rate();
+        let quality = self.quality.update(
+            now,
+            self.send_buffer.trr_tracker.rtt_variance(),
+            self.send_buffer.packets_sent,
+            self.send_buffer.packets_resent,
+        )?;
+
+        let rate = self.recommended_send_rate();
+        if rate != previous_rate {
+            if let Some(on_change) = &self.config.send_rate.on_change {
+                on_change(self.addr, rate);
+            }
+        }
+        Some(quality)
+    }
+
+    //unreliable sends per second the game should stay under for this connection
+    pub fn recommended_send_rate(&self) -> u32 {
+        self.config.send_rate.recommend(self.quality.quality())
+    }
+
     fn update_remote_seq(&mut self, remote_seq: u16) -> bool {
         if Sequence::is_less_than(self.remote_seq, remote_seq) {
             //update to the new remote sequence
@@ -293,17 +1118,17 @@ impl Channel {
 
     pub fn create_unreliable_packet(
         &mut self,
-        buffer: &mut Bytes,
+        payload: Payload,
         frag: bool,
         fragment_group_id: u16,
         fragment_id: u8,
         fragment_size: u8,
-    ) -> anyhow::Result<()> {
+    ) -> Option<Datagram> {
         let mut header = Header::new(
             self.unreliable_seq,
             self.session_key,
             SendType::Unreliable,
-            false,
+            frag,
         );
         header.fragment_group_id = fragment_group_id;
         header.fragment_id = fragment_id;
@@ -311,41 +1136,196 @@ impl Channel {
 
         self.write_header_ack_fields(&mut header);
 
-        let mut int_buffer = IntBuffer::new_at(4);
-        header.write(buffer, &mut int_buffer)?;
+        let mut buffer = self.take_buffer(header.get_header_size());
+        self.write_header(&header, &mut buffer);
 
         Sequence::increment(&mut self.unreliable_seq);
 
-        Ok(())
+        let expires_at = self
+            .config
+            .unreliable_send_timeout
+            .map(|timeout| Instant::now() + timeout);
+        self.filter_outbound(&header, payload)
+            .map(|payload| Datagram {
+                expires_at,
+                ..self.datagram(buffer, Some(payload))
+            })
     }
 
+    //the payload is kept in the send buffer for redelivery and shared with the returned datagram
     pub fn create_send_buffer(
         &mut self,
-        buffer: &mut Bytes,
+        payload: Payload,
+        kind: MessageKind,
         frag: bool,
         fragment_group_id: u16,
         fragment_id: u8,
         fragment_size: u8,
-    ) -> anyhow::Result<u16> {
+    ) -> (u16, Option<Datagram>) {
+        if frag {
+            if let Some(send) = self
+                .cancellable
+                .iter_mut()
+                .find(|send| send.group_id == fragment_group_id)
+            {
+                send.seqs.push(self.local_seq);
+            }
+        }
         let mut header = Header::new(self.local_seq, self.session_key, SendType::Reliable, frag);
+        header.packet_type = PacketType::reliable(kind, frag);
         header.fragment_group_id = fragment_group_id;
         header.fragment_id = fragment_id;
         header.fragment_size = fragment_size;
 
         self.write_header_ack_fields(&mut header);
 
-        let mut int_buffer = IntBuffer::new_at(4);
-        header.write(buffer, &mut int_buffer)?;
+        let mut buffer = self.take_buffer(header.get_header_size());
+        self.write_header(&header, &mut buffer);
 
-        let send_payload = self.send_buffer.push_send_buffer(
-            self.local_seq,
-            &buffer[4 + header.get_header_size()..], //pass the just the data
-            &header,
-        );
+        self.send_buffer
+            .push_send_buffer(self.local_seq, payload.clone(), &header);
 
+        let seq = self.local_seq;
         Sequence::increment(&mut self.local_seq);
 
-        Ok(send_payload.original_header.seq)
+        let datagram = self
+            .filter_outbound(&header, payload)
+            .map(|payload| self.datagram(buffer, Some(payload)));
+
+        (seq, datagram)
+    }
+
+    fn write_header(&self, header: &Header, buffer: &mut Bytes) {
+        if self.config.compact_header {
+            header.write_compact_into(buffer);
+        } else {
+            header.write_into(buffer);
+        }
+
+        if self.config.ack_delay {
+            buffer.extend_from_slice(&self.ack_delay().to_le_bytes());
+        }
+        if self.config.flow_control {
+            let window = self.receive_window();
+            self.advertised_window.set(window);
+            buffer.extend_from_slice(&window.to_le_bytes());
+        }
+    }
+
+    //how long the remote seq was held before its ack is sent, it saturates at about 650ms
+    fn ack_delay(&self) -> u16 {
+        let Some(received_at) = self.remote_seq_received_at else {
+            return 0;
+        };
+        let units = received_at.elapsed().as_micros() / ACK_DELAY_UNIT.as_micros();
+        units.min(u16::MAX as u128) as u16
+    }
+
+    //the header, its size, the ack delay and the window of the remote, the session key of a
+    //compact header is implied
+    fn read_header(&self, buffer: &[u8]) -> anyhow::Result<(Header, usize, Duration, Option<u16>)> {
+        let (header, header_size) = if self.config.compact_header {
+            Header::read_compact(buffer, self.session_key)?
+        } else {
+            let header = Header::read(buffer)?;
+            if header.session_key != self.session_key {
+                bail!("incorrect session key");
+            }
+            (header, header.get_header_size())
+        };
+
+        let mut size = header_size;
+        let mut ack_delay = Duration::ZERO;
+        if self.config.ack_delay {
+            let Some(delay) = buffer.get(size..size + ACK_DELAY_SIZE) else {
+                bail!("header is missing the ack delay");
+            };
+            let units = u16::from_le_bytes([delay[0], delay[1]]);
+            ack_delay = ACK_DELAY_UNIT * units as u32;
+            size += ACK_DELAY_SIZE;
+        }
+
+        let mut window = None;
+        if self.config.flow_control {
+            let Some(bytes) = buffer.get(size..size + WINDOW_SIZE) else {
+                bail!("header is missing the flow control window");
+            };
+            window = Some(u16::from_le_bytes([bytes[0], bytes[1]]));
+            size += WINDOW_SIZE;
+        }
+
+        Ok((header, size, ack_delay, window))
+    }
+
+    //whether the packet belongs to this session, a new connection request from the same address doesn't
+    pub fn is_session_packet(&self, buffer: &[u8]) -> bool {
+        if self.config.compact_header {
+            //compact packets start with their type, the handshake packets are never sent on a channel
+            let Some(Ok(packet_type)) = buffer.first().map(|&t| PacketType::try_from(t)) else {
+                return false;
+            };
+            return packet_type.is_session_variant();
+        }
+
+        matches!(Header::read(buffer), Ok(header) if header.session_key == self.session_key)
+    }
+
+    //whether the packet is ahead of every packet of its kind read so far, a captured one sent again
+    //never is. the disconnects aren't sequenced and never count as newer
+    pub fn is_newer(&self, header: &Header) -> bool {
+        match header.packet_type {
+            packet_type if packet_type.is_reliable_variant() => {
+                Sequence::is_greater_then(header.seq, self.remote_seq)
+            }
+            PacketType::PayloadUnreliable
+            | PacketType::PayloadUnreliableFrag
+            | PacketType::KeepAlive => self
+                .unreliable_window
+                .newest()
+                .is_none_or(|newest| Sequence::is_greater_then(header.seq, newest)),
+            _ => false,
+        }
+    }
+
+    //nothing of the session arrived for longer than the idle timeout
+    pub fn is_idle(&self, now: Instant) -> bool {
+        self.config
+            .idle_timeout
+            .is_some_and(|timeout| now.saturating_duration_since(self.last_received) >= timeout)
+    }
+
+    fn take_buffer(&self, capacity: usize) -> Bytes {
+        match &self.pool {
+            Some(pool) => pool.take(capacity),
+            None => buffer_pool::take_buffer(capacity),
+        }
+    }
+
+    fn datagram(&self, head: Bytes, payload: Option<Payload>) -> Datagram {
+        Datagram {
+            pool: self.pool.clone(),
+            ..Datagram::new(head, payload)
+        }
+    }
+
+    //the payload is only copied if there is middleware that could rewrite it
+    fn filter_outbound(&self, header: &Header, payload: Payload) -> Option<Payload> {
+        if self.config.middleware.is_empty() {
+            return Some(payload);
+        }
+
+        let mut data = payload.to_vec();
+        let action = self.config.middleware.run(&mut PacketContext {
+            addr: self.addr,
+            direction: Direction::Outbound,
+            header,
+            payload: &mut data,
+        });
+
+        match action {
+            Action::Continue => Some(Payload::new(&data)),
+            Action::Drop => None,
+        }
     }
 
     pub fn mark_acked_packets(&mut self, ack: u16, ack_bitfield: u32, received_at: &Instant) {
@@ -355,27 +1335,46 @@ impl Channel {
 
     //least significant bit is the remote_seq - 1 value
     pub fn generate_ack_field(&self) -> u32 {
-        let mut ack_bitfield = 0;
+        generate_ack_bits(self.remote_seq, |seq| self.received_packets.is_some(seq))
+    }
+}
 
-        let mut seq = self.remote_seq.wrapping_sub(1);
-        for pos in 0..32 {
-            if self.received_packets.is_some(seq) {
-                ack_bitfield.set_bit(pos, true);
-            }
-            seq = seq.wrapping_sub(1);
-        }
-        ack_bitfield
+//the group is still pending because the fragment didn't complete it, a fragment that was stored
+//already isn't progress
+fn progress_payload(
+    fragmentation: &FragmentationManager,
+    group_id: u16,
+    received_before: u64,
+) -> ReadPayload {
+    if fragmentation.stats.fragments_received == received_before {
+        return ReadPayload::None;
+    }
+    match fragmentation.progress(group_id) {
+        Some((received, total)) => ReadPayload::Progress(group_id, received, total),
+        None => ReadPayload::None,
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use crate::net::{
+        connections::{ControlPacket, FLAG_INTERLEAVE},
+        disconnect::DisconnectCode,
+        rtt_tracker::MAX_RTT,
+        FRAGMENT_SIZE,
+    };
+
     use super::*;
 
     #[test]
     fn generating_received_bitfields() {
-        let mut channel = Channel::new("127.0.0.1:9090".parse().unwrap(), 0, ChannelType::Client);
+        let mut channel = Channel::new(
+            "127.0.0.1:9090".parse().unwrap(),
+            0,
+            ChannelType::Client,
+            ChannelConfig::default(),
+        );
         channel.remote_seq = 5;
 
         let prev_remote_seq = channel.remote_seq - 1;
@@ -400,4 +1399,861 @@ mod tests {
 
         assert_eq!(channel.generate_ack_field(), ack_bitfield);
     }
+
+    #[test]
+    fn checksum_drops_corrupted_packets() {
+        let config = ChannelConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+
+        let mut send_queue = VecDeque::new();
+        for _ in 0..2 {
+            let send_event =
+                crate::net::packets::construct_send_event(&[1, 2, 3], SendType::Unreliable)
+                    .unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+
+        let mut packets = send_queue.into_iter().rev().map(|event| match event {
+            UdpSendEvent::Client(datagram) => datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec(),
+            _ => panic!("unexpected send event"),
+        });
+
+        let packet = packets.next().unwrap();
+        assert!(matches!(
+            receiver.read(packet, &Instant::now()),
+            Ok(ReadPayload::Single(payload)) if payload == [1, 2, 3]
+        ));
+
+        let mut packet = packets.next().unwrap();
+        packet[HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            receiver.read(packet, &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+        assert_eq!(receiver.corrupted_packets, 1);
+    }
+
+    #[test]
+    fn replayed_unreliable_packets_are_dropped() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, ChannelConfig::default());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, ChannelConfig::default());
+
+        let mut send_queue = VecDeque::new();
+        let send_event =
+            crate::net::packets::construct_send_event(&[1, 2, 3], SendType::Unreliable).unwrap();
+        sender.send_event(send_event, &mut send_queue).unwrap();
+        let packet = match send_queue.pop_back() {
+            Some(UdpSendEvent::Client(datagram)) => datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec(),
+            _ => panic!("unexpected send event"),
+        };
+
+        assert!(matches!(
+            receiver.read(packet.clone(), &Instant::now()),
+            Ok(ReadPayload::Single(_))
+        ));
+        assert!(matches!(
+            receiver.read(packet, &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+        assert_eq!(receiver.stats().replayed_packets, 1);
+    }
+
+    #[test]
+    fn unreliable_fragments_are_assembled() {
+        let config = ChannelConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+
+        let data: Bytes = (0..FRAGMENT_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let send_event =
+            crate::net::packets::construct_send_event(&data, SendType::Unreliable).unwrap();
+        let mut send_queue = VecDeque::new();
+        sender.send_event(send_event, &mut send_queue).unwrap();
+
+        let mut parts = None;
+        let mut progress = Vec::new();
+        for event in send_queue.into_iter().rev() {
+            let UdpSendEvent::Client(datagram) = event else {
+                panic!("unexpected send event");
+            };
+            match receiver
+                .read(
+                    datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec(),
+                    &Instant::now(),
+                )
+                .unwrap()
+            {
+                ReadPayload::Parts(p) => parts = Some(p),
+                ReadPayload::Progress(_, received, total) => progress.push((received, total)),
+                _ => {}
+            }
+        }
+
+        assert_eq!(parts.unwrap().concat(), data);
+        assert_eq!(progress, [(1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_not_progress() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, ChannelConfig::default());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, ChannelConfig::default());
+
+        let data: Bytes = vec![1; FRAGMENT_SIZE * 2 + 10];
+        let send_event =
+            crate::net::packets::construct_send_event(&data, SendType::Unreliable).unwrap();
+        let mut send_queue = VecDeque::new();
+        sender.send_event(send_event, &mut send_queue).unwrap();
+        let Some(UdpSendEvent::Client(datagram)) = send_queue.pop_back() else {
+            panic!("unexpected send event");
+        };
+        let packet = datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            receiver.read(packet.clone(), &Instant::now()),
+            Ok(ReadPayload::Progress(_, 1, 3))
+        ));
+
+        //the same fragment under a new seq so the replay window lets it through
+        let mut header = Header::read(&packet).unwrap();
+        let header_size = header.get_header_size();
+        header.seq += 10;
+        let mut duplicate = Vec::new();
+        header.write_into(&mut duplicate);
+        duplicate.extend_from_slice(&packet[header_size..]);
+        assert!(matches!(
+            receiver.read(duplicate, &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+    }
+
+    #[test]
+    fn group_ids_start_from_the_random_source() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, ChannelConfig::default());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, ChannelConfig::default());
+        sender.start_groups(&RandomSource::seeded(5));
+        let first = RandomSource::seeded(5).next_u64();
+        assert_eq!(sender.reliable_fragmentation.next_group_id(), first as u16);
+
+        let data: Bytes = (0..FRAGMENT_SIZE * 2).map(|i| i as u8).collect();
+        let send_event =
+            crate::net::packets::construct_send_event(&data, SendType::Unreliable).unwrap();
+        let mut send_queue = VecDeque::new();
+        sender.send_event(send_event, &mut send_queue).unwrap();
+
+        let mut parts = None;
+        for event in send_queue.into_iter().rev() {
+            let UdpSendEvent::Client(datagram) = event else {
+                panic!("unexpected send event");
+            };
+            let packet = datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+            let header = Header::read(&packet).unwrap();
+            assert_eq!(header.fragment_group_id, first as u16);
+            if let ReadPayload::Parts(p) = receiver.read(packet, &Instant::now()).unwrap() {
+                parts = Some(p);
+            }
+        }
+        assert_eq!(parts.unwrap().concat(), data);
+    }
+
+    #[test]
+    fn ordered_reliable_waits_for_fragmented_messages() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, ChannelConfig::default());
+        let mut receiver = Channel::new(
+            addr,
+            1,
+            ChannelType::Server,
+            ChannelConfig {
+                ordered_reliable: true,
+                ..Default::default()
+            },
+        );
+
+        let mut send_queue = VecDeque::new();
+        let large: Bytes = (0..FRAGMENT_SIZE * 2).map(|i| i as u8).collect();
+        for data in [&large[..], &[1], &[2]] {
+            let send_event =
+                crate::net::packets::construct_send_event(data, SendType::Reliable).unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+        let mut packets: Vec<Bytes> = send_queue
+            .into_iter()
+            .rev()
+            .map(|event| match event {
+                UdpSendEvent::ClientTracking(datagram, _) => {
+                    datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec()
+                }
+                _ => panic!("unexpected send event"),
+            })
+            .collect();
+
+        //the small messages and the last fragment overtake the first fragment
+        let first_fragment = packets.remove(0);
+        let mut delivered = Vec::new();
+        for packet in packets.into_iter().rev().chain([first_fragment]) {
+            receiver.read(packet, &Instant::now()).unwrap();
+            while let Some(payload) = receiver.take_released() {
+                delivered.push(match payload {
+                    ReadPayload::Single(buffer) => buffer,
+                    ReadPayload::Parts(parts) => parts.concat(),
+                    _ => panic!("unexpected payload"),
+                });
+            }
+        }
+
+        assert_eq!(delivered, [large, vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn middleware_rewrites_and_drops_payloads() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+
+        //a toy encoding on the way out that is undone on the way in
+        let mut sender_config = ChannelConfig::default();
+        sender_config.middleware.push(|context| {
+            if context.direction == Direction::Outbound {
+                context.payload.iter_mut().for_each(|b| *b ^= 0xFF);
+            }
+            Action::Continue
+        });
+        let mut receiver_config = ChannelConfig::default();
+        receiver_config.middleware.push(|context| {
+            if context.payload.first() == Some(&0xFF) {
+                return Action::Drop;
+            }
+            context.payload.iter_mut().for_each(|b| *b ^= 0xFF);
+            Action::Continue
+        });
+
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, sender_config);
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, receiver_config);
+
+        let mut send_queue = VecDeque::new();
+        for data in [[1, 2, 3], [0, 1, 2]] {
+            let send_event =
+                crate::net::packets::construct_send_event(&data, SendType::Reliable).unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+
+        let mut packets = send_queue.into_iter().rev().map(|event| match event {
+            UdpSendEvent::ClientTracking(datagram, _) => {
+                datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec()
+            }
+            _ => panic!("unexpected send event"),
+        });
+
+        assert!(matches!(
+            receiver.read(packets.next().unwrap(), &Instant::now()),
+            Ok(ReadPayload::Single(payload)) if payload == [1, 2, 3]
+        ));
+        //dropped packets aren't acked so they get resent
+        assert!(matches!(
+            receiver.read(packets.next().unwrap(), &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+        assert!(receiver.received_packets.is_none(1));
+    }
+
+    #[test]
+    fn connection_pool_gets_its_buffers_back() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            connection_pool_size: 4,
+            ..Default::default()
+        };
+        let mut channel = Channel::new(addr, 1, ChannelType::Server, config);
+        let pool = channel.pool.clone().unwrap();
+
+        let mut send_queue = VecDeque::new();
+        let send_event =
+            crate::net::packets::construct_send_event(&[1], SendType::Reliable).unwrap();
+        channel.send_event(send_event, &mut send_queue).unwrap();
+        channel.send_idle(true, &mut send_queue).unwrap();
+
+        //the socket recycles the datagrams once they are sent
+        for event in send_queue.drain(..) {
+            event.into_datagram().recycle();
+        }
+        assert_eq!(pool.len(), 2);
+
+        channel.send_idle(true, &mut send_queue).unwrap();
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn disconnect_is_acked() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut client = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut server = Channel::new(addr, 1, ChannelType::Server, config);
+
+        let reason = DisconnectReason::with_message(DisconnectCode::UserQuit, "bye");
+        let mut send_queue = VecDeque::new();
+        client
+            .send_event(SendEvent::Disconnect(reason.clone()), &mut send_queue)
+            .unwrap();
+        assert_eq!(send_queue.len(), 1);
+
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            server.read(packet, &Instant::now()),
+            Ok(ReadPayload::Disconnect(read)) if read == reason
+        ));
+
+        server.send_disconnect_ack(&mut send_queue);
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            client.read(packet, &Instant::now()),
+            Ok(ReadPayload::DisconnectAck)
+        ));
+    }
+
+    #[test]
+    fn compact_header_channels() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            compact_header: true,
+            ..Default::default()
+        };
+        let mut client = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut server = Channel::new(addr, 1, ChannelType::Server, config);
+
+        let mut send_queue = VecDeque::new();
+        let send_event =
+            crate::net::packets::construct_send_event(&[1, 2, 3], SendType::Reliable).unwrap();
+        client.send_event(send_event, &mut send_queue).unwrap();
+
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(packet.len() < HEADER_SIZE);
+        assert!(server.is_session_packet(&packet));
+        assert!(matches!(
+            server.read(packet, &Instant::now()),
+            Ok(ReadPayload::Single(payload)) if payload == [1, 2, 3]
+        ));
+
+        server.send_idle(false, &mut send_queue).unwrap();
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        client.read(packet, &Instant::now()).unwrap();
+        assert!(!client.has_pending_reliable());
+
+        //a new handshake from the same address isn't part of the session
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        assert!(!server.is_session_packet(&request[PROTOCOL_ID_SIZE..]));
+    }
+
+    #[test]
+    fn ack_delay_is_left_out_of_the_rtt() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            ack_delay: true,
+            ..Default::default()
+        };
+        let mut client = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut server = Channel::new(addr, 1, ChannelType::Server, config);
+        let initial_rtt = client.send_buffer.trr_tracker.average_rtt();
+
+        let mut send_queue = VecDeque::new();
+        let send_event =
+            crate::net::packets::construct_send_event(&[1, 2, 3], SendType::Reliable).unwrap();
+        client.send_event(send_event, &mut send_queue).unwrap();
+        let now = Instant::now();
+        client
+            .send_buffer
+            .mark_sent(0, now - Duration::from_millis(60));
+
+        //the server held the packet for 50ms before acking it
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            server.read(packet, &(now - Duration::from_millis(50))),
+            Ok(ReadPayload::Single(payload)) if payload == [1, 2, 3]
+        ));
+        server.send_idle(false, &mut send_queue).unwrap();
+
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        client.read(packet, &Instant::now()).unwrap();
+        assert!(!client.has_pending_reliable());
+
+        //a sample of about 10ms instead of 60ms
+        let average_rtt = client.send_buffer.trr_tracker.average_rtt();
+        assert!(average_rtt < (initial_rtt + Duration::from_millis(20)) / 2);
+    }
+
+    #[test]
+    fn keep_alive_carries_payload() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, ChannelConfig::default());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, ChannelConfig::default());
+
+        let mut send_queue = VecDeque::new();
+        sender.send_idle(true, &mut send_queue).unwrap();
+        sender.keep_alive_payload = Some(vec![4, 5]);
+        sender.send_idle(true, &mut send_queue).unwrap();
+
+        let mut packets = send_queue.into_iter().rev().map(|event| match event {
+            UdpSendEvent::Client(datagram) => datagram.to_vec()[PROTOCOL_ID_SIZE..].to_vec(),
+            _ => panic!("unexpected send event"),
+        });
+
+        assert!(matches!(
+            receiver.read(packets.next().unwrap(), &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+        assert!(matches!(
+            receiver.read(packets.next().unwrap(), &Instant::now()),
+            Ok(ReadPayload::KeepAlive(payload)) if payload == [4, 5]
+        ));
+    }
+
+    #[test]
+    fn keep_alive_sent_when_idle() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            keep_alive_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut channel = Channel::new(addr, 1, ChannelType::Client, config);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        channel
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        assert!(send_queue.is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        channel
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        assert_eq!(send_queue.len(), 1);
+    }
+
+    #[test]
+    fn acks_and_keep_alives_share_a_packet() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            keep_alive_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut channel = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+        channel.keep_alive_payload = Some(vec![7]);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        //an ack alone leaves the keep alive payload out
+        channel.send_ack = true;
+        channel
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        assert_eq!(send_queue.len(), 1);
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            receiver.read(packet, &Instant::now()),
+            Ok(ReadPayload::None)
+        ));
+
+        //both due in the same update still send one packet
+        std::thread::sleep(Duration::from_millis(20));
+        channel.send_ack = true;
+        channel
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        assert_eq!(send_queue.len(), 1);
+        let packet =
+            send_queue.pop_back().unwrap().datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+        assert!(matches!(
+            receiver.read(packet, &Instant::now()),
+            Ok(ReadPayload::KeepAlive(payload)) if payload == [7]
+        ));
+    }
+
+    #[test]
+    fn reliable_sends_queue_while_the_send_window_is_full() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            max_queued_reliable: Some(4),
+            ..Default::default()
+        };
+        let mut channel = Channel::new(addr, 1, ChannelType::Client, config);
+        channel.local_seq = u16::MAX - 100;
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+        let send = |channel: &mut Channel, send_queue: &mut VecDeque<UdpSendEvent>| {
+            let send_event =
+                crate::net::packets::construct_send_event(&[1], SendType::Reliable).unwrap();
+            channel.send_event(send_event, send_queue)
+        };
+
+        for _ in 0..BUFFER_WINDOW_SIZE + 4 {
+            send(&mut channel, &mut send_queue).unwrap();
+        }
+        assert_eq!(send_queue.len(), BUFFER_WINDOW_SIZE as usize);
+        assert_eq!(channel.stats().queued_reliable, 4);
+        let error = send(&mut channel, &mut send_queue).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&NetError::WindowFull));
+
+        //the oldest ones are acked across the wraparound and free their slots
+        send_queue.clear();
+        channel.mark_acked_packets(u16::MAX - 99, 0b1, &Instant::now());
+        channel
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        assert_eq!(send_queue.len(), 2);
+        assert_eq!(channel.stats().queued_reliable, 2);
+        assert!(channel.has_pending_reliable());
+    }
+
+    #[test]
+    fn receive_window_stays_within_the_buffer_window() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        for (window, kept) in [(0, 1), (1000, BUFFER_WINDOW_SIZE)] {
+            let config = ChannelConfig {
+                flow_control: true,
+                receive_window: window,
+                ..Default::default()
+            };
+            let channel = Channel::new(addr, 1, ChannelType::Server, config);
+            assert_eq!(channel.receive_window(), kept);
+            assert!(channel.config.max_queued_reliable.is_some());
+        }
+    }
+
+    #[test]
+    fn reliable_sends_wait_for_the_window_of_the_remote() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 4,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+        let packets = |send_queue: &mut VecDeque<UdpSendEvent>| -> Vec<Vec<u8>> {
+            send_queue
+                .drain(..)
+                .rev()
+                .map(|event| event.datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec())
+                .collect()
+        };
+
+        for i in 0..10 {
+            let send_event =
+                crate::net::packets::construct_send_event(&[i], SendType::Reliable).unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+        assert_eq!(send_queue.len(), 4);
+        assert_eq!(sender.stats().queued_reliable, 6);
+
+        //the game didn't take the messages, the window is used up
+        for packet in packets(&mut send_queue) {
+            receiver.read(packet, &Instant::now()).unwrap();
+        }
+        receiver.receive_backlog = 4;
+        receiver.send_idle(false, &mut send_queue).unwrap();
+        for packet in packets(&mut send_queue) {
+            sender.read(packet, &Instant::now()).unwrap();
+        }
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        assert!(send_queue.is_empty());
+
+        //taking them reopens the window without waiting for the keep alive
+        receiver.receive_backlog = 0;
+        receiver
+            .update(&mut marked_packets, &mut send_queue)
+            .unwrap();
+        for packet in packets(&mut send_queue) {
+            sender.read(packet, &Instant::now()).unwrap();
+        }
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        assert_eq!(send_queue.len(), 4);
+        assert_eq!(sender.stats().queued_reliable, 2);
+        assert!(sender.has_pending_reliable());
+    }
+
+    #[test]
+    fn queued_fragments_of_messages_are_interleaved() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 1,
+            interleave_fragments: true,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config.clone());
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        let first: Bytes = vec![1; FRAGMENT_SIZE * 4];
+        let second: Bytes = vec![2; FRAGMENT_SIZE * 2];
+        for data in [&first, &second] {
+            let send_event =
+                crate::net::packets::construct_send_event(data, SendType::Reliable).unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+        assert_eq!(send_queue.len(), 1);
+        assert_eq!(sender.stats().queued_reliable, 5);
+
+        //the window opens for all of them, the second message goes out between the fragments of
+        //the first and is complete before it
+        sender.send_window_end = sender.local_seq.wrapping_add(16);
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        assert_eq!(sender.stats().queued_reliable, 0);
+        let mut delivered = Vec::new();
+        for event in send_queue.drain(..).rev() {
+            let packet = event.datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+            if let ReadPayload::Parts(parts) = receiver.read(packet, &Instant::now()).unwrap() {
+                delivered.push(parts.concat());
+            }
+        }
+        assert_eq!(delivered, [second, first]);
+
+        //the order of the messages needs their fragments one after the other
+        assert_ne!(config.handshake_flags() & FLAG_INTERLEAVE, 0);
+        let ordered = ChannelConfig {
+            ordered_reliable: true,
+            ..config.clone()
+        };
+        assert_eq!(ordered.handshake_flags() & FLAG_INTERLEAVE, 0);
+        assert!(
+            !config
+                .with_flags(ordered.handshake_flags())
+                .interleave_fragments
+        );
+    }
+
+    #[test]
+    fn cancelling_an_interleaved_send_keeps_the_other_messages() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 1,
+            interleave_fragments: true,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        for (send_id, len) in [(1, FRAGMENT_SIZE * 4), (2, FRAGMENT_SIZE * 2)] {
+            let send_event =
+                crate::net::packets::construct_send_event(&vec![0; len], SendType::Reliable)
+                    .unwrap();
+            sender
+                .send_cancellable(send_id, send_event, &mut send_queue)
+                .unwrap();
+        }
+        sender.send_window_end = sender.local_seq.wrapping_add(16);
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        assert_eq!(sender.stats().queued_reliable, 0);
+
+        //the fragments of the two messages took turns, only the ones of the cancelled one are
+        //given up
+        let cancelled = sender.cancellable[0].seqs.clone();
+        let kept = sender.cancellable[1].seqs.clone();
+        assert_ne!(
+            cancelled.last().unwrap().wrapping_sub(cancelled[0]) as usize,
+            cancelled.len() - 1
+        );
+        assert!(sender.cancel_send(1, &mut send_queue).unwrap());
+        assert!(cancelled
+            .iter()
+            .all(|seq| sender.send_buffer.buffers.is_none(*seq)));
+        assert!(kept
+            .iter()
+            .all(|seq| !sender.send_buffer.buffers.is_none(*seq)));
+    }
+
+    #[test]
+    fn cancelled_sends_are_dropped_by_both_sides() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 1,
+            ordered_reliable: true,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        let snapshot: Bytes = vec![1; FRAGMENT_SIZE * 4];
+        let send_event =
+            crate::net::packets::construct_send_event(&snapshot, SendType::Reliable).unwrap();
+        sender
+            .send_cancellable(7, send_event, &mut send_queue)
+            .unwrap();
+        let next: Bytes = vec![2; 16];
+        let send_event =
+            crate::net::packets::construct_send_event(&next, SendType::Reliable).unwrap();
+        sender.send_event(send_event, &mut send_queue).unwrap();
+        assert_eq!(send_queue.len(), 1);
+        assert_eq!(sender.stats().queued_reliable, 4);
+
+        //the fragments still queued are dropped, the one sent isn't resent and the cancel goes out
+        //behind the message sent after it
+        assert!(sender.cancel_send(7, &mut send_queue).unwrap());
+        assert!(!sender.cancel_send(7, &mut send_queue).unwrap());
+        assert_eq!(sender.stats().queued_reliable, 2);
+        assert!(!sender.send_buffer.has_pending(sender.local_seq));
+
+        sender.send_window_end = sender.local_seq.wrapping_add(16);
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        let mut delivered = Vec::new();
+        for event in send_queue.drain(..).rev() {
+            let packet = event.datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+            let mut payload = receiver.read(packet, &Instant::now()).unwrap();
+            loop {
+                if let ReadPayload::Single(data) = payload {
+                    delivered.push(data);
+                }
+                match receiver.take_released() {
+                    Some(released) => payload = released,
+                    None => break,
+                }
+            }
+        }
+
+        //the message after it isn't held back waiting for the cancelled one
+        assert_eq!(delivered, [next]);
+        assert_eq!(receiver.stats().fragments.groups_cancelled, 1);
+        assert_eq!(receiver.reorder_buffer.len(), 0);
+
+        let send_event =
+            crate::net::packets::construct_send_event(&[3; 16], SendType::Reliable).unwrap();
+        assert!(sender
+            .send_cancellable(8, send_event, &mut send_queue)
+            .is_err());
+    }
+
+    #[test]
+    fn superseding_sends_replace_the_previous_one() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 1,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config.clone());
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        let send = |sender: &mut Channel, key: u64, data: &[u8], send_queue: &mut _| {
+            let send_event =
+                crate::net::packets::construct_send_event(data, SendType::Reliable).unwrap();
+            sender.send_superseding(key, send_event, send_queue)
+        };
+        //the first one goes out and the window closes, the queued ones of the key replace each other
+        send(&mut sender, 1, &[1], &mut send_queue).unwrap();
+        send(&mut sender, 1, &[2], &mut send_queue).unwrap();
+        send(&mut sender, 2, &[3], &mut send_queue).unwrap();
+        send(&mut sender, 1, &[4], &mut send_queue).unwrap();
+        assert_eq!(sender.stats().queued_reliable, 2);
+
+        //the one in flight isn't resent once its replacement is sent
+        let first_seq = sender.local_seq.wrapping_sub(1);
+        sender.send_window_end = sender.local_seq.wrapping_add(16);
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        assert!(sender.send_buffer.buffers.is_none(first_seq));
+
+        let mut delivered = Vec::new();
+        for event in send_queue.drain(..).rev().skip(1) {
+            let packet = event.datagram().to_vec()[PROTOCOL_ID_SIZE..].to_vec();
+            if let ReadPayload::Single(data) = receiver.read(packet, &Instant::now()).unwrap() {
+                delivered.push(data);
+            }
+        }
+        assert_eq!(delivered, [vec![3], vec![4]]);
+
+        let fragmented = vec![0; FRAGMENT_SIZE * 2];
+        assert!(send(&mut sender, 1, &fragmented, &mut send_queue).is_err());
+        let mut ordered = Channel::new(
+            addr,
+            1,
+            ChannelType::Client,
+            ChannelConfig {
+                ordered_reliable: true,
+                ..config
+            },
+        );
+        assert!(send(&mut ordered, 1, &[1], &mut send_queue).is_err());
+    }
+
+    #[test]
+    fn notable_events_are_logged() {
+        let addr = "127.0.0.1:9090".parse().unwrap();
+        let config = ChannelConfig {
+            flow_control: true,
+            receive_window: 1,
+            event_log_size: 8,
+            ..Default::default()
+        };
+        let mut sender = Channel::new(addr, 1, ChannelType::Client, config.clone());
+        let mut receiver = Channel::new(addr, 1, ChannelType::Server, config);
+        let mut marked_packets = Vec::new();
+        let mut send_queue = VecDeque::new();
+
+        for i in 0..3 {
+            let send_event =
+                crate::net::packets::construct_send_event(&[i], SendType::Reliable).unwrap();
+            sender.send_event(send_event, &mut send_queue).unwrap();
+        }
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+
+        //the first packet is lost and resent, the others wait for the window until it opens
+        sender
+            .send_buffer
+            .mark_sent(0, Instant::now() - MAX_RTT * 2);
+        //the timers of the update that just ran don't expire before the next tick
+        std::thread::sleep(Duration::from_millis(5));
+        sender.send_window_end = sender.local_seq.wrapping_add(16);
+        sender.update(&mut marked_packets, &mut send_queue).unwrap();
+        let kinds: Vec<_> = sender
+            .recent_events()
+            .iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                RecentEventKind::WindowStalled(2),
+                RecentEventKind::WindowReopened,
+                RecentEventKind::Resend(0),
+            ]
+        );
+
+        assert!(receiver.read(vec![0xAB; 4], &Instant::now()).is_err());
+        let events = receiver.recent_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, RecentEventKind::Malformed);
+    }
 }
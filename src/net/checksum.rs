@@ -0,0 +1,101 @@
+//CRC32C (Castagnoli) used for the optional packet integrity check
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+const TABLE: [u32; 256] = generate_table();
+
+const fn generate_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub const CHECKSUM_SIZE: usize = 4;
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_parts(&[data])
+}
+
+//checksum of the parts as if they were one continuous buffer
+pub fn crc32c_parts(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0_u32;
+    for part in parts {
+        for byte in part.iter() {
+            crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+//appends the checksum of everything past the offset to the end of the buffer
+pub fn append_checksum(buffer: &mut Vec<u8>, offset: usize) {
+    let crc = crc32c(&buffer[offset..]);
+    buffer.extend_from_slice(&crc.to_le_bytes());
+}
+
+//returns the length of the buffer without the checksum if the checksum is valid
+pub fn verify_checksum(buffer: &[u8]) -> Option<usize> {
+    if buffer.len() < CHECKSUM_SIZE {
+        return None;
+    }
+
+    let data_len = buffer.len() - CHECKSUM_SIZE;
+    let mut crc_bytes = [0_u8; CHECKSUM_SIZE];
+    crc_bytes.copy_from_slice(&buffer[data_len..]);
+
+    if crc32c(&buffer[..data_len]) == u32::from_le_bytes(crc_bytes) {
+        Some(data_len)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0_u8; 32]), 0x8A91_36AA);
+    }
+
+    #[test]
+    fn append_and_verify() {
+        let mut buffer = vec![9, 9, 1, 2, 3, 4, 5];
+        append_checksum(&mut buffer, 2);
+
+        assert_eq!(verify_checksum(&buffer[2..]), Some(5));
+
+        //flip a bit in the payload
+        buffer[4] ^= 0b100;
+        assert_eq!(verify_checksum(&buffer[2..]), None);
+    }
+
+    #[test]
+    fn parts_match_continuous() {
+        assert_eq!(
+            crc32c_parts(&[b"1234", b"", b"56789"]),
+            crc32c(b"123456789")
+        );
+    }
+
+    #[test]
+    fn verify_short_buffer() {
+        assert_eq!(verify_checksum(&[1, 2, 3]), None);
+    }
+}
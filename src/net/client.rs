@@ -1,74 +1,603 @@
-use std::{io, net::SocketAddr, sync::Arc, thread, time::Duration};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
-use crossbeam_channel::{Receiver, Sender};
 use log::error;
 
 use super::{
-    client_process::{ClientProcess, InternalClientEvent},
-    fragmentation_manager::FragmentationManager,
+    client_process::{ClientProcess, InternalClientCommand, InternalClientEvent},
+    command::CommandSender,
+    config::ClientConfig,
+    connections::{ConnectionId, HandshakeStage, HandshakeTimeout},
+    disconnect::{DisconnectCode, DisconnectReason},
+    fragmentation_manager::{FragmentationManager, FRAGMENT_SIZE},
     header::SendType,
-    packets::{self, SendEvent},
+    packets::{self, SendEvent, SendHandle},
+    queue::{self, OnFull, QueueReceiver, QueueSender, RecvTimeoutError},
+    request::{write_request_id, ResponseHandle},
+    socket::SocketError,
+    state::SharedLatestStates,
+    stats::{ConnectionStats, SendQueueStats, SharedConnectionStats, SharedSendQueueStats},
+    Bytes, SessionToken,
 };
 
+//a candidate of connect_any gets this head start before the next one is tried
+const CANDIDATE_DELAY: Duration = Duration::from_millis(250);
+
+//any interface and a port the system picks, in the family of the remote
+fn any_local_addr(remote_addr: SocketAddr) -> SocketAddr {
+    match remote_addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+//the addresses of a host like "play.example.com:7777", blocks on the lookup of the name
+fn resolve(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = host
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed resolving {host}: {e}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} has no addresses"),
+        ));
+    }
+    Ok(addrs)
+}
+
+//the error names the address, the kind and the os error are kept for the caller
+fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::bind(addr).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed binding the client to {addr}: {e}"),
+        )
+    })
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ClientEvent<'a> {
+    //the instant is when the packet completing the message arrived on the socket
+    Receive(&'a [u8], Instant),
+    //the server closed the connection, nothing else is received after it
+    Disconnected(DisconnectReason),
+    //a send or a receive of the socket failed, the client recovered from it
+    SocketError(SocketError),
+}
+
+//a stage of the handshake of a client, returned by PendingClient::poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectEvent {
+    //the connection request is sent, again when the handshake starts over
+    Connecting,
+    //the server answered with the challenge, the response is sent
+    ChallengeReceived,
+    //the server accepted the connection, PendingClient::into_client returns the client
+    Accepted(ConnectionId),
+    //the handshake failed, nothing follows
+    Denied(ConnectFailure),
+}
+
+//why the handshake of a client failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    //nothing listens on the port of the server
+    Refused,
+    //the server didn't finish the handshake, it can be full or not running. the stage the replies
+    //stopped at and the attempts made for it
+    NoResponse(HandshakeStage, u32),
+    //the socket failed
+    Socket(io::ErrorKind),
+    //the host has no address or its name couldn't be looked up
+    Unresolved,
+}
+
+impl ConnectFailure {
+    fn of(e: &anyhow::Error) -> Self {
+        if let Some(timeout) = e.downcast_ref::<HandshakeTimeout>() {
+            return ConnectFailure::NoResponse(timeout.stage, timeout.attempts);
+        }
+
+        match e.downcast_ref::<io::Error>() {
+            Some(e) if e.kind() == io::ErrorKind::ConnectionRefused => ConnectFailure::Refused,
+            Some(e) => ConnectFailure::Socket(e.kind()),
+            //the handshake ended in another way, the server sent something unexpected
+            None => ConnectFailure::Socket(io::ErrorKind::InvalidData),
+        }
+    }
+}
+
+//a client going through the handshake, the stages are polled without blocking the caller
+pub struct PendingClient {
+    //handed to the client once it's accepted
+    events: Option<QueueReceiver<InternalClientEvent>>,
+    commands: Option<QueueSender<InternalClientCommand>>,
+    client: Option<Client>,
+    error: Option<io::Error>,
+}
+
+impl PendingClient {
+    //the next stage of the handshake, None if no stage was reached within the timeout or the
+    //handshake is over
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Option<ConnectEvent>> {
+        let Some(events) = &self.events else {
+            return Ok(None);
+        };
+        if self.error.is_some() {
+            return Ok(None);
+        }
+
+        match events.recv_timeout(timeout) {
+            Ok(InternalClientEvent::Handshake(event)) => Ok(Some(event)),
+            Ok(InternalClientEvent::Connect(
+                client_id,
+                local_addr,
+                waker,
+                stats,
+                send_queue,
+                states,
+            )) => {
+                let (Some(events), Some(commands)) = (self.events.take(), self.commands.take())
+                else {
+                    return Ok(None);
+                };
+                self.client = Some(Client {
+                    client_id,
+                    local_addr,
+                    in_sends: CommandSender::new(commands, waker),
+                    out_events: events,
+                    stats,
+                    send_queue,
+                    states,
+                    next_request_id: AtomicU32::new(0),
+                    next_send_id: AtomicU32::new(0),
+                });
+                Ok(Some(ConnectEvent::Accepted(client_id)))
+            }
+            Ok(InternalClientEvent::ConnectFailed(failure, e)) => {
+                self.error = Some(e);
+                Ok(Some(ConnectEvent::Denied(failure)))
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::other("the process thread has stopped"))
+            }
+        }
+    }
+
+    //the client once Accepted was returned
+    pub fn into_client(self) -> Option<Client> {
+        self.client
+    }
+}
+
 pub struct Client {
-    client_id: u32,
-    in_sends: Sender<SendEvent>,
-    out_events: Receiver<InternalClientEvent>,
+    client_id: ConnectionId,
+    local_addr: SocketAddr,
+    in_sends: CommandSender<InternalClientCommand>,
+    out_events: QueueReceiver<InternalClientEvent>,
+    stats: SharedConnectionStats,
+    send_queue: SharedSendQueueStats,
+    states: SharedLatestStates,
+    next_request_id: AtomicU32,
+    next_send_id: AtomicU32,
 }
 
 impl Client {
     pub fn connect(addr: SocketAddr, remote_addr: SocketAddr) -> io::Result<Self> {
-        let (send_tx, send_rx) = crossbeam_channel::unbounded();
-        let (recv_tx, recv_rx) = crossbeam_channel::unbounded();
+        Self::connect_with_config(addr, remote_addr, ClientConfig::default())
+    }
+
+    //binds to a port the system picks, local_addr tells which one
+    pub fn connect_to(remote_addr: SocketAddr) -> io::Result<Self> {
+        Self::connect_to_with_config(remote_addr, ClientConfig::default())
+    }
+
+    pub fn connect_to_with_config(
+        remote_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        Self::connect_with_config(any_local_addr(remote_addr), remote_addr, config)
+    }
+
+    pub fn connect_with_config(
+        addr: SocketAddr,
+        remote_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        Self::connect_with_socket(bind(addr)?, remote_addr, config)
+    }
+
+    //connects from a socket that is already bound, options like the buffer sizes set on it are kept
+    pub fn connect_with_socket(
+        socket: UdpSocket,
+        remote_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        let mut pending = Self::start_connect_with_socket(socket, remote_addr, config);
+
+        //wait for the handshake to end
+        let deadline = Instant::now() + Duration::from_secs(50);
+        while Instant::now() < deadline {
+            match pending.poll(deadline.saturating_duration_since(Instant::now()))? {
+                Some(ConnectEvent::Accepted(_)) => {
+                    return Ok(pending.client.take().expect("accepted without a client"))
+                }
+                Some(ConnectEvent::Denied(_)) => {
+                    return Err(pending.error.take().expect("denied without an error"))
+                }
+                _ => {}
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "failed waiting for connection event",
+        ))
+    }
+
+    //races the handshakes with the addresses, a new one is started every 250ms or as soon as one
+    //fails. the first to complete is kept and the others are dropped, those that still get accepted
+    //disconnect right away. for servers that can be reached over IPv4 and IPv6 or in several regions
+    pub fn connect_any(remote_addrs: &[SocketAddr], config: ClientConfig) -> io::Result<Self> {
+        let mut candidates = remote_addrs.iter();
+        let mut pending: Vec<PendingClient> = Vec::with_capacity(remote_addrs.len());
+        let mut next_start = Instant::now();
+        let mut last_error = None;
+
+        let deadline = Instant::now() + Duration::from_secs(50);
+        while Instant::now() < deadline {
+            if pending.is_empty() || Instant::now() >= next_start {
+                match candidates.next() {
+                    Some(&remote_addr) => {
+                        let local_addr = any_local_addr(remote_addr);
+                        match Self::start_connect(local_addr, remote_addr, config.clone()) {
+                            Ok(client) => pending.push(client),
+                            Err(e) => last_error = Some(e),
+                        }
+                        next_start = Instant::now() + CANDIDATE_DELAY;
+                        continue;
+                    }
+                    None if pending.is_empty() => break,
+                    None => {}
+                }
+            }
+
+            let mut index = 0;
+            while index < pending.len() {
+                match pending[index].poll(Duration::from_millis(1)) {
+                    Ok(Some(ConnectEvent::Accepted(_))) => {
+                        let mut winner = pending.swap_remove(index);
+                        return Ok(winner.client.take().expect("accepted without a client"));
+                    }
+                    Ok(Some(ConnectEvent::Denied(_))) => {
+                        last_error = pending.swap_remove(index).error.take();
+                        next_start = Instant::now();
+                    }
+                    Ok(_) => index += 1,
+                    Err(e) => {
+                        last_error = Some(e);
+                        pending.swap_remove(index);
+                        next_start = Instant::now();
+                    }
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| match remote_addrs {
+            [] => io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to"),
+            _ => io::Error::new(
+                io::ErrorKind::TimedOut,
+                "failed waiting for connection event",
+            ),
+        }))
+    }
+
+    //resolves the host, like "play.example.com:7777", and races the handshakes with its addresses
+    //like connect_any. the name is looked up again by every call, connecting again after the
+    //server restarted finds it if it moved
+    pub fn connect_to_host(host: &str, config: ClientConfig) -> io::Result<Self> {
+        Self::connect_any(&resolve(host)?, config)
+    }
+
+    //starts the handshake with the first address of the host without blocking the caller on the
+    //lookup, the name is resolved on the thread of the client
+    pub fn start_connect_to_host(host: &str, config: ClientConfig) -> PendingClient {
+        let host = host.to_string();
+        Self::spawn_connect(config, move || {
+            let remote_addr = resolve(&host).map_err(|e| (ConnectFailure::Unresolved, e))?[0];
+            let socket = bind(any_local_addr(remote_addr))
+                .map_err(|e| (ConnectFailure::Socket(e.kind()), e))?;
+            Ok((socket, remote_addr))
+        })
+    }
 
-        thread::spawn(
-            move || match ClientProcess::connect(addr, remote_addr, send_tx, recv_rx) {
+    //starts the handshake without waiting for it, the stages are read from the returned client
+    pub fn start_connect(
+        addr: SocketAddr,
+        remote_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<PendingClient> {
+        Ok(Self::start_connect_with_socket(
+            bind(addr)?,
+            remote_addr,
+            config,
+        ))
+    }
+
+    pub fn start_connect_with_socket(
+        socket: UdpSocket,
+        remote_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> PendingClient {
+        Self::spawn_connect(config, move || Ok((socket, remote_addr)))
+    }
+
+    //the socket and the remote are set up on the thread of the client, a failure of that ends the
+    //handshake like a failed one
+    fn spawn_connect(
+        config: ClientConfig,
+        setup: impl FnOnce() -> Result<(UdpSocket, SocketAddr), (ConnectFailure, io::Error)>
+            + Send
+            + 'static,
+    ) -> PendingClient {
+        let (send_tx, send_rx) = queue::channel(queue::EVENT_CAPACITY, OnFull::Block);
+        let (recv_tx, recv_rx) = queue::channel(queue::COMMAND_CAPACITY, OnFull::Block);
+
+        let failed_tx = send_tx.clone();
+        thread::spawn(move || {
+            let (socket, remote_addr) = match setup() {
+                Ok(setup) => setup,
+                Err((failure, e)) => {
+                    let _ = failed_tx.send(InternalClientEvent::ConnectFailed(failure, e));
+                    return;
+                }
+            };
+            match ClientProcess::connect(socket, remote_addr, config, send_tx, recv_rx) {
                 Ok(mut process) => {
                     if let Err(e) = process.start() {
                         error!("error while running starting: {}", e)
                     }
                 }
-                Err(e) => error!("error while binding process: {}", e),
-            },
-        );
-
-        //wait for the start event
-        let client_id = match send_rx.recv_timeout(Duration::from_secs(50)) {
-            Ok(InternalClientEvent::Connect(client_id)) => client_id,
-            _ => panic!("failed waiting for connection event"),
-        };
+                Err(e) => {
+                    error!("error while binding process: {}", e);
+                    let failure = ConnectFailure::of(&e);
+                    let e = match e.downcast::<io::Error>() {
+                        Ok(e) => e,
+                        Err(e) if e.is::<HandshakeTimeout>() => {
+                            io::Error::new(io::ErrorKind::TimedOut, e.to_string())
+                        }
+                        Err(e) => io::Error::other(e.to_string()),
+                    };
+                    let _ = failed_tx.send(InternalClientEvent::ConnectFailed(failure, e));
+                }
+            }
+        });
 
-        Ok(Client {
-            client_id,
-            in_sends: recv_tx,
-            out_events: send_rx,
-        })
+        PendingClient {
+            events: Some(send_rx),
+            commands: Some(recv_tx),
+            client: None,
+            error: None,
+        }
     }
 
     pub fn send(&self, data: &[u8], send_type: SendType) -> anyhow::Result<()> {
         let send_event = packets::construct_send_event(data, send_type)?;
 
-        self.in_sends.send(send_event)?;
+        self.in_sends
+            .send(InternalClientCommand::Send(send_event))?;
+        Ok(())
+    }
+
+    //like send without copying the data, the packets hold on to it
+    pub fn send_shared(&self, data: Arc<[u8]>, send_type: SendType) -> anyhow::Result<()> {
+        let send_event = packets::construct_shared_send_event(data, send_type)?;
+
+        self.in_sends
+            .send(InternalClientCommand::Send(send_event))?;
+        Ok(())
+    }
+
+    //sends the data reliably, the reply of the server is passed to the handle instead of being read.
+    //the request is forgotten once the handle is dropped or after the request timeout
+    pub fn request(&self, data: &[u8]) -> anyhow::Result<ResponseHandle> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let send_event =
+            packets::construct_send_event(&write_request_id(request_id, data), SendType::Reliable)?;
+
+        let (handle, sender) = ResponseHandle::new(request_id);
+        self.in_sends.send(InternalClientCommand::Request(
+            request_id, send_event, sender,
+        ))?;
+        Ok(handle)
+    }
+
+    //a reliable message too large for one packet that cancel_send can take back while it's in
+    //flight, a channel remembers its 32 latest of them
+    pub fn send_cancellable(&self, data: &[u8]) -> anyhow::Result<SendHandle> {
+        if !FragmentationManager::should_fragment(data.len()) {
+            bail!("only messages sent in fragments can be cancelled");
+        }
+        let send_event = packets::construct_send_event(data, SendType::Reliable)?;
+
+        let send_id = self.next_send_id.fetch_add(1, Ordering::Relaxed);
+        self.in_sends
+            .send(InternalClientCommand::SendCancellable(send_id, send_event))?;
+        Ok(SendHandle { send_id })
+    }
+
+    //the fragments that weren't acked yet are dropped and the server drops the ones it has
+    pub fn cancel_send(&self, handle: SendHandle) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalClientCommand::CancelSend(handle.send_id))?;
+        Ok(())
+    }
+
+    //a reliable message that replaces the previous one sent with the key, which isn't resent anymore
+    //if it wasn't acked yet. the data has to fit in one packet and the channel can't have
+    //ordered_reliable
+    pub fn send_superseding(&self, key: u64, data: &[u8]) -> anyhow::Result<()> {
+        if FragmentationManager::should_fragment(data.len()) {
+            bail!("a superseding message has to fit in one packet");
+        }
+        let send_event = packets::construct_send_event(data, SendType::Reliable)?;
+
+        self.in_sends
+            .send(InternalClientCommand::SendSuperseding(key, send_event))?;
+        Ok(())
+    }
+
+    //the largest message that can be passed to send
+    pub fn max_message_size(&self) -> usize {
+        FragmentationManager::max_message_size()
+    }
+
+    //the ranges of data of the length to send as separate messages when it's larger than
+    //max_message_size
+    pub fn split_hint(&self, len: usize) -> Vec<Range<usize>> {
+        FragmentationManager::split_hint(len)
+    }
+
+    //the address the server sees the client at, with the port picked by the system for connect_to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    //the counters of the connection as of the last update of the client
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    //the server prints the same one for the connection, for matching the logs of both sides
+    pub fn session_token(&self) -> SessionToken {
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .session_token
+    }
+
+    //the packets waiting for the socket, with the highest depth and age seen
+    pub fn send_queue_stats(&self) -> SendQueueStats {
+        *self.send_queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //the newest value the server set for the state slot, None until one arrived
+    pub fn latest(&self, slot: u16) -> Option<Bytes> {
+        self.states
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&slot)
+            .map(|(_, value)| value.clone())
+    }
+
+    //unreliable sends per second recommended for the quality of the connection
+    pub fn recommended_send_rate(&self) -> u32 {
+        self.stats().recommended_send_rate
+    }
+
+    //attach data to the keep alive packets, an empty payload clears it
+    pub fn set_keepalive_payload(&self, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() > FRAGMENT_SIZE {
+            bail!("keep alive payload can't be larger than {FRAGMENT_SIZE} bytes");
+        }
+
+        let payload = if data.is_empty() {
+            None
+        } else {
+            Some(data.to_vec())
+        };
+
+        self.in_sends
+            .send(InternalClientCommand::SetKeepAlivePayload(payload))?;
         Ok(())
     }
 
     //TODO: make disconnect blocking
     pub fn disconnect(&self) -> anyhow::Result<()> {
-        self.in_sends.send(SendEvent::Disconnect)?;
+        self.disconnect_with_reason(DisconnectReason::new(DisconnectCode::UserQuit))
+    }
+
+    //the reason is passed to the server in the disconnect packets
+    pub fn disconnect_with_reason(&self, reason: DisconnectReason) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalClientCommand::Disconnect(reason, None))?;
         Ok(())
     }
 
+    //waits for the reliable messages that were already sent to be acked before disconnecting,
+    //the disconnect goes out after the timeout even if some of them are still pending
+    pub fn disconnect_draining(
+        &self,
+        reason: DisconnectReason,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalClientCommand::Disconnect(reason, Some(timeout)))?;
+        Ok(())
+    }
+
+    //fails on a socket error too, the error downcasts to the SocketError. once 4096 events are
+    //waiting the client holds its processing until the game read some
     pub fn read<'a>(&self, dest: &'a mut [u8], timeout: Duration) -> anyhow::Result<&'a [u8]> {
+        self.read_timestamped(dest, timeout)
+            .map(|(buffer, _)| buffer)
+    }
+
+    //also returns when the packet completing the message arrived on the socket
+    pub fn read_timestamped<'a>(
+        &self,
+        dest: &'a mut [u8],
+        timeout: Duration,
+    ) -> anyhow::Result<(&'a [u8], Instant)> {
+        match self.read_event(dest, timeout)? {
+            ClientEvent::Receive(buffer, received_at) => Ok((buffer, received_at)),
+            ClientEvent::Disconnected(reason) => {
+                bail!("disconnected by the server ({reason})")
+            }
+            //returned as the error, read_event reports it as an event instead
+            ClientEvent::SocketError(e) => Err(e.into()),
+        }
+    }
+
+    //like Server::set_wakeup, called from the client thread whenever an event can be read
+    pub fn set_wakeup<F>(&self, wakeup: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.out_events.set_wakeup(Some(Arc::new(wakeup)));
+    }
+
+    pub fn clear_wakeup(&self) {
+        self.out_events.set_wakeup(None);
+    }
+
+    //like read but the server closing the connection and the socket errors are returned as events
+    //instead of errors
+    pub fn read_event<'a>(
+        &self,
+        dest: &'a mut [u8],
+        timeout: Duration,
+    ) -> anyhow::Result<ClientEvent<'a>> {
         match self.out_events.recv_timeout(timeout) {
-            Ok(InternalClientEvent::Receive(buffer)) => {
+            Ok(InternalClientEvent::Receive(buffer, received_at)) => {
                 if dest.len() < buffer.len() {
                     bail!("destination size is not big enough.")
                 }
                 dest[..buffer.len()].copy_from_slice(&buffer);
-                Ok(&dest[..buffer.len()])
+                Ok(ClientEvent::Receive(&dest[..buffer.len()], received_at))
             }
-            Ok(InternalClientEvent::ReceiveParts(parts)) => {
+            Ok(InternalClientEvent::ReceiveParts(parts, received_at)) => {
                 let mut bytes_offset = 0;
                 for part in parts {
                     let part_len = part.len();
@@ -81,8 +610,10 @@ impl Client {
                     }
                 }
 
-                Ok(&dest[..bytes_offset])
+                Ok(ClientEvent::Receive(&dest[..bytes_offset], received_at))
             }
+            Ok(InternalClientEvent::Disconnected(reason)) => Ok(ClientEvent::Disconnected(reason)),
+            Ok(InternalClientEvent::SocketError(e)) => Ok(ClientEvent::SocketError(e)),
             Err(e) => panic!("error receiving {e}"),
             _ => panic!("unexpected event"),
         }
@@ -3,38 +3,82 @@ use std::{
     io,
     net::SocketAddr,
     rc::Rc,
-    sync::Arc,
+    sync::{Arc, Mutex},
     thread::{self},
     time::{Duration, Instant},
 };
 
 use anyhow::bail;
-use crossbeam_channel::{select, Receiver, Sender};
-use log::{error, info, warn};
-use mio::{net::UdpSocket, Token};
+use crossbeam_channel::Sender;
+use log::{debug, error, info, warn};
+use mio::{net::UdpSocket, Token, Waker};
 use rand::Rng;
 
 use super::{
     channel::{Channel, ChannelType, ReadPayload},
-    connections::{self, ConnectionHandshake},
+    client::{ConnectEvent, ConnectFailure},
+    config::ClientConfig,
+    connections::{self, ConnectionHandshake, ConnectionId, ControlPacket},
+    disconnect::{DisconnectCode, DisconnectReason},
     header::SendType,
     int_buffer::IntBuffer,
+    linger::Linger,
     packets::SendEvent,
+    queue::{QueueReceiver, QueueSender, TryRecvError},
+    request::ResponseSender,
     send_buffer::SendPayload,
-    socket::{Socket, UdpEvent, UdpSendEvent},
-    Bytes, PacketType, MAGIC_NUMBER_HEADER,
+    socket::{ReadBudget, Socket, SocketError, UdpEvent, UdpSendEvent},
+    state::{store_state, SharedLatestStates, STATE_MARKER},
+    stats::{SharedConnectionStats, SharedSendQueueStats},
+    ticker::Ticker,
+    Bytes, MessageKind, PacketType, SessionToken,
 };
 
 #[derive(PartialEq, Eq)]
 enum ClientState {
     Connected,
+    //waiting for the pending reliable packets to be acked before disconnecting
+    Draining,
+    //we closed the connection and wait for the server to ack it
     Disconnecting,
+    //the server closed the connection, its resent disconnects are acked until the linger ends
+    Disconnected,
 }
 
 pub enum InternalClientEvent {
-    Connect(u32),
-    Receive(Bytes),
-    ReceiveParts(Vec<Bytes>),
+    //the waker interrupts the poll of the process when a command is sent
+    Connect(
+        ConnectionId,
+        SocketAddr,
+        Arc<Waker>,
+        SharedConnectionStats,
+        SharedSendQueueStats,
+        SharedLatestStates,
+    ),
+    Receive(Bytes, Instant),
+    ReceiveParts(Vec<Bytes>, Instant),
+    Disconnected(DisconnectReason),
+    SocketError(SocketError),
+    //a stage of the handshake was reached
+    Handshake(ConnectEvent),
+    //the handshake failed, the process ended
+    ConnectFailed(ConnectFailure, io::Error),
+}
+
+pub enum InternalClientCommand {
+    //send a packet to the server
+    Send(SendEvent),
+    //send a request, its response is passed to the sender instead of the API
+    Request(u32, SendEvent, ResponseSender),
+    //send a reliable message in fragments that can be cancelled, by send id
+    SendCancellable(u32, SendEvent),
+    CancelSend(u32),
+    //send a reliable message that replaces the previous one with the key
+    SendSuperseding(u64, SendEvent),
+    //close the connection, the reliable packets are drained first for at most the duration
+    Disconnect(DisconnectReason, Option<Duration>),
+    //data attached to every keep alive packet, None clears it
+    SetKeepAlivePayload(Option<Bytes>),
 }
 
 pub struct ClientProcess {
@@ -43,96 +87,165 @@ pub struct ClientProcess {
     socket: Socket,
     send_queue: VecDeque<UdpSendEvent>,
     //API channels
-    out_events: Sender<InternalClientEvent>,
-    in_sends: Receiver<SendEvent>,
+    out_events: QueueSender<InternalClientEvent>,
+    in_sends: QueueReceiver<InternalClientCommand>,
     marked_packets_buf: Vec<Rc<SendPayload>>,
+    ticker: Ticker,
+    read_budget: ReadBudget,
+    //commands of the API handled since the last update
+    max_commands_per_tick: usize,
+    commands_handled: usize,
+    //set once the connection is closed by either side
+    linger: Option<Linger>,
+    //the disconnect that is sent once draining is done and the deadline of the drain
+    drain: Option<(DisconnectReason, Instant)>,
+    stats: SharedConnectionStats,
+    //requests waiting for their response by request id and when they expire, dropped once the
+    //connection closes
+    pending_requests: HashMap<u32, (ResponseSender, Instant)>,
+    request_timeout: Duration,
+    //the newest value of every state slot the server sent
+    states: SharedLatestStates,
 }
 
 impl ClientProcess {
     pub fn connect(
-        local_addr: SocketAddr,
+        socket: std::net::UdpSocket,
         remote_addr: SocketAddr,
-        out_events: Sender<InternalClientEvent>,
-        in_sends: Receiver<SendEvent>,
+        config: ClientConfig,
+        out_events: QueueSender<InternalClientEvent>,
+        in_sends: QueueReceiver<InternalClientCommand>,
     ) -> anyhow::Result<Self> {
-        let mut socket = Socket::connect(local_addr, remote_addr)?;
+        let mut socket = Socket::connect(socket, remote_addr, &config.socket)?;
+        socket.set_protocol_id(config.protocol_id);
+        let local_addr = socket.local_addr();
 
-        let connection_response = ConnectionHandshake::new(&mut socket).try_login()?;
+        let connection_response = ConnectionHandshake::new(
+            &mut socket,
+            config.channel.handshake_flags(),
+            &config.random,
+            config.challenge.as_ref(),
+            config.handshake,
+        )
+        .try_login(|event| {
+            let _ = out_events.send(InternalClientEvent::Handshake(event));
+        })?;
 
-        out_events.send(InternalClientEvent::Connect(
+        let mut channel = Channel::new(
+            local_addr,
+            connection_response.session_key,
+            ChannelType::Client,
+            config.channel.with_flags(connection_response.flags),
+        );
+        channel.start_groups(&config.random);
+        //published before the client is handed out so the session token is there right away
+        let stats = SharedConnectionStats::new(Mutex::new(channel.stats()));
+        info!(
+            "connected to {remote_addr} as {}, session {}",
             connection_response.connection_id,
-        ))?;
+            SessionToken::of(connection_response.session_key)
+        );
+        let states = SharedLatestStates::default();
+        //nobody waits for the client anymore, a connect_any that picked another address
+        let abandoned = out_events
+            .send(InternalClientEvent::Connect(
+                connection_response.connection_id,
+                local_addr,
+                socket.waker(),
+                stats.clone(),
+                socket.send_queue_stats(),
+                states.clone(),
+            ))
+            .is_err();
 
-        Ok(Self {
+        let mut process = Self {
             state: ClientState::Connected,
-            channel: Channel::new(
-                local_addr,
-                connection_response.session_key,
-                ChannelType::Client,
-            ),
+            channel,
             socket,
             send_queue: VecDeque::new(),
             in_sends,
             out_events,
             marked_packets_buf: Vec::new(),
-        })
+            ticker: Ticker::new(config.channel.update_interval),
+            read_budget: ReadBudget::new(config.max_reads_per_tick),
+            max_commands_per_tick: config.max_commands_per_tick,
+            commands_handled: 0,
+            linger: None,
+            drain: None,
+            stats,
+            pending_requests: HashMap::new(),
+            request_timeout: config.request_timeout,
+            states,
+        };
+        if abandoned {
+            info!("the connect was abandoned, disconnecting");
+            process.disconnect(DisconnectReason::new(DisconnectCode::UserQuit));
+        }
+        Ok(process)
     }
 
     pub fn start(&mut self) -> anyhow::Result<()> {
-        let interval_rx = crossbeam_channel::tick(Duration::from_millis(10));
         let mut udp_events = VecDeque::new();
 
         loop {
-            select! {
-                //constant updates
-                recv(interval_rx) -> _ => {
-                    self.update();
-                }
-                //send requests coming from the API
-                recv(self.in_sends) -> msg_result => {
-                    //prioritize update
-                    if interval_rx.try_recv().is_ok() {
-                        self.update();
+            self.tick();
+
+            //send requests coming from the API, the tick is checked in between so a flood can't delay it
+            while self.commands_handled < self.max_commands_per_tick {
+                match self.in_sends.try_recv() {
+                    Ok(command) => {
+                        self.commands_handled += 1;
+                        if let Err(e) = self.process_command(command) {
+                            warn!("failed processing send request: {e}")
+                        }
+                        self.tick();
                     }
-                    match msg_result {
-                        Ok(msg) => {
-                            if let Err(e) = self.process_send_request(msg) {
-                                warn!("failed processing send request: {e}")
-                            }
-                    },
-                        Err(e) => bail!("process ending {}", e),
-                    };
-                }
-                //incoming read packets
-                default => {
-                    if !self.send_queue.is_empty() {
-                        self.socket.enqueue_send_events(&mut self.send_queue);
+                    Err(TryRecvError::Empty) => break,
+                    //the API side can be dropped right after disconnecting, the handshake still has to finish
+                    Err(TryRecvError::Disconnected) if self.state != ClientState::Connected => {
+                        break
                     }
+                    Err(e) => bail!("process ending {}", e),
+                }
+            }
 
-                    self.socket.process(
-                        Instant::now() + Duration::from_millis(10),
-                        None,
-                        &mut udp_events,
-                    )?;
+            //incoming read packets until the next update is due
+            if !self.send_queue.is_empty() {
+                self.socket.enqueue_send_events(&mut self.send_queue);
+            }
 
-                    //we just processed the disconnect packets and we can finish the loop
-                    if self.state == ClientState::Disconnecting {
-                        return Ok(());
-                    }
+            let reads = self.socket.process(
+                self.ticker.deadline(),
+                self.read_budget.left(),
+                &mut udp_events,
+            )?;
+            self.read_budget.spend(reads);
+
+            //the disconnect handshake is done and we can finish the loop
+            if self.is_closed() && !self.socket.has_pending_sends() {
+                return Ok(());
+            }
 
-                    while let Some(udp_event) = udp_events.pop_back() {
-                        match udp_event {
-                            UdpEvent::Read(addr, buffer, received_at) => {
-                                if let Err(ref e) = self.process_read_request(addr, buffer, &received_at) {
-                                    error!("failed processing read request: {e}");
-                                };
-                            }
-                            UdpEvent::SentClient(seq, sent_at) => {
-                                self.channel.send_buffer.mark_sent(seq, sent_at);
-                            }
-                            _ => {}
+            while let Some(udp_event) = udp_events.pop_back() {
+                match udp_event {
+                    UdpEvent::Read(addr, buffer, received_at, _) => {
+                        if let Err(ref e) = self.process_read_request(addr, buffer, &received_at) {
+                            error!("failed processing read request: {e}");
+                        };
+                        self.tick();
+                    }
+                    UdpEvent::SentClient(seq, sent_at) => {
+                        self.channel.send_buffer.mark_sent(seq, sent_at);
+                    }
+                    UdpEvent::Error(e) => {
+                        let refused = e.kind == io::ErrorKind::ConnectionRefused;
+                        self.out_events.send(InternalClientEvent::SocketError(e))?;
+                        if refused {
+                            info!("the server refused the packets, its port is closed");
+                            self.close_locally(DisconnectCode::Refused)?;
                         }
                     }
+                    _ => {}
                 }
             }
         }
@@ -140,45 +253,276 @@ impl ClientProcess {
         Ok(())
     }
 
+    //runs the update if it's due
+    fn tick(&mut self) {
+        if self.ticker.is_due(Instant::now()) {
+            self.update();
+        }
+    }
+
     fn process_read_request(
         &mut self,
         addr: SocketAddr,
         buffer: Bytes,
         received_at: &Instant,
     ) -> anyhow::Result<()> {
-        match self.channel.read(buffer, received_at)? {
-            ReadPayload::Single(payload) => self
-                .out_events
-                .send(InternalClientEvent::Receive(payload))?,
-            ReadPayload::Parts(parts) => self
-                .out_events
-                .send(InternalClientEvent::ReceiveParts(parts))?,
-            _ => {}
+        //a spoofed reconnect reply would close the connection, only the server is listened to
+        if Some(addr) != self.socket.remote_addr() {
+            debug!("dropped a packet from {addr}, it isn't the server");
+            return Ok(());
+        }
+
+        //channel packets are never as short as the control packets, so they can't be mistaken for one.
+        //the server can't name a compact session, so with compact headers a reply is never taken
+        //and the idle timeout closes the connection of a restarted server instead
+        if let Ok(ControlPacket::ReconnectRequired { session_key }) = ControlPacket::read(&buffer) {
+            if session_key == self.channel.session_key && !self.channel.config.compact_header {
+                info!("the server doesn't know the session anymore, it restarted");
+                self.close_locally(DisconnectCode::ServerRestarted)?;
+            }
+            return Ok(());
+        }
+
+        let payload = self.channel.read(buffer, received_at)?;
+        self.process_payload(payload, received_at)
+    }
+
+    //the payload read and the ones the channel released with it
+    fn process_payload(
+        &mut self,
+        mut payload: ReadPayload,
+        received_at: &Instant,
+    ) -> anyhow::Result<()> {
+        loop {
+            match payload {
+                //responses go to the handle of their request instead of the API
+                ReadPayload::Response(request_id, response) => {
+                    self.route_response(request_id, response)
+                }
+                //state values only replace the value of their slot
+                ReadPayload::Single(payload) if store_state(&self.states, &payload) => {}
+                ReadPayload::Parts(parts)
+                    if parts[0].starts_with(&STATE_MARKER)
+                        && store_state(&self.states, &parts.concat()) => {}
+                ReadPayload::Single(payload) => self
+                    .out_events
+                    .send(InternalClientEvent::Receive(payload, *received_at))?,
+                ReadPayload::Parts(parts) => self
+                    .out_events
+                    .send(InternalClientEvent::ReceiveParts(parts, *received_at))?,
+                ReadPayload::Disconnect(reason) => {
+                    self.channel.send_disconnect_ack(&mut self.send_queue);
+
+                    //if we are already leaving our own disconnect is still resent until it's acked
+                    if self.state == ClientState::Connected || self.state == ClientState::Draining {
+                        info!("disconnected by the server ({reason})");
+                        self.state = ClientState::Disconnected;
+                        self.drain = None;
+                        self.pending_requests.clear();
+                        self.linger = Some(Linger::acking(&self.channel.config, Instant::now()));
+                        self.out_events
+                            .send(InternalClientEvent::Disconnected(reason))?;
+                    }
+                }
+                ReadPayload::DisconnectAck => {
+                    if let Some(linger) = &mut self.linger {
+                        linger.acked();
+                    }
+                }
+                _ => {}
+            }
+
+            //messages that waited for the one read or the jitter delay
+            match self.channel.take_released() {
+                Some(released) => payload = released,
+                None => break,
+            }
         }
 
         Ok(())
     }
 
+    fn process_command(&mut self, command: InternalClientCommand) -> anyhow::Result<()> {
+        match command {
+            InternalClientCommand::Send(send_event) => self.process_send_request(send_event),
+            InternalClientCommand::Request(request_id, send_event, response) => {
+                if self.state != ClientState::Connected {
+                    bail!("the connection is closed");
+                }
+                self.channel.send_message(
+                    MessageKind::Request,
+                    send_event,
+                    &mut self.send_queue,
+                )?;
+                let expires_at = Instant::now() + self.request_timeout;
+                self.pending_requests
+                    .insert(request_id, (response, expires_at));
+                Ok(())
+            }
+            InternalClientCommand::SendCancellable(send_id, send_event) => {
+                if self.state != ClientState::Connected {
+                    bail!("the connection is closed");
+                }
+                self.channel
+                    .send_cancellable(send_id, send_event, &mut self.send_queue)
+            }
+            InternalClientCommand::SendSuperseding(key, send_event) => {
+                if self.state != ClientState::Connected {
+                    bail!("the connection is closed");
+                }
+                self.channel
+                    .send_superseding(key, send_event, &mut self.send_queue)
+            }
+            InternalClientCommand::CancelSend(send_id) => {
+                if !self.channel.cancel_send(send_id, &mut self.send_queue)? {
+                    debug!("send {send_id} is too old to cancel");
+                }
+                Ok(())
+            }
+            InternalClientCommand::Disconnect(reason, drain_timeout) => {
+                if self.state != ClientState::Connected {
+                    bail!("the connection is closed");
+                }
+
+                match drain_timeout {
+                    Some(timeout) => {
+                        self.state = ClientState::Draining;
+                        self.drain = Some((reason, Instant::now() + timeout));
+                    }
+                    None => self.disconnect(reason),
+                }
+                Ok(())
+            }
+            InternalClientCommand::SetKeepAlivePayload(payload) => {
+                self.channel.keep_alive_payload = payload;
+                Ok(())
+            }
+        }
+    }
+
+    fn route_response(&mut self, request_id: u32, response: Bytes) {
+        match self.pending_requests.remove(&request_id) {
+            Some((sender, _)) => sender.send(response),
+            None => debug!("dropped the response to expired or unknown request {request_id}"),
+        }
+    }
+
     fn process_send_request(&mut self, send_event: SendEvent) -> anyhow::Result<()> {
-        //clear all other outbound packets if the client is disconnecting
-        if let SendEvent::Disconnect = send_event {
-            self.socket.empty_send_events();
-            self.state = ClientState::Disconnecting;
+        if self.state != ClientState::Connected {
+            bail!("the connection is closed");
         }
 
         self.channel.send_event(send_event, &mut self.send_queue)
     }
 
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        //clear all other outbound packets
+        self.socket.empty_send_events();
+        self.send_queue.clear();
+        self.pending_requests.clear();
+        self.state = ClientState::Disconnecting;
+        self.linger = Some(Linger::closing(
+            reason.clone(),
+            &self.channel.config,
+            Instant::now(),
+        ));
+
+        if let Err(e) = self
+            .channel
+            .send_event(SendEvent::Disconnect(reason), &mut self.send_queue)
+        {
+            error!("failed sending disconnect: {e}");
+        }
+    }
+
+    //the server can't take part in the disconnect handshake anymore, the connection ends right away
+    fn close_locally(&mut self, code: DisconnectCode) -> anyhow::Result<()> {
+        if self.state != ClientState::Connected && self.state != ClientState::Draining {
+            return Ok(());
+        }
+
+        self.socket.empty_send_events();
+        self.send_queue.clear();
+        self.pending_requests.clear();
+        self.drain = None;
+        self.linger = None;
+        self.state = ClientState::Disconnected;
+        self.out_events
+            .send(InternalClientEvent::Disconnected(DisconnectReason::new(
+                code,
+            )))?;
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        (self.state == ClientState::Disconnecting || self.state == ClientState::Disconnected)
+            && self
+                .linger
+                .as_ref()
+                .is_none_or(|linger| linger.is_finished(Instant::now()))
+    }
+
     fn update(&mut self) {
-        if self.state != ClientState::Connected {
+        self.read_budget.reset();
+        self.commands_handled = 0;
+        if self.state == ClientState::Disconnecting || self.state == ClientState::Disconnected {
+            //resend the disconnect until the server acks it
+            let resend = self
+                .linger
+                .as_mut()
+                .and_then(|linger| linger.poll_resend(Instant::now()));
+            if let Some(reason) = resend {
+                if let Err(e) = self
+                    .channel
+                    .send_event(SendEvent::Disconnect(reason), &mut self.send_queue)
+                {
+                    error!("failed resending disconnect: {e}");
+                }
+            }
+            return;
+        }
+
+        //the unreliable messages that became due since the last packet
+        let now = Instant::now();
+        if self.channel.release_jittered(now) {
+            if let Err(e) = self.process_payload(ReadPayload::None, &now) {
+                error!("failed delivering jittered messages: {e}");
+            }
+        }
+
+        //nobody waits for these responses anymore
+        self.pending_requests
+            .retain(|_, (sender, expires_at)| !sender.is_dropped() && now < *expires_at);
+
+        if self.channel.is_idle(now) {
+            info!("nothing received from the server for too long, disconnecting");
+            if let Err(e) = self.close_locally(DisconnectCode::Idle) {
+                error!("failed closing the idle connection: {e}");
+            }
             return;
         }
 
+        //a game that doesn't read its events shrinks the window the server may send into
+        self.channel.receive_backlog = self.out_events.len();
         if let Err(e) = self
             .channel
             .update(&mut self.marked_packets_buf, &mut self.send_queue)
         {
             error!("error updating channel: {e}");
         }
+        if let Some(quality) = self.channel.poll_quality(Instant::now()) {
+            debug!("quality of the connection changed to {quality:?}");
+        }
+        self.channel
+            .write_stats(&mut self.stats.lock().unwrap_or_else(|e| e.into_inner()));
+
+        //the disconnect goes out once everything reliable is acked or the drain timed out
+        if let Some((_, deadline)) = &self.drain {
+            if !self.channel.has_pending_reliable() || Instant::now() >= *deadline {
+                if let Some((reason, _)) = self.drain.take() {
+                    self.disconnect(reason);
+                }
+            }
+        }
     }
 }
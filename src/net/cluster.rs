@@ -0,0 +1,117 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+use super::{
+    config::{IdSpace, ServerConfig},
+    connections::{shard_of, ConnectionId},
+    server::{Server, ServerEvent},
+};
+
+//servers on consecutive ports of one machine, like one simulation per core. the servers split the
+//connection ids so an id names one connection of the cluster, and their events are read as one
+//stream
+pub struct ServerCluster {
+    servers: Vec<Server>,
+    //rung by the wakeup of every server, holds one ring so a wakeup between two reads isn't lost
+    doorbell: Receiver<()>,
+    //the server the next read looks at first, so a busy one can't starve the others
+    next: AtomicUsize,
+    workers: usize,
+}
+
+impl ServerCluster {
+    //instance i listens on the port of addr plus i, every instance gets the config. a port of 0
+    //lets the system pick the port of every instance
+    pub fn start(addr: SocketAddr, instances: u16, config: ServerConfig) -> anyhow::Result<Self> {
+        if instances == 0 {
+            bail!("a cluster needs at least one instance");
+        }
+
+        let (ring, doorbell) = crossbeam_channel::bounded(1);
+        let servers = (0..instances)
+            .map(|part| {
+                let port = match addr.port() {
+                    0 => 0,
+                    port => match port.checked_add(part) {
+                        Some(port) => port,
+                        None => bail!("the ports of the instances run past {}", u16::MAX),
+                    },
+                };
+                let server = Server::start_with_config(
+                    SocketAddr::new(addr.ip(), port),
+                    ServerConfig {
+                        id_space: IdSpace {
+                            part,
+                            parts: instances,
+                        },
+                        ..config.clone()
+                    },
+                )?;
+                let ring = ring.clone();
+                server.set_wakeup(move || {
+                    let _ = ring.try_send(());
+                });
+                Ok(server)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            servers,
+            doorbell,
+            next: AtomicUsize::new(0),
+            workers: config.workers.max(1),
+        })
+    }
+
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    //the instance that handed out the connection id
+    pub fn instance_of(&self, connection_id: ConnectionId) -> usize {
+        shard_of(connection_id, (self.servers.len() * self.workers) as u32) / self.workers
+    }
+
+    //the server that has the connection, for the calls taking a connection id
+    pub fn server_of(&self, connection_id: ConnectionId) -> &Server {
+        &self.servers[self.instance_of(connection_id)]
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.servers.iter().map(Server::local_addr).collect()
+    }
+
+    //the next event of any of the instances with the instance it's from. like a read after a
+    //wakeup, another read of the same server can take the event first and this finds nothing
+    pub fn read<'a>(
+        &self,
+        dest: &'a mut [u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Option<(usize, ServerEvent<'a>)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let start = self.next.load(Ordering::Relaxed);
+            let ready = (0..self.servers.len())
+                .map(|i| (start + i) % self.servers.len())
+                .find(|&i| self.servers[i].has_events());
+            if let Some(instance) = ready {
+                self.next.store(instance + 1, Ordering::Relaxed);
+                let event = self.servers[instance].read(dest, Duration::ZERO)?;
+                return Ok(event.map(|event| (instance, event)));
+            }
+
+            match self.doorbell.recv_deadline(deadline) {
+                Ok(()) => {}
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                //the wakeups of the servers hold the senders for as long as the cluster lives
+                Err(RecvTimeoutError::Disconnected) => bail!("the wakeups of the cluster are gone"),
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use mio::Waker;
+
+use super::queue::QueueSender;
+
+//the API side of the command channel, the process thread is woken up so the command is handled
+//right away instead of when the socket poll times out
+pub struct CommandSender<T> {
+    sender: QueueSender<T>,
+    waker: Arc<Waker>,
+}
+
+impl<T> CommandSender<T> {
+    pub fn new(sender: QueueSender<T>, waker: Arc<Waker>) -> Self {
+        Self { sender, waker }
+    }
+
+    pub fn send(&self, command: T) -> anyhow::Result<()> {
+        self.sender
+            .send(command)
+            .map_err(|_| anyhow!("the process thread has stopped"))?;
+        self.waker.wake()?;
+        Ok(())
+    }
+}
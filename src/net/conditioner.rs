@@ -0,0 +1,175 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebugConditions {
+    //fixed delay added to every packet
+    pub latency: Duration,
+    //random delay in the range of -jitter..=jitter added on top of the latency
+    pub jitter: Duration,
+    //chance of a packet being dropped, from 0.0 to 1.0
+    pub loss: f32,
+}
+
+impl DebugConditions {
+    pub fn is_noop(&self) -> bool {
+        self.latency.is_zero() && self.jitter.is_zero() && self.loss <= 0.0
+    }
+}
+
+struct DelayedItem<T> {
+    release_at: Instant,
+    item: T,
+}
+
+impl<T> PartialEq for DelayedItem<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.release_at == other.release_at
+    }
+}
+
+impl<T> Eq for DelayedItem<T> {}
+
+impl<T> PartialOrd for DelayedItem<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for DelayedItem<T> {
+    //reversed so the binary heap pops the earliest release time first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.release_at.cmp(&self.release_at)
+    }
+}
+
+//holds back items according to the debug conditions, items can get reordered because of the jitter
+pub struct LinkConditioner<T> {
+    pub conditions: DebugConditions,
+    queue: BinaryHeap<DelayedItem<T>>,
+}
+
+impl<T> LinkConditioner<T> {
+    pub fn new(conditions: DebugConditions) -> Self {
+        Self {
+            conditions,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    //returns false if the item was dropped
+    pub fn push(&mut self, item: T) -> bool {
+        let mut rng = rand::thread_rng();
+
+        if self.conditions.loss > 0.0 && rng.gen::<f32>() < self.conditions.loss {
+            return false;
+        }
+
+        let mut delay = self.conditions.latency;
+        if !self.conditions.jitter.is_zero() {
+            let jitter = self.conditions.jitter.mul_f32(rng.gen::<f32>());
+            delay = if rng.gen::<bool>() {
+                delay + jitter
+            } else {
+                delay.saturating_sub(jitter)
+            };
+        }
+
+        self.queue.push(DelayedItem {
+            release_at: Instant::now() + delay,
+            item,
+        });
+
+        true
+    }
+
+    pub fn pop_ready(&mut self, now: Instant) -> Option<T> {
+        if self.queue.peek()?.release_at <= now {
+            return self.queue.pop().map(|delayed| delayed.item);
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_loss_drops_everything() {
+        let mut conditioner = LinkConditioner::new(DebugConditions {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 1.0,
+        });
+
+        for i in 0..100 {
+            assert!(!conditioner.push(i));
+        }
+        assert!(conditioner.is_empty());
+    }
+
+    #[test]
+    fn latency_holds_items_back() {
+        let latency = Duration::from_millis(50);
+        let mut conditioner = LinkConditioner::new(DebugConditions {
+            latency,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        });
+
+        assert!(conditioner.push(1));
+        assert!(conditioner.push(2));
+
+        assert_eq!(conditioner.pop_ready(Instant::now()), None);
+
+        let later = Instant::now() + latency;
+        assert!(conditioner.pop_ready(later).is_some());
+        assert!(conditioner.pop_ready(later).is_some());
+        assert_eq!(conditioner.pop_ready(later), None);
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let latency = Duration::from_millis(100);
+        let jitter = Duration::from_millis(20);
+        let mut conditioner = LinkConditioner::new(DebugConditions {
+            latency,
+            jitter,
+            loss: 0.0,
+        });
+
+        let start = Instant::now();
+        for i in 0..100 {
+            conditioner.push(i);
+        }
+
+        //nothing can be released before latency - jitter
+        assert_eq!(
+            conditioner.pop_ready(start + latency - jitter - Duration::from_millis(1)),
+            None
+        );
+
+        let mut released = 0;
+        while conditioner
+            .pop_ready(Instant::now() + latency + jitter)
+            .is_some()
+        {
+            released += 1;
+        }
+        assert_eq!(released, 100);
+    }
+}
@@ -0,0 +1,451 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::proto::{
+    challenge::{ChallengeScheme, SipHashChallenge},
+    ProtocolId,
+};
+
+use super::{
+    conditioner::DebugConditions,
+    connections::{
+        FLAG_ACK_DELAY, FLAG_CHECKSUM, FLAG_COMPACT_HEADER, FLAG_FLOW_CONTROL, FLAG_INTERLEAVE,
+    },
+    limits::{HeldReadLimits, MessageLimits, OutboundLimits},
+    middleware::MiddlewareChain,
+    quality::{QualityThresholds, SendRateConfig},
+    random::RandomSource,
+    rtt_tracker::RttConfig,
+    validation::PayloadValidator,
+    BUFFER_WINDOW_SIZE,
+};
+
+//settings applied to every channel, some of them are negotiated with the remote during the handshake
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    //append a CRC32C checksum to every packet, only used if both sides enable it
+    pub checksum: bool,
+    //send a smaller header without the session key, packets are matched to the session only by
+    //their address, only used if both sides enable it. a restarted server can't ask the client to
+    //reconnect, the idle timeout closes the connection instead
+    pub compact_header: bool,
+    //every packet tells how long the ack it carries was held, the rtt is measured without the
+    //time the remote took to reply. only used if both sides enable it
+    pub ack_delay: bool,
+    //every packet tells how many more reliable packets the remote may send past the last one it
+    //acked, a receiver that falls behind slows the reliable stream of the sender instead of having
+    //it resent into a full window. only used if both sides enable it
+    pub flow_control: bool,
+    //the reliable packets advertised with flow control, messages read but not taken by the game
+    //yet are subtracted from it. it's kept between 1 and BUFFER_WINDOW_SIZE, more than the window
+    //couldn't be told apart from the old packets
+    pub receive_window: u16,
+    //reliable packets waiting for room in the send window, a send past it fails with WindowFull.
+    //None queues however many there are
+    pub max_queued_reliable: Option<usize>,
+    //deliver reliable messages in the order they were sent, a small message doesn't overtake a large
+    //fragmented one sent before it. only applied locally to the messages read
+    pub ordered_reliable: bool,
+    //the fragments of the large reliable messages waiting for the window are sent in turns, so a
+    //message sent later makes progress too. only used if both sides set it without ordered_reliable,
+    //the order needs the fragments of a message one after the other
+    pub interleave_fragments: bool,
+    //hold unreliable messages for up to this long and release them in the order they were sent at
+    //the pace they usually arrive, for games interpolating between snapshots. one that arrives
+    //after a message sent later was released is dropped. only applied locally to the messages read
+    pub jitter_delay: Option<Duration>,
+    //an unreliable message that isn't reassembled after this is dropped, a stale snapshot is useless
+    pub unreliable_fragment_timeout: Duration,
+    //unreliable messages reassembled at the same time, the oldest is dropped for a new one
+    pub max_unreliable_fragment_groups: usize,
+    //an unreliable packet waiting this long for a blocked socket is dropped instead of sent late,
+    //None sends it however late it is
+    pub unreliable_send_timeout: Option<Duration>,
+    //send a keep alive packet if nothing else was sent for this long
+    pub keep_alive_interval: Duration,
+    //the connection is closed with DisconnectCode::Idle if nothing was received for this long,
+    //None keeps it however long the remote is silent
+    pub idle_timeout: Option<Duration>,
+    //how often resends, acks and keep alives are processed
+    pub update_interval: Duration,
+    //called for every packet read and every payload packet sent, only applied locally
+    pub middleware: MiddlewareChain,
+    //packet head buffers (protocol id and header) kept by every connection for its own packets, the
+    //payloads aren't pooled. 0 shares the pool of the process thread
+    pub connection_pool_size: usize,
+    //how long a closed channel waits for its disconnect to be acked and acks the disconnects of the remote
+    pub disconnect_linger: Duration,
+    //the disconnect is resent this often until it's acked
+    pub disconnect_resend_interval: Duration,
+    //when the quality of the connection is considered degraded or bad
+    pub quality: QualityThresholds,
+    //the unreliable send rate recommended for the quality of the connection
+    pub send_rate: SendRateConfig,
+    //how the rtt is estimated and how long reliable packets wait for their ack before a resend
+    pub rtt: RttConfig,
+    //the latest resends, timeouts, malformed packets and window stalls kept for
+    //Server::recent_events, 0 keeps none
+    pub event_log_size: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            checksum: false,
+            compact_header: false,
+            ack_delay: false,
+            flow_control: false,
+            receive_window: BUFFER_WINDOW_SIZE,
+            //a few of the largest messages
+            max_queued_reliable: Some(4096),
+            ordered_reliable: false,
+            interleave_fragments: false,
+            jitter_delay: None,
+            unreliable_fragment_timeout: Duration::from_millis(250),
+            max_unreliable_fragment_groups: 8,
+            unreliable_send_timeout: Some(Duration::from_millis(100)),
+            keep_alive_interval: Duration::from_secs(1),
+            idle_timeout: Some(Duration::from_secs(10)),
+            update_interval: Duration::from_millis(10),
+            middleware: MiddlewareChain::default(),
+            connection_pool_size: 0,
+            disconnect_linger: Duration::from_secs(1),
+            disconnect_resend_interval: Duration::from_millis(100),
+            quality: QualityThresholds::default(),
+            send_rate: SendRateConfig::default(),
+            rtt: RttConfig::default(),
+            event_log_size: 64,
+        }
+    }
+}
+
+impl ChannelConfig {
+    //features requested during the handshake
+    pub fn handshake_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.compact_header {
+            flags |= FLAG_COMPACT_HEADER;
+        }
+        if self.ack_delay {
+            flags |= FLAG_ACK_DELAY;
+        }
+        if self.flow_control {
+            flags |= FLAG_FLOW_CONTROL;
+        }
+        if self.interleave_fragments && !self.ordered_reliable {
+            flags |= FLAG_INTERLEAVE;
+        }
+        flags
+    }
+
+    //config with the features agreed on in the handshake
+    pub fn with_flags(&self, flags: u8) -> ChannelConfig {
+        let mut config = self.clone();
+        config.checksum = flags & FLAG_CHECKSUM != 0;
+        config.compact_header = flags & FLAG_COMPACT_HEADER != 0;
+        config.ack_delay = flags & FLAG_ACK_DELAY != 0;
+        config.flow_control = flags & FLAG_FLOW_CONTROL != 0;
+        config.interleave_fragments = flags & FLAG_INTERLEAVE != 0;
+        config
+    }
+}
+
+//options set on the socket before it's used, None keeps what the OS or the provided socket has
+#[derive(Debug, Clone, Default)]
+pub struct SocketConfig {
+    //kernel buffer sizes in bytes, the OS can round them or cap them at its own limit
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    //the 6 bit DSCP code point of the outgoing packets, 46 (expedited forwarding) for latency
+    //sensitive traffic. set as the traffic class on IPv6 sockets, the platforms without it fail to
+    //bind
+    pub dscp: Option<u8>,
+    //the time to live or the hop limit on IPv6
+    pub ttl: Option<u32>,
+    //copy the header and the payload of a datagram into one buffer before sending it. otherwise
+    //they're handed to the OS in parts, outside of unix they're always copied
+    pub gather_sends: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub max_clients: usize,
+    pub channel: ChannelConfig,
+    pub socket: SocketConfig,
+    //minimum time between two server info responses to the same address
+    pub server_info_interval: Duration,
+    //server info responses sent per second across all addresses
+    pub max_server_info_responses: usize,
+    //master server the server registers itself with
+    pub master_server: Option<SocketAddr>,
+    pub master_heartbeat_interval: Duration,
+    //datagrams read from the socket between two updates for all addresses together, shared round
+    //robin between them. the rest waits in the socket buffer for the next update so a flood can't
+    //make an update late. with several workers the socket thread reads this many per update
+    //interval for all of them. None reads everything that arrives
+    pub max_reads_per_tick: Option<usize>,
+    //commands of the API handled between two updates, the rest waits for the next update so a
+    //flood of sends can't hold up the reads
+    pub max_commands_per_tick: usize,
+    //generates the server salts of the handshakes
+    pub random: RandomSource,
+    //derives the session keys from the salts, the clients have to use the same scheme
+    pub challenge: Arc<dyn ChallengeScheme>,
+    //packets of other games are dropped, the clients have to use the same id
+    pub protocol_id: ProtocolId,
+    //emit a ProtocolError at most this often for an address sending packets with another protocol id
+    pub protocol_error_interval: Option<Duration>,
+    //emit a ReceiveProgress for every fragment of a message that isn't complete yet
+    pub receive_progress: bool,
+    //emit a Handshaking when a challenge is sent and a HandshakeFailed when a handshake ends without
+    //a connection, for following how many of the connect requests get connected
+    pub handshake_events: bool,
+    //connections sending this many malformed packets are kicked, a connected address sending
+    //packets of another session likely tampers with them. None never kicks
+    pub malformed_packet_limit: Option<u64>,
+    //looks at every payload before its Receive is emitted and decides whether it's delivered
+    pub payload_validator: Option<PayloadValidator>,
+    //the rates and the size of the messages every connection can send
+    pub message_limits: MessageLimits,
+    //how often the value of a state slot is sent again while it doesn't change
+    pub state_resend_interval: Duration,
+    //threads processing the connections, above 1 the socket gets a thread of its own that hands
+    //the datagrams to the workers. every worker has the connections of a share of the addresses
+    pub workers: usize,
+    //what happens when a client asks to connect from an address that already has a connection
+    pub duplicate_policy: DuplicatePolicy,
+    //caps on what's held for a connection that doesn't take what it's sent
+    pub outbound_limits: OutboundLimits,
+    //caps on the payloads received from a paused connection that wait for its resume
+    pub held_read_limits: HeldReadLimits,
+    //where Server::start binds when the port of the address is taken, local_addr of the server
+    //tells where it ended up
+    pub port_fallback: PortFallback,
+    //the share of the connection ids the server hands out, the servers of a ServerCluster split
+    //them so an id names the connection in all of them
+    pub id_space: IdSpace,
+    //an address whose connection closed can't finish a new handshake for this long, so the late
+    //packets of the old session still go to the closed connection. the client keeps answering the
+    //challenge and is connected once it ends, it has to be shorter than the handshake timeout of
+    //5 seconds
+    pub reconnect_cooldown: Duration,
+    //a connection whose packets start to arrive from another address moves to it, like a client
+    //behind a NAT that handed out another port, and an AddressChanged is emitted. the packet has to
+    //carry the session key and be newer than the ones read, so compact headers never move. the
+    //connection moves once its channel took the packet, after the payload of it is delivered
+    pub address_migration: bool,
+}
+
+//the server hands out the ids whose slot index is part modulo parts, the workers split that share
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdSpace {
+    pub part: u16,
+    pub parts: u16,
+}
+
+impl Default for IdSpace {
+    fn default() -> Self {
+        Self { part: 0, parts: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PortFallback {
+    //a taken port fails the start
+    #[default]
+    None,
+    //the next ports up to this many above the taken one, for servers that are found by scanning
+    Nearby(u16),
+    //the next ports like Nearby and then one the system picks, for tests running side by side
+    NearbyThenEphemeral(u16),
+}
+
+//clients behind the same NAT share its ip and are told apart by the port of their mapping, a
+//client that restarts can get the mapping of its old connection before that one timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    //one connection per ip, the connection is replaced once the new client answered the challenge
+    ReplaceExisting,
+    //one connection per ip, the handshakes of the ip fail while it has a connection
+    RejectNew,
+    //one connection per ip and port, a request from the address of a connection fails
+    #[default]
+    AllowMultiplePorts,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: 64,
+            channel: ChannelConfig::default(),
+            socket: SocketConfig::default(),
+            server_info_interval: Duration::from_millis(500),
+            max_server_info_responses: 256,
+            master_server: None,
+            master_heartbeat_interval: Duration::from_secs(30),
+            max_reads_per_tick: None,
+            max_commands_per_tick: 4096,
+            random: RandomSource::default(),
+            challenge: Arc::new(SipHashChallenge),
+            protocol_id: ProtocolId::DEFAULT,
+            protocol_error_interval: None,
+            receive_progress: false,
+            handshake_events: false,
+            malformed_packet_limit: Some(32),
+            payload_validator: None,
+            message_limits: MessageLimits::default(),
+            state_resend_interval: Duration::from_millis(100),
+            workers: 1,
+            duplicate_policy: DuplicatePolicy::default(),
+            outbound_limits: OutboundLimits::default(),
+            held_read_limits: HeldReadLimits::default(),
+            port_fallback: PortFallback::default(),
+            id_space: IdSpace::default(),
+            reconnect_cooldown: Duration::ZERO,
+            address_migration: false,
+        }
+    }
+}
+
+//values changed on a running server, None keeps the current one. the clients stay connected and
+//take the new values at the next update
+#[derive(Clone, Default)]
+pub struct ServerConfigUpdate {
+    //lowering it below the connected clients only refuses the new ones
+    pub max_clients: Option<usize>,
+    pub keep_alive_interval: Option<Duration>,
+    pub unreliable_send_timeout: Option<Option<Duration>>,
+    pub disconnect_linger: Option<Duration>,
+    //the unreliable send rate recommended for every connection
+    pub send_rate: Option<SendRateConfig>,
+    pub max_reads_per_tick: Option<Option<usize>>,
+    pub max_commands_per_tick: Option<usize>,
+    pub malformed_packet_limit: Option<Option<u64>>,
+    pub message_limits: Option<MessageLimits>,
+    pub outbound_limits: Option<OutboundLimits>,
+    //network conditions simulated on every connection, the ones connecting later too. Some(None)
+    //clears them
+    pub debug_conditions: Option<Option<DebugConditions>>,
+}
+
+impl ServerConfigUpdate {
+    pub fn apply_to(&self, config: &mut ServerConfig) {
+        if let Some(max_clients) = self.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(max_reads_per_tick) = self.max_reads_per_tick {
+            config.max_reads_per_tick = max_reads_per_tick;
+        }
+        if let Some(max_commands_per_tick) = self.max_commands_per_tick {
+            config.max_commands_per_tick = max_commands_per_tick;
+        }
+        if let Some(malformed_packet_limit) = self.malformed_packet_limit {
+            config.malformed_packet_limit = malformed_packet_limit;
+        }
+        if let Some(message_limits) = self.message_limits {
+            config.message_limits = message_limits;
+        }
+        if let Some(outbound_limits) = self.outbound_limits {
+            config.outbound_limits = outbound_limits;
+        }
+        self.apply_to_channel(&mut config.channel);
+    }
+
+    //only the values that can change on a live channel, the negotiated features are kept
+    pub fn apply_to_channel(&self, config: &mut ChannelConfig) {
+        if let Some(keep_alive_interval) = self.keep_alive_interval {
+            config.keep_alive_interval = keep_alive_interval;
+        }
+        if let Some(unreliable_send_timeout) = self.unreliable_send_timeout {
+            config.unreliable_send_timeout = unreliable_send_timeout;
+        }
+        if let Some(disconnect_linger) = self.disconnect_linger {
+            config.disconnect_linger = disconnect_linger;
+        }
+        if let Some(send_rate) = &self.send_rate {
+            config.send_rate = send_rate.clone();
+        }
+    }
+}
+
+//how the client retries the handshake when the server doesn't reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    //how long the first attempt of a stage waits for the reply
+    pub reply_timeout: Duration,
+    //the packet of a stage is sent this often before the stage fails
+    pub attempts: usize,
+    //a failed stage restarts the handshake from the request this often
+    pub rounds: usize,
+    pub backoff: HandshakeBackoff,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            reply_timeout: Duration::from_millis(150),
+            attempts: 5,
+            rounds: 5,
+            backoff: HandshakeBackoff::Fixed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeBackoff {
+    //every attempt waits the reply timeout
+    Fixed,
+    //the wait doubles with every attempt of a stage up to the maximum
+    Exponential { max_timeout: Duration },
+}
+
+impl HandshakeConfig {
+    //the wait for the reply to the attempt of a stage, counted from 0
+    pub fn timeout(&self, attempt: usize) -> Duration {
+        match self.backoff {
+            HandshakeBackoff::Fixed => self.reply_timeout,
+            HandshakeBackoff::Exponential { max_timeout } => self
+                .reply_timeout
+                .saturating_mul(1 << attempt.min(16))
+                .min(max_timeout.max(self.reply_timeout)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub channel: ChannelConfig,
+    pub socket: SocketConfig,
+    pub handshake: HandshakeConfig,
+    //generates the client salt of the handshake
+    pub random: RandomSource,
+    //has to match the scheme of the server
+    pub challenge: Arc<dyn ChallengeScheme>,
+    //has to match the id of the server
+    pub protocol_id: ProtocolId,
+    //datagrams read from the socket between two updates, the rest waits in the socket buffer for
+    //the next update. None reads everything that arrives
+    pub max_reads_per_tick: Option<usize>,
+    //commands of the API handled between two updates, the rest waits for the next update
+    pub max_commands_per_tick: usize,
+    //a request without a response after this long is forgotten, waiting on its handle fails
+    pub request_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            channel: ChannelConfig::default(),
+            socket: SocketConfig::default(),
+            handshake: HandshakeConfig::default(),
+            random: RandomSource::default(),
+            challenge: Arc::new(SipHashChallenge),
+            protocol_id: ProtocolId::DEFAULT,
+            max_reads_per_tick: None,
+            max_commands_per_tick: 4096,
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::proto::challenge::{ChallengeScheme, SipHashChallenge};
+
+use super::{
+    connections::ControlPacket,
+    disconnect::{DisconnectCode, DisconnectReason},
+    fragmentation_manager::FRAGMENT_SIZE,
+    header::{Header, SendType},
+    sequence::Sequence,
+    Bytes, PacketType, ProtocolId, Server, ServerEvent, PROTOCOL_ID_SIZE,
+};
+
+//reliable sequences of the server remembered for the acks, as many as the ack bits cover
+const ACKED_HISTORY: usize = 33;
+
+//the session agreed on in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerSession {
+    pub connection_id: u32,
+    pub session_key: u64,
+    pub flags: u8,
+}
+
+//a client written against the wire format with a plain socket, without the channel of the crate.
+//every packet is built by hand in the simplest form the server accepts, so it doubles as a
+//reference for clients in other languages: what it sends is what they have to send. it only
+//speaks the full header without any of the flags
+pub struct ScriptedPeer {
+    socket: UdpSocket,
+    server: SocketAddr,
+    pub protocol_id: ProtocolId,
+    pub challenge: Arc<dyn ChallengeScheme>,
+    session: Option<PeerSession>,
+    local_seq: u16,
+    unreliable_seq: u16,
+    next_fragment_group: u16,
+    //the reliable sequences of the server that were received, newest last
+    received: VecDeque<u16>,
+    //our reliable sequences the server acked
+    acked: HashSet<u16>,
+    //reliable fragments by group until the message is complete
+    fragments: HashMap<u16, Vec<Option<Bytes>>>,
+}
+
+impl ScriptedPeer {
+    pub fn bind(addr: SocketAddr, server: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            server,
+            protocol_id: ProtocolId::DEFAULT,
+            challenge: Arc::new(SipHashChallenge),
+            session: None,
+            local_seq: 0,
+            unreliable_seq: 0,
+            next_fragment_group: 0,
+            received: VecDeque::new(),
+            acked: HashSet::new(),
+            fragments: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    //sends from another address with the session kept, like a NAT that handed out another port
+    pub fn rebind(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.socket = UdpSocket::bind(addr)?;
+        Ok(())
+    }
+
+    pub fn session(&self) -> Option<PeerSession> {
+        self.session
+    }
+
+    //request, challenge, response and accept, each packet is sent once
+    pub fn handshake(&mut self, flags: u8, timeout: Duration) -> anyhow::Result<PeerSession> {
+        let client_salt = rand::random();
+        self.send_control(ControlPacket::ConnectionRequest { client_salt, flags })?;
+        let server_salt = match self.recv_control(timeout)? {
+            ControlPacket::Challenge {
+                client_tag,
+                server_salt,
+            } if client_tag == self.challenge.client_tag(client_salt) => server_salt,
+            packet => bail!("expected the challenge of the request, got {packet:?}"),
+        };
+
+        let session_key = self.challenge.session_key(client_salt, server_salt);
+        self.send_control(ControlPacket::ChallengeResponse {
+            response: self.challenge.response(session_key),
+        })?;
+        let session = match self.recv_control(timeout)? {
+            ControlPacket::ConnectionAccepted {
+                connection_id,
+                flags,
+            } => PeerSession {
+                connection_id,
+                session_key,
+                flags,
+            },
+            packet => bail!("expected the connection to be accepted, got {packet:?}"),
+        };
+        self.session = Some(session);
+        Ok(session)
+    }
+
+    //returns the sequence of the packet
+    pub fn send_reliable(&mut self, payload: &[u8]) -> anyhow::Result<u16> {
+        let seq = self.local_seq;
+        Sequence::increment(&mut self.local_seq);
+        self.send_reliable_as(seq, payload)?;
+        Ok(seq)
+    }
+
+    //sends the packet again with the sequence it had, like a resend after a lost ack
+    pub fn send_reliable_as(&mut self, seq: u16, payload: &[u8]) -> anyhow::Result<()> {
+        let header = Header::new(seq, self.session_key()?, SendType::Reliable, false);
+        self.send_packet(header, payload)
+    }
+
+    pub fn send_unreliable(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let header = Header::new(
+            self.unreliable_seq,
+            self.session_key()?,
+            SendType::Unreliable,
+            false,
+        );
+        Sequence::increment(&mut self.unreliable_seq);
+        self.send_packet(header, payload)
+    }
+
+    //every fragment takes the next reliable sequence in the order of the fragments, they are sent
+    //in the order given by the fragment ids. returns the sequences of the fragments
+    pub fn send_fragmented(&mut self, message: &[u8], order: &[u8]) -> anyhow::Result<Vec<u16>> {
+        let chunks: Vec<&[u8]> = message.chunks(FRAGMENT_SIZE).collect();
+        if chunks.len() > u8::MAX as usize {
+            bail!("the message has more than {} fragments", u8::MAX);
+        }
+        let group = self.next_fragment_group;
+        self.next_fragment_group = self.next_fragment_group.wrapping_add(1);
+        let seqs: Vec<u16> = chunks
+            .iter()
+            .map(|_| {
+                let seq = self.local_seq;
+                Sequence::increment(&mut self.local_seq);
+                seq
+            })
+            .collect();
+
+        let session_key = self.session_key()?;
+        for &fragment_id in order {
+            let Some(chunk) = chunks.get(fragment_id as usize) else {
+                bail!("the message has no fragment {fragment_id}");
+            };
+            let mut header = Header::new(
+                seqs[fragment_id as usize],
+                session_key,
+                SendType::Reliable,
+                true,
+            );
+            header.fragment_group_id = group;
+            header.fragment_id = fragment_id;
+            header.fragment_size = chunks.len() as u8;
+            self.send_packet(header, chunk)?;
+        }
+        Ok(seqs)
+    }
+
+    pub fn send_disconnect(&mut self, reason: &DisconnectReason) -> anyhow::Result<()> {
+        let header = Header::new_disconnect(self.unreliable_seq, self.session_key()?);
+        Sequence::increment(&mut self.unreliable_seq);
+        let mut payload = Vec::new();
+        reason.write_into(&mut payload);
+        self.send_packet(header, &payload)
+    }
+
+    //the next channel packet of the session, None if none arrived in time. the reliable ones are
+    //acked with the next packet sent
+    pub fn recv(&mut self, timeout: Duration) -> anyhow::Result<Option<(Header, Bytes)>> {
+        let session_key = self.session_key()?;
+        let deadline = Instant::now() + timeout;
+        while let Some(datagram) = self.recv_datagram(deadline)? {
+            let Ok(header) = Header::read(&datagram) else {
+                continue;
+            };
+            if header.session_key != session_key || !header.packet_type.is_session_variant() {
+                continue;
+            }
+
+            self.mark_acked(header.ack, header.ack_bits);
+            if matches!(
+                header.packet_type,
+                PacketType::PayloadReliable | PacketType::PayloadReliableFrag
+            ) {
+                self.received.retain(|seq| *seq != header.seq);
+                self.received.push_back(header.seq);
+                if self.received.len() > ACKED_HISTORY {
+                    self.received.pop_front();
+                }
+            }
+            let payload = datagram[header.get_header_size()..].to_vec();
+            return Ok(Some((header, payload)));
+        }
+        Ok(None)
+    }
+
+    //the next reliable message, the fragmented ones are put together
+    pub fn recv_message(&mut self, timeout: Duration) -> anyhow::Result<Bytes> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let Some((header, payload)) =
+                self.recv(deadline.saturating_duration_since(Instant::now()))?
+            else {
+                bail!("no message arrived in {timeout:?}");
+            };
+            match header.packet_type {
+                PacketType::PayloadReliable => return Ok(payload),
+                PacketType::PayloadReliableFrag => {
+                    if let Some(message) = self.insert_fragment(&header, payload) {
+                        return Ok(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    //reads until the server acked the sequence
+    pub fn wait_for_ack(&mut self, seq: u16, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !self.acked.contains(&seq) {
+            if self
+                .recv(deadline.saturating_duration_since(Instant::now()))?
+                .is_none()
+            {
+                bail!("sequence {seq} wasn't acked in {timeout:?}");
+            }
+        }
+        Ok(())
+    }
+
+    //sends a keep alive carrying the acks
+    pub fn send_acks(&mut self) -> anyhow::Result<()> {
+        let header = Header::new_keep_alive(self.unreliable_seq, self.session_key()?);
+        Sequence::increment(&mut self.unreliable_seq);
+        self.send_packet(header, &[])
+    }
+
+    fn insert_fragment(&mut self, header: &Header, payload: Bytes) -> Option<Bytes> {
+        let parts = self
+            .fragments
+            .entry(header.fragment_group_id)
+            .or_insert_with(|| vec![None; header.fragment_size as usize]);
+        if let Some(part) = parts.get_mut(header.fragment_id as usize) {
+            *part = Some(payload);
+        }
+        if parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let parts = self.fragments.remove(&header.fragment_group_id)?;
+        Some(parts.into_iter().flatten().flatten().collect())
+    }
+
+    fn mark_acked(&mut self, ack: u16, ack_bits: u32) {
+        self.acked.insert(ack);
+        for i in 0..32 {
+            if ack_bits & (1 << i) != 0 {
+                self.acked.insert(ack.wrapping_sub(i + 1));
+            }
+        }
+    }
+
+    //the newest reliable sequence received and the ones before it, like the channel sends them
+    fn acks(&self) -> (u16, u32) {
+        let Some(&newest) =
+            self.received
+                .iter()
+                .max_by(|a, b| match Sequence::is_less_than(**a, **b) {
+                    true => std::cmp::Ordering::Less,
+                    false => std::cmp::Ordering::Greater,
+                })
+        else {
+            return (0, 0);
+        };
+        let mut ack_bits = 0;
+        for i in 0..32 {
+            if self.received.contains(&newest.wrapping_sub(i + 1)) {
+                ack_bits |= 1 << i;
+            }
+        }
+        (newest, ack_bits)
+    }
+
+    fn send_packet(&mut self, mut header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        (header.ack, header.ack_bits) = self.acks();
+        let mut datagram = self.protocol_id.0.to_vec();
+        header.write_into(&mut datagram);
+        datagram.extend_from_slice(payload);
+        self.socket.send_to(&datagram, self.server)?;
+        Ok(())
+    }
+
+    fn send_control(&mut self, packet: ControlPacket) -> anyhow::Result<()> {
+        let mut datagram = packet.write();
+        self.protocol_id.write_into(&mut datagram);
+        self.socket.send_to(&datagram, self.server)?;
+        Ok(())
+    }
+
+    fn recv_control(&mut self, timeout: Duration) -> anyhow::Result<ControlPacket> {
+        let deadline = Instant::now() + timeout;
+        while let Some(datagram) = self.recv_datagram(deadline)? {
+            if let Ok(packet) = ControlPacket::read(&datagram) {
+                return Ok(packet);
+            }
+        }
+        bail!("no handshake packet arrived in {timeout:?}")
+    }
+
+    //the next datagram of the server with the protocol id stripped
+    fn recv_datagram(&mut self, deadline: Instant) -> anyhow::Result<Option<Bytes>> {
+        let mut buffer = vec![0; FRAGMENT_SIZE * 2];
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(timeout))?;
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, addr))
+                    if addr == self.server && self.protocol_id.matches(&buffer[..len]) =>
+                {
+                    return Ok(Some(buffer[PROTOCOL_ID_SIZE..len].to_vec()))
+                }
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn session_key(&self) -> anyhow::Result<u64> {
+        self.session
+            .map(|session| session.session_key)
+            .ok_or_else(|| anyhow!("the handshake wasn't done"))
+    }
+}
+
+//a Rust server that sends every message it reads back reliably to the peer, the counterpart the
+//checks of run_conformance expect. the connections are only known by id so the address of the
+//peer is given up front
+pub struct EchoServer {
+    pub server: Arc<Server>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EchoServer {
+    pub fn start(addr: SocketAddr, peer: SocketAddr) -> anyhow::Result<Self> {
+        let server = Arc::new(Server::start(addr, 4)?);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = thread::spawn({
+            let server = server.clone();
+            let running = running.clone();
+            move || {
+                let mut buf = vec![0; super::MAX_FRAGMENT_SIZE];
+                while running.load(Ordering::Acquire) {
+                    if let Ok(Some(ServerEvent::Receive(_, data, _))) =
+                        server.read(&mut buf, Duration::from_millis(50))
+                    {
+                        let _ = server.send(peer, data, SendType::Reliable);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            server,
+            running,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    //None if it passed
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+//the checks in order, each one needs the ones before it
+const CHECKS: [&str; 6] = [
+    "handshake",
+    "reliable_acked",
+    "reliable_echoed",
+    "fragments_out_of_order",
+    "duplicate_dropped",
+    "disconnect_acked",
+];
+
+//runs the scripted peer against a server that echoes every message, like EchoServer. the checks
+//after a failed one are reported as skipped
+pub fn run_conformance(
+    local: SocketAddr,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Vec<CheckResult> {
+    let mut peer = match ScriptedPeer::bind(local, server) {
+        Ok(peer) => peer,
+        Err(e) => {
+            return CHECKS
+                .iter()
+                .map(|&name| CheckResult {
+                    name,
+                    error: Some(format!("binding the peer failed: {e}")),
+                })
+                .collect()
+        }
+    };
+
+    let mut failed = false;
+    CHECKS
+        .iter()
+        .map(|&name| {
+            let error = if failed {
+                Some("skipped after a failed check".to_string())
+            } else {
+                run_check(&mut peer, name, timeout)
+                    .err()
+                    .map(|e| e.to_string())
+            };
+            failed |= error.is_some();
+            CheckResult { name, error }
+        })
+        .collect()
+}
+
+fn run_check(peer: &mut ScriptedPeer, name: &str, timeout: Duration) -> anyhow::Result<()> {
+    match name {
+        "handshake" => {
+            let session = peer.handshake(0, timeout)?;
+            if session.flags != 0 {
+                bail!(
+                    "no flags were requested, {:#x} were accepted",
+                    session.flags
+                );
+            }
+        }
+        "reliable_acked" => {
+            let seq = peer.send_reliable(b"ping")?;
+            peer.wait_for_ack(seq, timeout)?;
+            expect_message(peer, b"ping", timeout)?;
+        }
+        "reliable_echoed" => {
+            peer.send_reliable(b"echo")?;
+            expect_message(peer, b"echo", timeout)?;
+        }
+        "fragments_out_of_order" => {
+            let message: Vec<u8> = (0..FRAGMENT_SIZE * 2 + 100)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            let seqs = peer.send_fragmented(&message, &[2, 0, 1])?;
+            for seq in seqs {
+                peer.wait_for_ack(seq, timeout)?;
+            }
+            expect_message(peer, &message, timeout)?;
+        }
+        "duplicate_dropped" => {
+            let seq = peer.send_reliable(b"once")?;
+            expect_message(peer, b"once", timeout)?;
+            peer.send_reliable_as(seq, b"once")?;
+            //the duplicate isn't echoed, the next message is
+            peer.send_reliable(b"next")?;
+            expect_message(peer, b"next", timeout)?;
+        }
+        "disconnect_acked" => {
+            peer.send_disconnect(&DisconnectReason::new(DisconnectCode::UserQuit))?;
+            let deadline = Instant::now() + timeout;
+            loop {
+                let Some((header, _)) =
+                    peer.recv(deadline.saturating_duration_since(Instant::now()))?
+                else {
+                    bail!("the disconnect wasn't acked in {timeout:?}");
+                };
+                if header.packet_type == PacketType::DisconnectAck {
+                    break;
+                }
+            }
+        }
+        _ => bail!("unknown check {name}"),
+    }
+    Ok(())
+}
+
+fn expect_message(
+    peer: &mut ScriptedPeer,
+    expected: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let message = peer.recv_message(timeout)?;
+    peer.send_acks()?;
+    if message != expected {
+        bail!(
+            "expected a message of {} bytes, got {} bytes",
+            expected.len(),
+            message.len()
+        );
+    }
+    Ok(())
+}
@@ -1,32 +1,310 @@
-use std::{collections::VecDeque, rc::Rc, sync::Arc, time::Instant};
+use std::{
+    collections::{HashSet, VecDeque},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::bail;
 use crossbeam_channel::Sender;
 use log::error;
 
 use crate::net::{
-    channel::{Channel, ChannelType},
+    channel::{Channel, ChannelType, ReadPayload},
+    conditioner::{DebugConditions, LinkConditioner},
+    config::ChannelConfig,
     header::{Header, SendType},
+    limits::{
+        payload_size, HeldReadLimits, MessageLimits, OutboundAction, OutboundLimits,
+        OutboundOverflow, RateLimiter,
+    },
+    packets::SendEvent,
     send_buffer::SendPayload,
     socket::UdpSendEvent,
+    state::StateSlots,
+    Bytes, MessageKind,
 };
 
 use super::identity::Identity;
 
+//simulated network conditions applied to both directions of a connection
+pub struct DebugLink {
+    pub outbound: LinkConditioner<UdpSendEvent>,
+    pub inbound: LinkConditioner<Bytes>,
+}
+
+//how a send is made, plain sends can't be taken back or replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendKind {
+    Plain,
+    Cancellable(u32),
+    Superseding(u64),
+    //the response to a request of the client
+    Response,
+}
+
 pub struct Connection {
     pub identity: Identity,
     pub channel: Channel,
     pub received_at: Instant,
     pub last_received: Instant,
+    pub debug_link: Option<DebugLink>,
+    //keys of the filtered broadcasts the connection receives, like the map cells around the player
+    pub interests: HashSet<u64>,
+    //while paused the received payloads and the reliable sends are held until resume
+    pub paused: bool,
+    held_reads: VecDeque<(ReadPayload, Instant)>,
+    held_read_bytes: usize,
+    //payloads dropped because the held reads were full
+    pub held_reads_dropped: u64,
+    //drops the messages over the limits of the server
+    pub limiter: RateLimiter,
+    //the newest value of every state slot, sent on every update it's due
+    pub state_slots: StateSlots,
+    pub outbound_limits: OutboundLimits,
+    //sends refused by the outbound limits
+    pub outbound_dropped: u64,
+    //made the same way once the connection is resumed
+    held_sends: VecDeque<(SendEvent, SendKind)>,
+    send_buf: VecDeque<UdpSendEvent>,
 }
 
 impl Connection {
-    pub fn new(identity: Identity) -> Self {
+    pub fn new(identity: Identity, config: ChannelConfig) -> Self {
+        let mut channel = Channel::new(
+            identity.addr,
+            identity.session_key,
+            ChannelType::Server,
+            config,
+        );
+        channel.socket = Some(identity.ingress);
+
         Self {
-            channel: Channel::new(identity.addr, identity.session_key, ChannelType::Server),
+            channel,
             identity,
             received_at: Instant::now(),
             last_received: Instant::now(),
+            debug_link: None,
+            interests: HashSet::new(),
+            paused: false,
+            held_reads: VecDeque::new(),
+            held_read_bytes: 0,
+            held_reads_dropped: 0,
+            limiter: RateLimiter::new(MessageLimits::default()),
+            state_slots: StateSlots::new(),
+            outbound_limits: OutboundLimits::default(),
+            outbound_dropped: 0,
+            held_sends: VecDeque::new(),
+            send_buf: VecDeque::new(),
+        }
+    }
+
+    //the packets held back keep the release time they got, cleared conditions only let them out
+    //and the link goes away once it's empty
+    pub fn set_debug_conditions(&mut self, conditions: Option<DebugConditions>) {
+        let conditions = conditions.filter(|c| !c.is_noop());
+        match &mut self.debug_link {
+            Some(debug_link) => {
+                let conditions = conditions.unwrap_or(DebugConditions {
+                    latency: Duration::ZERO,
+                    jitter: Duration::ZERO,
+                    loss: 0.0,
+                });
+                debug_link.outbound.conditions = conditions;
+                debug_link.inbound.conditions = conditions;
+            }
+            None => {
+                self.debug_link = conditions.map(|conditions| DebugLink {
+                    outbound: LinkConditioner::new(conditions),
+                    inbound: LinkConditioner::new(conditions),
+                })
+            }
+        }
+        self.drop_drained_link();
+    }
+
+    fn drop_drained_link(&mut self) {
+        let drained = self.debug_link.as_ref().is_some_and(|debug_link| {
+            debug_link.outbound.conditions.is_noop()
+                && debug_link.outbound.is_empty()
+                && debug_link.inbound.is_empty()
+        });
+        if drained {
+            self.debug_link = None;
+        }
+    }
+
+    pub fn send_event(
+        &mut self,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        self.send(send_event, SendKind::Plain, send_queue)
+    }
+
+    //sent in response packets so the client doesn't read it as a message
+    pub fn send_response(
+        &mut self,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        self.send(send_event, SendKind::Response, send_queue)
+    }
+
+    //a reliable message in fragments that cancel_send can take back
+    pub fn send_cancellable(
+        &mut self,
+        send_id: u32,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        self.send(send_event, SendKind::Cancellable(send_id), send_queue)
+    }
+
+    //replaces the previous send with the key, a held one too
+    pub fn send_superseding(
+        &mut self,
+        key: u64,
+        send_event: SendEvent,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        self.send(send_event, SendKind::Superseding(key), send_queue)
+    }
+
+    //a held send is dropped, false if the channel forgot the send
+    pub fn cancel_send(
+        &mut self,
+        send_id: u32,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<bool> {
+        let count = self.held_sends.len();
+        self.held_sends
+            .retain(|(_, kind)| *kind != SendKind::Cancellable(send_id));
+        if self.held_sends.len() != count {
+            return Ok(true);
+        }
+
+        let send_queue = match self.debug_link {
+            Some(_) => &mut self.send_buf,
+            None => send_queue,
+        };
+        let result = self.channel.cancel_send(send_id, send_queue);
+        if self.debug_link.is_some() {
+            self.condition_outbound();
+        }
+        result
+    }
+
+    fn send(
+        &mut self,
+        send_event: SendEvent,
+        kind: SendKind,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> anyhow::Result<()> {
+        //a disconnect always goes out
+        let capped =
+            self.outbound_limits.max_packets.is_some() || self.outbound_limits.max_bytes.is_some();
+        if capped && !matches!(send_event, SendEvent::Disconnect(_)) {
+            let action = self.outbound_limits.check(
+                self.queued(),
+                send_event.size(),
+                send_event.is_reliable(),
+            );
+            if action != OutboundAction::Queue {
+                self.outbound_dropped += 1;
+                bail!(OutboundOverflow {
+                    kick: action == OutboundAction::Kick,
+                });
+            }
+        }
+
+        //the unreliable payloads would be stale by the time the connection is resumed
+        if self.paused {
+            match send_event {
+                SendEvent::Single(_, true) | SendEvent::Fragmented(_, true) => {
+                    if let SendKind::Superseding(_) = kind {
+                        self.held_sends.retain(|(_, held)| *held != kind);
+                    }
+                    self.held_sends.push_back((send_event, kind));
+                    return Ok(());
+                }
+                SendEvent::Single(..) | SendEvent::Fragmented(..) => return Ok(()),
+                SendEvent::Disconnect(_) => {}
+            }
+        }
+
+        let send_queue = match self.debug_link {
+            Some(_) => &mut self.send_buf,
+            None => send_queue,
+        };
+        let result = match kind {
+            SendKind::Plain => self.channel.send_event(send_event, send_queue),
+            SendKind::Cancellable(send_id) => self
+                .channel
+                .send_cancellable(send_id, send_event, send_queue),
+            SendKind::Superseding(key) => {
+                self.channel.send_superseding(key, send_event, send_queue)
+            }
+            SendKind::Response => {
+                self.channel
+                    .send_message(MessageKind::Response, send_event, send_queue)
+            }
+        };
+        if self.debug_link.is_some() {
+            self.condition_outbound();
+        }
+        result
+    }
+
+    //what's held back for the connection, the packets and the bytes
+    fn queued(&self) -> (usize, usize) {
+        let (packets, bytes) = self.channel.queued_reliable();
+        self.held_sends
+            .iter()
+            .map(|(send_event, _)| send_event.size())
+            .fold((packets, bytes), |(packets, bytes), size| {
+                (packets + size.0, bytes + size.1)
+            })
+    }
+
+    //sends the held reliable payloads, one that fails doesn't keep back the ones after it. returns
+    //the errors of the failed ones, the held reads are left for the caller to deliver
+    pub fn resume(&mut self, send_queue: &mut VecDeque<UdpSendEvent>) -> Vec<anyhow::Error> {
+        self.paused = false;
+        std::mem::take(&mut self.held_sends)
+            .into_iter()
+            .filter_map(|(send_event, kind)| self.send(send_event, kind, send_queue).err())
+            .collect()
+    }
+
+    //false if the payload doesn't fit in the limits and was dropped
+    pub fn hold_read(
+        &mut self,
+        payload: ReadPayload,
+        received_at: Instant,
+        limits: &HeldReadLimits,
+    ) -> bool {
+        let size = payload_size(&payload).unwrap_or(0);
+        if !limits.allows((self.held_reads.len(), self.held_read_bytes), size) {
+            self.held_reads_dropped += 1;
+            return false;
         }
+
+        self.held_read_bytes += size;
+        self.held_reads.push_back((payload, received_at));
+        true
+    }
+
+    pub fn take_held_read(&mut self) -> Option<(ReadPayload, Instant)> {
+        let (payload, received_at) = self.held_reads.pop_front()?;
+        self.held_read_bytes -= payload_size(&payload).unwrap_or(0);
+        Some((payload, received_at))
+    }
+
+    pub fn pop_ready_inbound(&mut self, now: Instant) -> Option<Bytes> {
+        self.debug_link
+            .as_mut()
+            .and_then(|debug_link| debug_link.inbound.pop_ready(now))
     }
 
     pub fn update(
@@ -34,8 +312,34 @@ impl Connection {
         marked_packets: &mut Vec<Rc<SendPayload>>,
         send_queue: &mut VecDeque<UdpSendEvent>,
     ) {
-        if let Err(e) = self.channel.update(marked_packets, send_queue) {
+        let result = if self.debug_link.is_none() {
+            self.channel.update(marked_packets, send_queue)
+        } else {
+            self.channel.update(marked_packets, &mut self.send_buf)
+        };
+
+        if let Err(e) = result {
             error!("error updating channel: {e}");
         }
+
+        self.condition_outbound();
+
+        //release the packets that have been held back long enough
+        if let Some(debug_link) = &mut self.debug_link {
+            let now = Instant::now();
+            while let Some(send_event) = debug_link.outbound.pop_ready(now) {
+                send_queue.push_front(send_event);
+            }
+        }
+        self.drop_drained_link();
+    }
+
+    fn condition_outbound(&mut self) {
+        if let Some(debug_link) = &mut self.debug_link {
+            //the oldest packets are at the back of the queue
+            while let Some(send_event) = self.send_buf.pop_back() {
+                debug_link.outbound.push(send_event);
+            }
+        }
     }
 }
@@ -0,0 +1,250 @@
+use anyhow::bail;
+
+use crate::net::{
+    bytes_with_header, int_buffer::IntBuffer, Bytes, NetError, PacketType, PROTOCOL_ID_SIZE,
+};
+
+//channel features negotiated during the handshake
+pub const FLAG_CHECKSUM: u8 = 1;
+pub const FLAG_COMPACT_HEADER: u8 = 2;
+pub const FLAG_ACK_DELAY: u8 = 4;
+pub const FLAG_FLOW_CONTROL: u8 = 8;
+pub const FLAG_INTERLEAVE: u8 = 16;
+
+//packets exchanged during the connection handshake, they don't carry the regular header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlPacket {
+    //the client sends the features it wants to use
+    ConnectionRequest { client_salt: u64, flags: u8 },
+    //the client tag lets the client match the challenge to its request
+    Challenge { client_tag: u64, server_salt: u64 },
+    //proof that the client derived the session key, the key itself is never sent
+    ChallengeResponse { response: u64 },
+    //the server replies with the features both sides agreed on
+    ConnectionAccepted { connection_id: u32, flags: u8 },
+    //the server got session packets from an address it has no connection for, the session key of
+    //the packet is echoed so the client can check it's meant for its session
+    ReconnectRequired { session_key: u64 },
+}
+
+impl ControlPacket {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            ControlPacket::ConnectionRequest { .. } => PacketType::ConnectionRequest,
+            ControlPacket::Challenge { .. } => PacketType::Challenge,
+            ControlPacket::ChallengeResponse { .. } => PacketType::ChallengeResponse,
+            ControlPacket::ConnectionAccepted { .. } => PacketType::ConnectionAccepted,
+            ControlPacket::ReconnectRequired { .. } => PacketType::ReconnectRequired,
+        }
+    }
+
+    //size of the packet without the protocol id
+    pub fn size(&self) -> usize {
+        Self::size_of(self.packet_type()).expect("control packet type has a size")
+    }
+
+    pub fn size_of(packet_type: PacketType) -> Option<usize> {
+        match packet_type {
+            PacketType::ConnectionRequest => Some(10),
+            PacketType::Challenge => Some(17),
+            PacketType::ChallengeResponse => Some(9),
+            PacketType::ConnectionAccepted => Some(6),
+            PacketType::ReconnectRequired => Some(9),
+            _ => None,
+        }
+    }
+
+    //creates a buffer prefixed with the protocol id ready to be sent
+    pub fn write(&self) -> Bytes {
+        let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+        let mut buffer = bytes_with_header!(self.size());
+
+        int_buffer.write_u8(self.packet_type() as u8, &mut buffer);
+        match *self {
+            ControlPacket::ConnectionRequest { client_salt, flags } => {
+                int_buffer.write_u64(client_salt, &mut buffer);
+                int_buffer.write_u8(flags, &mut buffer);
+            }
+            ControlPacket::Challenge {
+                client_tag,
+                server_salt,
+            } => {
+                int_buffer.write_u64(client_tag, &mut buffer);
+                int_buffer.write_u64(server_salt, &mut buffer);
+            }
+            ControlPacket::ChallengeResponse { response } => {
+                int_buffer.write_u64(response, &mut buffer);
+            }
+            ControlPacket::ConnectionAccepted {
+                connection_id,
+                flags,
+            } => {
+                int_buffer.write_u32(connection_id, &mut buffer);
+                int_buffer.write_u8(flags, &mut buffer);
+            }
+            ControlPacket::ReconnectRequired { session_key } => {
+                int_buffer.write_u64(session_key, &mut buffer);
+            }
+        }
+
+        buffer
+    }
+
+    //reads a packet with the protocol id already stripped
+    pub fn read(buffer: &[u8]) -> anyhow::Result<ControlPacket> {
+        if buffer.is_empty() {
+            bail!(NetError::EmptyPacket);
+        }
+
+        let mut int_buffer = IntBuffer::default();
+        let packet_type = PacketType::try_from(int_buffer.read_u8(buffer))?;
+
+        let Some(expected) = Self::size_of(packet_type) else {
+            bail!(NetError::UnexpectedPacketType(packet_type));
+        };
+
+        if buffer.len() != expected {
+            bail!(NetError::InvalidLength {
+                packet_type,
+                expected,
+                actual: buffer.len(),
+            });
+        }
+
+        let packet = match packet_type {
+            PacketType::ConnectionRequest => ControlPacket::ConnectionRequest {
+                client_salt: int_buffer.read_u64(buffer),
+                flags: int_buffer.read_u8(buffer),
+            },
+            PacketType::Challenge => ControlPacket::Challenge {
+                client_tag: int_buffer.read_u64(buffer),
+                server_salt: int_buffer.read_u64(buffer),
+            },
+            PacketType::ChallengeResponse => ControlPacket::ChallengeResponse {
+                response: int_buffer.read_u64(buffer),
+            },
+            PacketType::ConnectionAccepted => ControlPacket::ConnectionAccepted {
+                connection_id: int_buffer.read_u32(buffer),
+                flags: int_buffer.read_u8(buffer),
+            },
+            PacketType::ReconnectRequired => ControlPacket::ReconnectRequired {
+                session_key: int_buffer.read_u64(buffer),
+            },
+            _ => unreachable!("only control packet types have a size"),
+        };
+
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read_roundtrip() {
+        let packets = [
+            ControlPacket::ConnectionRequest {
+                client_salt: 1,
+                flags: FLAG_CHECKSUM,
+            },
+            ControlPacket::Challenge {
+                client_tag: 2,
+                server_salt: 3,
+            },
+            ControlPacket::ChallengeResponse { response: 4 },
+            ControlPacket::ConnectionAccepted {
+                connection_id: 5,
+                flags: FLAG_CHECKSUM,
+            },
+            ControlPacket::ReconnectRequired { session_key: 6 },
+        ];
+
+        for packet in packets {
+            let buffer = packet.write();
+            assert_eq!(buffer.len(), packet.size() + PROTOCOL_ID_SIZE);
+            assert_eq!(
+                ControlPacket::read(&buffer[PROTOCOL_ID_SIZE..]).unwrap(),
+                packet
+            );
+        }
+    }
+
+    #[test]
+    fn payload_type_is_not_control() {
+        let buffer = [PacketType::PayloadReliable as u8; 9];
+        assert_eq!(
+            read_error(&buffer),
+            NetError::UnexpectedPacketType(PacketType::PayloadReliable)
+        );
+    }
+
+    #[test]
+    fn empty_packet() {
+        assert_eq!(read_error(&[]), NetError::EmptyPacket);
+    }
+
+    #[test]
+    fn garbage_packet_type() {
+        assert_eq!(read_error(&[0, 1, 2, 3]), NetError::UnknownPacketType(0));
+        assert_eq!(read_error(&[255; 17]), NetError::UnknownPacketType(255));
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let packets = [
+            ControlPacket::ConnectionRequest {
+                client_salt: 1,
+                flags: FLAG_CHECKSUM,
+            },
+            ControlPacket::Challenge {
+                client_tag: 2,
+                server_salt: 3,
+            },
+            ControlPacket::ChallengeResponse { response: 4 },
+            ControlPacket::ConnectionAccepted {
+                connection_id: 5,
+                flags: FLAG_CHECKSUM,
+            },
+            ControlPacket::ReconnectRequired { session_key: 6 },
+        ];
+
+        for packet in packets {
+            let buffer = packet.write();
+            let data = &buffer[PROTOCOL_ID_SIZE..];
+
+            for len in 1..data.len() {
+                assert_eq!(
+                    read_error(&data[..len]),
+                    NetError::InvalidLength {
+                        packet_type: packet.packet_type(),
+                        expected: packet.size(),
+                        actual: len,
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_packet() {
+        let mut buffer = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        buffer.push(0);
+
+        assert!(matches!(
+            read_error(&buffer[PROTOCOL_ID_SIZE..]),
+            NetError::InvalidLength { actual: 11, .. }
+        ));
+    }
+
+    fn read_error(buffer: &[u8]) -> NetError {
+        ControlPacket::read(buffer)
+            .unwrap_err()
+            .downcast::<NetError>()
+            .unwrap()
+    }
+}
@@ -1,33 +1,41 @@
-use std::{
-    net::SocketAddr,
-    sync::atomic::{AtomicU32, Ordering},
-    time::Instant,
-};
+use std::{net::SocketAddr, time::Instant};
 
-use rand::Rng;
+use crate::{net::random::RandomSource, proto::challenge::ChallengeScheme};
 
-static CONNECTION_ID_COUNTER: AtomicU32 = AtomicU32::new(1);
+use super::ConnectionId;
 
 #[derive(Clone)]
 pub struct Identity {
-    pub connection_id: u32,
+    //handed out once the handshake finished
+    pub connection_id: ConnectionId,
     pub addr: SocketAddr,
+    //the index of the socket of the server the client sends to, the replies leave from it
+    pub ingress: usize,
     pub client_salt: u64,
     pub server_salt: u64,
     pub session_key: u64,
+    //features negotiated during the handshake
+    pub flags: u8,
     pub created_at: Instant,
 }
 
 impl Identity {
-    pub fn new(addr: SocketAddr, client_salt: u64) -> Self {
-        let server_salt = rand::thread_rng().gen();
+    pub fn new(
+        addr: SocketAddr,
+        client_salt: u64,
+        random: &RandomSource,
+        challenge: &dyn ChallengeScheme,
+    ) -> Self {
+        let server_salt = random.next_u64();
 
         Self {
-            connection_id: CONNECTION_ID_COUNTER.fetch_add(1, Ordering::SeqCst),
+            connection_id: ConnectionId::default(),
             addr,
+            ingress: 0,
             client_salt,
             server_salt,
-            session_key: client_salt ^ server_salt,
+            session_key: challenge.session_key(client_salt, server_salt),
+            flags: 0,
             created_at: Instant::now(),
         }
     }
@@ -1,26 +1,63 @@
 use std::{
     collections::VecDeque,
+    fmt, io,
     time::{Duration, Instant},
 };
 
 use anyhow::bail;
 use crossbeam_channel::{Receiver, Sender};
-use log::warn;
-use rand::Rng;
+use log::{debug, warn};
 
 use crate::net::{
     bytes, bytes_with_header,
+    client::ConnectEvent,
+    config::HandshakeConfig,
     int_buffer::IntBuffer,
+    random::RandomSource,
     socket::{Socket, UdpEvent, UdpSendEvent},
-    Bytes, PacketType, MAGIC_NUMBER_HEADER,
+    Bytes, PacketType,
 };
+use crate::proto::challenge::ChallengeScheme;
 
-const REPLY_TIMEOUT: Duration = Duration::from_millis(150);
-const RETRIES: usize = 5;
+use super::{ConnectionId, ControlPacket};
+
+//the part of the handshake that was waiting for a reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    //the connection request wasn't answered with a challenge
+    Challenge,
+    //the challenge response wasn't answered with an accept
+    Accept,
+}
+
+//the server stopped replying, the stage it failed at and the packets sent for that stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimeout {
+    pub stage: HandshakeStage,
+    pub attempts: u32,
+}
+
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reply = match self.stage {
+            HandshakeStage::Challenge => "challenge",
+            HandshakeStage::Accept => "accept",
+        };
+        write!(
+            f,
+            "failed connecting to server, no {reply} received, attempts: {}",
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for HandshakeTimeout {}
 
 pub struct ConnectionResponse {
     pub session_key: u64,
-    pub connection_id: u32,
+    pub connection_id: ConnectionId,
+    //features the server agreed on
+    pub flags: u8,
 }
 
 pub struct ConnectionHandshake<'a> {
@@ -28,32 +65,63 @@ pub struct ConnectionHandshake<'a> {
     events: VecDeque<UdpEvent>,
     client_salt: u64,
     server_salt: Option<u64>,
+    flags: u8,
+    challenge: &'a dyn ChallengeScheme,
+    config: HandshakeConfig,
 }
 
 impl<'a> ConnectionHandshake<'a> {
-    pub fn new(socket: &'a mut Socket) -> ConnectionHandshake {
+    pub fn new(
+        socket: &'a mut Socket,
+        flags: u8,
+        random: &RandomSource,
+        challenge: &'a dyn ChallengeScheme,
+        config: HandshakeConfig,
+    ) -> ConnectionHandshake<'a> {
         ConnectionHandshake {
             socket,
             events: VecDeque::with_capacity(1),
-            client_salt: rand::thread_rng().gen(),
+            client_salt: random.next_u64(),
             server_salt: None,
+            flags,
+            challenge,
+            config,
         }
     }
 
-    pub fn try_login(&mut self) -> anyhow::Result<ConnectionResponse> {
-        for _ in 0..RETRIES {
+    //the stages reached are passed to progress, the outcome is returned
+    pub fn try_login(
+        &mut self,
+        mut progress: impl FnMut(ConnectEvent),
+    ) -> anyhow::Result<ConnectionResponse> {
+        let mut timeout = HandshakeTimeout {
+            stage: HandshakeStage::Challenge,
+            attempts: 0,
+        };
+        let mut challenge_attempts = 0;
+        let mut accept_attempts = 0;
+
+        for _ in 0..self.config.rounds.max(1) {
             self.server_salt = None;
+            progress(ConnectEvent::Connecting);
 
-            for _ in 0..RETRIES {
+            for attempt in 0..self.config.attempts.max(1) {
                 //send connection request
                 self.send_connection_request();
+                challenge_attempts += 1;
+                timeout = HandshakeTimeout {
+                    stage: HandshakeStage::Challenge,
+                    attempts: challenge_attempts,
+                };
 
                 //wait for the challenge
-                match self.read_challenge() {
+                match self.read_challenge(self.config.timeout(attempt)) {
                     Ok(server_salt) => {
                         self.server_salt = Some(server_salt);
+                        progress(ConnectEvent::ChallengeReceived);
                         break;
                     }
+                    Err(e) if is_refused(&e) => return Err(e),
                     Err(e) => {
                         warn!("failed reading connection challenge: {e}");
                     }
@@ -61,18 +129,27 @@ impl<'a> ConnectionHandshake<'a> {
             }
 
             if let Some(server_salt) = self.server_salt {
-                for _ in 0..RETRIES {
+                for attempt in 0..self.config.attempts.max(1) {
                     //send the challenge response
                     self.send_challenge_response(server_salt);
+                    accept_attempts += 1;
+                    timeout = HandshakeTimeout {
+                        stage: HandshakeStage::Accept,
+                        attempts: accept_attempts,
+                    };
 
                     //wait for accept or deny response
-                    match self.read_connection_status() {
-                        Ok(connection_id) => {
+                    match self.read_connection_status(self.config.timeout(attempt)) {
+                        Ok((connection_id, flags)) => {
                             return Ok(ConnectionResponse {
-                                session_key: self.client_salt ^ server_salt,
+                                session_key: self
+                                    .challenge
+                                    .session_key(self.client_salt, server_salt),
                                 connection_id,
+                                flags,
                             });
                         }
+                        Err(e) if is_refused(&e) => return Err(e),
                         Err(e) => {
                             warn!("failed reading connection challenge response: {e}");
                         }
@@ -81,68 +158,111 @@ impl<'a> ConnectionHandshake<'a> {
             }
         }
 
-        bail!("failed connecting to server");
+        Err(timeout.into())
     }
 
     fn send_connection_request(&mut self) {
-        let mut int_buffer = IntBuffer::new_at(4);
-
-        let mut buffer = bytes_with_header!(9);
-
-        int_buffer.write_u8(PacketType::ConnectionRequest as u8, &mut buffer);
-        int_buffer.write_u64(self.client_salt, &mut buffer);
+        let buffer = ControlPacket::ConnectionRequest {
+            client_salt: self.client_salt,
+            flags: self.flags,
+        }
+        .write();
 
-        self.socket.enqueue_send_event(UdpSendEvent::Client(buffer));
+        self.socket
+            .enqueue_send_event(UdpSendEvent::Client(buffer.into()));
     }
 
-    fn read_challenge(&mut self) -> anyhow::Result<u64> {
-        let buffer: Vec<u8> = self.read_udp_event()?;
-
-        let mut int_buffer = IntBuffer::default();
-        let state = PacketType::try_from(int_buffer.read_u8(&buffer))?;
-
-        if self.client_salt != int_buffer.read_u64(&buffer) {
-            bail!("invalid client salt");
+    //datagrams that aren't the reply, like a challenge of an earlier request, are ignored until the
+    //timeout instead of ending the attempt
+    fn read_challenge(&mut self, timeout: Duration) -> anyhow::Result<u64> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let buffer: Vec<u8> = self.read_udp_event(deadline)?;
+
+            match ControlPacket::read(&buffer) {
+                Ok(ControlPacket::Challenge {
+                    client_tag,
+                    server_salt,
+                }) if self.challenge.client_tag(self.client_salt) == client_tag => {
+                    return Ok(server_salt)
+                }
+                Ok(ControlPacket::Challenge { .. }) => {
+                    debug!("ignored a challenge with an invalid client tag")
+                }
+                Ok(packet) => debug!(
+                    "ignored {:?} while waiting for the challenge",
+                    packet.packet_type()
+                ),
+                Err(e) => debug!("ignored a datagram while waiting for the challenge: {e}"),
+            }
         }
-        let server_salt = int_buffer.read_u64(&buffer);
-
-        Ok(server_salt)
     }
 
-    fn read_connection_status(&mut self) -> anyhow::Result<u32> {
-        let buffer: Vec<u8> = self.read_udp_event()?;
-
-        let mut int_buffer = IntBuffer::default();
-        let state = PacketType::try_from(int_buffer.read_u8(&buffer))?;
-
-        if state == PacketType::ConnectionAccepted {
-            return Ok(int_buffer.read_u32(&buffer));
+    //the accept isn't tied to the session, only the address of the server vouches for it
+    fn read_connection_status(&mut self, timeout: Duration) -> anyhow::Result<(ConnectionId, u8)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let buffer: Vec<u8> = self.read_udp_event(deadline)?;
+
+            match ControlPacket::read(&buffer) {
+                Ok(ControlPacket::ConnectionAccepted {
+                    connection_id,
+                    flags,
+                }) => {
+                    //the server can't enable features we didn't ask for
+                    if flags & !self.flags != 0 {
+                        bail!("server accepted unrequested features {flags:#b}");
+                    }
+                    return Ok((ConnectionId::from_bits(connection_id), flags));
+                }
+                Ok(packet) => debug!(
+                    "ignored {:?} while waiting for the accept",
+                    packet.packet_type()
+                ),
+                Err(e) => debug!("ignored a datagram while waiting for the accept: {e}"),
+            }
         }
-
-        bail!("connection not accepted");
     }
 
     fn send_challenge_response(&mut self, server_salt: u64) {
-        let mut int_buffer = IntBuffer::new_at(4);
-
-        let mut buffer = bytes_with_header!(9);
-
-        int_buffer.write_u8(PacketType::ChallengeResponse as u8, &mut buffer);
-        int_buffer.write_u64(self.client_salt ^ server_salt, &mut buffer);
-
-        self.socket.enqueue_send_event(UdpSendEvent::Client(buffer));
-    }
-
-    fn read_udp_event(&mut self) -> anyhow::Result<Bytes> {
-        self.events.clear();
+        let session_key = self.challenge.session_key(self.client_salt, server_salt);
+        let buffer = ControlPacket::ChallengeResponse {
+            response: self.challenge.response(session_key),
+        }
+        .write();
 
         self.socket
-            .process(Instant::now() + REPLY_TIMEOUT, Some(1), &mut self.events)?;
+            .enqueue_send_event(UdpSendEvent::Client(buffer.into()));
+    }
 
-        if let Some(UdpEvent::Read(_, buffer, _)) = self.events.pop_back() {
-            return Ok(buffer);
+    //a reply from another address than the server is dropped and the wait goes on, a spoofed
+    //challenge can't take the place of the one of the server
+    fn read_udp_event(&mut self, deadline: Instant) -> anyhow::Result<Bytes> {
+        loop {
+            self.events.clear();
+            self.socket.process(deadline, Some(1), &mut self.events)?;
+
+            match self.events.pop_back() {
+                Some(UdpEvent::Read(addr, buffer, ..))
+                    if Some(addr) == self.socket.remote_addr() =>
+                {
+                    return Ok(buffer)
+                }
+                //the connected socket already drops them where the system filters by the peer
+                Some(UdpEvent::Read(addr, ..)) if Instant::now() < deadline => {
+                    warn!("dropped a handshake reply from {addr}, it isn't the server");
+                }
+                Some(UdpEvent::Error(e)) if e.kind == io::ErrorKind::ConnectionRefused => {
+                    return Err(io::Error::new(e.kind, e.message).into())
+                }
+                _ => bail!("expected read event"),
+            }
         }
-
-        bail!("expected read event")
     }
 }
+
+//nothing listens on the port of the server, the retries can't succeed
+fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
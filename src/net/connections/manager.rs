@@ -3,154 +3,1286 @@ use std::{
     collections::{HashMap, VecDeque},
     net::SocketAddr,
     rc::Rc,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
 use crossbeam_channel::Sender;
+use log::{debug, error};
 
 use crate::net::{
-    bytes_with_header, int_buffer::IntBuffer, send_buffer::SendPayload, socket::UdpSendEvent,
+    bytes_with_header,
+    channel::{Channel, ReadPayload},
+    conditioner::DebugConditions,
+    config::{DuplicatePolicy, ServerConfig, ServerConfigUpdate},
+    header::Header,
+    int_buffer::IntBuffer,
+    linger::Linger,
+    packets::SendEvent,
+    send_buffer::SendPayload,
+    socket::{Datagram, UdpSendEvent},
     Bytes, PacketType,
 };
 
+//where the connections of a worker are, the io thread routes their datagrams and commands with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRoute {
+    Opened(u64, SocketAddr, ConnectionId),
+    //the connection moved from the first address to the second
+    Moved(SocketAddr, SocketAddr, ConnectionId),
+    Closed(u64, SocketAddr),
+}
+
 pub enum ConnectionStatus {
+    //the packet isn't part of a handshake
     Rejected,
+    //the challenge was sent
     Connecting,
-    Connected(u32),
+    Connected(ConnectionId),
+    Failed(HandshakeFailure),
+    //the challenge was answered but the connections of the ip have to be closed first, the
+    //handshake is finished with accept_replacing
+    Replacing(Vec<ConnectionId>),
+}
+
+//why a client at the handshake didn't get connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    //every slot is taken
+    ServerFull,
+    //the response to the challenge doesn't prove the session key
+    InvalidResponse,
+    //the client didn't answer the challenge within the handshake timeout
+    TimedOut,
+    //the address already has a connection, or its ip does and the duplicate policy allows only one
+    AddressInUse,
 }
 
-use super::{identity::Identity, Connection};
+use super::{
+    identity::Identity,
+    slots::{ConnectionId, SlotMap},
+    Connection, ControlPacket,
+};
+
+//a client has to answer the challenge within this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+//an address sending packets of an unknown session gets at most one reconnect reply per interval
+const RECONNECT_REPLY_INTERVAL: Duration = Duration::from_millis(100);
+
+//a removed connection, its channel only takes part in the disconnect handshake until the linger ends
+struct ClosedConnection {
+    channel: Channel,
+    linger: Linger,
+}
 
 pub struct ConnectionManager {
+    config: ServerConfig,
     capacity: usize,
-    active_clients: usize,
-    connections: Vec<Option<Connection>>,
-    addr_map: HashMap<SocketAddr, usize>,
+    //shared with the other shards of the server, the capacity is for all of them
+    active_clients: Arc<AtomicUsize>,
+    connections: SlotMap<Connection>,
+    addr_map: HashMap<SocketAddr, ConnectionId>,
+    //the connections by session key, for the packets that arrive from another address
+    sessions: HashMap<u64, ConnectionId>,
     connect_requests: HashMap<SocketAddr, Identity>,
+    //the handshake that answered the challenge while the connections it replaces are closed
+    replacing: Option<Identity>,
+    //until when the addresses whose connection closed can't finish a handshake
+    cooldowns: HashMap<SocketAddr, Instant>,
+    closed_connections: HashMap<SocketAddr, ClosedConnection>,
+    //when the last reconnect reply was sent to an address
+    reconnect_replies: HashMap<SocketAddr, Instant>,
     marked_packets_buf: Vec<Rc<SendPayload>>,
+    //simulated on every connection by a config update
+    debug_conditions: Option<DebugConditions>,
+    //tells the io thread of a server with workers where the sessions are
+    session_routes: Option<Sender<SessionRoute>>,
 }
 
 impl ConnectionManager {
-    pub fn new(max_clients: usize) -> Self {
+    pub fn new(config: ServerConfig) -> Self {
+        Self::sharded(config, 0, 1, Arc::default())
+    }
+
+    //one of the managers of a server that splits its connections between worker threads
+    pub fn sharded(
+        config: ServerConfig,
+        shard: u32,
+        shards: u32,
+        active_clients: Arc<AtomicUsize>,
+    ) -> Self {
+        let max_clients = config.max_clients;
+        //a random first generation keeps the ids of a restarted server from matching the old ones
+        let first_generation = config.random.next_u64() as u16;
+        //the workers split the part of the id space, so the shard of an id is still its worker
+        let parts = config.id_space.parts.max(1) as u32;
+        let shard = config.id_space.part as u32 * shards + shard;
+        let shards = parts * shards;
+
         ConnectionManager {
             capacity: max_clients,
-            active_clients: 0,
+            active_clients,
             addr_map: HashMap::with_capacity(max_clients),
-            connections: (0..max_clients).map(|_| None).collect(),
+            sessions: HashMap::with_capacity(max_clients),
+            connections: SlotMap::new(max_clients, shard as u16, shards as u16, first_generation),
+            config,
             connect_requests: HashMap::new(),
+            replacing: None,
+            cooldowns: HashMap::new(),
+            closed_connections: HashMap::new(),
+            reconnect_replies: HashMap::new(),
             marked_packets_buf: Vec::new(),
+            debug_conditions: None,
+            session_routes: None,
         }
     }
 
-    pub fn get_client_mut(&mut self, addr: &SocketAddr) -> Option<&mut Connection> {
-        if let Some(connection_index) = self.addr_map.get(addr) {
-            if let Some(Some(client_opt)) = self.connections.get_mut(*connection_index) {
-                return Some(client_opt);
-            }
+    pub fn set_session_routes(&mut self, session_routes: Sender<SessionRoute>) {
+        self.session_routes = Some(session_routes);
+    }
+
+    //the io thread is gone once the routes can't be sent, the worker ends with it
+    fn route(&self, route: SessionRoute) {
+        if let Some(session_routes) = &self.session_routes {
+            _ = session_routes.send(route);
         }
-        None
+    }
+
+    pub fn get_client_mut(&mut self, addr: &SocketAddr) -> Option<&mut Connection> {
+        let connection_id = self.addr_map.get(addr)?;
+        self.connections.get_mut(*connection_id)
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.values()
+    }
+
+    pub fn connections_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.connections.values_mut()
+    }
+
+    pub fn get_client_by_id_mut(&mut self, connection_id: ConnectionId) -> Option<&mut Connection> {
+        self.connections.get_mut(connection_id)
     }
 
     pub fn process_connect(
         &mut self,
         addr: &SocketAddr,
+        ingress: usize,
         buffer: Bytes,
         send_queue: &mut VecDeque<UdpSendEvent>,
     ) -> anyhow::Result<ConnectionStatus> {
-        if !self.has_free_slots() {
+        let packet = ControlPacket::read(&buffer)?;
+
+        let duplicates = !self.duplicates(addr).is_empty();
+        let replace = self.config.duplicate_policy == DuplicatePolicy::ReplaceExisting;
+        if duplicates && !replace && matches!(packet, ControlPacket::ConnectionRequest { .. }) {
+            return Ok(ConnectionStatus::Failed(HandshakeFailure::AddressInUse));
+        }
+
+        //a replacing client takes the slot of the connection it replaces
+        let replacing = duplicates && replace;
+        if !self.has_free_slots() && !replacing {
+            //a handshake in progress can't finish either
+            let handshaking = self.connect_requests.remove(addr).is_some();
+            if handshaking || matches!(packet, ControlPacket::ConnectionRequest { .. }) {
+                return Ok(ConnectionStatus::Failed(HandshakeFailure::ServerFull));
+            }
             return Ok(ConnectionStatus::Rejected);
         }
 
-        let mut int_buffer = IntBuffer::default();
-        let state = PacketType::try_from(int_buffer.read_u8(&buffer))?;
+        //a request with another salt is a new client at the address, like one that gave up on its
+        //handshake and started over, the handshake left behind is dropped
+        if let ControlPacket::ConnectionRequest { client_salt, .. } = packet {
+            if self
+                .connect_requests
+                .get(addr)
+                .is_some_and(|identity| identity.client_salt != client_salt)
+            {
+                self.connect_requests.remove(addr);
+            }
+        }
 
         //check if theres already a connect in process
         if let Some(identity) = self.connect_requests.get(addr) {
-            if state == PacketType::ChallengeResponse
-                && identity.session_key == int_buffer.read_u64(&buffer)
-            {
-                let connection_id = identity.connection_id;
-                if let Some(buffer) = self.finish_challenge(addr) {
-                    send_queue.push_back(UdpSendEvent::Server(buffer, *addr));
-                    return Ok(ConnectionStatus::Connected(connection_id));
+            if let ControlPacket::ChallengeResponse { response } = packet {
+                if self.config.challenge.response(identity.session_key) != response {
+                    return Ok(ConnectionStatus::Failed(HandshakeFailure::InvalidResponse));
+                }
+                //the client answers again until the cooldown is over
+                if self
+                    .cooldowns
+                    .get(addr)
+                    .is_some_and(|until| Instant::now() < *until)
+                {
+                    debug!("{addr} reconnected during its cooldown");
+                    return Ok(ConnectionStatus::Rejected);
+                }
+                //only replaced once the client proved it gets the packets sent to the address
+                let replaced = self.duplicates(addr);
+                if !replaced.is_empty() {
+                    self.replacing = self.connect_requests.remove(addr);
+                    return Ok(ConnectionStatus::Replacing(replaced));
                 }
+                return Ok(self.accept_replacing(addr, send_queue));
             }
-        } else {
-            let client_salt = int_buffer.read_u64(&buffer);
-            let identity = Identity::new(*addr, client_salt);
+        } else if let ControlPacket::ConnectionRequest { client_salt, flags } = packet {
+            let mut identity = Identity::new(
+                *addr,
+                client_salt,
+                &self.config.random,
+                self.config.challenge.as_ref(),
+            );
+            identity.flags = flags & self.config.channel.handshake_flags();
+            identity.ingress = ingress;
 
             self.connect_requests.insert(*addr, identity.clone());
 
             //generate challenge packet
-            let mut buffer = bytes_with_header!(17);
-            int_buffer.goto(4);
-
-            int_buffer.write_u8(PacketType::Challenge as u8, &mut buffer);
-            int_buffer.write_u64(client_salt, &mut buffer);
-            int_buffer.write_u64(identity.server_salt, &mut buffer);
+            let buffer = ControlPacket::Challenge {
+                client_tag: self.config.challenge.client_tag(client_salt),
+                server_salt: identity.server_salt,
+            }
+            .write();
 
-            send_queue.push_back(UdpSendEvent::Server(buffer, *addr));
+            send_queue.push_back(UdpSendEvent::Server(
+                Datagram::from_socket(buffer, ingress),
+                *addr,
+            ));
             return Ok(ConnectionStatus::Connecting);
         }
 
         Ok(ConnectionStatus::Rejected)
     }
 
-    fn finish_challenge(&mut self, addr: &SocketAddr) -> Option<Bytes> {
-        if let Some(connection_index) = self.get_free_slot_index() {
-            //remove the identity from the connect requests
-            if let Some(identity) = self.connect_requests.remove(addr) {
-                let mut buffer = bytes_with_header!(5);
-                let mut int_buffer = IntBuffer::new_at(4);
-
-                int_buffer.write_u8(PacketType::ConnectionAccepted as u8, &mut buffer);
-                int_buffer.write_u32(identity.connection_id, &mut buffer);
-
-                //insert the client
-                self.insert_connection(connection_index, &identity);
+    //connects the client that answered the challenge, the connections it replaces are closed
+    pub fn accept_replacing(
+        &mut self,
+        addr: &SocketAddr,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> ConnectionStatus {
+        match self.finish_challenge(addr) {
+            Some((connection_id, datagram)) => {
+                send_queue.push_back(UdpSendEvent::Server(datagram, *addr));
+                ConnectionStatus::Connected(connection_id)
+            }
+            None => ConnectionStatus::Failed(HandshakeFailure::ServerFull),
+        }
+    }
 
-                return Some(buffer);
+    //the connections a client at the address conflicts with under the duplicate policy
+    fn duplicates(&self, addr: &SocketAddr) -> Vec<ConnectionId> {
+        match self.config.duplicate_policy {
+            DuplicatePolicy::AllowMultiplePorts => {
+                self.addr_map.get(addr).copied().into_iter().collect()
             }
+            DuplicatePolicy::ReplaceExisting | DuplicatePolicy::RejectNew => self
+                .addr_map
+                .iter()
+                .filter(|(connected, _)| connected.ip() == addr.ip())
+                .map(|(_, connection_id)| *connection_id)
+                .collect(),
         }
+    }
 
-        None
+    fn finish_challenge(&mut self, addr: &SocketAddr) -> Option<(ConnectionId, Datagram)> {
+        //remove the identity from the connect requests
+        let identity = match self.replacing.take() {
+            Some(identity) if identity.addr == *addr => identity,
+            _ => self.connect_requests.remove(addr)?,
+        };
+        //insert the client, another shard can have taken the last slot in the meantime
+        let connection_id = self.insert_connection(identity.clone())?;
+
+        let buffer = ControlPacket::ConnectionAccepted {
+            connection_id: connection_id.to_bits(),
+            flags: identity.flags,
+        }
+        .write();
+        Some((
+            connection_id,
+            Datagram::from_socket(buffer, identity.ingress),
+        ))
     }
 
     pub fn update(&mut self, send_queue: &mut VecDeque<UdpSendEvent>) {
-        for connection in self.connections.iter_mut().flatten() {
+        for connection in self.connections.values_mut() {
             connection.update(&mut self.marked_packets_buf, send_queue);
         }
+
+        let now = Instant::now();
+        self.closed_connections.retain(|addr, closed| {
+            if let Some(reason) = closed.linger.poll_resend(now) {
+                if let Err(e) = closed
+                    .channel
+                    .send_event(SendEvent::Disconnect(reason), send_queue)
+                {
+                    error!("failed resending disconnect to {addr}: {e}");
+                }
+            }
+            !closed.linger.is_finished(now)
+        });
+        self.reconnect_replies
+            .retain(|_, sent_at| now.duration_since(*sent_at) < RECONNECT_REPLY_INTERVAL);
+        self.cooldowns.retain(|_, until| now < *until);
     }
 
-    fn insert_connection(&mut self, index: usize, identity: &Identity) {
-        self.connections
-            .insert(index, Some(Connection::new(identity.clone())));
-        self.addr_map.insert(identity.addr, index);
-        self.active_clients += 1;
+    //the addresses of the handshakes that timed out waiting for the challenge response
+    pub fn expire_handshakes(&mut self, now: Instant, expired: &mut Vec<SocketAddr>) {
+        self.connect_requests.retain(|addr, identity| {
+            let pending = now.duration_since(identity.created_at) < HANDSHAKE_TIMEOUT;
+            if !pending {
+                expired.push(*addr);
+            }
+            pending
+        });
     }
 
-    pub fn disconnect_connection(&mut self, addr: SocketAddr) -> Option<u32> {
-        let mut client_id = None;
+    //packets of a session the server doesn't know, like the ones of a client connected to a previous
+    //run of the server, are answered with a reconnect reply instead of being read as a connect.
+    //false if the packet isn't a session packet
+    pub fn process_unknown_session(
+        &mut self,
+        addr: &SocketAddr,
+        ingress: usize,
+        buffer: &[u8],
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> bool {
+        if ControlPacket::read(buffer).is_ok() {
+            return false;
+        }
+        let Some(session_key) = read_session_key(buffer) else {
+            return false;
+        };
+        //a compact packet doesn't name its session and the client couldn't check a reply to it,
+        //it notices the restart with the idle timeout instead
+        let Some(session_key) = session_key else {
+            return true;
+        };
+
+        let now = Instant::now();
+        if self
+            .reconnect_replies
+            .get(addr)
+            .is_some_and(|sent_at| now.duration_since(*sent_at) < RECONNECT_REPLY_INTERVAL)
+        {
+            return true;
+        }
+        self.reconnect_replies.insert(*addr, now);
+
+        debug!("packet of an unknown session from {addr}, asking it to reconnect");
+        let buffer = ControlPacket::ReconnectRequired { session_key }.write();
+        send_queue.push_back(UdpSendEvent::Server(
+            Datagram::from_socket(buffer, ingress),
+            *addr,
+        ));
+        true
+    }
+
+    //the connection of the session a packet from another address could move, see address_migration
+    //of the config. returns the connection and the address it reads the packet at
+    pub fn migrating(
+        &self,
+        addr: &SocketAddr,
+        buffer: &[u8],
+    ) -> Option<(ConnectionId, SocketAddr)> {
+        if !self.config.address_migration || self.addr_map.contains_key(addr) {
+            return None;
+        }
+        let header = Header::read(buffer).ok()?;
+        if !header.packet_type.is_session_variant() {
+            return None;
+        }
+        let connection_id = *self.sessions.get(&header.session_key)?;
+        let connection = self.connections.get(connection_id)?;
+        if connection.channel.config.compact_header || !connection.channel.is_newer(&header) {
+            return None;
+        }
+        Some((connection_id, connection.identity.addr))
+    }
+
+    //moves the connection to the address the packet came from once its channel took the packet, it
+    //isn't newer anymore then. a packet the channel dropped leaves it where it is. returns the
+    //address it had
+    pub fn migrate(
+        &mut self,
+        connection_id: ConnectionId,
+        addr: &SocketAddr,
+        buffer: &[u8],
+        ingress: usize,
+    ) -> Option<SocketAddr> {
+        let header = Header::read(buffer).ok()?;
+        let connection = self.connections.get_mut(connection_id)?;
+        if connection.identity.session_key != header.session_key
+            || connection.channel.is_newer(&header)
+        {
+            return None;
+        }
+
+        let old = std::mem::replace(&mut connection.identity.addr, *addr);
+        connection.identity.ingress = ingress;
+        connection.channel.addr = *addr;
+        connection.channel.socket = Some(ingress);
+        self.addr_map.remove(&old);
+        self.addr_map.insert(*addr, connection_id);
+        self.route(SessionRoute::Moved(old, *addr, connection_id));
+        Some(old)
+    }
+
+    //packets of a closed connection only ack its disconnects, anything that isn't part of its session
+    //like a new connection request from the same address is handed back
+    pub fn process_closed_read(
+        &mut self,
+        addr: &SocketAddr,
+        buffer: Bytes,
+        received_at: &Instant,
+        send_queue: &mut VecDeque<UdpSendEvent>,
+    ) -> Option<Bytes> {
+        let Some(closed) = self.closed_connections.get_mut(addr) else {
+            return Some(buffer);
+        };
+        if !closed.channel.is_session_packet(&buffer) {
+            return Some(buffer);
+        }
+
+        match closed.channel.read(buffer, received_at) {
+            Ok(ReadPayload::Disconnect(_)) => closed.channel.send_disconnect_ack(send_queue),
+            Ok(ReadPayload::DisconnectAck) => closed.linger.acked(),
+            Ok(_) => {}
+            Err(e) => debug!("failed reading packet of closed connection {addr}: {e}"),
+        }
+        None
+    }
+
+    //collect the inbound packets held back by the debug conditions that are ready to be processed,
+    //they are marked as received on release so the simulated latency shows up in the timings
+    pub fn take_delayed_reads(&mut self, reads: &mut Vec<(SocketAddr, Bytes, Instant)>) {
+        let now = Instant::now();
+        for connection in self.connections.values_mut() {
+            while let Some(buffer) = connection.pop_ready_inbound(now) {
+                reads.push((connection.identity.addr, buffer, now));
+            }
+        }
+    }
+
+    fn insert_connection(&mut self, mut identity: Identity) -> Option<ConnectionId> {
+        if !self.connections.has_free_slots() {
+            return None;
+        }
+        let reserved =
+            self.active_clients
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                    (active < self.capacity).then_some(active + 1)
+                });
+        if reserved.is_err() {
+            return None;
+        }
 
-        if let Some(index) = self.addr_map.get(&addr).cloned() {
-            let slot = &self.connections[index];
-            if let Some(connection) = slot {
-                self.active_clients -= 1;
-                client_id = Some(connection.identity.connection_id);
+        let channel_config = self.config.channel.with_flags(identity.flags);
+        let addr = identity.addr;
+        let session_key = identity.session_key;
+        let debug_conditions = self.debug_conditions;
+        let message_limits = self.config.message_limits;
+        let outbound_limits = self.config.outbound_limits;
+        let random = &self.config.random;
+        let connection_id = self.connections.insert_with(|connection_id| {
+            identity.connection_id = connection_id;
+            let mut connection = Connection::new(identity, channel_config);
+            connection.channel.start_groups(random);
+            connection.limiter.set_limits(message_limits);
+            connection.outbound_limits = outbound_limits;
+            if debug_conditions.is_some() {
+                connection.set_debug_conditions(debug_conditions);
             }
-            self.addr_map.remove(&addr);
-            self.connections[index] = None;
+            connection
+        })?;
+        self.addr_map.insert(addr, connection_id);
+        self.sessions.insert(session_key, connection_id);
+        self.route(SessionRoute::Opened(session_key, addr, connection_id));
+        self.closed_connections.remove(&addr);
+        self.cooldowns.remove(&addr);
+        Some(connection_id)
+    }
+
+    //frees the slot of the connection and keeps its channel for the disconnect handshake. a
+    //handshake of the address that raced the close starts over, one replacing the connection was
+    //taken out before
+    pub fn close_connection(&mut self, addr: SocketAddr, linger: Linger) -> Option<ConnectionId> {
+        let connection_id = self.addr_map.remove(&addr)?;
+        let connection = self.connections.remove(connection_id)?;
+        self.sessions.remove(&connection.identity.session_key);
+        self.route(SessionRoute::Closed(connection.identity.session_key, addr));
+        self.active_clients.fetch_sub(1, Ordering::AcqRel);
+        self.connect_requests.remove(&addr);
+        if !self.config.reconnect_cooldown.is_zero() {
+            self.cooldowns
+                .insert(addr, Instant::now() + self.config.reconnect_cooldown);
         }
 
-        client_id
+        self.closed_connections.insert(
+            addr,
+            ClosedConnection {
+                channel: connection.channel,
+                linger,
+            },
+        );
+        Some(connection_id)
+    }
+
+    //across all shards
+    pub fn active_clients(&self) -> usize {
+        self.active_clients.load(Ordering::Acquire)
+    }
+
+    //whether the connection id was handed out by this shard
+    pub fn owns(&self, connection_id: ConnectionId) -> bool {
+        self.connections.owns(connection_id)
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    //the new connections get the changed config, the connected ones have their channel changed in place
+    pub fn update_config(&mut self, update: &ServerConfigUpdate) {
+        update.apply_to(&mut self.config);
+        if let Some(max_clients) = update.max_clients {
+            self.capacity = max_clients;
+            self.connections.grow(max_clients);
+        }
+        if let Some(debug_conditions) = update.debug_conditions {
+            self.debug_conditions = debug_conditions;
+        }
+
+        for connection in self.connections.values_mut() {
+            update.apply_to_channel(&mut connection.channel.config);
+            if let Some(debug_conditions) = update.debug_conditions {
+                connection.set_debug_conditions(debug_conditions);
+            }
+            if let Some(message_limits) = update.message_limits {
+                connection.limiter.set_limits(message_limits);
+            }
+            if let Some(outbound_limits) = update.outbound_limits {
+                connection.outbound_limits = outbound_limits;
+            }
+        }
     }
 
     fn has_free_slots(&self) -> bool {
-        self.active_clients < self.capacity
+        self.active_clients() < self.capacity && self.connections.has_free_slots()
+    }
+}
+
+//the session key of a packet sent on a channel, compact headers don't carry it so it's None for them
+pub fn read_session_key(buffer: &[u8]) -> Option<Option<u64>> {
+    if let Ok(header) = Header::read(buffer) {
+        if header.packet_type.is_session_variant() {
+            return Some(Some(header.session_key));
+        }
+    }
+
+    let packet_type = PacketType::try_from(*buffer.first()?).ok()?;
+    packet_type.is_session_variant().then_some(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        net::{
+            conditioner::DebugConditions,
+            disconnect::{DisconnectCode, DisconnectReason},
+            fragmentation_manager::FRAGMENT_SIZE,
+            packets::Payload,
+            random::RandomSource,
+            PROTOCOL_ID_SIZE,
+        },
+        proto::{
+            challenge::{ChallengeScheme, SipHashChallenge},
+            header::SendType,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn process_connect_truncated_packets() {
+        let mut manager = ConnectionManager::new(test_config());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        for len in 0..request.len() - PROTOCOL_ID_SIZE {
+            let buffer = request[PROTOCOL_ID_SIZE..PROTOCOL_ID_SIZE + len].to_vec();
+            assert!(manager
+                .process_connect(&addr, 0, buffer, &mut send_queue)
+                .is_err());
+        }
+
+        assert!(send_queue.is_empty());
+        assert!(manager.connect_requests.is_empty());
+    }
+
+    #[test]
+    fn process_connect_garbage_packets() {
+        let mut manager = ConnectionManager::new(test_config());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let request_size = ControlPacket::size_of(PacketType::ConnectionRequest).unwrap();
+
+        for packet_type in 0..=u8::MAX {
+            for len in 0..20 {
+                let mut buffer = vec![0xAB_u8; len];
+                if let Some(first) = buffer.first_mut() {
+                    *first = packet_type;
+                }
+                //only a well formed connection request can start the handshake
+                let status = manager.process_connect(&addr, 0, buffer, &mut send_queue);
+                if packet_type == PacketType::ConnectionRequest as u8 && len == request_size {
+                    assert!(matches!(status, Ok(ConnectionStatus::Connecting)));
+                    manager.connect_requests.clear();
+                    send_queue.clear();
+                } else {
+                    assert!(!matches!(status, Ok(ConnectionStatus::Connecting)));
+                }
+            }
+        }
+
+        assert!(send_queue.is_empty());
+    }
+
+    #[test]
+    fn seeded_challenges_are_reproducible() {
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+
+        let challenge = || {
+            let mut manager = ConnectionManager::new(ServerConfig {
+                random: RandomSource::seeded(3),
+                ..test_config()
+            });
+            let mut send_queue = VecDeque::new();
+            manager
+                .process_connect(
+                    &addr,
+                    0,
+                    request[PROTOCOL_ID_SIZE..].to_vec(),
+                    &mut send_queue,
+                )
+                .unwrap();
+            match send_queue.pop_back() {
+                Some(UdpSendEvent::Server(datagram, _)) => datagram.head,
+                _ => panic!("no challenge was sent"),
+            }
+        };
+
+        assert_eq!(challenge(), challenge());
     }
 
-    fn get_free_slot_index(&self) -> Option<usize> {
-        (0..self.capacity).find(|&i| self.connections.get(i).unwrap().is_none())
+    #[test]
+    fn challenge_response_proves_session_key() {
+        let mut manager = ConnectionManager::new(test_config());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let scheme = SipHashChallenge;
+
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        manager
+            .process_connect(
+                &addr,
+                0,
+                request[PROTOCOL_ID_SIZE..].to_vec(),
+                &mut send_queue,
+            )
+            .unwrap();
+
+        let server_salt = match send_queue.pop_back() {
+            Some(UdpSendEvent::Server(datagram, _)) => {
+                match ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..]).unwrap() {
+                    ControlPacket::Challenge {
+                        client_tag,
+                        server_salt,
+                    } => {
+                        //the salt isn't echoed back
+                        assert_eq!(client_tag, scheme.client_tag(1));
+                        server_salt
+                    }
+                    packet => panic!("expected challenge, got {packet:?}"),
+                }
+            }
+            _ => panic!("no challenge was sent"),
+        };
+
+        //the session key itself isn't accepted as the response
+        let session_key = scheme.session_key(1, server_salt);
+        for response in [1 ^ server_salt, session_key] {
+            let packet = ControlPacket::ChallengeResponse { response }.write();
+            let status = manager.process_connect(
+                &addr,
+                0,
+                packet[PROTOCOL_ID_SIZE..].to_vec(),
+                &mut send_queue,
+            );
+            assert!(matches!(
+                status,
+                Ok(ConnectionStatus::Failed(HandshakeFailure::InvalidResponse))
+            ));
+        }
+
+        let packet = ControlPacket::ChallengeResponse {
+            response: scheme.response(session_key),
+        }
+        .write();
+        let status = manager.process_connect(
+            &addr,
+            0,
+            packet[PROTOCOL_ID_SIZE..].to_vec(),
+            &mut send_queue,
+        );
+        assert!(matches!(status, Ok(ConnectionStatus::Connected(_))));
+    }
+
+    #[test]
+    fn unanswered_challenges_expire() {
+        let mut manager = ConnectionManager::new(test_config());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        manager
+            .process_connect(
+                &addr,
+                0,
+                request[PROTOCOL_ID_SIZE..].to_vec(),
+                &mut send_queue,
+            )
+            .unwrap();
+
+        let mut expired = Vec::new();
+        manager.expire_handshakes(Instant::now(), &mut expired);
+        assert!(expired.is_empty());
+        manager.expire_handshakes(Instant::now() + HANDSHAKE_TIMEOUT, &mut expired);
+        assert_eq!(expired, [addr]);
+        assert!(manager.connect_requests.is_empty());
+    }
+
+    #[test]
+    fn closed_connections_finish_the_disconnect_handshake() {
+        let config = test_config();
+        let mut manager = ConnectionManager::new(config.clone());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+
+        let identity = Identity::new(addr, 1, &config.random, config.challenge.as_ref());
+        let connection_id = manager.insert_connection(identity).unwrap();
+        let session_key = manager.get_client_mut(&addr).unwrap().channel.session_key;
+        let linger = Linger::closing(DisconnectReason::default(), &config.channel, Instant::now());
+        assert_eq!(manager.close_connection(addr, linger), Some(connection_id));
+        assert!(manager.get_client_by_id_mut(connection_id).is_none());
+        assert_eq!(manager.active_clients(), 0);
+
+        let packet = |header: Header| {
+            let mut buffer = Vec::new();
+            header.write_into(&mut buffer);
+            buffer
+        };
+
+        //a resent disconnect of the remote is acked again
+        let disconnect = packet(Header::new_disconnect(0, session_key));
+        assert!(manager
+            .process_closed_read(&addr, disconnect, &Instant::now(), &mut send_queue)
+            .is_none());
+        assert_eq!(send_queue.len(), 1);
+
+        //packets from outside of the session are handed back
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 2,
+            flags: 0,
+        }
+        .write()[PROTOCOL_ID_SIZE..]
+            .to_vec();
+        assert_eq!(
+            manager.process_closed_read(&addr, request.clone(), &Instant::now(), &mut send_queue),
+            Some(request)
+        );
+
+        let ack = packet(Header::new_disconnect_ack(1, session_key));
+        assert!(manager
+            .process_closed_read(&addr, ack, &Instant::now(), &mut send_queue)
+            .is_none());
+        manager.update(&mut send_queue);
+        assert!(manager.closed_connections.is_empty());
+    }
+
+    #[test]
+    fn closed_addresses_cool_down_before_reconnecting() {
+        let config = ServerConfig {
+            duplicate_policy: DuplicatePolicy::ReplaceExisting,
+            reconnect_cooldown: Duration::from_secs(1),
+            ..test_config()
+        };
+        let mut manager = ConnectionManager::new(config.clone());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let scheme = SipHashChallenge;
+
+        let request = |manager: &mut ConnectionManager, client_salt| {
+            let mut send_queue = VecDeque::new();
+            let request = ControlPacket::ConnectionRequest {
+                client_salt,
+                flags: 0,
+            }
+            .write();
+            manager
+                .process_connect(
+                    &addr,
+                    0,
+                    request[PROTOCOL_ID_SIZE..].to_vec(),
+                    &mut send_queue,
+                )
+                .unwrap();
+            let Some(UdpSendEvent::Server(datagram, _)) = send_queue.pop_back() else {
+                panic!("no challenge was sent");
+            };
+            let Ok(ControlPacket::Challenge { server_salt, .. }) =
+                ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..])
+            else {
+                panic!("expected a challenge");
+            };
+            let session_key = scheme.session_key(client_salt, server_salt);
+            ControlPacket::ChallengeResponse {
+                response: scheme.response(session_key),
+            }
+            .write()[PROTOCOL_ID_SIZE..]
+                .to_vec()
+        };
+
+        //a handshake started while the address was connected doesn't outlive the connection
+        let identity = Identity::new(addr, 1, &config.random, config.challenge.as_ref());
+        manager.insert_connection(identity).unwrap();
+        request(&mut manager, 2);
+        let linger = Linger::closing(DisconnectReason::default(), &config.channel, Instant::now());
+        manager.close_connection(addr, linger);
+        assert!(manager.connect_requests.is_empty());
+
+        //a client that started over replaces the handshake it left behind
+        request(&mut manager, 3);
+        let response = request(&mut manager, 4);
+        let status = manager.process_connect(&addr, 0, response.clone(), &mut send_queue);
+        assert!(matches!(status, Ok(ConnectionStatus::Rejected)));
+
+        //the answer after the cooldown connects
+        manager.cooldowns.insert(addr, Instant::now());
+        manager.update(&mut send_queue);
+        assert!(manager.cooldowns.is_empty());
+        let status = manager.process_connect(&addr, 0, response, &mut send_queue);
+        assert!(matches!(status, Ok(ConnectionStatus::Connected(_))));
+    }
+
+    #[test]
+    fn shards_share_the_capacity() {
+        let active_clients = Arc::new(AtomicUsize::new(0));
+        let mut shards: Vec<_> = (0..2)
+            .map(|shard| {
+                ConnectionManager::sharded(test_config(), shard, 2, active_clients.clone())
+            })
+            .collect();
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+
+        //both handshakes start while there is a free slot
+        let responses: Vec<_> = shards
+            .iter_mut()
+            .map(|manager| {
+                manager
+                    .process_connect(
+                        &addr,
+                        0,
+                        request[PROTOCOL_ID_SIZE..].to_vec(),
+                        &mut send_queue,
+                    )
+                    .unwrap();
+                let Some(UdpSendEvent::Server(datagram, _)) = send_queue.pop_back() else {
+                    panic!("no challenge was sent");
+                };
+                let Ok(ControlPacket::Challenge { server_salt, .. }) =
+                    ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..])
+                else {
+                    panic!("expected a challenge");
+                };
+                let scheme = SipHashChallenge;
+                ControlPacket::ChallengeResponse {
+                    response: scheme.response(scheme.session_key(1, server_salt)),
+                }
+                .write()[PROTOCOL_ID_SIZE..]
+                    .to_vec()
+            })
+            .collect();
+
+        let status = shards[1].process_connect(&addr, 0, responses[1].clone(), &mut send_queue);
+        let Ok(ConnectionStatus::Connected(connection_id)) = status else {
+            panic!("expected the connection to be accepted");
+        };
+        assert!(shards[1].owns(connection_id) && !shards[0].owns(connection_id));
+        //the slot was taken by the other shard
+        let status = shards[0].process_connect(&addr, 0, responses[0].clone(), &mut send_queue);
+        assert!(matches!(
+            status,
+            Ok(ConnectionStatus::Failed(HandshakeFailure::ServerFull))
+        ));
+        assert_eq!(shards[0].active_clients(), 1);
+    }
+
+    #[test]
+    fn unknown_sessions_are_asked_to_reconnect() {
+        let mut manager = ConnectionManager::new(test_config());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+
+        let mut keep_alive = Vec::new();
+        Header::new_keep_alive(3, 42).write_into(&mut keep_alive);
+        assert!(manager.process_unknown_session(&addr, 0, &keep_alive, &mut send_queue));
+        let Some(UdpSendEvent::Server(datagram, _)) = send_queue.pop_back() else {
+            panic!("expected a reconnect reply");
+        };
+        assert_eq!(
+            ControlPacket::read(&datagram.to_vec()[PROTOCOL_ID_SIZE..]).unwrap(),
+            ControlPacket::ReconnectRequired { session_key: 42 }
+        );
+
+        //the replies to an address are limited
+        assert!(manager.process_unknown_session(&addr, 0, &keep_alive, &mut send_queue));
+        assert!(send_queue.is_empty());
+
+        //a compact packet is dropped without a reply, it doesn't name the session
+        let compact_addr = "127.0.0.1:9001".parse().unwrap();
+        let mut compact = Vec::new();
+        Header::new_keep_alive(3, 42).write_compact_into(&mut compact);
+        assert!(manager.process_unknown_session(&compact_addr, 0, &compact, &mut send_queue));
+        assert!(send_queue.is_empty());
+
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 2,
+            flags: 0,
+        }
+        .write();
+        assert!(!manager.process_unknown_session(
+            &addr,
+            0,
+            &request[PROTOCOL_ID_SIZE..],
+            &mut send_queue
+        ));
+    }
+
+    #[test]
+    fn sessions_move_to_the_address_of_newer_packets() {
+        let config = ServerConfig {
+            address_migration: true,
+            ..test_config()
+        };
+        let mut manager = ConnectionManager::new(config.clone());
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let rebound = "127.0.0.1:9001".parse().unwrap();
+        let identity = Identity::new(addr, 1, &config.random, config.challenge.as_ref());
+        let session_key = identity.session_key;
+        let connection_id = manager.insert_connection(identity).unwrap();
+
+        let keep_alive = |seq, session_key| {
+            let mut buffer = Vec::new();
+            Header::new_keep_alive(seq, session_key).write_into(&mut buffer);
+            buffer
+        };
+        assert_eq!(manager.migrating(&rebound, &keep_alive(3, 42)), None);
+        assert_eq!(
+            manager.migrating(&rebound, &keep_alive(3, session_key)),
+            Some((connection_id, addr))
+        );
+
+        //the connection stays until its channel took the packet
+        assert_eq!(
+            manager.migrate(connection_id, &rebound, &keep_alive(3, session_key), 0),
+            None
+        );
+        assert!(manager.get_client_mut(&rebound).is_none());
+        manager
+            .get_client_mut(&addr)
+            .unwrap()
+            .channel
+            .read(keep_alive(3, session_key), &Instant::now())
+            .unwrap();
+        assert_eq!(
+            manager.migrate(connection_id, &rebound, &keep_alive(3, session_key), 0),
+            Some(addr)
+        );
+        assert!(manager.get_client_mut(&addr).is_none());
+        let connection = manager.get_client_mut(&rebound).unwrap();
+        assert_eq!(connection.identity.addr, rebound);
+
+        //a packet read already can't take the connection elsewhere
+        assert_eq!(manager.migrating(&addr, &keep_alive(3, session_key)), None);
+        assert_eq!(
+            manager.migrating(&addr, &keep_alive(4, session_key)),
+            Some((connection_id, rebound))
+        );
+
+        //the session is forgotten with the connection
+        let linger = Linger::closing(DisconnectReason::default(), &config.channel, Instant::now());
+        manager.close_connection(rebound, linger);
+        assert_eq!(manager.migrating(&addr, &keep_alive(5, session_key)), None);
+    }
+
+    #[test]
+    fn duplicate_policy_decides_requests_of_a_connected_ip() {
+        let config = test_config();
+        let connected: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let other_port: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write()[PROTOCOL_ID_SIZE..]
+            .to_vec();
+
+        let connected_manager = |duplicate_policy, max_clients| {
+            let mut manager = ConnectionManager::new(ServerConfig {
+                duplicate_policy,
+                max_clients,
+                ..test_config()
+            });
+            let identity = Identity::new(connected, 1, &config.random, config.challenge.as_ref());
+            let connection_id = manager.insert_connection(identity).unwrap();
+            (manager, connection_id)
+        };
+        let mut send_queue = VecDeque::new();
+
+        let (mut manager, _) = connected_manager(DuplicatePolicy::AllowMultiplePorts, 2);
+        for (addr, connecting) in [(connected, false), (other_port, true)] {
+            let status = manager.process_connect(&addr, 0, request.clone(), &mut send_queue);
+            match connecting {
+                true => assert!(matches!(status, Ok(ConnectionStatus::Connecting))),
+                false => assert!(matches!(
+                    status,
+                    Ok(ConnectionStatus::Failed(HandshakeFailure::AddressInUse))
+                )),
+            }
+        }
+
+        let (mut manager, _) = connected_manager(DuplicatePolicy::RejectNew, 2);
+        for addr in [connected, other_port] {
+            let status = manager.process_connect(&addr, 0, request.clone(), &mut send_queue);
+            assert!(matches!(
+                status,
+                Ok(ConnectionStatus::Failed(HandshakeFailure::AddressInUse))
+            ));
+        }
+
+        //the server is full but the new client takes the slot of the old one
+        let (mut manager, replaced) = connected_manager(DuplicatePolicy::ReplaceExisting, 1);
+        send_queue.clear();
+        let status = manager.process_connect(&other_port, 0, request.clone(), &mut send_queue);
+        assert!(matches!(status, Ok(ConnectionStatus::Connecting)));
+        let server_salt = match send_queue.pop_back() {
+            Some(UdpSendEvent::Server(datagram, _)) => {
+                match ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..]).unwrap() {
+                    ControlPacket::Challenge { server_salt, .. } => server_salt,
+                    packet => panic!("expected challenge, got {packet:?}"),
+                }
+            }
+            _ => panic!("no challenge was sent"),
+        };
+        let session_key = config.challenge.session_key(1, server_salt);
+        let response = ControlPacket::ChallengeResponse {
+            response: config.challenge.response(session_key),
+        }
+        .write()[PROTOCOL_ID_SIZE..]
+            .to_vec();
+        match manager.process_connect(&other_port, 0, response, &mut send_queue) {
+            Ok(ConnectionStatus::Replacing(connection_ids)) => {
+                assert_eq!(connection_ids, [replaced])
+            }
+            _ => panic!("expected the connection to be replaced"),
+        }
+
+        let linger = Linger::closing(
+            DisconnectReason::new(DisconnectCode::Kicked),
+            &config.channel,
+            Instant::now(),
+        );
+        manager.close_connection(connected, linger);
+        assert!(matches!(
+            manager.accept_replacing(&other_port, &mut send_queue),
+            ConnectionStatus::Connected(_)
+        ));
+        assert!(manager.get_client_mut(&other_port).is_some());
+    }
+
+    #[test]
+    fn connections_reply_from_the_socket_they_sent_to() {
+        let config = test_config();
+        let mut manager = ConnectionManager::new(config.clone());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let scheme = SipHashChallenge;
+
+        let request = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+        manager
+            .process_connect(
+                &addr,
+                1,
+                request[PROTOCOL_ID_SIZE..].to_vec(),
+                &mut send_queue,
+            )
+            .unwrap();
+        let Some(UdpSendEvent::Server(datagram, _)) = send_queue.pop_back() else {
+            panic!("no challenge was sent");
+        };
+        assert_eq!(datagram.socket, Some(1));
+        let Ok(ControlPacket::Challenge { server_salt, .. }) =
+            ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..])
+        else {
+            panic!("expected a challenge");
+        };
+
+        let response = ControlPacket::ChallengeResponse {
+            response: scheme.response(scheme.session_key(1, server_salt)),
+        }
+        .write();
+        let status = manager.process_connect(
+            &addr,
+            1,
+            response[PROTOCOL_ID_SIZE..].to_vec(),
+            &mut send_queue,
+        );
+        let Ok(ConnectionStatus::Connected(connection_id)) = status else {
+            panic!("expected the connection");
+        };
+        let Some(UdpSendEvent::Server(datagram, _)) = send_queue.pop_back() else {
+            panic!("the connection wasn't accepted");
+        };
+        assert_eq!(datagram.socket, Some(1));
+
+        //the sends of the connection leave from it too, and the socket is gone with it
+        let connection = manager.get_client_by_id_mut(connection_id).unwrap();
+        assert_eq!(connection.identity.ingress, 1);
+        connection
+            .channel
+            .send_event(
+                SendEvent::Single(Payload::new(&[1]), false),
+                &mut send_queue,
+            )
+            .unwrap();
+        assert_eq!(
+            send_queue.pop_back().map(|send| send.datagram().socket),
+            Some(Some(1))
+        );
+    }
+
+    #[test]
+    fn a_failed_held_send_doesnt_keep_back_the_others() {
+        let mut config = test_config();
+        config.channel.max_queued_reliable = Some(2);
+        let mut manager = ConnectionManager::new(config.clone());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let identity = Identity::new(addr, 1, &config.random, config.challenge.as_ref());
+        let connection_id = manager.insert_connection(identity).unwrap();
+        let connection = manager.get_client_by_id_mut(connection_id).unwrap();
+
+        //the message in fragments doesn't fit in the queue of the channel
+        connection.paused = true;
+        let fragmented = vec![0; FRAGMENT_SIZE * 4];
+        for data in [&[1][..], &fragmented, &[2]] {
+            let send_event =
+                crate::net::packets::construct_send_event(data, SendType::Reliable).unwrap();
+            connection.send_event(send_event, &mut send_queue).unwrap();
+        }
+        assert!(send_queue.is_empty());
+
+        let failed = connection.resume(&mut send_queue);
+        assert_eq!(failed.len(), 1);
+        assert!(!connection.paused);
+        assert_eq!(send_queue.len(), 2);
+    }
+
+    #[test]
+    fn clearing_the_debug_conditions_keeps_the_held_packets() {
+        let config = test_config();
+        let mut manager = ConnectionManager::new(config.clone());
+        let mut send_queue = VecDeque::new();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let identity = Identity::new(addr, 1, &config.random, config.challenge.as_ref());
+        let connection_id = manager.insert_connection(identity).unwrap();
+        let connection = manager.get_client_by_id_mut(connection_id).unwrap();
+
+        let conditions = DebugConditions {
+            latency: Duration::from_secs(10),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        };
+        connection.set_debug_conditions(Some(conditions));
+        let send_event =
+            crate::net::packets::construct_send_event(&[1], SendType::Reliable).unwrap();
+        connection.send_event(send_event, &mut send_queue).unwrap();
+        connection
+            .debug_link
+            .as_mut()
+            .unwrap()
+            .inbound
+            .push(vec![2]);
+        assert!(send_queue.is_empty());
+
+        //the packets on their way still arrive, the link stays until they did
+        connection.set_debug_conditions(None);
+        let debug_link = connection.debug_link.as_ref().unwrap();
+        assert_eq!(
+            (debug_link.outbound.len(), debug_link.inbound.len()),
+            (1, 1)
+        );
+        let later = Instant::now() + Duration::from_secs(11);
+        assert_eq!(connection.pop_ready_inbound(later), Some(vec![2]));
+        while connection
+            .debug_link
+            .as_mut()
+            .unwrap()
+            .outbound
+            .pop_ready(later)
+            .is_some()
+        {}
+        connection.update(&mut Vec::new(), &mut send_queue);
+        assert!(connection.debug_link.is_none());
+    }
+
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            max_clients: 1,
+            ..Default::default()
+        }
     }
 }
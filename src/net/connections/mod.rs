@@ -1,9 +1,18 @@
 mod connection;
+mod control;
 mod identity;
 mod login;
 mod manager;
+mod slots;
 
 pub use connection::Connection;
+pub use control::{
+    ControlPacket, FLAG_ACK_DELAY, FLAG_CHECKSUM, FLAG_COMPACT_HEADER, FLAG_FLOW_CONTROL,
+    FLAG_INTERLEAVE,
+};
 pub use identity::Identity;
-pub use login::ConnectionHandshake;
-pub use manager::{ConnectionManager, ConnectionStatus};
+pub use login::{ConnectionHandshake, HandshakeStage, HandshakeTimeout};
+pub use manager::{
+    read_session_key, ConnectionManager, ConnectionStatus, HandshakeFailure, SessionRoute,
+};
+pub use slots::{shard_of, ConnectionId, MAX_CONNECTION_SLOTS};
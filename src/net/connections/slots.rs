@@ -0,0 +1,205 @@
+use std::{collections::VecDeque, fmt};
+
+//the connection ids of a server have to fit the slots of every shard
+pub const MAX_CONNECTION_SLOTS: usize = 1 << 16;
+
+//the slot of a connection and the generation of the slot when the connection got it. slots are
+//reused by later connections but the id of a closed connection never addresses them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionId {
+    pub index: u16,
+    pub generation: u16,
+}
+
+impl ConnectionId {
+    //the form the id is sent to the client in
+    pub fn to_bits(self) -> u32 {
+        (self.generation as u32) << 16 | self.index as u32
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            index: bits as u16,
+            generation: (bits >> 16) as u16,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u16,
+    value: Option<T>,
+}
+
+//fixed capacity slots addressed by generational ids. the slots of a shard are the indices that are
+//the shard modulo the shard count, so the shard of an id is known without asking the shards
+pub struct SlotMap<T> {
+    slots: Vec<Slot<T>>,
+    //the slot freed the longest ago is reused first
+    free: VecDeque<usize>,
+    shard: u16,
+    shards: u16,
+    first_generation: u16,
+}
+
+impl<T> SlotMap<T> {
+    //the generations start at first_generation so ids from before a restart of the server don't
+    //address the new connections
+    pub fn new(capacity: usize, shard: u16, shards: u16, first_generation: u16) -> Self {
+        Self {
+            slots: (0..capacity)
+                .map(|_| Slot {
+                    generation: first_generation,
+                    value: None,
+                })
+                .collect(),
+            free: (0..capacity).collect(),
+            shard,
+            shards,
+            first_generation,
+        }
+    }
+
+    //adds slots up to the capacity, a smaller capacity keeps the slots there are
+    pub fn grow(&mut self, capacity: usize) {
+        for local in self.slots.len()..capacity {
+            self.slots.push(Slot {
+                generation: self.first_generation,
+                value: None,
+            });
+            self.free.push_back(local);
+        }
+    }
+
+    pub fn has_free_slots(&self) -> bool {
+        !self.free.is_empty()
+    }
+
+    //None if every slot is taken
+    pub fn insert_with(&mut self, value: impl FnOnce(ConnectionId) -> T) -> Option<ConnectionId> {
+        let local = self.free.pop_front()?;
+        let slot = &mut self.slots[local];
+        let id = ConnectionId {
+            index: (local * self.shards as usize + self.shard as usize) as u16,
+            generation: slot.generation,
+        };
+        slot.value = Some(value(id));
+        Some(id)
+    }
+
+    //the slot gets a new generation so the id is stale from now on
+    pub fn remove(&mut self, id: ConnectionId) -> Option<T> {
+        let local = self.local_index(id)?;
+        let value = self.slots[local].value.take()?;
+        self.slots[local].generation = self.slots[local].generation.wrapping_add(1);
+        self.free.push_back(local);
+        Some(value)
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<&T> {
+        self.slots[self.local_index(id)?].value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: ConnectionId) -> Option<&mut T> {
+        let local = self.local_index(id)?;
+        self.slots[local].value.as_mut()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|slot| slot.value.as_mut())
+    }
+
+    pub fn owns(&self, id: ConnectionId) -> bool {
+        shard_of(id, self.shards as u32) == self.shard as usize
+    }
+
+    fn local_index(&self, id: ConnectionId) -> Option<usize> {
+        if !self.owns(id) {
+            return None;
+        }
+        let local = id.index as usize / self.shards as usize;
+        let slot = self.slots.get(local)?;
+        (slot.generation == id.generation && slot.value.is_some()).then_some(local)
+    }
+}
+
+//the shard that handed out the connection id
+pub fn shard_of(connection_id: ConnectionId, shards: u32) -> usize {
+    connection_id.index as usize % shards.max(1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_ids_dont_address_reused_slots() {
+        let mut slots = SlotMap::new(2, 0, 1, 7);
+        let first = slots.insert_with(|_| "first").unwrap();
+        let second = slots.insert_with(|_| "second").unwrap();
+        assert_eq!(first.generation, 7);
+        assert!(slots.insert_with(|_| "full").is_none());
+
+        assert_eq!(slots.remove(first), Some("first"));
+        assert_eq!(slots.remove(first), None);
+        let third = slots.insert_with(|_| "third").unwrap();
+        assert_eq!(third.index, first.index);
+        assert_ne!(third, first);
+        assert_eq!(slots.get(first), None);
+        assert_eq!(slots.get(third), Some(&"third"));
+        assert_eq!(slots.get(second), Some(&"second"));
+        assert_eq!(ConnectionId::from_bits(third.to_bits()), third);
+    }
+
+    #[test]
+    fn grown_slots_are_handed_out() {
+        let mut slots = SlotMap::new(1, 1, 2, 0);
+        slots.insert_with(|_| "first").unwrap();
+        assert!(!slots.has_free_slots());
+
+        slots.grow(2);
+        let second = slots.insert_with(|_| "second").unwrap();
+        assert_eq!(second.index, 3);
+        assert_eq!(slots.get(second), Some(&"second"));
+
+        slots.grow(1);
+        assert_eq!(slots.values().count(), 2);
+    }
+
+    #[test]
+    fn freed_slots_are_reused_last() {
+        let mut slots = SlotMap::new(3, 0, 1, 0);
+        let first = slots.insert_with(|_| 1).unwrap();
+        slots.remove(first);
+        //the slots that were never used come first
+        let next = slots.insert_with(|_| 2).unwrap();
+        assert_ne!(next.index, first.index);
+    }
+
+    #[test]
+    fn shards_hand_out_their_own_indices() {
+        let mut shards: Vec<_> = (0..3).map(|shard| SlotMap::new(2, shard, 3, 0)).collect();
+        for (shard, slots) in shards.iter_mut().enumerate() {
+            while let Some(id) = slots.insert_with(|id| id) {
+                assert_eq!(shard_of(id, 3), shard);
+                assert!(slots.owns(id));
+            }
+        }
+        let id = ConnectionId {
+            index: 1,
+            generation: 0,
+        };
+        assert_eq!(shards[1].get(id), Some(&id));
+        assert_eq!(shards[0].get(id), None);
+    }
+}
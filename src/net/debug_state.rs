@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use super::fragmentation_manager::FragmentGroupState;
+
+//a snapshot of the sequencing of a channel for bug reports about reliable messages that stopped
+//arriving, serializable with the serde feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelDebugState {
+    //the next sequences used for sending
+    pub local_seq: u16,
+    pub unreliable_seq: u16,
+    //the newest sequence received from the remote
+    pub remote_seq: u16,
+    //the ack bits sent with the next packet, relative to the remote sequence
+    pub ack_bits: u32,
+    //sequences of the receive window that arrived
+    pub received_in_window: u16,
+    pub window_size: u16,
+    //the reliable packets that weren't acked yet, newest first
+    pub outstanding: Vec<OutstandingPacket>,
+    pub average_rtt: Duration,
+    pub rtt_variance: Duration,
+    //the messages that are still being reassembled
+    pub reliable_fragments: Vec<FragmentGroupState>,
+    pub unreliable_fragments: Vec<FragmentGroupState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OutstandingPacket {
+    pub seq: u16,
+    pub size: usize,
+    //since the packet was created
+    pub age: Duration,
+    //false while the packet waits to be resent
+    pub in_flight: bool,
+    //the packet is past the send timeout and won't be resent anymore
+    pub expired: bool,
+}
@@ -0,0 +1,91 @@
+use std::{collections::VecDeque, time::Duration};
+
+//something that went wrong or stalled on a channel, kept so a postmortem doesn't need verbose
+//logging of every connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum RecentEventKind {
+    //a reliable packet sent again because its ack didn't arrive in time
+    Resend(u16),
+    //a reliable packet that wasn't acked within the send timeout, it isn't resent anymore
+    SendTimedOut(u16),
+    //messages dropped since the last update because their fragments didn't all arrive in time
+    FragmentsTimedOut(u64),
+    //a packet of the session that couldn't be read
+    Malformed,
+    //reliable packets wait for the window of the remote, with how many are waiting
+    WindowStalled(usize),
+    //the packets that waited for the window are sent
+    WindowReopened,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RecentEvent {
+    //since the channel was created
+    pub at: Duration,
+    pub kind: RecentEventKind,
+}
+
+//the latest events of a channel, the oldest is dropped for a new one
+pub struct EventLog {
+    events: VecDeque<RecentEvent>,
+    capacity: usize,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, at: Duration, kind: RecentEventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(RecentEvent { at, kind });
+    }
+
+    //oldest first
+    pub fn events(&self) -> Vec<RecentEvent> {
+        self.events.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_oldest_events_make_room() {
+        let mut log = EventLog::new(2);
+        for seq in 0..3 {
+            log.push(
+                Duration::from_millis(seq as u64),
+                RecentEventKind::Resend(seq),
+            );
+        }
+        assert_eq!(
+            log.events(),
+            [
+                RecentEvent {
+                    at: Duration::from_millis(1),
+                    kind: RecentEventKind::Resend(1),
+                },
+                RecentEvent {
+                    at: Duration::from_millis(2),
+                    kind: RecentEventKind::Resend(2),
+                },
+            ]
+        );
+
+        let mut off = EventLog::new(0);
+        off.push(Duration::ZERO, RecentEventKind::Malformed);
+        assert!(off.events().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//entry points for running the wire format parsing without sockets, used by the fuzz targets
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use super::{
+    config::ServerConfig,
+    connections::{ConnectionManager, ControlPacket},
+    fragmentation_manager::FragmentationManager,
+    header::Header,
+    socket::UdpSendEvent,
+    Bytes, PacketType, ProtocolId, PROTOCOL_ID_SIZE,
+};
+
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum ParsedPacket {
+    Control(ControlPacket),
+    Payload(Header, Bytes),
+}
+
+//parses a raw datagram including the default protocol id
+#[doc(hidden)]
+pub fn parse_incoming(buffer: &[u8]) -> anyhow::Result<ParsedPacket> {
+    if !ProtocolId::DEFAULT.matches(buffer) {
+        bail!("missing protocol id");
+    }
+    let data = &buffer[PROTOCOL_ID_SIZE..];
+
+    //payload packets carry the packet type after the sequence, control packets start with it
+    if let Ok(header) = Header::read(data) {
+        if !is_control_type(header.packet_type) {
+            let payload = data[header.get_header_size()..].to_vec();
+
+            if header.packet_type.is_frag_variant() {
+                FragmentationManager::new().insert_fragment(
+                    &header,
+                    payload.clone(),
+                    Duration::ZERO,
+                )?;
+            }
+
+            return Ok(ParsedPacket::Payload(header, payload));
+        }
+    }
+
+    Ok(ParsedPacket::Control(ControlPacket::read(data)?))
+}
+
+fn is_control_type(packet_type: PacketType) -> bool {
+    matches!(
+        packet_type,
+        PacketType::ConnectionRequest
+            | PacketType::Challenge
+            | PacketType::ChallengeResponse
+            | PacketType::ConnectionAccepted
+            | PacketType::ReconnectRequired
+    )
+}
+
+//drives the server side read path the same way the server process does
+#[doc(hidden)]
+pub struct ServerHarness {
+    connection_manager: ConnectionManager,
+    send_queue: VecDeque<UdpSendEvent>,
+}
+
+impl ServerHarness {
+    pub fn new(max_clients: usize) -> Self {
+        Self {
+            connection_manager: ConnectionManager::new(ServerConfig {
+                max_clients,
+                ..Default::default()
+            }),
+            send_queue: VecDeque::new(),
+        }
+    }
+
+    //completes a valid handshake for the address and returns the session key
+    pub fn connect(&mut self, addr: SocketAddr) -> anyhow::Result<u64> {
+        let client_salt = 0;
+        let request = ControlPacket::ConnectionRequest {
+            client_salt,
+            flags: 0,
+        };
+        self.feed(addr, &request.write()[PROTOCOL_ID_SIZE..])?;
+
+        let server_salt = match self.send_queue.pop_back() {
+            Some(UdpSendEvent::Server(datagram, _)) => {
+                match ControlPacket::read(&datagram.head[PROTOCOL_ID_SIZE..])? {
+                    ControlPacket::Challenge { server_salt, .. } => server_salt,
+                    packet => bail!("expected challenge, got {packet:?}"),
+                }
+            }
+            _ => bail!("no challenge was sent"),
+        };
+
+        let challenge = self.connection_manager.config().challenge.clone();
+        let session_key = challenge.session_key(client_salt, server_salt);
+        let response = challenge.response(session_key);
+        self.feed(
+            addr,
+            &ControlPacket::ChallengeResponse { response }.write()[PROTOCOL_ID_SIZE..],
+        )?;
+
+        if self.connection_manager.get_client_mut(&addr).is_none() {
+            bail!("connection was not established");
+        }
+
+        Ok(session_key)
+    }
+
+    //feeds a datagram with the protocol id already stripped, like the socket emits them
+    pub fn feed(&mut self, addr: SocketAddr, buffer: &[u8]) -> anyhow::Result<()> {
+        if let Some(connection) = self.connection_manager.get_client_mut(&addr) {
+            connection.channel.read(buffer.to_vec(), &Instant::now())?;
+        } else {
+            self.connection_manager.process_connect(
+                &addr,
+                0,
+                buffer.to_vec(),
+                &mut self.send_queue,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self) {
+        self.connection_manager.update(&mut self.send_queue);
+        self.send_queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::header::SendType;
+    use crate::net::int_buffer::IntBuffer;
+    use crate::net::{bytes_with_header, header::HEADER_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn parse_control_and_payload() {
+        let buffer = ControlPacket::ConnectionRequest {
+            client_salt: 7,
+            flags: 0,
+        }
+        .write();
+        assert!(matches!(
+            parse_incoming(&buffer),
+            Ok(ParsedPacket::Control(ControlPacket::ConnectionRequest {
+                client_salt: 7,
+                ..
+            }))
+        ));
+
+        let header = Header::new(1, 2, SendType::Reliable, false);
+        let mut buffer = bytes_with_header!(HEADER_SIZE + 3);
+        header
+            .write(&mut buffer, &mut IntBuffer::new_at(PROTOCOL_ID_SIZE))
+            .unwrap();
+        match parse_incoming(&buffer) {
+            Ok(ParsedPacket::Payload(parsed, payload)) => {
+                assert_eq!(parsed.seq, 1);
+                assert_eq!(payload.len(), 3);
+            }
+            other => panic!("expected payload, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn harness_handshake() {
+        let mut harness = ServerHarness::new(1);
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        assert!(harness.connect(addr).is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+//the least recently seen address is forgotten once more are sending invalid packets
+pub const MAX_INVALID_SOURCES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSource {
+    pub addr: SocketAddr,
+    pub count: u64,
+    pub last_seen: Instant,
+    last_reported: Option<Instant>,
+}
+
+//datagrams dropped because they didn't start with our protocol id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvalidPacketStats {
+    pub total: u64,
+    //the most recently seen address first
+    pub sources: Vec<InvalidSource>,
+}
+
+impl InvalidPacketStats {
+    //returns the count of the address if it should be reported again
+    pub fn record(
+        &mut self,
+        addr: SocketAddr,
+        now: Instant,
+        report_interval: Option<Duration>,
+    ) -> Option<u64> {
+        self.total += 1;
+
+        let mut source = match self.sources.iter().position(|source| source.addr == addr) {
+            Some(index) => self.sources.remove(index),
+            None => InvalidSource {
+                addr,
+                count: 0,
+                last_seen: now,
+                last_reported: None,
+            },
+        };
+        source.count += 1;
+        source.last_seen = now;
+
+        let report = report_interval.and_then(|interval| {
+            let due = source
+                .last_reported
+                .is_none_or(|reported| now.saturating_duration_since(reported) >= interval);
+            due.then(|| {
+                source.last_reported = Some(now);
+                source.count
+            })
+        });
+
+        self.sources.insert(0, source);
+        self.sources.truncate(MAX_INVALID_SOURCES);
+        report
+    }
+}
+
+//written by the socket, read by the API
+pub type SharedInvalidPacketStats = Arc<Mutex<InvalidPacketStats>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_seen_source_is_dropped() {
+        let mut stats = InvalidPacketStats::default();
+        let now = Instant::now();
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        for port in 0..MAX_INVALID_SOURCES as u16 {
+            stats.record(addr(port), now, None);
+        }
+        //seen again so it's the most recent one
+        stats.record(addr(0), now, None);
+        stats.record(addr(1000), now, None);
+
+        assert_eq!(stats.total, MAX_INVALID_SOURCES as u64 + 2);
+        assert_eq!(stats.sources.len(), MAX_INVALID_SOURCES);
+        assert_eq!(stats.sources[0].addr, addr(1000));
+        assert_eq!(stats.sources[1].addr, addr(0));
+        assert_eq!(stats.sources[1].count, 2);
+        assert!(!stats.sources.iter().any(|source| source.addr == addr(1)));
+    }
+
+    #[test]
+    fn reports_are_rate_limited() {
+        let mut stats = InvalidPacketStats::default();
+        let now = Instant::now();
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let interval = Some(Duration::from_secs(1));
+
+        assert_eq!(stats.record(addr, now, None), None);
+        assert_eq!(stats.record(addr, now, interval), Some(2));
+        assert_eq!(stats.record(addr, now, interval), None);
+        assert_eq!(
+            stats.record(addr, now + Duration::from_secs(1), interval),
+            Some(4)
+        );
+    }
+}
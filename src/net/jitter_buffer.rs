@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{channel::ReadPayload, sequence::Sequence};
+
+//weight of a new sample in the average spacing of the arrivals
+const INTERVAL_SMOOTHING: f64 = 0.125;
+
+struct HeldPayload {
+    index: u16,
+    payload: ReadPayload,
+    release_at: Instant,
+}
+
+//holds unreliable messages for up to the delay after they arrive and releases them in the order
+//they were sent. the releases are spaced by how far apart the messages usually arrive, so a burst
+//after a stall comes out at the pace the sender had instead of all at once
+pub struct JitterBuffer {
+    delay: Duration,
+    //ordered by index, the front is released first
+    held: VecDeque<HeldPayload>,
+    //a message sent before it that arrives later is dropped, the game moved past it
+    last_released: Option<u16>,
+    last_release_at: Option<Instant>,
+    //the newest message that arrived and when, for measuring the spacing
+    last_arrival: Option<(u16, Instant)>,
+    interval: Option<Duration>,
+    pub late: u64,
+}
+
+impl JitterBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            held: VecDeque::new(),
+            last_released: None,
+            last_release_at: None,
+            last_arrival: None,
+            interval: None,
+            late: 0,
+        }
+    }
+
+    pub fn insert(&mut self, index: u16, payload: ReadPayload, received_at: Instant) {
+        if self
+            .last_released
+            .is_some_and(|last| !Sequence::is_less_than(last, index))
+        {
+            self.late += 1;
+            return;
+        }
+        self.measure_interval(index, received_at);
+
+        let latest = received_at + self.delay;
+        let position = self
+            .held
+            .iter()
+            .rposition(|held| Sequence::is_less_than(held.index, index))
+            .map_or(0, |position| position + 1);
+        if self
+            .held
+            .get(position)
+            .is_some_and(|held| held.index == index)
+        {
+            return;
+        }
+
+        let release_at = match self.held.get(position) {
+            //arrived after a message sent later, it goes out right before that one
+            Some(next) => next.release_at.min(latest),
+            None => {
+                let previous = match position {
+                    0 => self.last_release_at,
+                    _ => Some(self.held[position - 1].release_at),
+                };
+                match (previous, self.interval) {
+                    (Some(previous), Some(interval)) => {
+                        (previous + interval).clamp(received_at, latest)
+                    }
+                    _ => latest,
+                }
+            }
+        };
+        self.held.insert(
+            position,
+            HeldPayload {
+                index,
+                payload,
+                release_at,
+            },
+        );
+    }
+
+    //the messages that are due, a message isn't released before the ones sent before it
+    pub fn release(&mut self, now: Instant, released: &mut VecDeque<ReadPayload>) {
+        while self.held.front().is_some_and(|held| held.release_at <= now) {
+            let Some(held) = self.held.pop_front() else {
+                return;
+            };
+            self.last_released = Some(held.index);
+            self.last_release_at = Some(held.release_at);
+            released.push_back(held.payload);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    fn measure_interval(&mut self, index: u16, received_at: Instant) {
+        if let Some((last_index, last_received_at)) = self.last_arrival {
+            if !Sequence::is_less_than(last_index, index) {
+                return;
+            }
+            let gap = Sequence::distance(last_index, index).max(1) as u32;
+            let sample = received_at.saturating_duration_since(last_received_at) / gap;
+            self.interval = Some(match self.interval {
+                Some(interval) => {
+                    interval.mul_f64(1.0 - INTERVAL_SMOOTHING) + sample.mul_f64(INTERVAL_SMOOTHING)
+                }
+                None => sample,
+            });
+        }
+        self.last_arrival = Some((index, received_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(value: u8) -> ReadPayload {
+        ReadPayload::Single(vec![value])
+    }
+
+    fn values(released: &mut VecDeque<ReadPayload>) -> Vec<u8> {
+        released
+            .drain(..)
+            .map(|payload| match payload {
+                ReadPayload::Single(buffer) => buffer[0],
+                _ => panic!("unexpected payload"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_released_in_order_after_the_delay() {
+        let delay = Duration::from_millis(50);
+        let mut buffer = JitterBuffer::new(delay);
+        let mut released = VecDeque::new();
+        let start = Instant::now();
+
+        buffer.insert(2, single(2), start);
+        buffer.insert(1, single(1), start + Duration::from_millis(5));
+        buffer.release(start + Duration::from_millis(40), &mut released);
+        assert!(released.is_empty());
+
+        buffer.release(start + delay, &mut released);
+        assert_eq!(values(&mut released), [1, 2]);
+
+        //sent before the released ones, too late
+        buffer.insert(0, single(0), start + delay);
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.late, 1);
+    }
+
+    #[test]
+    fn a_burst_is_released_at_the_pace_of_the_sender() {
+        let delay = Duration::from_millis(100);
+        let step = Duration::from_millis(20);
+        let mut buffer = JitterBuffer::new(delay);
+        let mut released = VecDeque::new();
+        let start = Instant::now();
+
+        for i in 0..3 {
+            buffer.insert(i, single(i as u8), start + step * i as u32);
+        }
+        //3 to 5 were held up and arrive together
+        let burst = start + step * 5;
+        for i in 3..6 {
+            buffer.insert(i, single(i as u8), burst);
+        }
+
+        let mut releases = Vec::new();
+        let mut now = start;
+        while now < burst + delay * 2 {
+            buffer.release(now, &mut released);
+            for value in values(&mut released) {
+                releases.push((value, now));
+            }
+            now += Duration::from_millis(1);
+        }
+        assert_eq!(
+            releases.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4, 5]
+        );
+        //the burst is spread out, the last one is still within the delay
+        for pair in releases.windows(2) {
+            let spacing = pair[1].1 - pair[0].1;
+            assert!(spacing >= step / 2, "{spacing:?}");
+        }
+        assert!(releases[5].1 <= burst + delay);
+    }
+}
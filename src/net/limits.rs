@@ -0,0 +1,322 @@
+use std::{fmt, time::Instant};
+
+use super::channel::ReadPayload;
+
+//limits on the messages a connection sends, a message over them is dropped. None doesn't limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageLimits {
+    pub max_messages_per_sec: Option<u32>,
+    pub max_bytes_per_sec: Option<u64>,
+    pub max_message_size: Option<usize>,
+    //the connection is kicked at this many messages over the limits, None never kicks
+    pub violation_limit: Option<u64>,
+}
+
+//the limit a dropped message went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    MessageSize,
+    MessageRate,
+    ByteRate,
+    //the payloads held while the connection is paused
+    HeldReads,
+}
+
+//caps on the payloads held for a paused connection until it's resumed, a fragmented message
+//counts once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeldReadLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub policy: HeldReadPolicy,
+}
+
+impl Default for HeldReadLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 1024,
+            max_bytes: 1024 * 1024,
+            policy: HeldReadPolicy::default(),
+        }
+    }
+}
+
+impl HeldReadLimits {
+    //held is the messages and the bytes held already
+    pub fn allows(&self, held: (usize, usize), size: usize) -> bool {
+        held.0 < self.max_messages && held.1.saturating_add(size) <= self.max_bytes
+    }
+}
+
+//what happens to a payload received while the held reads of the paused connection are full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeldReadPolicy {
+    //the payload is dropped and reported with a LimitExceeded
+    #[default]
+    Drop,
+    //the connection is kicked
+    Disconnect,
+}
+
+//the size of a message, None for what isn't one
+pub fn payload_size(payload: &ReadPayload) -> Option<usize> {
+    match payload {
+        ReadPayload::Single(buffer) => Some(buffer.len()),
+        ReadPayload::Parts(parts) => Some(parts.iter().map(|part| part.len()).sum()),
+        ReadPayload::Request(_, data) => Some(data.len()),
+        _ => None,
+    }
+}
+
+//caps on what the server holds for a connection that doesn't take it, the reliable packets waiting
+//for room in the send window and the sends held while it's paused. a fragmented message counts
+//every fragment. None doesn't cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutboundLimits {
+    pub max_packets: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub policy: OutboundPolicy,
+}
+
+//what happens to a send that would go over the outbound limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutboundPolicy {
+    //the unreliable sends are dropped at the limits, the reliable ones are still queued up to twice
+    //the limits before the connection is kicked
+    DropUnreliableFirst,
+    //the connection is kicked, it can't keep up with what it's sent
+    Disconnect,
+    //the send is dropped and reported with an OutboundLimitExceeded
+    #[default]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundAction {
+    Queue,
+    Drop,
+    Kick,
+}
+
+impl OutboundLimits {
+    //queued is what the connection holds already, the packets and the bytes
+    pub fn check(
+        &self,
+        queued: (usize, usize),
+        send: (usize, usize),
+        reliable: bool,
+    ) -> OutboundAction {
+        let over = |scale: usize| {
+            self.max_packets
+                .is_some_and(|max| queued.0 + send.0 > max.saturating_mul(scale))
+                || self
+                    .max_bytes
+                    .is_some_and(|max| queued.1 + send.1 > max.saturating_mul(scale))
+        };
+        if !over(1) {
+            return OutboundAction::Queue;
+        }
+        match self.policy {
+            OutboundPolicy::DropUnreliableFirst if !reliable => OutboundAction::Drop,
+            OutboundPolicy::DropUnreliableFirst if !over(2) => OutboundAction::Queue,
+            OutboundPolicy::DropUnreliableFirst | OutboundPolicy::Disconnect => {
+                OutboundAction::Kick
+            }
+            OutboundPolicy::Error => OutboundAction::Drop,
+        }
+    }
+}
+
+//a send to the connection was refused by the outbound limits, kick is set if the policy removes
+//the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundOverflow {
+    pub kick: bool,
+}
+
+impl fmt::Display for OutboundOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the send goes over the outbound limits of the connection"
+        )
+    }
+}
+
+impl std::error::Error for OutboundOverflow {}
+
+//token buckets refilled at the rates of the limits, up to a second of them can be used at once
+pub struct RateLimiter {
+    limits: MessageLimits,
+    messages: f64,
+    bytes: f64,
+    last_refill: Option<Instant>,
+    //messages dropped for going over the limits
+    pub violations: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: MessageLimits) -> Self {
+        Self {
+            messages: limits.max_messages_per_sec.unwrap_or(0) as f64,
+            bytes: limits.max_bytes_per_sec.unwrap_or(0) as f64,
+            limits,
+            last_refill: None,
+            violations: 0,
+        }
+    }
+
+    //the buckets keep what they hold, capped at the new rates
+    pub fn set_limits(&mut self, limits: MessageLimits) {
+        self.limits = limits;
+        self.messages = self
+            .messages
+            .min(limits.max_messages_per_sec.unwrap_or(0) as f64);
+        self.bytes = self.bytes.min(limits.max_bytes_per_sec.unwrap_or(0) as f64);
+    }
+
+    //the limit the payload goes over, None takes it from the buckets. only the messages are limited
+    pub fn check(&mut self, payload: &ReadPayload, now: Instant) -> Option<Limit> {
+        let size = payload_size(payload)?;
+
+        let exceeded = self.exceeded(size, now);
+        if exceeded.is_some() {
+            self.violations += 1;
+        }
+        exceeded
+    }
+
+    fn exceeded(&mut self, size: usize, now: Instant) -> Option<Limit> {
+        if self.limits.max_message_size.is_some_and(|max| size > max) {
+            return Some(Limit::MessageSize);
+        }
+
+        self.refill(now);
+        if self.limits.max_messages_per_sec.is_some() && self.messages < 1.0 {
+            return Some(Limit::MessageRate);
+        }
+        if self.limits.max_bytes_per_sec.is_some() && self.bytes < size as f64 {
+            return Some(Limit::ByteRate);
+        }
+
+        if self.limits.max_messages_per_sec.is_some() {
+            self.messages -= 1.0;
+        }
+        if self.limits.max_bytes_per_sec.is_some() {
+            self.bytes -= size as f64;
+        }
+        None
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = match self.last_refill {
+            Some(last_refill) => now.saturating_duration_since(last_refill).as_secs_f64(),
+            None => 0.0,
+        };
+        self.last_refill = Some(now);
+
+        if let Some(rate) = self.limits.max_messages_per_sec {
+            self.messages = (self.messages + elapsed * rate as f64).min(rate as f64);
+        }
+        if let Some(rate) = self.limits.max_bytes_per_sec {
+            self.bytes = (self.bytes + elapsed * rate as f64).min(rate as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn messages_over_the_rates_are_dropped() {
+        let mut limiter = RateLimiter::new(MessageLimits {
+            max_messages_per_sec: Some(2),
+            max_bytes_per_sec: Some(100),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let message = ReadPayload::Single(vec![0; 40]);
+
+        assert_eq!(limiter.check(&message, now), None);
+        assert_eq!(limiter.check(&message, now), None);
+        assert_eq!(limiter.check(&message, now), Some(Limit::MessageRate));
+
+        //a second refills both, the bytes run out first
+        let later = now + Duration::from_secs(1);
+        let large = ReadPayload::Parts(vec![vec![0; 40], vec![0; 40]]);
+        assert_eq!(limiter.check(&large, later), None);
+        assert_eq!(limiter.check(&large, later), Some(Limit::ByteRate));
+        assert_eq!(limiter.check(&ReadPayload::DisconnectAck, later), None);
+        assert_eq!(limiter.violations, 2);
+    }
+
+    #[test]
+    fn outbound_policy_decides_sends_over_the_limits() {
+        let limits = |policy| OutboundLimits {
+            max_packets: Some(4),
+            max_bytes: Some(1000),
+            policy,
+        };
+
+        let error = limits(OutboundPolicy::Error);
+        assert_eq!(error.check((3, 100), (1, 100), true), OutboundAction::Queue);
+        assert_eq!(error.check((4, 100), (1, 100), true), OutboundAction::Drop);
+        assert_eq!(error.check((0, 950), (1, 100), false), OutboundAction::Drop);
+
+        let disconnect = limits(OutboundPolicy::Disconnect);
+        assert_eq!(
+            disconnect.check((4, 100), (1, 100), false),
+            OutboundAction::Kick
+        );
+
+        let drop_unreliable = limits(OutboundPolicy::DropUnreliableFirst);
+        assert_eq!(
+            drop_unreliable.check((4, 100), (1, 100), false),
+            OutboundAction::Drop
+        );
+        assert_eq!(
+            drop_unreliable.check((4, 100), (1, 100), true),
+            OutboundAction::Queue
+        );
+        assert_eq!(
+            drop_unreliable.check((8, 100), (1, 100), true),
+            OutboundAction::Kick
+        );
+
+        assert_eq!(
+            OutboundLimits::default().check((usize::MAX / 2, 0), (1, 1), true),
+            OutboundAction::Queue
+        );
+    }
+
+    #[test]
+    fn held_reads_are_capped_by_messages_and_bytes() {
+        let limits = HeldReadLimits {
+            max_messages: 2,
+            max_bytes: 100,
+            policy: HeldReadPolicy::Drop,
+        };
+
+        assert!(limits.allows((1, 50), 50));
+        assert!(!limits.allows((2, 0), 1));
+        assert!(!limits.allows((1, 60), 41));
+    }
+
+    #[test]
+    fn large_messages_are_dropped() {
+        let mut limiter = RateLimiter::new(MessageLimits {
+            max_message_size: Some(4),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check(&ReadPayload::Single(vec![0; 5]), now),
+            Some(Limit::MessageSize)
+        );
+        assert_eq!(limiter.check(&ReadPayload::Single(vec![0; 4]), now), None);
+    }
+}
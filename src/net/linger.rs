@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use super::{config::ChannelConfig, disconnect::DisconnectReason};
+
+//a closed channel that is kept around for a short time so both sides agree the session ended,
+//the side that closed resends its disconnect until it's acked and the other side acks the resends
+pub struct Linger {
+    //None once the disconnect is acked or if the remote closed the channel
+    reason: Option<DisconnectReason>,
+    //the remote closed the channel
+    acking: bool,
+    resend_interval: Duration,
+    next_resend: Instant,
+    deadline: Instant,
+}
+
+impl Linger {
+    //we closed the channel, the first disconnect was already sent
+    pub fn closing(reason: DisconnectReason, config: &ChannelConfig, now: Instant) -> Self {
+        Self {
+            reason: Some(reason),
+            acking: false,
+            resend_interval: config.disconnect_resend_interval,
+            next_resend: now + config.disconnect_resend_interval,
+            deadline: now + config.disconnect_linger,
+        }
+    }
+
+    //the remote closed the channel, we only ack the disconnects it resends
+    pub fn acking(config: &ChannelConfig, now: Instant) -> Self {
+        Self {
+            reason: None,
+            acking: true,
+            resend_interval: config.disconnect_resend_interval,
+            next_resend: now,
+            deadline: now + config.disconnect_linger,
+        }
+    }
+
+    //the disconnect to send again if a resend is due
+    pub fn poll_resend(&mut self, now: Instant) -> Option<DisconnectReason> {
+        if now < self.next_resend {
+            return None;
+        }
+
+        self.next_resend = now + self.resend_interval;
+        self.reason.clone()
+    }
+
+    pub fn acked(&mut self) {
+        self.reason = None;
+    }
+
+    //an acked disconnect ends the linger right away, acking has to wait for the deadline
+    //because we don't know if the remote got our ack
+    pub fn is_finished(&self, now: Instant) -> bool {
+        now >= self.deadline || (!self.acking && self.reason.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::disconnect::DisconnectCode;
+
+    use super::*;
+
+    #[test]
+    fn disconnect_is_resent_until_acked() {
+        let config = ChannelConfig::default();
+        let now = Instant::now();
+        let reason = DisconnectReason::new(DisconnectCode::Kicked);
+        let mut linger = Linger::closing(reason.clone(), &config, now);
+
+        assert_eq!(linger.poll_resend(now), None);
+        let later = now + config.disconnect_resend_interval;
+        assert_eq!(linger.poll_resend(later), Some(reason));
+        assert_eq!(linger.poll_resend(later), None);
+        assert!(!linger.is_finished(later));
+
+        linger.acked();
+        assert_eq!(
+            linger.poll_resend(later + config.disconnect_resend_interval),
+            None
+        );
+        assert!(linger.is_finished(later));
+    }
+
+    #[test]
+    fn linger_ends_at_the_deadline() {
+        let config = ChannelConfig::default();
+        let now = Instant::now();
+        let linger = Linger::acking(&config, now);
+
+        assert!(!linger.is_finished(now));
+        assert!(linger.is_finished(now + config.disconnect_linger));
+    }
+}
@@ -0,0 +1,757 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use log::{debug, error, info, warn};
+
+use super::{
+    bytes_with_header,
+    int_buffer::IntBuffer,
+    server_info::{ResponseLimiter, ServerInfo, INFO_REQUEST_SIZE},
+    socket::is_transient,
+    unconnected::MAX_UNCONNECTED_SIZE,
+    Bytes, NetError, PacketType, ProtocolId, PROTOCOL_ID_SIZE,
+};
+
+//packet type, start index, total count and entry count
+const LIST_RESPONSE_HEADER_SIZE: usize = 6;
+//packet type, token, player count and max players
+const HEARTBEAT_HEADER_SIZE: usize = 17;
+//packet type and token, smaller than any heartbeat so answering one can't amplify it
+const MASTER_CHALLENGE_SIZE: usize = 9;
+//an ipv6 entry without the payload
+const MAX_ENTRY_HEADER_SIZE: usize = 1 + 16 + 2 + 4 + 4 + 2;
+//payloads are cut so any entry fits in a single page
+const MAX_LIST_PAYLOAD_SIZE: usize =
+    MAX_UNCONNECTED_SIZE - LIST_RESPONSE_HEADER_SIZE - MAX_ENTRY_HEADER_SIZE;
+//pages answered to one address per second and to all of them together
+const LIST_PAGES_PER_ADDRESS: usize = 32;
+const MAX_LIST_RESPONSES: usize = 1024;
+//a page request without a response is sent again after this long
+const LIST_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+//a registered server as returned by the master server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerListEntry {
+    pub addr: SocketAddr,
+    pub info: ServerInfo,
+}
+
+//the token is the one of the latest challenge of the master server, 0 before the first one
+pub fn write_heartbeat(token: u64, info: &ServerInfo) -> Bytes {
+    let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+    let mut buffer = bytes_with_header!(HEARTBEAT_HEADER_SIZE + info.payload.len());
+
+    int_buffer.write_u8(PacketType::MasterHeartbeat as u8, &mut buffer);
+    int_buffer.write_u64(token, &mut buffer);
+    int_buffer.write_u32(info.players, &mut buffer);
+    int_buffer.write_u32(info.max_players, &mut buffer);
+    int_buffer.write_slice(&info.payload, &mut buffer);
+
+    buffer
+}
+
+//returns the token and the info of a heartbeat with the protocol id already stripped
+pub fn read_heartbeat(buffer: &[u8]) -> anyhow::Result<(u64, ServerInfo)> {
+    if buffer.len() < HEARTBEAT_HEADER_SIZE {
+        bail!(NetError::InvalidLength {
+            packet_type: PacketType::MasterHeartbeat,
+            expected: HEARTBEAT_HEADER_SIZE,
+            actual: buffer.len(),
+        });
+    }
+
+    let mut int_buffer = IntBuffer::new_at(1);
+    let token = int_buffer.read_u64(buffer);
+    let info = ServerInfo {
+        players: int_buffer.read_u32(buffer),
+        max_players: int_buffer.read_u32(buffer),
+        payload: buffer[HEARTBEAT_HEADER_SIZE..].to_vec(),
+    };
+    Ok((token, info))
+}
+
+pub fn write_master_challenge(token: u64) -> Bytes {
+    let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+    let mut buffer = bytes_with_header!(MASTER_CHALLENGE_SIZE);
+
+    int_buffer.write_u8(PacketType::MasterChallenge as u8, &mut buffer);
+    int_buffer.write_u64(token, &mut buffer);
+
+    buffer
+}
+
+//returns the token of a challenge with the protocol id already stripped
+pub fn read_master_challenge(buffer: &[u8]) -> anyhow::Result<u64> {
+    if buffer.first() != Some(&(PacketType::MasterChallenge as u8)) {
+        bail!("not a master challenge");
+    }
+    if buffer.len() != MASTER_CHALLENGE_SIZE {
+        bail!(NetError::InvalidLength {
+            packet_type: PacketType::MasterChallenge,
+            expected: MASTER_CHALLENGE_SIZE,
+            actual: buffer.len(),
+        });
+    }
+
+    let mut int_buffer = IntBuffer::new_at(1);
+    Ok(int_buffer.read_u64(buffer))
+}
+
+//requests are padded the same way as the info requests
+pub fn write_list_request(start: u16) -> Bytes {
+    let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+    let mut buffer = bytes_with_header!(INFO_REQUEST_SIZE);
+
+    int_buffer.write_u8(PacketType::MasterListRequest as u8, &mut buffer);
+    int_buffer.write_u16(start, &mut buffer);
+
+    buffer
+}
+
+//returns the start index of a request with the protocol id already stripped
+pub fn read_list_request(buffer: &[u8]) -> anyhow::Result<u16> {
+    if buffer.len() != INFO_REQUEST_SIZE {
+        bail!(NetError::InvalidLength {
+            packet_type: PacketType::MasterListRequest,
+            expected: INFO_REQUEST_SIZE,
+            actual: buffer.len(),
+        });
+    }
+
+    let mut int_buffer = IntBuffer::new_at(1);
+    Ok(int_buffer.read_u16(buffer))
+}
+
+//a page of the server list, returns the start index, the total count and the entries
+pub fn read_list_response(buffer: &[u8]) -> anyhow::Result<(u16, u16, Vec<ServerListEntry>)> {
+    let packet_type = PacketType::MasterListResponse;
+    if buffer.len() < LIST_RESPONSE_HEADER_SIZE {
+        bail!(NetError::InvalidLength {
+            packet_type,
+            expected: LIST_RESPONSE_HEADER_SIZE,
+            actual: buffer.len(),
+        });
+    }
+
+    let mut int_buffer = IntBuffer::default();
+    if int_buffer.read_u8(buffer) != packet_type as u8 {
+        bail!(NetError::UnexpectedPacketType(PacketType::try_from(
+            buffer[0]
+        )?));
+    }
+    let start = int_buffer.read_u16(buffer);
+    let total = int_buffer.read_u16(buffer);
+    let count = int_buffer.read_u8(buffer);
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        entries.push(read_entry(buffer, &mut int_buffer)?);
+    }
+
+    Ok((start, total, entries))
+}
+
+fn entry_size(entry: &ServerListEntry) -> usize {
+    let ip_size = match entry.addr.ip() {
+        IpAddr::V4(_) => 4,
+        IpAddr::V6(_) => 16,
+    };
+    //family, ip, port, players, max players, payload length and payload
+    1 + ip_size + 2 + 4 + 4 + 2 + entry.info.payload.len()
+}
+
+fn write_entry(entry: &ServerListEntry, int_buffer: &mut IntBuffer, buffer: &mut [u8]) {
+    match entry.addr.ip() {
+        IpAddr::V4(ip) => {
+            int_buffer.write_u8(4, buffer);
+            int_buffer.write_slice(&ip.octets(), buffer);
+        }
+        IpAddr::V6(ip) => {
+            int_buffer.write_u8(6, buffer);
+            int_buffer.write_slice(&ip.octets(), buffer);
+        }
+    }
+    int_buffer.write_u16(entry.addr.port(), buffer);
+    int_buffer.write_u32(entry.info.players, buffer);
+    int_buffer.write_u32(entry.info.max_players, buffer);
+    int_buffer.write_u16(entry.info.payload.len() as u16, buffer);
+    int_buffer.write_slice(&entry.info.payload, buffer);
+}
+
+fn read_entry(buffer: &[u8], int_buffer: &mut IntBuffer) -> anyhow::Result<ServerListEntry> {
+    let truncated = |expected: usize| NetError::InvalidLength {
+        packet_type: PacketType::MasterListResponse,
+        expected,
+        actual: buffer.len(),
+    };
+
+    if buffer.len() < int_buffer.index + 1 {
+        bail!(truncated(int_buffer.index + 1));
+    }
+    let ip_size = match int_buffer.read_u8(buffer) {
+        4 => 4,
+        6 => 16,
+        family => bail!("unknown address family {family}"),
+    };
+
+    let fixed_end = int_buffer.index + ip_size + 2 + 4 + 4 + 2;
+    if buffer.len() < fixed_end {
+        bail!(truncated(fixed_end));
+    }
+
+    let ip_bytes = &buffer[int_buffer.index..int_buffer.index + ip_size];
+    let ip = if ip_size == 4 {
+        let mut octets = [0_u8; 4];
+        octets.copy_from_slice(ip_bytes);
+        IpAddr::V4(Ipv4Addr::from(octets))
+    } else {
+        let mut octets = [0_u8; 16];
+        octets.copy_from_slice(ip_bytes);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    int_buffer.jump(ip_size);
+
+    let port = int_buffer.read_u16(buffer);
+    let players = int_buffer.read_u32(buffer);
+    let max_players = int_buffer.read_u32(buffer);
+    let payload_len = int_buffer.read_u16(buffer) as usize;
+
+    let payload_end = int_buffer.index + payload_len;
+    if buffer.len() < payload_end {
+        bail!(truncated(payload_end));
+    }
+    let payload = buffer[int_buffer.index..payload_end].to_vec();
+    int_buffer.jump(payload_len);
+
+    Ok(ServerListEntry {
+        addr: SocketAddr::new(ip, port),
+        info: ServerInfo {
+            players,
+            max_players,
+            payload,
+        },
+    })
+}
+
+//servers that sent a heartbeat recently, the ones that stop are dropped after the timeout. a
+//heartbeat only counts with the token the master sent to its address, so a spoofed source can't
+//register anything
+pub struct MasterRegistry {
+    servers: HashMap<SocketAddr, (ServerInfo, Instant)>,
+    timeout: Duration,
+    //new servers aren't registered while the list is full
+    max_servers: usize,
+    //keys the tokens, they can't be derived from the address alone
+    secret: RandomState,
+    started: Instant,
+    //the list requests aren't authenticated, the pages sent back are limited like the server info
+    list_limiter: ResponseLimiter,
+}
+
+impl MasterRegistry {
+    pub fn new(timeout: Duration, max_servers: usize) -> Self {
+        Self {
+            servers: HashMap::new(),
+            timeout,
+            max_servers,
+            secret: RandomState::new(),
+            started: Instant::now(),
+            list_limiter: ResponseLimiter::new(
+                LIST_PAGES_PER_ADDRESS,
+                Duration::from_secs(1),
+                MAX_LIST_RESPONSES,
+            ),
+        }
+    }
+
+    //the tokens change every timeout, the one of the previous period is still accepted so a
+    //heartbeat sent right before the change counts
+    fn token(&self, addr: SocketAddr, now: Instant, previous: bool) -> u64 {
+        let elapsed = now.saturating_duration_since(self.started).as_millis();
+        let period = elapsed / self.timeout.as_millis().max(1);
+        let period = if previous {
+            period.saturating_sub(1)
+        } else {
+            period
+        };
+        self.secret.hash_one((addr, period))
+    }
+
+    fn valid_token(&self, addr: SocketAddr, token: u64, now: Instant) -> bool {
+        token == self.token(addr, now, false) || token == self.token(addr, now, true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    //registers the server or refreshes its info, false if the list is full
+    pub fn heartbeat(&mut self, addr: SocketAddr, mut info: ServerInfo, now: Instant) -> bool {
+        if !self.servers.contains_key(&addr) && self.servers.len() >= self.max_servers {
+            warn!("server list is full, {addr} wasn't registered");
+            return false;
+        }
+
+        info.payload.truncate(MAX_LIST_PAYLOAD_SIZE);
+        if self.servers.insert(addr, (info, now)).is_none() {
+            info!("registered server {addr}");
+        }
+        true
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.servers.retain(|addr, (_, last_heartbeat)| {
+            let alive = now.duration_since(*last_heartbeat) < timeout;
+            if !alive {
+                info!("server {addr} stopped sending heartbeats");
+            }
+            alive
+        });
+        self.list_limiter.update(now);
+    }
+
+    //the page of the list starting at the index that fits in one packet
+    pub fn write_page(&self, start: u16) -> Bytes {
+        //sort so the pages stay stable between requests
+        let mut entries: Vec<ServerListEntry> = self
+            .servers
+            .iter()
+            .map(|(addr, (info, _))| ServerListEntry {
+                addr: *addr,
+                info: info.clone(),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.addr);
+        let total = entries.len().min(u16::MAX as usize);
+
+        let mut page = Vec::new();
+        let mut size = LIST_RESPONSE_HEADER_SIZE;
+        for entry in entries.iter().take(total).skip(start as usize) {
+            if size + entry_size(entry) > MAX_UNCONNECTED_SIZE || page.len() == u8::MAX as usize {
+                break;
+            }
+            size += entry_size(entry);
+            page.push(entry);
+        }
+
+        let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+        let mut buffer = bytes_with_header!(size);
+        int_buffer.write_u8(PacketType::MasterListResponse as u8, &mut buffer);
+        int_buffer.write_u16(start, &mut buffer);
+        int_buffer.write_u16(total as u16, &mut buffer);
+        int_buffer.write_u8(page.len() as u8, &mut buffer);
+        for entry in page {
+            write_entry(entry, &mut int_buffer, &mut buffer);
+        }
+
+        buffer
+    }
+
+    //handles a packet with the protocol id already stripped, returns the response to send back
+    pub fn process(
+        &mut self,
+        addr: SocketAddr,
+        buffer: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<Option<Bytes>> {
+        match buffer
+            .first()
+            .map(|packet_type| PacketType::try_from(*packet_type))
+        {
+            Some(Ok(PacketType::MasterHeartbeat)) => {
+                let (token, info) = read_heartbeat(buffer)?;
+                if self.valid_token(addr, token, now) {
+                    self.heartbeat(addr, info, now);
+                    return Ok(None);
+                }
+                //the first heartbeat or an expired token, the server sends it again with this one
+                Ok(Some(write_master_challenge(self.token(addr, now, false))))
+            }
+            Some(Ok(PacketType::MasterListRequest)) => {
+                let start = read_list_request(buffer)?;
+                if !self.list_limiter.allows(addr, now) {
+                    debug!("list request from {addr} over the limit");
+                    return Ok(None);
+                }
+                Ok(Some(self.write_page(start)))
+            }
+            Some(Ok(packet_type)) => bail!(NetError::UnexpectedPacketType(packet_type)),
+            Some(Err(e)) => Err(e),
+            None => bail!(NetError::EmptyPacket),
+        }
+    }
+}
+
+//a standalone master server that keeps track of the servers registering with it, it stops when
+//it's dropped
+pub struct MasterServer {
+    pub addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MasterServer {
+    //only servers and clients using the same protocol id are listed and answered, at most
+    //max_servers of them
+    pub fn start(
+        addr: SocketAddr,
+        protocol_id: ProtocolId,
+        server_timeout: Duration,
+        max_servers: usize,
+    ) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let addr = socket.local_addr()?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = thread::spawn({
+            let running = running.clone();
+            let registry = MasterRegistry::new(server_timeout, max_servers);
+            move || {
+                if let Err(e) = run_master(socket, protocol_id, registry, &running) {
+                    error!("master server stopped: {e}");
+                }
+            }
+        });
+
+        Ok(MasterServer {
+            addr,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    //waits for the thread to finish the packet it's on
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MasterServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_master(
+    socket: UdpSocket,
+    protocol_id: ProtocolId,
+    mut registry: MasterRegistry,
+    running: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut buf = [0_u8; PROTOCOL_ID_SIZE + 1 + MAX_UNCONNECTED_SIZE];
+
+    while running.load(Ordering::Acquire) {
+        match socket.recv_from(&mut buf) {
+            Ok((size, addr)) => {
+                if !protocol_id.matches(&buf[..size]) {
+                    continue;
+                }
+
+                match registry.process(addr, &buf[PROTOCOL_ID_SIZE..size], Instant::now()) {
+                    Ok(Some(mut response)) => {
+                        protocol_id.write_into(&mut response);
+                        match socket.send_to(&response, addr) {
+                            Ok(_) => {}
+                            Err(ref e) if is_transient(e) => {
+                                debug!("failed answering {addr}: {e}")
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => debug!("dropped master packet from {addr}: {e}"),
+                }
+            }
+            Err(ref e) if would_block(e) => {}
+            //an ICMP reply to an earlier response only concerns that address
+            Err(ref e) if is_transient(e) => debug!("master socket error: {e}"),
+            Err(e) => return Err(e.into()),
+        }
+
+        registry.update(Instant::now());
+    }
+
+    Ok(())
+}
+
+//fetches every server registered with the master server, page by page
+pub fn fetch_server_list(
+    local_addr: SocketAddr,
+    master_addr: SocketAddr,
+    protocol_id: ProtocolId,
+    timeout: Duration,
+) -> anyhow::Result<Vec<ServerListEntry>> {
+    let socket = UdpSocket::bind(local_addr)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; PROTOCOL_ID_SIZE + 1 + MAX_UNCONNECTED_SIZE];
+    let mut entries = Vec::new();
+
+    loop {
+        let start = entries.len() as u16;
+        let mut request = write_list_request(start);
+        protocol_id.write_into(&mut request);
+
+        //the request or its response can get lost, it's sent again until the deadline
+        let (total, page) = 'page: loop {
+            socket.send_to(&request, master_addr)?;
+            let resend_at = Instant::now() + LIST_RESEND_INTERVAL;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    bail!("server list fetch timed out");
+                }
+                if now >= resend_at {
+                    continue 'page;
+                }
+                socket.set_read_timeout(Some(resend_at.min(deadline) - now))?;
+
+                match socket.recv_from(&mut buf) {
+                    Ok((size, addr)) => {
+                        if addr != master_addr || !protocol_id.matches(&buf[..size]) {
+                            continue;
+                        }
+                        //responses to an older request are ignored
+                        match read_list_response(&buf[PROTOCOL_ID_SIZE..size]) {
+                            Ok((page_start, total, page)) if page_start == start => {
+                                break 'page (total, page)
+                            }
+                            _ => continue,
+                        }
+                    }
+                    Err(ref e) if would_block(e) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+
+        let page_len = page.len();
+        entries.extend(page);
+
+        if page_len == 0 || entries.len() >= total as usize {
+            return Ok(entries);
+        }
+    }
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(players: u32, payload: &[u8]) -> ServerInfo {
+        ServerInfo {
+            players,
+            max_players: 16,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn heartbeat_registers_and_expires() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let spoofed = "127.0.0.1:9001".parse().unwrap();
+        let now = Instant::now();
+
+        //the first heartbeat is answered with the token of the address
+        let heartbeat = write_heartbeat(0, &info(2, b"map"));
+        let challenge = registry
+            .process(addr, &heartbeat[PROTOCOL_ID_SIZE..], now)
+            .unwrap()
+            .unwrap();
+        assert!(challenge.len() < heartbeat.len());
+        let token = read_master_challenge(&challenge[PROTOCOL_ID_SIZE..]).unwrap();
+        assert!(registry.is_empty());
+
+        //the token only registers the address it was sent to
+        let heartbeat = write_heartbeat(token, &info(2, b"map"));
+        assert!(registry
+            .process(spoofed, &heartbeat[PROTOCOL_ID_SIZE..], now)
+            .unwrap()
+            .is_some());
+        assert!(registry.is_empty());
+        assert!(registry
+            .process(addr, &heartbeat[PROTOCOL_ID_SIZE..], now)
+            .unwrap()
+            .is_none());
+        assert_eq!(registry.len(), 1);
+
+        registry.update(now + Duration::from_secs(5));
+        assert_eq!(registry.len(), 1);
+
+        registry.update(now + Duration::from_secs(10));
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn full_list_keeps_the_registered_servers() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 1);
+        let first = "127.0.0.1:9000".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(registry.heartbeat(first, info(1, b"a"), now));
+        assert!(!registry.heartbeat("127.0.0.1:9001".parse().unwrap(), info(1, b"b"), now));
+        //a registered server can still refresh its info
+        assert!(registry.heartbeat(first, info(2, b"a"), now));
+        assert_eq!(registry.len(), 1);
+
+        registry.update(now + Duration::from_secs(10));
+        assert!(registry.heartbeat("127.0.0.1:9001".parse().unwrap(), info(1, b"b"), now));
+    }
+
+    #[test]
+    fn list_roundtrip() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        let v4: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[::1]:9001".parse().unwrap();
+        registry.heartbeat(v4, info(1, b"a"), now);
+        registry.heartbeat(v6, info(2, b"bb"), now);
+
+        let request = write_list_request(0);
+        let response = registry
+            .process(
+                "127.0.0.1:5000".parse().unwrap(),
+                &request[PROTOCOL_ID_SIZE..],
+                now,
+            )
+            .unwrap()
+            .unwrap();
+
+        let (start, total, entries) = read_list_response(&response[PROTOCOL_ID_SIZE..]).unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(total, 2);
+        assert_eq!(
+            entries,
+            vec![
+                ServerListEntry {
+                    addr: v4,
+                    info: info(1, b"a")
+                },
+                ServerListEntry {
+                    addr: v6,
+                    info: info(2, b"bb")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn list_is_paged() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        //every entry takes up more than a third of a packet
+        for port in 0..5 {
+            registry.heartbeat(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000 + port),
+                info(0, &[0; 400]),
+                now,
+            );
+        }
+
+        let (_, total, first) =
+            read_list_response(&registry.write_page(0)[PROTOCOL_ID_SIZE..]).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(first.len(), 2);
+
+        let (start, _, last) =
+            read_list_response(&registry.write_page(4)[PROTOCOL_ID_SIZE..]).unwrap();
+        assert_eq!(start, 4);
+        assert_eq!(last.len(), 1);
+    }
+
+    #[test]
+    fn list_requests_are_rate_limited() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let other = "127.0.0.1:5001".parse().unwrap();
+        let request = write_list_request(0);
+        let mut process = |addr, now| {
+            registry
+                .process(addr, &request[PROTOCOL_ID_SIZE..], now)
+                .unwrap()
+        };
+
+        for _ in 0..LIST_PAGES_PER_ADDRESS {
+            assert!(process(addr, now).is_some());
+        }
+        assert!(process(addr, now).is_none());
+        assert!(process(other, now).is_some());
+        assert!(process(addr, now + Duration::from_secs(1)).is_some());
+    }
+
+    #[test]
+    fn lost_list_requests_are_sent_again() {
+        let master = UdpSocket::bind("127.0.0.1:9478").unwrap();
+        master
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let master_addr = master.local_addr().unwrap();
+        let server_addr = "10.0.0.1:9000".parse().unwrap();
+        let responder = thread::spawn(move || {
+            let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+            registry.heartbeat(server_addr, info(1, b"a"), Instant::now());
+            let mut buf = [0; PROTOCOL_ID_SIZE + 1 + MAX_UNCONNECTED_SIZE];
+            //the first request is lost
+            master.recv_from(&mut buf).unwrap();
+            let (size, addr) = master.recv_from(&mut buf).unwrap();
+            let mut response = registry
+                .process(addr, &buf[PROTOCOL_ID_SIZE..size], Instant::now())
+                .unwrap()
+                .unwrap();
+            ProtocolId::DEFAULT.write_into(&mut response);
+            master.send_to(&response, addr).unwrap();
+        });
+
+        let list = fetch_server_list(
+            "127.0.0.1:9479".parse().unwrap(),
+            master_addr,
+            ProtocolId::DEFAULT,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        responder.join().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].addr, server_addr);
+    }
+
+    #[test]
+    fn truncated_list_response() {
+        let mut registry = MasterRegistry::new(Duration::from_secs(10), 16);
+        registry.heartbeat(
+            "127.0.0.1:9000".parse().unwrap(),
+            info(1, b"map"),
+            Instant::now(),
+        );
+
+        let response = registry.write_page(0);
+        let data = &response[PROTOCOL_ID_SIZE..];
+        for len in 0..data.len() {
+            assert!(read_list_response(&data[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use super::{header::Header, Bytes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    //inbound packets are ignored and not acked, outbound reliable packets are resent later
+    Drop,
+}
+
+//what a middleware sees of a packet, the payload can be rewritten in place
+pub struct PacketContext<'a> {
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub header: &'a Header,
+    pub payload: &'a mut Bytes,
+}
+
+pub type Middleware = Arc<dyn Fn(&mut PacketContext) -> Action + Send + Sync>;
+
+//runs on every channel, the middleware is called in the order it was added
+//and the first one that drops the packet stops the chain
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    middleware: Vec<Middleware>,
+}
+
+impl MiddlewareChain {
+    pub fn push<F>(&mut self, middleware: F)
+    where
+        F: Fn(&mut PacketContext) -> Action + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty()
+    }
+
+    pub fn run(&self, context: &mut PacketContext) -> Action {
+        for middleware in &self.middleware {
+            if middleware(context) == Action::Drop {
+                return Action::Drop;
+            }
+        }
+        Action::Continue
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MiddlewareChain({})", self.middleware.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::net::SendType;
+
+    use super::*;
+
+    #[test]
+    fn drop_stops_the_chain() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut chain = MiddlewareChain::default();
+
+        chain.push(|context| {
+            context.payload.push(1);
+            Action::Continue
+        });
+        chain.push(|context| {
+            if context.payload.len() > 1 {
+                Action::Drop
+            } else {
+                Action::Continue
+            }
+        });
+        let counter = calls.clone();
+        chain.push(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Action::Continue
+        });
+
+        let header = Header::new(0, 0, SendType::Reliable, false);
+        let mut run = |mut payload: Bytes| {
+            let action = chain.run(&mut PacketContext {
+                addr: "127.0.0.1:9000".parse().unwrap(),
+                direction: Direction::Inbound,
+                header: &header,
+                payload: &mut payload,
+            });
+            (action, payload)
+        };
+
+        assert_eq!(run(vec![]), (Action::Continue, vec![1]));
+        assert_eq!(run(vec![0]), (Action::Drop, vec![0, 1]));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -2,82 +2,107 @@ use std::time::Duration;
 
 use anyhow::bail;
 
+pub(crate) use crate::proto::{
+    bytes, bytes_with_header, disconnect, fragmentation_manager, header, int_buffer, sequence,
+};
+pub use crate::proto::{
+    Bytes, MessageKind, PacketType, ProtocolId, BUFFER_SIZE, BUFFER_WINDOW_SIZE, PROTOCOL_ID_SIZE,
+};
+
 //mod array_pool;
+mod alloc_counters;
+mod buffer_pool;
 mod channel;
+mod checksum;
 mod client;
 mod client_process;
+mod cluster;
+mod command;
+mod conditioner;
+mod config;
+#[cfg(any(test, feature = "conformance"))]
+mod conformance;
 mod connections;
-mod fragmentation_manager;
-mod header;
-mod int_buffer;
+mod debug_state;
+mod event_log;
+pub mod fuzzing;
+mod invalid_packets;
+mod jitter_buffer;
+mod limits;
+mod linger;
+mod master;
+mod middleware;
 mod packets;
+mod pipeline;
+mod profile;
+mod quality;
+mod queue;
+mod random;
+mod read_scheduler;
+mod redundancy;
+mod reorder_buffer;
+mod request;
 mod rtt_tracker;
+mod schedule;
 mod send_buffer;
-mod sequence;
 mod server;
+mod server_info;
 mod server_process;
 mod socket;
+mod state;
+mod stats;
+mod subscription;
+mod tags;
+mod ticker;
+mod timer_wheel;
+mod unconnected;
+mod validation;
+mod wire_format;
+mod wireshark;
 
-pub use client::Client;
-pub use fragmentation_manager::{FRAGMENT_SIZE, MAX_FRAGMENT_SIZE};
+pub use crate::proto::{
+    DisconnectCode, DisconnectReason, InvalidSessionToken, NetError, SessionToken,
+};
+#[cfg(feature = "alloc-counters")]
+pub use alloc_counters::CountingAllocator;
+pub use alloc_counters::{allocation_stats, AllocationStats};
+pub use client::{Client, ClientEvent, ConnectEvent, ConnectFailure, PendingClient};
+pub use cluster::ServerCluster;
+pub use conditioner::DebugConditions;
+pub use config::{
+    ChannelConfig, ClientConfig, DuplicatePolicy, HandshakeBackoff, HandshakeConfig, IdSpace,
+    PortFallback, ServerConfig, ServerConfigUpdate, SocketConfig,
+};
+#[cfg(any(test, feature = "conformance"))]
+pub use conformance::{run_conformance, CheckResult, EchoServer, PeerSession, ScriptedPeer};
+pub use connections::{ConnectionId, HandshakeFailure, HandshakeStage};
+pub use debug_state::{ChannelDebugState, OutstandingPacket};
+pub use event_log::{RecentEvent, RecentEventKind};
+pub use fragmentation_manager::{
+    FragmentGroupState, FragmentStats, FRAGMENT_SIZE, MAX_FRAGMENT_COUNT, MAX_FRAGMENT_SIZE,
+};
 pub use header::SendType;
+pub use invalid_packets::{InvalidPacketStats, InvalidSource, MAX_INVALID_SOURCES};
+pub use limits::{
+    HeldReadLimits, HeldReadPolicy, Limit, MessageLimits, OutboundLimits, OutboundPolicy,
+};
+pub use master::{fetch_server_list, MasterServer, ServerListEntry};
+pub use middleware::{Action, Direction, MiddlewareChain, PacketContext};
+pub use packets::SendHandle;
+pub use profile::{Percentiles, ProcessProfile};
+pub use quality::{ConnectionQuality, QualityThresholds, SendRateCallback, SendRateConfig};
+pub use random::RandomSource;
+pub use redundancy::{RedundantReceiver, RedundantSender};
+pub use request::{RequestHandle, ResponseHandle};
+pub use rtt_tracker::{RetransmitTimeout, RttConfig};
+pub use schedule::ScheduleHandle;
 pub use server::{Server, ServerEvent};
-
-pub const MAGIC_NUMBER_HEADER: [u8; 4] = [1, 27, 25, 14];
-pub const BUFFER_SIZE: u16 = 1024;
-//always has to be less than BUFFER SIZE
-pub const BUFFER_WINDOW_SIZE: u16 = 256;
-
-pub type Bytes = Vec<u8>;
-macro_rules! bytes {
-    ($size:expr) => {{
-        vec![0_u8; $size]
-    }};
-}
-
-macro_rules! bytes_with_header {
-    ($payload_size:expr) => {{
-        let mut buffer = vec![0_u8; $payload_size + 4];
-        buffer[..4].copy_from_slice(&crate::net::MAGIC_NUMBER_HEADER);
-        buffer
-    }};
-}
-pub(crate) use {bytes, bytes_with_header};
-
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum PacketType {
-    ConnectionRequest = 1,
-    Challenge = 2,
-    ChallengeResponse = 3,
-    ConnectionAccepted = 4,
-    PayloadReliableFrag = 5,
-    PayloadReliable = 6,
-    PayloadUnreliableFrag = 7,
-    PayloadUnreliable = 8,
-    Disconnect = 9,
-}
-
-impl PacketType {
-    pub fn is_frag_variant(&self) -> bool {
-        *self == PacketType::PayloadReliableFrag || *self == PacketType::PayloadUnreliableFrag
-    }
-}
-impl TryFrom<u8> for PacketType {
-    type Error = anyhow::Error;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            1 => Ok(PacketType::ConnectionRequest),
-            2 => Ok(PacketType::Challenge),
-            3 => Ok(PacketType::ChallengeResponse),
-            4 => Ok(PacketType::ConnectionAccepted),
-            5 => Ok(PacketType::PayloadReliableFrag),
-            6 => Ok(PacketType::PayloadReliable),
-            7 => Ok(PacketType::PayloadUnreliableFrag),
-            8 => Ok(PacketType::PayloadUnreliable),
-            9 => Ok(PacketType::Disconnect),
-            _ => bail!("couldn't parse value '{value}' to packet type"),
-        }
-    }
-}
+pub use server_info::{query_server_info, ServerInfo, MAX_INFO_PAYLOAD_SIZE};
+pub use socket::{SocketError, SocketRecovery};
+pub use stats::{ConnectionStats, SendQueueStats};
+pub use subscription::EventReceiver;
+pub use unconnected::MAX_UNCONNECTED_SIZE;
+pub use validation::{PayloadValidator, Verdict};
+pub use wire_format::{
+    wire_format, Field, FlagInfo, HandshakeStep, Layout, PacketTypeInfo, WireFormat,
+};
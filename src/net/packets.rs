@@ -2,60 +2,88 @@ use std::sync::Arc;
 
 use anyhow::bail;
 
+pub use crate::proto::payload::Payload;
+
 use super::{
-    bytes, bytes_with_header,
+    alloc_counters::{self, AllocPath},
+    bytes,
+    disconnect::DisconnectReason,
     fragmentation_manager::{FragmentationManager, FRAGMENT_SIZE},
-    header::{FRAG_HEADER_SIZE, HEADER_SIZE},
-    int_buffer::IntBuffer,
-    Bytes, SendType, MAGIC_NUMBER_HEADER,
+    Bytes, NetError, SendType,
 };
 
+#[derive(Clone)]
 pub enum SendEvent {
-    Single(Bytes, bool),
-    Fragmented(Vec<Bytes>, bool),
-    Disconnect,
+    Single(Payload, bool),
+    Fragmented(Vec<Payload>, bool),
+    Disconnect(DisconnectReason),
 }
 
-//prepare the appropriate sized byte arrays so we don't have to reallocate and copy the data from this point on
-pub fn construct_send_event(data: &[u8], send_type: SendType) -> anyhow::Result<SendEvent> {
-    let data_len = data.len();
+//a message in fragments sent with send_cancellable, passed back to cancel_send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SendHandle {
+    pub send_id: u32,
+}
 
-    if data_len == 0 {
-        bail!("data length cannot be 0");
+impl SendEvent {
+    //the packets the send takes and their payload bytes
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            SendEvent::Single(payload, _) => (1, payload.len()),
+            SendEvent::Fragmented(fragments, _) => (
+                fragments.len(),
+                fragments.iter().map(|fragment| fragment.len()).sum(),
+            ),
+            SendEvent::Disconnect(_) => (0, 0),
+        }
     }
 
-    if FragmentationManager::exceeds_max_length(data_len) {
-        bail!("packets of this size aren't supported");
+    pub fn is_reliable(&self) -> bool {
+        matches!(
+            self,
+            SendEvent::Single(_, true) | SendEvent::Fragmented(_, true)
+        )
     }
+}
 
-    let mut int_buffer = IntBuffer::default();
-
-    if FragmentationManager::should_fragment(data_len) {
-        let chunks = data.chunks(FRAGMENT_SIZE);
-
-        let chunk_count = chunks.len();
-        let mut fragments = Vec::with_capacity(chunk_count);
+pub fn construct_send_event(data: &[u8], send_type: SendType) -> anyhow::Result<SendEvent> {
+    let _path = alloc_counters::enter(AllocPath::Send);
+    check_length(data.len())?;
+    Ok(send_event_of(Payload::new(data), send_type))
+}
 
-        for chunk in chunks {
-            int_buffer.goto(4 + FRAG_HEADER_SIZE);
+//the packets share the data with the caller instead of a copy of it
+pub fn construct_shared_send_event(
+    data: Arc<[u8]>,
+    send_type: SendType,
+) -> anyhow::Result<SendEvent> {
+    let _path = alloc_counters::enter(AllocPath::Send);
+    check_length(data.len())?;
+    Ok(send_event_of(Payload::shared(data), send_type))
+}
 
-            let mut buffer = bytes_with_header!(chunk.len() + FRAG_HEADER_SIZE);
-            int_buffer.write_slice(chunk, &mut buffer);
+fn check_length(data_len: usize) -> anyhow::Result<()> {
+    if data_len == 0 {
+        bail!("data length cannot be 0");
+    }
 
-            fragments.push(buffer);
-        }
+    if FragmentationManager::exceeds_max_length(data_len) {
+        bail!(NetError::PayloadTooLarge {
+            size: data_len,
+            max: FragmentationManager::max_message_size(),
+        });
+    }
+    Ok(())
+}
 
-        Ok(SendEvent::Fragmented(
-            fragments,
+fn send_event_of(payload: Payload, send_type: SendType) -> SendEvent {
+    if FragmentationManager::should_fragment(payload.len()) {
+        SendEvent::Fragmented(
+            payload.chunks(FRAGMENT_SIZE),
             send_type == SendType::Reliable,
-        ))
+        )
     } else {
-        int_buffer.goto(4 + HEADER_SIZE);
-
-        let mut buffer = bytes_with_header!(data_len + HEADER_SIZE);
-        int_buffer.write_slice(data, &mut buffer);
-
-        Ok(SendEvent::Single(buffer, send_type == SendType::Reliable))
+        SendEvent::Single(payload, send_type == SendType::Reliable)
     }
 }
 
@@ -76,7 +104,16 @@ mod tests {
     #[test]
     fn packet_exceeds_max_size() {
         let buffer = bytes!(MAX_FRAGMENT_SIZE + 1);
-        assert!(construct_send_event(&buffer, SendType::Reliable).is_err());
+        let Err(e) = construct_send_event(&buffer, SendType::Reliable) else {
+            panic!("the payload is too large");
+        };
+        assert_eq!(
+            e.downcast_ref::<NetError>(),
+            Some(&NetError::PayloadTooLarge {
+                size: MAX_FRAGMENT_SIZE + 1,
+                max: MAX_FRAGMENT_SIZE,
+            })
+        );
     }
 
     #[test]
@@ -98,12 +135,7 @@ mod tests {
 
         assert!(matches!(send, SendEvent::Single(_, _)));
         if let SendEvent::Single(packet, _) = send {
-            assert_eq!(packet.len(), buffer.len() + HEADER_SIZE + 4);
-            assert_eq!(
-                //we need to remove the header to get the actual data
-                &packet[HEADER_SIZE + 4..],
-                &buffer
-            );
+            assert_eq!(&packet[..], &buffer[..]);
         }
     }
 
@@ -120,14 +152,32 @@ mod tests {
         assert!(matches!(send, SendEvent::Fragmented(_, _)));
         if let SendEvent::Fragmented(chunks, _) = send {
             assert_eq!(chunks.len(), 2);
+            assert_eq!(chunks[0].len(), FRAGMENT_SIZE);
             assert_eq!(
-                //we need to remove the header to get the actual data
-                &chunks
-                    .into_iter()
-                    .flat_map(|f| f[4 + FRAG_HEADER_SIZE..].to_vec())
-                    .collect::<Vec<u8>>(),
+                &chunks.iter().flat_map(|f| f.to_vec()).collect::<Vec<u8>>(),
                 &buffer
             );
         }
     }
+
+    #[test]
+    fn shared_sends_keep_the_data() {
+        let data: Arc<[u8]> = Arc::from(bytes!(FRAGMENT_SIZE * 2 + 1));
+        let Ok(SendEvent::Fragmented(chunks, true)) =
+            construct_shared_send_event(data.clone(), SendType::Reliable)
+        else {
+            panic!("expected a reliable fragmented send");
+        };
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].as_ptr(), data.as_ptr());
+
+        let small: Arc<[u8]> = Arc::from(&[1, 2, 3][..]);
+        let Ok(SendEvent::Single(payload, false)) =
+            construct_shared_send_event(small.clone(), SendType::Unreliable)
+        else {
+            panic!("expected an unreliable single send");
+        };
+        assert_eq!(payload.as_ptr(), small.as_ptr());
+        assert!(construct_shared_send_event(Arc::from(&[][..]), SendType::Reliable).is_err());
+    }
 }
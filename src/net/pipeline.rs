@@ -0,0 +1,452 @@
+//a server with more than one worker splits the work between threads: the io thread owns the socket
+//and routes the datagrams and the commands, every worker runs a ServerProcess for its share of the
+//connections. a new connection goes to the worker of its address, the connection ids are handed
+//out so the worker can be found from them. the workers report where their sessions are, so the
+//datagrams of a session go to the worker of its connection even after its address changed
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::{Receiver, Sender};
+use log::debug;
+use mio::Waker;
+
+use super::{
+    config::{DuplicatePolicy, ServerConfig},
+    connections::{read_session_key, shard_of, ConnectionId, ConnectionManager, SessionRoute},
+    profile::SharedPhaseSamples,
+    queue::{self, OnFull, QueueReceiver, QueueSender, Signal, TryRecvError},
+    server_process::{InternalServerCommand, InternalServerEvent, ServerProcess},
+    socket::{ReadBudget, Socket, UdpEvent, UdpSendEvent},
+    stats::SharedServerStats,
+    subscription::Subscribers,
+    ticker::Ticker,
+    unconnected::SharedUnconnectedHandler,
+};
+
+//the worker side of the handoff with the io thread
+pub struct WorkerLink {
+    reads: QueueReceiver<UdpEvent>,
+    //shared by the reads and the commands so the worker can wait on both
+    signal: Arc<Signal>,
+    sends: Sender<UdpSendEvent>,
+    //interrupts the poll of the io thread so the sends go out right away
+    waker: Arc<Waker>,
+    //the phase timings of the worker, the API reads them
+    samples: SharedPhaseSamples,
+}
+
+impl WorkerLink {
+    pub fn samples(&self) -> SharedPhaseSamples {
+        self.samples.clone()
+    }
+
+    //hands the queued sends to the io thread
+    pub fn send(&self, send_queue: &mut VecDeque<UdpSendEvent>) -> anyhow::Result<()> {
+        if send_queue.is_empty() {
+            return Ok(());
+        }
+
+        while let Some(send_event) = send_queue.pop_back() {
+            self.sends
+                .send(send_event)
+                .map_err(|_| anyhow!("the io thread has stopped"))?;
+        }
+        self.waker.wake()?;
+        Ok(())
+    }
+
+    //waits until the deadline for datagrams or commands, the commands are left for the caller. None
+    //only waits for datagrams
+    pub fn process(
+        &self,
+        deadline: Instant,
+        commands: Option<&QueueReceiver<InternalServerCommand>>,
+        events: &mut VecDeque<UdpEvent>,
+    ) {
+        self.signal.wait_until(deadline, || {
+            self.reads.is_ready() || commands.is_some_and(|commands| commands.is_ready())
+        });
+
+        while let Ok(event) = self.reads.try_recv() {
+            events.push_front(event);
+        }
+    }
+}
+
+//the io thread side of a worker
+struct Worker {
+    reads: QueueSender<UdpEvent>,
+    commands: QueueSender<InternalServerCommand>,
+}
+
+pub struct IoProcess {
+    socket: Socket,
+    //API channels
+    out_events: QueueSender<InternalServerEvent>,
+    //the events of the io thread itself, the workers have their own subscribers
+    subscribers: Subscribers,
+    in_sends: QueueReceiver<InternalServerCommand>,
+    workers: Vec<Worker>,
+    sends: Receiver<UdpSendEvent>,
+    send_queue: VecDeque<UdpSendEvent>,
+    unconnected_handler: SharedUnconnectedHandler,
+    //the sends of the workers and the commands wake the thread, this only bounds the poll and
+    //renews the read budget
+    ticker: Ticker,
+    read_budget: ReadBudget,
+    duplicate_policy: DuplicatePolicy,
+    //its challenges go to the first worker, the one sending the heartbeats
+    master_server: Option<SocketAddr>,
+    //the connections of the workers by session and by address
+    session_routes: Receiver<SessionRoute>,
+    sessions: HashMap<u64, ConnectionId>,
+    addrs: HashMap<SocketAddr, ConnectionId>,
+}
+
+impl IoProcess {
+    //starts the workers, the server is started once this returns
+    pub fn bind(
+        sockets: Vec<std::net::UdpSocket>,
+        config: ServerConfig,
+        out_events: QueueSender<InternalServerEvent>,
+        in_sends: QueueReceiver<InternalServerCommand>,
+    ) -> anyhow::Result<Self> {
+        let mut socket = Socket::from_std_all(sockets, &config.socket)?;
+        socket.set_protocol_id(config.protocol_id);
+        socket.report_invalid_packets(config.protocol_error_interval);
+
+        let stats = SharedServerStats::default();
+        let unconnected_handler = SharedUnconnectedHandler::default();
+        let active_clients = Arc::new(AtomicUsize::new(0));
+        let (sends_tx, sends) = crossbeam_channel::unbounded();
+        let (session_routes_tx, session_routes) = crossbeam_channel::unbounded();
+        let shards = config.workers as u32;
+        let mut samples = Vec::new();
+
+        let workers = (0..shards)
+            .map(|shard| {
+                //the io thread never waits on a worker, a busy worker loses datagrams and only grows
+                //its backlog of commands
+                let signal = Arc::new(Signal::default());
+                let (reads_tx, reads) =
+                    queue::channel_with_signal(queue::READ_CAPACITY, OnFull::Drop, signal.clone());
+                let (commands_tx, commands) = queue::channel_with_signal(
+                    queue::COMMAND_CAPACITY,
+                    OnFull::Grow,
+                    signal.clone(),
+                );
+                let link = WorkerLink {
+                    reads,
+                    signal,
+                    sends: sends_tx.clone(),
+                    waker: socket.waker(),
+                    samples: SharedPhaseSamples::default(),
+                };
+                samples.push(link.samples());
+                let config = config.clone();
+                let active_clients = active_clients.clone();
+                let out_events = out_events.clone();
+                let stats = stats.clone();
+                let unconnected_handler = unconnected_handler.clone();
+                let session_routes = session_routes_tx.clone();
+
+                //the connections can't leave the thread they are created on
+                thread::Builder::new()
+                    .name(format!("server worker {shard}"))
+                    .spawn(move || {
+                        let mut connection_manager =
+                            ConnectionManager::sharded(config, shard, shards, active_clients);
+                        connection_manager.set_session_routes(session_routes);
+                        let mut process = ServerProcess::worker(
+                            link,
+                            connection_manager,
+                            shard as usize,
+                            out_events,
+                            commands,
+                            stats,
+                            unconnected_handler,
+                        );
+                        if let Err(e) = process.start() {
+                            debug!("worker {shard} ended: {e}");
+                        }
+                    })?;
+
+                Ok(Worker {
+                    reads: reads_tx,
+                    commands: commands_tx,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        out_events.send(InternalServerEvent::ServerStarted(
+            socket.waker(),
+            socket.invalid_packets(),
+            socket.send_queue_stats(),
+            stats,
+            samples,
+        ))?;
+
+        Ok(Self {
+            socket,
+            out_events,
+            subscribers: Subscribers::default(),
+            in_sends,
+            workers,
+            sends,
+            send_queue: VecDeque::new(),
+            unconnected_handler,
+            ticker: Ticker::new(config.channel.update_interval),
+            read_budget: ReadBudget::new(config.max_reads_per_tick),
+            duplicate_policy: config.duplicate_policy,
+            master_server: config.master_server,
+            session_routes,
+            sessions: HashMap::new(),
+            addrs: HashMap::new(),
+        })
+    }
+
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        let mut udp_events = VecDeque::new();
+
+        loop {
+            //before the commands, a send to a connection opened a moment ago goes to its worker
+            self.update_routes();
+            loop {
+                match self.in_sends.try_recv() {
+                    Ok(InternalServerCommand::Shutdown) => return self.shut_down(),
+                    Ok(command) => self.route_command(command)?,
+                    Err(TryRecvError::Empty) => break,
+                    Err(e) => bail!("process ending {}", e),
+                }
+            }
+
+            while let Ok(send_event) = self.sends.try_recv() {
+                self.send_queue.push_front(send_event);
+            }
+            if !self.send_queue.is_empty() {
+                self.socket.enqueue_send_events(&mut self.send_queue);
+            }
+
+            if self.ticker.is_due(Instant::now()) {
+                self.read_budget.reset();
+            }
+            let reads = self.socket.process(
+                self.ticker.deadline(),
+                self.read_budget.left(),
+                &mut udp_events,
+            )?;
+            self.read_budget.spend(reads);
+
+            self.update_routes();
+            while let Some(udp_event) = udp_events.pop_back() {
+                match udp_event {
+                    UdpEvent::Read(addr, ..) if Some(addr) == self.master_server => {
+                        self.workers[0]
+                            .reads
+                            .send(udp_event)
+                            .map_err(|_| anyhow!("worker 0 has stopped"))?;
+                    }
+                    UdpEvent::Read(addr, ref buffer, ..) => {
+                        let worker = self.worker_of_read(addr, buffer);
+                        self.workers[worker]
+                            .reads
+                            .send(udp_event)
+                            .map_err(|_| anyhow!("worker {worker} has stopped"))?;
+                    }
+                    UdpEvent::SentServer(addr, ..) => {
+                        let worker = self.worker_of_addr(addr);
+                        self.workers[worker]
+                            .reads
+                            .send(udp_event)
+                            .map_err(|_| anyhow!("worker {worker} has stopped"))?;
+                    }
+                    UdpEvent::Invalid(addr, count) => {
+                        debug!("{count} invalid packets from {addr}");
+                        self.emit(InternalServerEvent::ProtocolError(addr, count))?;
+                    }
+                    UdpEvent::Error(e) => self.emit(InternalServerEvent::SocketError(e))?,
+                    UdpEvent::SentClient(..) => {}
+                }
+            }
+        }
+    }
+
+    //the workers send their disconnects and end, the io thread sends them once every worker is gone
+    fn shut_down(&mut self) -> anyhow::Result<()> {
+        self.route_command(InternalServerCommand::Shutdown)?;
+        while let Ok(send_event) = self.sends.recv() {
+            self.send_queue.push_front(send_event);
+        }
+        self.socket.enqueue_send_events(&mut self.send_queue);
+        self.socket.flush()
+    }
+
+    fn emit(&mut self, event: InternalServerEvent) -> anyhow::Result<()> {
+        self.subscribers.publish(&event);
+        self.out_events.send(event)?;
+        Ok(())
+    }
+
+    fn route_command(&mut self, command: InternalServerCommand) -> anyhow::Result<()> {
+        let shards = self.workers.len() as u32;
+        let worker = match command {
+            InternalServerCommand::Send(addr, _)
+            | InternalServerCommand::SendUnconnected(addr, _)
+            | InternalServerCommand::SendCancellable(addr, _, _)
+            | InternalServerCommand::CancelSend(addr, _)
+            | InternalServerCommand::SendSuperseding(addr, _, _) => self.worker_of_addr(addr),
+            InternalServerCommand::SetDebugConditions(connection_id, _)
+            | InternalServerCommand::Disconnect(connection_id, _)
+            | InternalServerCommand::Reply(connection_id, _)
+            | InternalServerCommand::SetInterest(connection_id, _)
+            | InternalServerCommand::Pause(connection_id)
+            | InternalServerCommand::Resume(connection_id)
+            | InternalServerCommand::SendRepeated(_, connection_id, _, _)
+            | InternalServerCommand::DebugDump(connection_id, _)
+            | InternalServerCommand::RecentEvents(connection_id, _)
+            | InternalServerCommand::SetState(connection_id, _, _)
+            | InternalServerCommand::Tag(connection_id, _) => shard_of(connection_id, shards),
+            InternalServerCommand::SetUnconnectedHandler(handler) => {
+                *self
+                    .unconnected_handler
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) = handler;
+                return Ok(());
+            }
+            //every worker answers server info queries and has connections to broadcast to
+            InternalServerCommand::SetServerInfoPayload(ref payload) => {
+                return self
+                    .broadcast(|| InternalServerCommand::SetServerInfoPayload(payload.clone()))
+            }
+            InternalServerCommand::BroadcastFiltered(key, ref send_event) => {
+                return self.broadcast(|| {
+                    InternalServerCommand::BroadcastFiltered(key, send_event.clone())
+                })
+            }
+            InternalServerCommand::CancelRepeated(schedule_id) => {
+                return self.broadcast(|| InternalServerCommand::CancelRepeated(schedule_id))
+            }
+            InternalServerCommand::UpdateConfig(ref update) => {
+                if let Some(max_reads_per_tick) = update.max_reads_per_tick {
+                    self.read_budget.set_max(max_reads_per_tick);
+                }
+                return self.broadcast(|| InternalServerCommand::UpdateConfig(update.clone()));
+            }
+            InternalServerCommand::Subscribe(sender) => {
+                self.subscribers.add(sender.clone());
+                return self.broadcast(|| InternalServerCommand::Subscribe(sender.clone()));
+            }
+            InternalServerCommand::Shutdown => {
+                return self.broadcast(|| InternalServerCommand::Shutdown)
+            }
+        };
+
+        self.send_command(worker, command)
+    }
+
+    fn update_routes(&mut self) {
+        while let Ok(route) = self.session_routes.try_recv() {
+            match route {
+                SessionRoute::Opened(session_key, addr, connection_id) => {
+                    self.sessions.insert(session_key, connection_id);
+                    self.addrs.insert(addr, connection_id);
+                }
+                SessionRoute::Moved(old, new, connection_id) => {
+                    self.addrs.remove(&old);
+                    self.addrs.insert(new, connection_id);
+                }
+                SessionRoute::Closed(session_key, addr) => {
+                    self.sessions.remove(&session_key);
+                    self.addrs.remove(&addr);
+                }
+            }
+        }
+    }
+
+    //a packet of a known session goes to the worker of its connection whatever address it came
+    //from, a compact header has no session key so its address is used
+    fn worker_of_read(&self, addr: SocketAddr, buffer: &[u8]) -> usize {
+        match read_session_key(buffer)
+            .flatten()
+            .and_then(|session_key| self.sessions.get(&session_key))
+        {
+            Some(connection_id) => shard_of(*connection_id, self.workers.len() as u32),
+            None => self.worker_of_addr(addr),
+        }
+    }
+
+    //the worker of the connection on the address, new addresses go where their handshake is done
+    fn worker_of_addr(&self, addr: SocketAddr) -> usize {
+        match self.addrs.get(&addr) {
+            Some(connection_id) => shard_of(*connection_id, self.workers.len() as u32),
+            None => shard_of_addr(addr, self.workers.len(), self.duplicate_policy),
+        }
+    }
+
+    fn broadcast(&self, command: impl Fn() -> InternalServerCommand) -> anyhow::Result<()> {
+        for worker in 0..self.workers.len() {
+            self.send_command(worker, command())?;
+        }
+        Ok(())
+    }
+
+    fn send_command(&self, worker: usize, command: InternalServerCommand) -> anyhow::Result<()> {
+        self.workers[worker]
+            .commands
+            .send(command)
+            .map_err(|_| anyhow!("worker {worker} has stopped"))
+    }
+}
+
+//the worker that has the connections of the address. a policy allowing one connection per ip
+//needs all the addresses of the ip on one worker to find the duplicates
+pub fn shard_of_addr(addr: SocketAddr, shards: usize, policy: DuplicatePolicy) -> usize {
+    let mut hasher = DefaultHasher::new();
+    match policy {
+        DuplicatePolicy::AllowMultiplePorts => addr.hash(&mut hasher),
+        DuplicatePolicy::ReplaceExisting | DuplicatePolicy::RejectNew => {
+            addr.ip().hash(&mut hasher)
+        }
+    }
+    (hasher.finish() % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_stay_on_their_worker() {
+        let addrs: Vec<SocketAddr> = (0..64)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], 9000 + port)))
+            .collect();
+
+        let shards: Vec<_> = addrs
+            .iter()
+            .map(|addr| shard_of_addr(*addr, 4, DuplicatePolicy::AllowMultiplePorts))
+            .collect();
+        assert!(shards.iter().all(|&shard| shard < 4));
+        assert_eq!(
+            shards,
+            addrs
+                .iter()
+                .map(|addr| shard_of_addr(*addr, 4, DuplicatePolicy::AllowMultiplePorts))
+                .collect::<Vec<_>>()
+        );
+        //the addresses are spread over every worker
+        assert!((0..4).all(|shard| shards.contains(&shard)));
+
+        //unless the ports of an ip have to be compared
+        assert!(addrs
+            .iter()
+            .all(|addr| shard_of_addr(*addr, 4, DuplicatePolicy::RejectNew)
+                == shard_of_addr(addrs[0], 4, DuplicatePolicy::RejectNew)));
+    }
+}
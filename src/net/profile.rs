@@ -0,0 +1,194 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+//the ticks the percentiles are taken over, a few seconds at the usual update intervals
+const PROFILE_TICKS: usize = 512;
+
+//what the loop of a server process spends its time on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    //waiting on the socket and writing the queued packets to it, the idle time is in here
+    Poll,
+    //the datagrams that arrived, handshakes and channel packets
+    Reads,
+    //the updates of the channels, resends and keep alives among them
+    Updates,
+    //the commands of the API, the sends of the game mostly
+    Sends,
+}
+
+const PHASES: [Phase; 4] = [Phase::Poll, Phase::Reads, Phase::Updates, Phase::Sends];
+
+//the time of every phase during one tick, a tick ends when the next update starts
+type TickSample = [Duration; PHASES.len()];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let at = |percent: usize| samples[(samples.len() - 1) * percent / 100];
+        Self {
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: at(100),
+        }
+    }
+}
+
+//the time per tick of the phases over the latest ticks of a process. a busy time close to the
+//update interval means the thread can't keep up and the updates start to run late
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProcessProfile {
+    //since the process started, the percentiles only look at the latest ones
+    pub ticks: u64,
+    pub poll: Percentiles,
+    pub reads: Percentiles,
+    pub updates: Percentiles,
+    pub sends: Percentiles,
+    //everything but the poll
+    pub busy: Percentiles,
+}
+
+//the latest ticks of a process, written by the process thread and read by the API
+#[derive(Default)]
+pub struct PhaseSamples {
+    ticks: u64,
+    latest: VecDeque<TickSample>,
+}
+
+impl PhaseSamples {
+    fn push(&mut self, sample: TickSample) {
+        if self.latest.len() == PROFILE_TICKS {
+            self.latest.pop_front();
+        }
+        self.latest.push_back(sample);
+        self.ticks += 1;
+    }
+
+    pub fn profile(&self) -> ProcessProfile {
+        let phase =
+            |phase: Phase| Percentiles::of(self.latest.iter().map(|s| s[phase as usize]).collect());
+        ProcessProfile {
+            ticks: self.ticks,
+            poll: phase(Phase::Poll),
+            reads: phase(Phase::Reads),
+            updates: phase(Phase::Updates),
+            sends: phase(Phase::Sends),
+            busy: Percentiles::of(
+                self.latest
+                    .iter()
+                    .map(|s| s.iter().sum::<Duration>() - s[Phase::Poll as usize])
+                    .collect(),
+            ),
+        }
+    }
+}
+
+pub type SharedPhaseSamples = Arc<Mutex<PhaseSamples>>;
+
+//adds up the phases of the current tick. the updates run in between the other phases, their time is
+//taken out of the phase they interrupted
+#[derive(Default)]
+pub struct PhaseTimer {
+    tick: TickSample,
+    //of all ticks, a phase can span the end of a tick
+    updates: Duration,
+    samples: SharedPhaseSamples,
+}
+
+impl PhaseTimer {
+    pub fn samples(&self) -> SharedPhaseSamples {
+        self.samples.clone()
+    }
+
+    pub fn set_samples(&mut self, samples: SharedPhaseSamples) {
+        self.samples = samples;
+    }
+
+    //the mark to pass to end once the phase is done
+    pub fn start(&self) -> (Instant, Duration) {
+        (Instant::now(), self.updates)
+    }
+
+    pub fn end(&mut self, phase: Phase, (started, updates): (Instant, Duration)) {
+        let nested = self.updates - updates;
+        self.tick[phase as usize] += started.elapsed().saturating_sub(nested);
+    }
+
+    //called as an update starts, the update counts to the tick it starts
+    pub fn end_tick(&mut self) {
+        let tick = std::mem::take(&mut self.tick);
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(tick);
+    }
+
+    pub fn add_update(&mut self, elapsed: Duration) {
+        self.tick[Phase::Updates as usize] += elapsed;
+        self.updates += elapsed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_the_latest_ticks() {
+        let mut samples = PhaseSamples::default();
+        for i in 0..PROFILE_TICKS as u64 + 100 {
+            let mut tick = TickSample::default();
+            tick[Phase::Poll as usize] = Duration::from_millis(10);
+            tick[Phase::Reads as usize] = Duration::from_micros(i % PROFILE_TICKS as u64);
+            tick[Phase::Updates as usize] = Duration::from_micros(5);
+            samples.push(tick);
+        }
+
+        let profile = samples.profile();
+        assert_eq!(profile.ticks, PROFILE_TICKS as u64 + 100);
+        assert_eq!(profile.poll.p50, Duration::from_millis(10));
+        //every value below PROFILE_TICKS once
+        assert_eq!(profile.reads.max, Duration::from_micros(511));
+        assert_eq!(profile.reads.p50, Duration::from_micros(255));
+        assert_eq!(profile.reads.p99, Duration::from_micros(505));
+        assert_eq!(profile.sends, Percentiles::default());
+        assert_eq!(profile.busy.max, Duration::from_micros(516));
+
+        assert_eq!(PhaseSamples::default().profile(), ProcessProfile::default());
+    }
+
+    #[test]
+    fn updates_are_taken_out_of_the_phase_they_interrupt() {
+        let mut timer = PhaseTimer::default();
+        let mark = timer.start();
+        std::thread::sleep(Duration::from_millis(20));
+        timer.end_tick();
+        timer.add_update(Duration::from_millis(15));
+        timer.end(Phase::Reads, mark);
+        timer.end_tick();
+
+        let samples = timer.samples();
+        let samples = samples.lock().unwrap();
+        let tick = samples.latest.back().unwrap();
+        assert_eq!(tick[Phase::Updates as usize], Duration::from_millis(15));
+        assert!(tick[Phase::Reads as usize] >= Duration::from_millis(5));
+        assert!(tick[Phase::Reads as usize] + Duration::from_millis(15) <= mark.0.elapsed());
+    }
+}
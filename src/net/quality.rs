@@ -0,0 +1,199 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionQuality {
+    Good,
+    Degraded,
+    Bad,
+}
+
+//a connection is degraded once it's above any of the degraded thresholds and bad once it's above
+//any of the bad ones
+#[derive(Debug, Clone)]
+pub struct QualityThresholds {
+    pub degraded_rtt_variance: Duration,
+    pub bad_rtt_variance: Duration,
+    //share of the reliable packets that had to be resent
+    pub degraded_loss: f32,
+    pub bad_loss: f32,
+    //how often the quality is evaluated, the loss is measured over this window
+    pub interval: Duration,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            degraded_rtt_variance: Duration::from_millis(20),
+            bad_rtt_variance: Duration::from_millis(50),
+            degraded_loss: 0.02,
+            bad_loss: 0.1,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl QualityThresholds {
+    pub fn classify(&self, rtt_variance: Duration, loss: f32) -> ConnectionQuality {
+        if rtt_variance > self.bad_rtt_variance || loss > self.bad_loss {
+            ConnectionQuality::Bad
+        } else if rtt_variance > self.degraded_rtt_variance || loss > self.degraded_loss {
+            ConnectionQuality::Degraded
+        } else {
+            ConnectionQuality::Good
+        }
+    }
+}
+
+//called from the process thread with the address of the connection and the new rate
+pub type SendRateCallback = Arc<dyn Fn(SocketAddr, u32) + Send + Sync>;
+
+//the unreliable send rate recommended to the game per connection, in sends per second. games can
+//lower their snapshot frequency for connections that can't keep up
+#[derive(Clone)]
+pub struct SendRateConfig {
+    //recommended while the connection is good
+    pub max: u32,
+    //the recommendation never goes below this
+    pub min: u32,
+    pub on_change: Option<SendRateCallback>,
+}
+
+impl Default for SendRateConfig {
+    fn default() -> Self {
+        Self {
+            max: 60,
+            min: 10,
+            on_change: None,
+        }
+    }
+}
+
+impl SendRateConfig {
+    //halved for every step the quality is below good, the quality covers the loss and the jitter
+    pub fn recommend(&self, quality: ConnectionQuality) -> u32 {
+        let rate = match quality {
+            ConnectionQuality::Good => self.max,
+            ConnectionQuality::Degraded => self.max / 2,
+            ConnectionQuality::Bad => self.max / 4,
+        };
+        rate.max(self.min)
+    }
+}
+
+impl fmt::Debug for SendRateConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendRateConfig")
+            .field("max", &self.max)
+            .field("min", &self.min)
+            .field("on_change", &self.on_change.is_some())
+            .finish()
+    }
+}
+
+//classifies a connection on every interval from the counters of its channel
+pub struct QualityMonitor {
+    thresholds: QualityThresholds,
+    quality: ConnectionQuality,
+    next_check: Instant,
+    //the packet counters at the start of the window
+    sent: u64,
+    resent: u64,
+}
+
+impl QualityMonitor {
+    //every connection starts out as good
+    pub fn new(thresholds: QualityThresholds, now: Instant) -> Self {
+        Self {
+            next_check: now + thresholds.interval,
+            thresholds,
+            quality: ConnectionQuality::Good,
+            sent: 0,
+            resent: 0,
+        }
+    }
+
+    pub fn quality(&self) -> ConnectionQuality {
+        self.quality
+    }
+
+    //the new quality if it changed, the counters are the totals of the connection
+    pub fn update(
+        &mut self,
+        now: Instant,
+        rtt_variance: Duration,
+        sent: u64,
+        resent: u64,
+    ) -> Option<ConnectionQuality> {
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + self.thresholds.interval;
+
+        let window_sent = sent - self.sent;
+        let window_resent = resent - self.resent;
+        self.sent = sent;
+        self.resent = resent;
+
+        let loss = if window_sent == 0 {
+            0.0
+        } else {
+            window_resent as f32 / window_sent as f32
+        };
+
+        let quality = self.thresholds.classify(rtt_variance, loss);
+        if quality == self.quality {
+            return None;
+        }
+        self.quality = quality;
+        Some(quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_are_reported_once_per_interval() {
+        let thresholds = QualityThresholds::default();
+        let interval = thresholds.interval;
+        let now = Instant::now();
+        let mut monitor = QualityMonitor::new(thresholds, now);
+        let jitter = Duration::from_millis(30);
+
+        assert_eq!(monitor.update(now, jitter, 0, 0), None);
+        assert_eq!(
+            monitor.update(now + interval, jitter, 100, 0),
+            Some(ConnectionQuality::Degraded)
+        );
+        assert_eq!(monitor.update(now + interval * 2, jitter, 200, 1), None);
+        //20 of the 100 packets of this window were resent
+        assert_eq!(
+            monitor.update(now + interval * 3, Duration::ZERO, 300, 21),
+            Some(ConnectionQuality::Bad)
+        );
+        assert_eq!(
+            monitor.update(now + interval * 4, Duration::ZERO, 300, 21),
+            Some(ConnectionQuality::Good)
+        );
+        assert_eq!(monitor.quality(), ConnectionQuality::Good);
+    }
+
+    #[test]
+    fn send_rate_follows_the_quality() {
+        let config = SendRateConfig {
+            max: 60,
+            min: 20,
+            on_change: None,
+        };
+
+        assert_eq!(config.recommend(ConnectionQuality::Good), 60);
+        assert_eq!(config.recommend(ConnectionQuality::Degraded), 30);
+        assert_eq!(config.recommend(ConnectionQuality::Bad), 20);
+    }
+}
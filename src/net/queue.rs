@@ -0,0 +1,374 @@
+//the queues between the API and the process threads and between the io thread and the workers.
+//bounded crossbeam channels hold the values, one per direction, the signal lets a thread wait on
+//several receivers at once and calls the wakeup of the application
+use std::{
+    sync::{
+        atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+
+pub use crossbeam_channel::{RecvTimeoutError, SendError, TryRecvError};
+
+//commands from the API, a full queue blocks the game thread until the process thread caught up
+pub const COMMAND_CAPACITY: usize = 1024;
+//the datagrams of a worker, a worker that can't keep up loses them like a full socket buffer would
+pub const READ_CAPACITY: usize = 4096;
+//events for the API, a full queue holds up the process thread until the game read some of them.
+//the tags and the subscribers get their own queue of this size
+pub const EVENT_CAPACITY: usize = 4096;
+
+//what a sender does when its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFull {
+    //waits for the receiver to make space, used between the API and the process threads
+    Block,
+    //drops the value, only for datagrams that could have been lost on the way anyway
+    Drop,
+    //the queue has no capacity, the io thread never waits on the commands of a worker
+    Grow,
+}
+
+//called on the sending thread after every value, lets a loop of the application wait on its own
+//handle instead of on the receiver
+pub type Wakeup = Arc<dyn Fn() + Send + Sync>;
+
+//wakes a receiver waiting for values, receivers sharing one can be waited on together
+#[derive(Default)]
+pub struct Signal {
+    lock: Mutex<()>,
+    condvar: Condvar,
+    //the threads waiting, every one of them is woken by a value
+    waiting: AtomicUsize,
+    wakeup: Mutex<Option<Wakeup>>,
+    //the lock is only taken when there is a wakeup to call
+    has_wakeup: AtomicBool,
+}
+
+impl Signal {
+    //waits until ready returns true or the deadline passes, false on the deadline
+    pub fn wait_until(&self, deadline: Instant, ready: impl Fn() -> bool) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            let guard = lock(&self.lock);
+            self.waiting.fetch_add(1, Ordering::SeqCst);
+            //a value pushed before the count went up is seen here, one pushed after it notifies
+            fence(Ordering::SeqCst);
+            if !ready() {
+                let _ = self.condvar.wait_timeout(guard, deadline - now);
+            }
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    //the wakeup is called first, a receiver that was waiting for the value could set a new one
+    //before this returns
+    fn notify(&self) {
+        if self.has_wakeup.load(Ordering::Acquire) {
+            //called without the lock so the wakeup can replace itself
+            let wakeup = lock(&self.wakeup).clone();
+            if let Some(wakeup) = wakeup {
+                wakeup();
+            }
+        }
+        self.notify_waiting();
+    }
+
+    //only wakes the threads waiting on the signal, the wakeup isn't called
+    fn notify_waiting(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.lock);
+            self.condvar.notify_all();
+        }
+    }
+
+    fn set_wakeup(&self, wakeup: Option<Wakeup>) {
+        let mut current = lock(&self.wakeup);
+        self.has_wakeup.store(wakeup.is_some(), Ordering::Release);
+        *current = wakeup;
+    }
+}
+
+struct Shared {
+    senders: AtomicUsize,
+    signal: Arc<Signal>,
+    on_full: OnFull,
+}
+
+pub struct QueueSender<T> {
+    sender: Sender<T>,
+    shared: Arc<Shared>,
+}
+
+impl<T> QueueSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.shared.on_full {
+            OnFull::Block | OnFull::Grow => self.sender.send(value)?,
+            OnFull::Drop => match self.sender.try_send(value) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return Ok(()),
+                Err(TrySendError::Disconnected(value)) => return Err(SendError(value)),
+            },
+        }
+
+        self.shared.signal.notify();
+        Ok(())
+    }
+
+    //values the receiver didn't take yet
+    pub fn len(&self) -> usize {
+        self.sender.len()
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            sender: self.sender.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let last = self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1;
+        //a receiver waiting for values learns that this sender is gone, the wakeup is only called
+        //when the receiver can read the disconnect
+        if last {
+            self.shared.signal.notify();
+        } else {
+            self.shared.signal.notify_waiting();
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    receiver: Receiver<T>,
+    shared: Arc<Shared>,
+}
+
+impl<T> QueueReceiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_deadline(Instant::now() + timeout)
+    }
+
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if !self.shared.signal.wait_until(deadline, || self.is_ready()) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    //None removes it. it's called right away if values are already waiting, they wouldn't call it
+    pub fn set_wakeup(&self, wakeup: Option<Wakeup>) {
+        self.shared.signal.set_wakeup(wakeup.clone());
+        if let Some(wakeup) = wakeup.filter(|_| self.is_ready()) {
+            wakeup();
+        }
+    }
+
+    //a value can be read or every sender is gone
+    pub fn is_ready(&self) -> bool {
+        !self.receiver.is_empty() || self.shared.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+pub fn channel<T>(capacity: usize, on_full: OnFull) -> (QueueSender<T>, QueueReceiver<T>) {
+    channel_with_signal(capacity, on_full, Arc::default())
+}
+
+//the receiver is woken through the signal, a thread can wait on several receivers sharing one
+pub fn channel_with_signal<T>(
+    capacity: usize,
+    on_full: OnFull,
+    signal: Arc<Signal>,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (sender, receiver) = match on_full {
+        OnFull::Block | OnFull::Drop => crossbeam_channel::bounded(capacity.max(1)),
+        OnFull::Grow => crossbeam_channel::unbounded(),
+    };
+    let shared = Arc::new(Shared {
+        senders: AtomicUsize::new(1),
+        signal,
+        on_full,
+    });
+
+    (
+        QueueSender {
+            sender,
+            shared: shared.clone(),
+        },
+        QueueReceiver { receiver, shared },
+    )
+}
+
+//a panic while holding one of the locks doesn't leave the queue in a broken state
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn values_keep_their_order() {
+        let (sender, receiver) = channel(4, OnFull::Grow);
+        for i in 0..3 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(receiver.try_recv(), Ok(0));
+        //a growing queue takes more than the capacity
+        for i in 3..10 {
+            sender.send(i).unwrap();
+        }
+        let received: Vec<_> = (1..10).map(|_| receiver.try_recv().unwrap()).collect();
+        assert_eq!(received, (1..10).collect::<Vec<_>>());
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn cloned_senders_are_all_read() {
+        let (sender, receiver) = channel(2, OnFull::Grow);
+        let other = sender.clone();
+        sender.send(1).unwrap();
+        other.send(2).unwrap();
+        sender.send(3).unwrap();
+
+        let mut received: Vec<_> = (0..3).map(|_| receiver.try_recv().unwrap()).collect();
+        received.sort();
+        assert_eq!(received, [1, 2, 3]);
+
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        drop(other);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn full_queue_blocks_until_read() {
+        let (sender, receiver) = channel(2, OnFull::Block);
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                sender.send(i).unwrap();
+            }
+        });
+
+        for i in 0..1000 {
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(i));
+        }
+        producer.join().unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn full_queue_drops_the_values() {
+        let (sender, receiver) = channel(2, OnFull::Drop);
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(sender.len(), 2);
+        assert_eq!(receiver.try_recv(), Ok(0));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        drop(receiver);
+        assert_eq!(sender.send(4), Err(SendError(4)));
+    }
+
+    #[test]
+    fn receive_times_out_and_blocked_send_fails_without_receiver() {
+        let (sender, receiver) = channel::<u32>(1, OnFull::Block);
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        sender.send(1).unwrap();
+        let dropper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(receiver);
+        });
+        assert_eq!(sender.send(2), Err(SendError(2)));
+        dropper.join().unwrap();
+    }
+
+    #[test]
+    fn every_waiting_receiver_is_woken() {
+        let (sender, receiver) = channel(4, OnFull::Block);
+        let receiver = Arc::new(receiver);
+        let waiting = receiver.clone();
+        let patient = thread::spawn(move || {
+            let start = Instant::now();
+            let value = waiting.recv_timeout(Duration::from_secs(5));
+            (value, start.elapsed())
+        });
+
+        //the other waiter giving up doesn't keep the value from waking the first
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        sender.send(1).unwrap();
+        let (value, waited) = patient.join().unwrap();
+        assert_eq!(value, Ok(1));
+        assert!(waited < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn wakeup_is_called_for_every_value() {
+        use std::sync::atomic::AtomicUsize;
+
+        let (sender, receiver) = channel(4, OnFull::Grow);
+        sender.send(0).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        //a value is already waiting so it's called right away
+        receiver.set_wakeup(Some(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        receiver.set_wakeup(None);
+        sender.send(3).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+//the random source used for the handshake salts, clones share the same generator
+//so the configs stay cloneable and a test can make the handshakes reproducible
+#[derive(Clone)]
+pub struct RandomSource(Arc<Mutex<dyn RngCore + Send>>);
+
+impl RandomSource {
+    pub fn new<R: RngCore + Send + 'static>(rng: R) -> Self {
+        Self(Arc::new(Mutex::new(rng)))
+    }
+
+    //same seed gives the same salts, not meant for anything but tests
+    pub fn seeded(seed: u64) -> Self {
+        Self::new(StdRng::seed_from_u64(seed))
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        //a panic while generating doesn't leave the generator in an unusable state
+        let mut rng = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        rng.next_u64()
+    }
+}
+
+//a cryptographically secure generator seeded by the os
+impl Default for RandomSource {
+    fn default() -> Self {
+        Self::new(StdRng::from_entropy())
+    }
+}
+
+impl fmt::Debug for RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RandomSource")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let a = RandomSource::seeded(7);
+        let b = RandomSource::seeded(7);
+        assert_eq!(a.next_u64(), b.next_u64());
+
+        //clones draw from the same generator
+        let c = a.clone();
+        assert_ne!(a.next_u64(), c.next_u64());
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use super::Bytes;
+
+//queues the reads of every address separately and hands them out round robin,
+//so a single address flooding the socket can't starve the others during a tick.
+//the reads of a tick are bounded by the socket, see max_reads_per_tick of the config
+pub struct ReadScheduler {
+    //the reads with when they arrived and the socket they arrived on
+    queues: HashMap<SocketAddr, VecDeque<(Bytes, Instant, usize)>>,
+    //addresses with queued reads in the order they are served
+    order: VecDeque<SocketAddr>,
+}
+
+impl ReadScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, addr: SocketAddr, buffer: Bytes, received_at: Instant, ingress: usize) {
+        let queue = self.queues.entry(addr).or_default();
+        if queue.is_empty() {
+            self.order.push_back(addr);
+        }
+        queue.push_back((buffer, received_at, ingress));
+    }
+
+    pub fn pop(&mut self) -> Option<(SocketAddr, Bytes, Instant, usize)> {
+        let addr = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&addr)?;
+        let (buffer, received_at, ingress) = queue.pop_front()?;
+
+        //go to the back of the line if there is more to read
+        if queue.is_empty() {
+            self.queues.remove(&addr);
+        } else {
+            self.order.push_back(addr);
+        }
+
+        Some((addr, buffer, received_at, ingress))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_robin_between_addresses() {
+        let mut scheduler = ReadScheduler::new();
+        let spammy: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let quiet: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let now = Instant::now();
+
+        for i in 0..3 {
+            scheduler.push(spammy, vec![i], now, 0);
+        }
+        scheduler.push(quiet, vec![10], now, 0);
+
+        let order: Vec<(SocketAddr, u8)> = std::iter::from_fn(|| scheduler.pop())
+            .map(|(addr, buffer, ..)| (addr, buffer[0]))
+            .collect();
+        assert_eq!(
+            order,
+            vec![(spammy, 0), (quiet, 10), (spammy, 1), (spammy, 2)]
+        );
+        assert!(scheduler.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+
+use anyhow::bail;
+
+use super::{Bytes, FRAGMENT_SIZE};
+
+//the newest index and the count of inputs in front of every message
+const BUNDLE_HEADER_SIZE: usize = 5;
+//in front of every input
+const INPUT_HEADER_SIZE: usize = 2;
+
+//sends every input with the ones before it that weren't acked yet, like the inputs of a shooter sent
+//unreliably. a lost message costs nothing as long as one of the next ones arrives
+pub struct RedundantSender {
+    next_index: u32,
+    //the unacked inputs by index, oldest first
+    pending: VecDeque<(u32, Bytes)>,
+    max_inputs: usize,
+}
+
+impl RedundantSender {
+    //a message carries at most max_inputs inputs, the newest one included
+    pub fn new(max_inputs: usize) -> Self {
+        Self {
+            next_index: 0,
+            pending: VecDeque::new(),
+            max_inputs: max_inputs.clamp(1, u8::MAX as usize),
+        }
+    }
+
+    //the message to send unreliably with the input and the unacked ones before it. the oldest are left
+    //out so the message fits in a single fragment, a fragmented one would be lost with any fragment
+    pub fn push(&mut self, input: &[u8]) -> anyhow::Result<Bytes> {
+        if BUNDLE_HEADER_SIZE + INPUT_HEADER_SIZE + input.len() > FRAGMENT_SIZE {
+            bail!("an input can't be larger than a single fragment with its headers");
+        }
+
+        let index = self.next_index;
+        self.next_index = self.next_index.wrapping_add(1);
+        self.pending.push_back((index, input.to_vec()));
+        while self.pending.len() > self.max_inputs {
+            self.pending.pop_front();
+        }
+
+        //the newest inputs that fit, counted from the back
+        let mut size = BUNDLE_HEADER_SIZE;
+        let mut count = 0;
+        for (_, input) in self.pending.iter().rev() {
+            if size + INPUT_HEADER_SIZE + input.len() > FRAGMENT_SIZE {
+                break;
+            }
+            size += INPUT_HEADER_SIZE + input.len();
+            count += 1;
+        }
+
+        let mut message = Vec::with_capacity(size);
+        message.extend_from_slice(&index.to_le_bytes());
+        message.push(count as u8);
+        for (_, input) in self.pending.iter().skip(self.pending.len() - count) {
+            message.extend_from_slice(&(input.len() as u16).to_le_bytes());
+            message.extend_from_slice(input);
+        }
+        Ok(message)
+    }
+
+    //the receiver has the inputs up to the index, like the last input index the server puts in its
+    //snapshots. they aren't sent again
+    pub fn ack(&mut self, index: u32) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|(pending, _)| index.wrapping_sub(*pending) < u32::MAX / 2)
+        {
+            self.pending.pop_front();
+        }
+    }
+
+    pub fn unacked(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+//reads the messages of a RedundantSender, every input is returned once
+#[derive(Default)]
+pub struct RedundantReceiver {
+    last_index: Option<u32>,
+}
+
+impl RedundantReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //the inputs of the message that weren't read before with their index, oldest first. a message
+    //older than the last one read returns none
+    pub fn read<'a>(&mut self, message: &'a [u8]) -> anyhow::Result<Vec<(u32, &'a [u8])>> {
+        if message.len() < BUNDLE_HEADER_SIZE {
+            bail!("input message too short");
+        }
+        let newest = u32::from_le_bytes(message[..4].try_into().unwrap());
+        let count = message[4] as u32;
+
+        let mut inputs = Vec::with_capacity(count as usize);
+        let mut offset = BUNDLE_HEADER_SIZE;
+        for i in 0..count {
+            if message.len() < offset + INPUT_HEADER_SIZE {
+                bail!("input message truncated");
+            }
+            let len = u16::from_le_bytes(
+                message[offset..offset + INPUT_HEADER_SIZE]
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            offset += INPUT_HEADER_SIZE;
+            if message.len() < offset + len {
+                bail!("input message truncated");
+            }
+
+            let index = newest.wrapping_sub(count - 1 - i);
+            if self.is_new(index) {
+                inputs.push((index, &message[offset..offset + len]));
+            }
+            offset += len;
+        }
+
+        if self.is_new(newest) && count > 0 {
+            self.last_index = Some(newest);
+        }
+        Ok(inputs)
+    }
+
+    //the index to ack to the sender, None until an input was read
+    pub fn last_index(&self) -> Option<u32> {
+        self.last_index
+    }
+
+    fn is_new(&self, index: u32) -> bool {
+        match self.last_index {
+            Some(last) => index != last && index.wrapping_sub(last) < u32::MAX / 2,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_messages_are_covered_by_the_next_ones() {
+        let mut sender = RedundantSender::new(3);
+        let mut receiver = RedundantReceiver::new();
+
+        let first = sender.push(&[0]).unwrap();
+        assert_eq!(receiver.read(&first).unwrap(), vec![(0, &[0][..])]);
+
+        //the second message is lost, the third carries its input
+        sender.push(&[1]).unwrap();
+        let third = sender.push(&[2, 2]).unwrap();
+        assert_eq!(
+            receiver.read(&third).unwrap(),
+            vec![(1, &[1][..]), (2, &[2, 2][..])]
+        );
+        //a late or duplicated message returns nothing new
+        assert!(receiver.read(&first).unwrap().is_empty());
+        assert!(receiver.read(&third).unwrap().is_empty());
+        assert_eq!(receiver.last_index(), Some(2));
+    }
+
+    #[test]
+    fn acked_inputs_are_not_sent_again() {
+        let mut sender = RedundantSender::new(8);
+        for input in 0..4 {
+            sender.push(&[input]).unwrap();
+        }
+        sender.ack(2);
+        assert_eq!(sender.unacked(), 1);
+
+        let message = sender.push(&[4]).unwrap();
+        let inputs = RedundantReceiver::new().read(&message).unwrap();
+        assert_eq!(inputs, vec![(3, &[3][..]), (4, &[4][..])]);
+    }
+
+    #[test]
+    fn messages_fit_in_a_fragment() {
+        let mut sender = RedundantSender::new(8);
+        let input = vec![0; FRAGMENT_SIZE / 3];
+        for _ in 0..4 {
+            assert!(sender.push(&input).unwrap().len() <= FRAGMENT_SIZE);
+        }
+        assert!(sender.push(&vec![0; FRAGMENT_SIZE]).is_err());
+        assert!(RedundantReceiver::new()
+            .read(&[0, 0, 0, 0, 1, 5, 0])
+            .is_err());
+    }
+}
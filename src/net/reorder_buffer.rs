@@ -0,0 +1,174 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use super::{channel::ReadPayload, sequence::Sequence};
+
+//the sender stops resending a reliable packet after this, a message missing for longer won't arrive
+const GAP_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct HeldMessage {
+    //packets the message was sent in, the index of the next message follows them
+    len: u16,
+    payload: ReadPayload,
+    completed_at: Instant,
+}
+
+//releases whole reliable messages in the order they were submitted. every reliable packet takes the
+//next local sequence of the sender and the fragments of a message are sent one after the other, so
+//the sequence of the first packet is the index of the message
+pub struct ReorderBuffer {
+    next_index: u16,
+    held: HashMap<u16, HeldMessage>,
+}
+
+impl ReorderBuffer {
+    pub fn new() -> Self {
+        Self {
+            next_index: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    //index of the message a reliable packet belongs to
+    pub fn message_index(seq: u16, fragment_id: u8) -> u16 {
+        seq.wrapping_sub(fragment_id as u16)
+    }
+
+    //holds the completed message until the ones submitted before it were released
+    pub fn insert(
+        &mut self,
+        index: u16,
+        len: u16,
+        payload: ReadPayload,
+        now: Instant,
+        released: &mut VecDeque<ReadPayload>,
+    ) {
+        //the gap before it was already skipped, late is better than never
+        if Sequence::is_less_than(index, self.next_index) {
+            released.push_back(payload);
+            return;
+        }
+
+        self.held.insert(
+            index,
+            HeldMessage {
+                len,
+                payload,
+                completed_at: now,
+            },
+        );
+        self.release(now, released);
+    }
+
+    //messages that are next in order, a gap is skipped once it can't be filled anymore
+    pub fn release(&mut self, now: Instant, released: &mut VecDeque<ReadPayload>) {
+        loop {
+            while let Some(message) = self.held.remove(&self.next_index) {
+                self.next_index = self.next_index.wrapping_add(message.len);
+                released.push_back(message.payload);
+            }
+
+            let Some((&index, message)) = self.oldest_held() else {
+                return;
+            };
+            if now.saturating_duration_since(message.completed_at) < GAP_TIMEOUT {
+                return;
+            }
+            self.next_index = index;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    fn oldest_held(&self) -> Option<(&u16, &HeldMessage)> {
+        self.held.iter().min_by(|(a, _), (b, _)| {
+            if a == b {
+                Ordering::Equal
+            } else if Sequence::is_less_than(**a, **b) {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(value: u8) -> ReadPayload {
+        ReadPayload::Single(vec![value])
+    }
+
+    fn values(released: &mut VecDeque<ReadPayload>) -> Vec<u8> {
+        released
+            .drain(..)
+            .map(|payload| match payload {
+                ReadPayload::Single(buffer) => buffer[0],
+                ReadPayload::Parts(parts) => parts[0][0],
+                _ => panic!("unexpected payload"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_released_in_submit_order() {
+        let mut buffer = ReorderBuffer::new();
+        let mut released = VecDeque::new();
+        let now = Instant::now();
+
+        //a fragmented message in 3 packets, then two single ones that complete first
+        buffer.insert(3, 1, single(2), now, &mut released);
+        buffer.insert(4, 1, single(3), now, &mut released);
+        assert!(released.is_empty());
+
+        buffer.insert(
+            0,
+            3,
+            ReadPayload::Parts(vec![vec![1], vec![1]]),
+            now,
+            &mut released,
+        );
+        assert_eq!(values(&mut released), vec![1, 2, 3]);
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn lost_messages_are_skipped() {
+        let mut buffer = ReorderBuffer::new();
+        let mut released = VecDeque::new();
+        let now = Instant::now();
+
+        buffer.insert(2, 1, single(2), now, &mut released);
+        buffer.release(now + Duration::from_secs(1), &mut released);
+        assert!(released.is_empty());
+
+        buffer.release(now + GAP_TIMEOUT, &mut released);
+        assert_eq!(values(&mut released), vec![2]);
+
+        //the skipped message is delivered if it completes after all
+        buffer.insert(0, 1, single(0), now, &mut released);
+        assert_eq!(values(&mut released), vec![0]);
+    }
+
+    #[test]
+    fn indexes_wrap_around() {
+        let mut buffer = ReorderBuffer::new();
+        buffer.next_index = u16::MAX - 1;
+        let mut released = VecDeque::new();
+        let now = Instant::now();
+
+        buffer.insert(1, 1, single(3), now, &mut released);
+        buffer.insert(u16::MAX, 2, single(2), now, &mut released);
+        assert!(released.is_empty());
+
+        buffer.insert(u16::MAX - 1, 1, single(1), now, &mut released);
+        assert_eq!(values(&mut released), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::bail;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+
+use super::{connections::ConnectionId, Bytes};
+
+//requests and responses are sent in packets of their own type with the request id in front
+pub const REQUEST_ID_SIZE: usize = 4;
+
+pub fn write_request_id(request_id: u32, data: &[u8]) -> Bytes {
+    let mut buffer = Vec::with_capacity(REQUEST_ID_SIZE + data.len());
+    buffer.extend_from_slice(&request_id.to_le_bytes());
+    buffer.extend_from_slice(data);
+    buffer
+}
+
+//the request id and the data after it, None if the message is too short for the id
+pub fn split_request_id(mut message: Bytes) -> Option<(u32, Bytes)> {
+    if message.len() < REQUEST_ID_SIZE {
+        return None;
+    }
+
+    let request_id = u32::from_le_bytes(message[..REQUEST_ID_SIZE].try_into().unwrap());
+    _ = message.drain(..REQUEST_ID_SIZE);
+    Some((request_id, message))
+}
+
+//identifies a request received by the server, passed back to reply to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestHandle {
+    pub connection_id: ConnectionId,
+    pub request_id: u32,
+}
+
+//the response to a request sent by the client, it's never returned by a read
+pub struct ResponseHandle {
+    request_id: u32,
+    receiver: Receiver<Bytes>,
+    //the client forgets the request once the handle is dropped
+    _alive: Arc<()>,
+}
+
+//kept by the client until the response arrives, the handle was dropped or the request expired
+pub struct ResponseSender {
+    sender: Sender<Bytes>,
+    handle: Weak<()>,
+}
+
+impl ResponseSender {
+    pub fn send(self, response: Bytes) {
+        _ = self.sender.send(response);
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.handle.strong_count() == 0
+    }
+}
+
+impl ResponseHandle {
+    pub(crate) fn new(request_id: u32) -> (Self, ResponseSender) {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let alive = Arc::new(());
+        let response_sender = ResponseSender {
+            sender,
+            handle: Arc::downgrade(&alive),
+        };
+        let handle = Self {
+            request_id,
+            receiver,
+            _alive: alive,
+        };
+        (handle, response_sender)
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    //None if the server didn't reply before the timeout, fails once the request expired or the
+    //connection closed first
+    pub fn wait(&self, timeout: Duration) -> anyhow::Result<Option<Bytes>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(response) => Ok(Some(response)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!("the request expired or the connection closed before the response arrived")
+            }
+        }
+    }
+
+    pub fn try_get(&self) -> anyhow::Result<Option<Bytes>> {
+        match self.receiver.try_recv() {
+            Ok(response) => Ok(Some(response)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                bail!("the request expired or the connection closed before the response arrived")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_roundtrip() {
+        let message = write_request_id(7, &[1, 2, 3]);
+
+        assert_eq!(split_request_id(message), Some((7, vec![1, 2, 3])));
+        assert_eq!(split_request_id(vec![1, 2, 3]), None);
+        assert_eq!(split_request_id(vec![7, 0, 0, 0]), Some((7, vec![])));
+    }
+
+    #[test]
+    fn dropping_the_handle_is_seen_by_the_sender() {
+        let (handle, sender) = ResponseHandle::new(1);
+        assert!(!sender.is_dropped());
+
+        drop(handle);
+        assert!(sender.is_dropped());
+    }
+}
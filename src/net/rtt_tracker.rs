@@ -1,19 +1,75 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 pub const MAX_RTT: Duration = Duration::from_millis(100);
 pub const MIN_RTT: Duration = Duration::from_millis(10);
 pub const INFLATE_RTT_PERCENTAGE: u32 = 25; //25%
 
+//how long a reliable packet waits for its ack before it's resent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetransmitTimeout {
+    //the average of all the samples inflated by the percentage, steady but slow to follow changes
+    Average { inflate_percentage: u32 },
+    //the smoothed rtt plus the variance times the factor like TCP does (RFC 6298 uses 4), resends
+    //late on a jittery connection and early on a stable one
+    Smoothed { variance_factor: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RttConfig {
+    pub retransmit_timeout: RetransmitTimeout,
+    //the timeout is clamped between these, the maximum bounds the resend delay on a lossy connection
+    pub min_timeout: Duration,
+    pub max_timeout: Duration,
+    //a new sample moves the smoothed rtt by 1/n of its difference, 8 like TCP
+    pub rtt_smoothing: u32,
+    //a new sample moves the variance by 1/n of its difference, 4 like TCP
+    pub variance_smoothing: u32,
+    //the latest samples kept for the stats, 0 keeps none
+    pub sample_history: usize,
+}
+
+impl Default for RttConfig {
+    fn default() -> Self {
+        Self {
+            retransmit_timeout: RetransmitTimeout::Average {
+                inflate_percentage: INFLATE_RTT_PERCENTAGE,
+            },
+            min_timeout: MIN_RTT,
+            max_timeout: MAX_RTT,
+            rtt_smoothing: 8,
+            variance_smoothing: 4,
+            sample_history: 16,
+        }
+    }
+}
+
 pub struct RttTracker {
+    config: RttConfig,
     total_rtt: Duration,
     num_measurements: u32,
+    //moving averages of the rtt and of its deviation from it, weighted like TCP does
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    //the latest samples, oldest first
+    samples: VecDeque<Duration>,
 }
 
 impl RttTracker {
     pub fn new() -> Self {
+        Self::with_config(RttConfig::default())
+    }
+
+    pub fn with_config(config: RttConfig) -> Self {
         RttTracker {
             total_rtt: (MIN_RTT + MAX_RTT) / 2,
             num_measurements: 1,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            samples: VecDeque::with_capacity(config.sample_history),
+            config,
         }
     }
 
@@ -21,6 +77,42 @@ impl RttTracker {
         let rtt = received_at.duration_since(sent_at);
         self.total_rtt += rtt;
         self.num_measurements += 1;
+
+        match self.smoothed_rtt {
+            Some(smoothed) => {
+                let rtt_smoothing = self.config.rtt_smoothing.max(1);
+                let variance_smoothing = self.config.variance_smoothing.max(1);
+                let deviation = smoothed.abs_diff(rtt);
+                self.rtt_variance =
+                    (self.rtt_variance * (variance_smoothing - 1) + deviation) / variance_smoothing;
+                self.smoothed_rtt = Some((smoothed * (rtt_smoothing - 1) + rtt) / rtt_smoothing);
+            }
+            None => {
+                self.smoothed_rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+        }
+
+        if self.config.sample_history > 0 {
+            if self.samples.len() == self.config.sample_history {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(rtt);
+        }
+    }
+
+    //how much the rtt jumps around, zero until the first measurement
+    pub fn rtt_variance(&self) -> Duration {
+        self.rtt_variance
+    }
+
+    //None until the first measurement
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.samples.iter().copied()
     }
 
     pub fn average_rtt(&self) -> Duration {
@@ -28,12 +120,58 @@ impl RttTracker {
     }
 
     pub fn recommended_max_rtt(&self) -> Duration {
-        let average_rtt = self.total_rtt / self.num_measurements;
+        let timeout = match self.config.retransmit_timeout {
+            RetransmitTimeout::Average { inflate_percentage } => {
+                let average_rtt = self.average_rtt();
+                average_rtt + average_rtt * inflate_percentage / 100
+            }
+            //the average stands in until there is a sample
+            RetransmitTimeout::Smoothed { variance_factor } => match self.smoothed_rtt {
+                Some(smoothed) => smoothed + self.rtt_variance * variance_factor,
+                None => self.average_rtt(),
+            },
+        };
 
-        Duration::clamp(
-            average_rtt + (average_rtt / INFLATE_RTT_PERCENTAGE),
-            MIN_RTT,
-            MAX_RTT,
+        timeout.clamp(
+            self.config.min_timeout,
+            self.config.max_timeout.max(self.config.min_timeout),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothed_timeout_follows_the_variance() {
+        let mut tracker = RttTracker::with_config(RttConfig {
+            retransmit_timeout: RetransmitTimeout::Smoothed { variance_factor: 4 },
+            max_timeout: Duration::from_secs(1),
+            sample_history: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        tracker.record_rtt(now, now + Duration::from_millis(40));
+        //40ms plus 4 times the initial variance of half the sample
+        assert_eq!(tracker.recommended_max_rtt(), Duration::from_millis(120));
+
+        for rtt in [40, 40, 40, 40, 40, 40, 40, 40] {
+            tracker.record_rtt(now, now + Duration::from_millis(rtt));
+        }
+        assert!(tracker.recommended_max_rtt() < Duration::from_millis(60));
+        assert_eq!(tracker.samples().count(), 2);
+    }
+
+    #[test]
+    fn average_timeout_is_inflated() {
+        let mut tracker = RttTracker::new();
+        let now = Instant::now();
+        for _ in 0..9 {
+            tracker.record_rtt(now, now + Duration::from_millis(55));
+        }
+        assert_eq!(tracker.average_rtt(), Duration::from_millis(55));
+        assert_eq!(tracker.recommended_max_rtt(), Duration::from_micros(68_750));
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use super::{connections::ConnectionId, packets::SendEvent};
+
+//returned by Server::send_repeated, passed to Server::cancel_repeated to stop the sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScheduleHandle {
+    pub schedule_id: u32,
+}
+
+struct Schedule {
+    schedule_id: u32,
+    connection_id: ConnectionId,
+    send_event: SendEvent,
+    interval: Duration,
+    next_send: Instant,
+}
+
+//packets sent to a connection on an interval by the process thread, checked on every update so
+//the interval is rounded up to the update interval
+#[derive(Default)]
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+}
+
+impl Scheduler {
+    //the first send happens on the next update
+    pub fn add(
+        &mut self,
+        schedule_id: u32,
+        connection_id: ConnectionId,
+        send_event: SendEvent,
+        interval: Duration,
+        now: Instant,
+    ) {
+        self.schedules.push(Schedule {
+            schedule_id,
+            connection_id,
+            send_event,
+            interval,
+            next_send: now,
+        });
+    }
+
+    pub fn cancel(&mut self, schedule_id: u32) -> bool {
+        let count = self.schedules.len();
+        self.schedules
+            .retain(|schedule| schedule.schedule_id != schedule_id);
+        self.schedules.len() != count
+    }
+
+    //passes the due packets with their connection id to send, a schedule is dropped once send
+    //returns false because its connection is gone
+    pub fn send_due(
+        &mut self,
+        now: Instant,
+        mut send: impl FnMut(ConnectionId, SendEvent) -> bool,
+    ) {
+        self.schedules.retain_mut(|schedule| {
+            if now < schedule.next_send {
+                return true;
+            }
+
+            //sends missed while the loop was busy are skipped instead of bursting
+            schedule.next_send += schedule.interval;
+            if schedule.next_send <= now {
+                schedule.next_send = now + schedule.interval;
+            }
+            send(schedule.connection_id, schedule.send_event.clone())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{packets::construct_send_event, SendType};
+
+    use super::*;
+
+    fn due(scheduler: &mut Scheduler, now: Instant) -> Vec<u16> {
+        let mut connections = Vec::new();
+        scheduler.send_due(now, |connection_id, _| {
+            connections.push(connection_id.index);
+            true
+        });
+        connections
+    }
+
+    fn id(index: u16) -> ConnectionId {
+        ConnectionId {
+            index,
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn sends_repeat_until_cancelled() {
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        let interval = Duration::from_millis(100);
+        let send_event = construct_send_event(&[1], SendType::Unreliable).unwrap();
+        scheduler.add(0, id(5), send_event.clone(), interval, now);
+        scheduler.add(1, id(6), send_event, interval * 2, now);
+
+        assert_eq!(due(&mut scheduler, now), [5, 6]);
+        assert!(due(&mut scheduler, now).is_empty());
+        assert_eq!(due(&mut scheduler, now + interval), [5]);
+        //the missed sends aren't made up for
+        assert_eq!(due(&mut scheduler, now + interval * 10), [5, 6]);
+        assert!(due(&mut scheduler, now + interval * 10).is_empty());
+
+        assert!(scheduler.cancel(0));
+        assert!(!scheduler.cancel(0));
+        assert_eq!(due(&mut scheduler, now + interval * 20), [6]);
+    }
+
+    #[test]
+    fn schedule_of_a_missing_connection_is_dropped() {
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        let send_event = construct_send_event(&[1], SendType::Unreliable).unwrap();
+        scheduler.add(0, id(5), send_event, Duration::from_millis(100), now);
+
+        scheduler.send_due(now, |_, _| false);
+        assert!(due(&mut scheduler, now + Duration::from_secs(1)).is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     cell::RefCell,
+    collections::HashMap,
     rc::Rc,
     time::{Duration, Instant},
 };
@@ -7,19 +8,39 @@ use std::{
 use bit_field::BitField;
 use log::{debug, warn};
 
-use crate::net::{sequence::SequenceBuffer, BUFFER_SIZE};
+use crate::{
+    net::{
+        sequence::{Sequence, SequenceBuffer},
+        BUFFER_SIZE,
+    },
+    proto::ack::acked_by_bits,
+};
 
-use super::{header::Header, rtt_tracker::RttTracker, Bytes, BUFFER_WINDOW_SIZE};
+use super::{
+    debug_state::OutstandingPacket,
+    header::Header,
+    packets::Payload,
+    rtt_tracker::{RttConfig, RttTracker},
+    timer_wheel::TimerWheel,
+    Bytes, BUFFER_WINDOW_SIZE,
+};
 
 const SEND_TIMEOUT: Duration = Duration::from_secs(3);
+const REDELIVERY_TICK: Duration = Duration::from_millis(1);
 
 pub struct SendBuffer {
     pub payload: Rc<SendPayload>,
+    //when the socket last handed it to the OS
     pub sent_at: Option<Instant>,
+    //an ack can't tell which of the sends it's for, so it isn't used for the rtt
+    pub resent: bool,
+    //the key of a superseding send
+    pub key: Option<u64>,
 }
 
 pub struct SendPayload {
-    pub buffer: Bytes,
+    //shared with the packets that are queued on the socket
+    pub data: Payload,
 
     pub original_header: Header,
 }
@@ -33,33 +54,68 @@ pub struct SendBufferManager {
     pub buffers: SequenceBuffer<SendBuffer>,
     pub received_acks: SequenceBuffer<ReceivedAck>,
     pub trr_tracker: RttTracker,
+    //reliable packets created and the redeliveries of them, a redelivery is counted as a loss
+    pub packets_sent: u64,
+    pub packets_resent: u64,
+    //the sequence and the send time of every sent packet at the time its ack is due, a timer is
+    //stale once the packet was acked or sent again
+    redelivery_timers: TimerWheel<(u16, Instant)>,
+    expired_timers: Vec<(u16, Instant)>,
+    //the oldest packet that wasn't acked or given up on, set by the first send
+    unacked_from: Option<u16>,
+    //the latest sequence sent with a key, a packet is only still its key's while it's buffered
+    superseding: HashMap<u64, u16>,
+    //the packets that were given up on at the send timeout, taken by the channel
+    pub timed_out: Vec<u16>,
 }
 
 impl SendBufferManager {
     pub fn new() -> Self {
+        Self::with_rtt(RttConfig::default())
+    }
+
+    pub fn with_rtt(rtt: RttConfig) -> Self {
         SendBufferManager {
             buffers: SequenceBuffer::with_size(BUFFER_SIZE),
             received_acks: SequenceBuffer::with_size(BUFFER_SIZE),
-            trr_tracker: RttTracker::new(),
+            trr_tracker: RttTracker::with_config(rtt),
+            packets_sent: 0,
+            packets_resent: 0,
+            redelivery_timers: TimerWheel::new(REDELIVERY_TICK),
+            expired_timers: Vec::new(),
+            unacked_from: None,
+            superseding: HashMap::new(),
+            timed_out: Vec::new(),
         }
     }
 
     pub fn mark_sent(&mut self, seq: u16, sent_at: Instant) {
         if let Some(buffer) = self.buffers.get_mut(seq) {
+            buffer.resent |= buffer.sent_at.is_some();
             buffer.sent_at = Some(sent_at);
+            let deadline = sent_at + self.trr_tracker.recommended_max_rtt();
+            self.redelivery_timers.insert(deadline, (seq, sent_at));
         }
     }
 
-    pub fn push_send_buffer(&mut self, seq: u16, data: &[u8], header: &Header) -> Rc<SendPayload> {
+    pub fn push_send_buffer(
+        &mut self,
+        seq: u16,
+        data: Payload,
+        header: &Header,
+    ) -> Rc<SendPayload> {
         let send_buffer = SendBuffer {
             payload: Rc::new(SendPayload {
-                buffer: data.to_vec(),
+                data,
                 original_header: *header,
             }),
             sent_at: None,
+            resent: false,
+            key: None,
         };
 
         let payload = send_buffer.payload.clone();
+        self.packets_sent += 1;
 
         self.received_acks.insert(
             seq,
@@ -77,21 +133,38 @@ impl SendBufferManager {
         //only record the latest one..
         self.ack_packet(ack, Some(received_at));
 
-        //if its 0 that means nothing is acked..
-        if ack_bitfield > 0 {
-            for bit_pos in 0..32_u16 {
-                if ack_bitfield.get_bit(bit_pos as usize) {
-                    let seq = ack.wrapping_sub(bit_pos).wrapping_sub(1);
-                    self.ack_packet(seq, None);
-                }
-            }
+        for seq in acked_by_bits(ack, ack_bitfield) {
+            self.ack_packet(seq, None);
+        }
+    }
+
+    //a packet that isn't resent anymore, done like an acked one but without an rtt sample
+    pub fn give_up(&mut self, seq: u16) {
+        self.ack_packet(seq, None);
+    }
+
+    //the packet is the latest of the key, the previous one isn't resent anymore
+    pub fn supersede(&mut self, key: u64, seq: u16) {
+        if let Some(buffer) = self.buffers.get_mut(seq) {
+            buffer.key = Some(key);
+        }
+        let Some(previous) = self.superseding.insert(key, seq) else {
+            return;
+        };
+        //the slot can have another packet since
+        if self
+            .buffers
+            .get(previous)
+            .is_some_and(|buffer| buffer.key == Some(key))
+        {
+            self.give_up(previous);
         }
     }
 
     fn ack_packet(&mut self, ack: u16, received_at: Option<&Instant>) {
         if let Some(received_at) = received_at {
             if let Some(buffer) = self.buffers.take(ack) {
-                if let Some(sent_at) = buffer.sent_at {
+                if let Some(sent_at) = buffer.sent_at.filter(|_| !buffer.resent) {
                     self.trr_tracker.record_rtt(sent_at, *received_at);
                 }
             }
@@ -105,40 +178,113 @@ impl SendBufferManager {
         }
     }
 
-    pub fn get_redelivery_packet(
-        &mut self,
-        local_seq: u16,
-        marked_packets: &mut Vec<Rc<SendPayload>>,
-    ) {
-        //start at the last sent packet
+    //reliable packets that are still waiting for an ack, the ones past the send timeout were given up on
+    pub fn has_pending(&self, local_seq: u16) -> bool {
         let mut current_seq = local_seq;
 
-        //loop through all items in the current window
-        for i in 0..BUFFER_WINDOW_SIZE {
+        for _ in 0..BUFFER_WINDOW_SIZE {
             if let Some(received_ack) = self.received_acks.get(current_seq) {
-                //if the current packet timed out we can safely finish checking older ones because they expired too
                 if received_ack.packet_created_at.elapsed() > SEND_TIMEOUT {
                     break;
                 }
+                if !received_ack.acked {
+                    return true;
+                }
+            }
 
+            current_seq = current_seq.wrapping_sub(1);
+        }
+
+        false
+    }
+
+    //packets from the oldest unacked one up to the local seq. the buffers only have a slot per seq
+    //modulo their size, so a packet sent too far past an unacked one would take its slot
+    pub fn in_flight(&mut self, local_seq: u16) -> u16 {
+        let unacked_from = self.unacked_from.get_or_insert(local_seq);
+        while *unacked_from != local_seq {
+            let done = self
+                .received_acks
+                .get(*unacked_from)
+                .is_none_or(|received_ack| {
+                    received_ack.acked || received_ack.packet_created_at.elapsed() > SEND_TIMEOUT
+                });
+            if !done {
+                break;
+            }
+            Sequence::increment(unacked_from);
+        }
+
+        Sequence::distance(*unacked_from, local_seq)
+    }
+
+    //the unacked packets of the window, the expired ones included
+    pub fn outstanding(&self, local_seq: u16, now: Instant) -> Vec<OutstandingPacket> {
+        let mut outstanding = Vec::new();
+        let mut current_seq = local_seq;
+
+        for _ in 0..BUFFER_WINDOW_SIZE {
+            let received_ack = self.received_acks.get(current_seq);
+            let send_buffer = self.buffers.get(current_seq);
+            if let (Some(received_ack), Some(send_buffer)) = (received_ack, send_buffer) {
                 if !received_ack.acked {
-                    if let Some(send_buffer) = self.buffers.get_mut(current_seq) {
-                        //we're only interested in packets that were sent already
-                        if let Some(sent_at) = send_buffer.sent_at {
-                            if sent_at.elapsed() > self.trr_tracker.recommended_max_rtt() {
-                                //requeue the item
-                                marked_packets.push(send_buffer.payload.clone());
-
-                                //mark it as not sent again
-                                send_buffer.sent_at = None;
-                            }
-                        }
-                    }
+                    let age = now.saturating_duration_since(received_ack.packet_created_at);
+                    outstanding.push(OutstandingPacket {
+                        seq: current_seq,
+                        size: send_buffer.payload.data.len(),
+                        age,
+                        in_flight: send_buffer.sent_at.is_some(),
+                        expired: age > SEND_TIMEOUT,
+                    });
                 }
             }
 
             current_seq = current_seq.wrapping_sub(1);
         }
+
+        outstanding
+    }
+
+    //only the packets whose ack is overdue are looked at instead of the whole window
+    pub fn get_redelivery_packet(
+        &mut self,
+        local_seq: u16,
+        marked_packets: &mut Vec<Rc<SendPayload>>,
+    ) {
+        let first = marked_packets.len();
+        self.redelivery_timers
+            .advance(Instant::now(), &mut self.expired_timers);
+
+        for (seq, sent_at) in self.expired_timers.drain(..) {
+            let Some(received_ack) = self.received_acks.get(seq) else {
+                continue;
+            };
+            if received_ack.acked {
+                continue;
+            }
+            let Some(send_buffer) = self.buffers.get_mut(seq) else {
+                continue;
+            };
+            if send_buffer.sent_at != Some(sent_at) {
+                continue;
+            }
+            //given up on once it timed out
+            if received_ack.packet_created_at.elapsed() > SEND_TIMEOUT {
+                self.timed_out.push(seq);
+                continue;
+            }
+
+            //requeue the item
+            marked_packets.push(send_buffer.payload.clone());
+            self.packets_resent += 1;
+
+            //mark it as not sent again
+            send_buffer.sent_at = None;
+        }
+
+        //newest first like the window is walked elsewhere
+        marked_packets[first..]
+            .sort_by_key(|payload| local_seq.wrapping_sub(payload.original_header.seq));
     }
 }
 
@@ -156,41 +302,143 @@ mod tests {
     fn redelivery_packets_timeout() {
         let mut send_buffer = SendBufferManager::new();
         let mut packets = Vec::new();
-        let d = &[0];
-        let temp_header = construct_temp_header();
+        let d = Payload::new(&[0]);
+        let temp_header = construct_temp_header(0);
 
-        send_buffer.push_send_buffer(0, d, &temp_header);
+        send_buffer.push_send_buffer(0, d.clone(), &temp_header);
         send_buffer.mark_sent(0, Instant::now());
-        send_buffer.push_send_buffer(1, d, &temp_header);
+        send_buffer.push_send_buffer(1, d.clone(), &temp_header);
         send_buffer.mark_sent(1, Instant::now());
         thread::sleep(SEND_TIMEOUT);
 
-        send_buffer.push_send_buffer(2, d, &temp_header);
+        send_buffer.push_send_buffer(2, d.clone(), &temp_header);
         send_buffer.mark_sent(2, Instant::now() - MAX_RTT);
-        send_buffer.push_send_buffer(3, d, &temp_header);
+        send_buffer.push_send_buffer(3, d.clone(), &temp_header);
         send_buffer.mark_sent(3, Instant::now() - MAX_RTT);
-        send_buffer.push_send_buffer(4, d, &temp_header);
+        send_buffer.push_send_buffer(4, d.clone(), &temp_header);
         send_buffer.mark_sent(4, Instant::now() - MAX_RTT);
 
         //because the enough time for redelivery hasn't passed we expect 0 redelivery packets
         send_buffer.get_redelivery_packet(4, &mut packets);
         assert_eq!(packets.len(), 3);
+        send_buffer.timed_out.sort_unstable();
+        assert_eq!(send_buffer.timed_out, [0, 1]);
     }
 
     #[test]
-    fn redelivery_packets() {
+    fn superseded_packets_are_not_resent() {
         let mut send_buffer = SendBufferManager::new();
         let mut packets = Vec::new();
-        let d = &[0];
+        let d = Payload::new(&[0]);
+        let temp_header = construct_temp_header(0);
+
+        for seq in 0..4 {
+            send_buffer.push_send_buffer(seq, d.clone(), &temp_header);
+            send_buffer.mark_sent(seq, Instant::now() - MAX_RTT);
+        }
+        send_buffer.supersede(7, 0);
+        send_buffer.supersede(8, 1);
+        send_buffer.supersede(7, 2);
+
+        send_buffer.get_redelivery_packet(3, &mut packets);
+        assert_eq!(packets.len(), 3);
+        assert!(send_buffer.buffers.is_none(0));
+
+        //an acked packet of the key was no one's to give up
+        send_buffer.mark_acked_packets(2, 0, &Instant::now());
+        send_buffer.push_send_buffer(4, d.clone(), &temp_header);
+        send_buffer.supersede(7, 4);
+        assert!(send_buffer.buffers.is_some(1));
+        assert!(send_buffer.buffers.is_some(3));
+    }
+
+    #[test]
+    fn outstanding_packets_are_listed() {
+        let mut send_buffer = SendBufferManager::new();
+        let d = Payload::new(&[0, 1]);
+
+        for seq in 0..3 {
+            send_buffer.push_send_buffer(seq, d.clone(), &construct_temp_header(seq));
+        }
+        send_buffer.mark_sent(0, Instant::now());
+        send_buffer.mark_acked_packets(1, 0, &Instant::now());
+
+        let outstanding = send_buffer.outstanding(2, Instant::now());
+        let seqs: Vec<_> = outstanding.iter().map(|packet| packet.seq).collect();
+        assert_eq!(seqs, [2, 0]);
+        assert!(!outstanding[0].in_flight);
+        assert!(outstanding[1].in_flight);
+        assert_eq!(outstanding[1].size, 2);
+        assert!(!outstanding[1].expired);
+    }
+
+    #[test]
+    fn resent_packets_are_not_sampled() {
+        let mut send_buffer = SendBufferManager::new();
+        let d = Payload::new(&[0]);
+        let initial_rtt = send_buffer.trr_tracker.average_rtt();
+        let now = Instant::now();
+
+        //the ack could be for either send, the sample would be too short if it's for the first
+        send_buffer.push_send_buffer(0, d.clone(), &construct_temp_header(0));
+        send_buffer.mark_sent(0, now);
+        send_buffer.mark_sent(0, now + MAX_RTT);
+        send_buffer.mark_acked_packets(0, 0, &(now + MAX_RTT + Duration::from_millis(1)));
+        assert_eq!(send_buffer.trr_tracker.average_rtt(), initial_rtt);
+
+        send_buffer.push_send_buffer(1, d, &construct_temp_header(1));
+        send_buffer.mark_sent(1, now);
+        send_buffer.mark_acked_packets(1, 0, &(now + Duration::from_millis(1)));
+        assert!(send_buffer.trr_tracker.average_rtt() < initial_rtt);
+    }
+
+    #[test]
+    fn pending_until_acked() {
+        let mut send_buffer = SendBufferManager::new();
+        let d = Payload::new(&[0]);
+        assert!(!send_buffer.has_pending(0));
+
+        send_buffer.push_send_buffer(0, d.clone(), &construct_temp_header(0));
+        send_buffer.push_send_buffer(1, d, &construct_temp_header(1));
+        send_buffer.mark_acked_packets(1, 0, &Instant::now());
+        assert!(send_buffer.has_pending(2));
+
+        send_buffer.mark_acked_packets(0, 0, &Instant::now());
+        assert!(!send_buffer.has_pending(2));
+    }
+
+    #[test]
+    fn in_flight_wraps_around() {
+        let mut send_buffer = SendBufferManager::new();
+        let d = Payload::new(&[0]);
+        let first = u16::MAX - 3;
+        assert_eq!(send_buffer.in_flight(first), 0);
+
+        let mut seq = first;
+        for _ in 0..6 {
+            send_buffer.push_send_buffer(seq, d.clone(), &construct_temp_header(seq));
+            Sequence::increment(&mut seq);
+        }
+        assert_eq!(send_buffer.in_flight(seq), 6);
+
+        //an ack after a gap leaves the window where it is
+        send_buffer.mark_acked_packets(first + 1, 0, &Instant::now());
+        assert_eq!(send_buffer.in_flight(seq), 6);
 
-        let temp_header = construct_temp_header();
+        send_buffer.mark_acked_packets(first, 0, &Instant::now());
+        send_buffer.mark_acked_packets(u16::MAX - 1, 0, &Instant::now());
+        assert_eq!(send_buffer.in_flight(seq), 3);
+    }
 
-        send_buffer.push_send_buffer(0, d, &temp_header);
-        send_buffer.push_send_buffer(1, d, &temp_header);
-        send_buffer.push_send_buffer(2, d, &temp_header);
-        send_buffer.push_send_buffer(3, d, &temp_header);
-        send_buffer.push_send_buffer(4, d, &temp_header);
-        send_buffer.push_send_buffer(5, d, &temp_header);
+    #[test]
+    fn redelivery_packets() {
+        let mut send_buffer = SendBufferManager::new();
+        let mut packets = Vec::new();
+        let d = Payload::new(&[0]);
+
+        for seq in 0..=5 {
+            send_buffer.push_send_buffer(seq, d.clone(), &construct_temp_header(seq));
+        }
         send_buffer.mark_sent(0, Instant::now());
         send_buffer.mark_sent(1, Instant::now());
         send_buffer.mark_sent(2, Instant::now());
@@ -226,12 +474,12 @@ mod tests {
         ack_bitfield.set_bit(31, true);
 
         //prepare send buffers
-        let d = &[0];
-        let temp_header = construct_temp_header();
+        let d = Payload::new(&[0]);
+        let temp_header = construct_temp_header(0);
 
         let mut seq = 5;
         for i in 0..33 {
-            send_buffer.push_send_buffer(seq, d, &temp_header);
+            send_buffer.push_send_buffer(seq, d.clone(), &temp_header);
 
             seq = seq.wrapping_sub(1);
         }
@@ -268,9 +516,9 @@ mod tests {
         );
     }
 
-    fn construct_temp_header() -> Header {
+    fn construct_temp_header(seq: u16) -> Header {
         Header {
-            seq: 0,
+            seq,
             packet_type: crate::net::PacketType::PayloadReliable,
             fragment_group_id: 0,
             fragment_id: 0,
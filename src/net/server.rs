@@ -1,99 +1,1023 @@
-use std::{io, net::SocketAddr, sync::Arc, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::bail;
-use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
-use log::error;
+use anyhow::{anyhow, bail, Context};
+use log::{error, warn};
 
 use super::{
+    command::CommandSender,
+    conditioner::DebugConditions,
+    config::{PortFallback, ServerConfig, ServerConfigUpdate},
+    connections::{ConnectionId, HandshakeFailure, MAX_CONNECTION_SLOTS},
+    debug_state::ChannelDebugState,
+    disconnect::DisconnectReason,
+    event_log::RecentEvent,
     fragmentation_manager::FragmentationManager,
     header::SendType,
-    packets::{self, SendEvent},
-    server_process::{InternalServerEvent, ServerProcess},
+    invalid_packets::{InvalidPacketStats, SharedInvalidPacketStats},
+    limits::Limit,
+    packets::{self, SendEvent, SendHandle},
+    pipeline::IoProcess,
+    profile::{ProcessProfile, SharedPhaseSamples},
+    quality::ConnectionQuality,
+    queue::{self, OnFull, QueueReceiver, RecvTimeoutError},
+    request::{write_request_id, RequestHandle},
+    schedule::ScheduleHandle,
+    server_info::MAX_INFO_PAYLOAD_SIZE,
+    server_process::{InternalServerCommand, InternalServerEvent, ServerProcess},
+    socket::SocketError,
+    stats::{ConnectionStats, SendQueueStats, SharedSendQueueStats, SharedServerStats},
+    subscription::{subscription, EventReceiver},
+    tags::TagQueueEnds,
+    unconnected::{write_unconnected, MAX_UNCONNECTED_SIZE},
+    validation::Verdict,
+    Bytes, SessionToken,
 };
 
+//how long start waits for the process thread to set up the sockets
+const START_TIMEOUT: Duration = Duration::from_secs(50);
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum ServerEvent<'a> {
-    NewConnection(u32),
-    ConnectionLost(u32),
-    Receive(u32, &'a [u8]),
+    NewConnection(ConnectionId),
+    ConnectionLost(ConnectionId, DisconnectReason),
+    //the instant is when the packet completing the message arrived on the socket
+    Receive(ConnectionId, &'a [u8], Instant),
+    //the address keeps sending packets with another protocol id, only emitted if
+    //protocol_error_interval is set, the count is every invalid packet seen from it
+    ProtocolError(SocketAddr, u64),
+    //a message sent with Client::request, the handle is passed to reply
+    Request(RequestHandle, &'a [u8], Instant),
+    //a fragment of a message from the connection arrived, only emitted if receive_progress is set,
+    //the group with the fragments received and the total, a Receive follows once all arrived
+    ReceiveProgress(ConnectionId, u16, u8, u8),
+    //the payload the client set with Client::set_keepalive_payload, it comes with every keep alive
+    //of the client and is never part of the Receive events
+    KeepAlivePayload(ConnectionId, &'a [u8], Instant),
+    //the quality of the connection crossed one of the thresholds of the channel config
+    QualityChanged(ConnectionId, ConnectionQuality),
+    //a send or a receive of the socket failed, the server recovered from it
+    SocketError(SocketError),
+    //the connection sent a packet with another session key or a header that doesn't decode, the
+    //count is every malformed packet of the connection. it's kicked at malformed_packet_limit
+    MalformedPacket(ConnectionId, u64),
+    //the address asked to connect and was sent the challenge, only emitted if handshake_events is
+    //set like HandshakeFailed
+    Handshaking(SocketAddr),
+    //the address at the handshake didn't get connected
+    HandshakeFailed(SocketAddr, HandshakeFailure),
+    //the payload validator didn't accept a payload of the connection, a flagged one is still
+    //received after this
+    PayloadFlagged(ConnectionId, Verdict),
+    //a message of the connection went over the message limits and was dropped, the count is every
+    //message dropped for it. it's kicked at the violation limit
+    LimitExceeded(ConnectionId, Limit, u64),
+    //a send to the connection was dropped because the server holds too much for it already, the
+    //count is every send dropped for it. see the outbound limits of the config
+    OutboundLimitExceeded(ConnectionId, u64),
+    //packets of the connection arrived from another address and it moved there, with the old and
+    //the new address. only emitted with address_migration of the config
+    AddressChanged(ConnectionId, SocketAddr, SocketAddr),
 }
 
 pub struct Server {
-    in_sends: Sender<(SocketAddr, SendEvent)>,
-    out_events: Receiver<InternalServerEvent>,
+    in_sends: CommandSender<InternalServerCommand>,
+    out_events: QueueReceiver<InternalServerEvent>,
+    invalid_packets: SharedInvalidPacketStats,
+    send_queue: SharedSendQueueStats,
+    stats: SharedServerStats,
+    phase_samples: Vec<SharedPhaseSamples>,
+    next_schedule_id: AtomicU32,
+    next_send_id: AtomicU32,
+    //the workers of every server of the id space all get the max_clients of an update
+    id_shards: usize,
+    //the queues of the tags by name, created by the first tag or read of the name and forgotten
+    //once no connection has the tag and its events were read
+    tag_queues: Mutex<HashMap<String, TagQueueEnds>>,
+    //where the sockets ended up, the port can differ from the asked one with a port_fallback
+    local_addrs: Vec<SocketAddr>,
+}
+
+fn tag_queue(tag_queues: &mut HashMap<String, TagQueueEnds>, tag: &str) -> TagQueueEnds {
+    tag_queues
+        .entry(tag.to_string())
+        .or_insert_with(|| Arc::new(crossbeam_channel::bounded(queue::EVENT_CAPACITY)))
+        .clone()
+}
+
+//binds the address, or one of the fallback ports of the policy while the port is taken. the error
+//of the asked address is returned if none of them is free
+fn bind(addr: SocketAddr, fallback: PortFallback) -> io::Result<UdpSocket> {
+    let error = match UdpSocket::bind(addr) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && addr.port() != 0 => e,
+        result => return result,
+    };
+    let (nearby, ephemeral) = match fallback {
+        PortFallback::None => return Err(error),
+        PortFallback::Nearby(ports) => (ports, false),
+        PortFallback::NearbyThenEphemeral(ports) => (ports, true),
+    };
+    let nearby = (1..=nearby).filter_map(|offset| addr.port().checked_add(offset));
+    let ephemeral = ephemeral.then_some(0);
+    for port in nearby.chain(ephemeral) {
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(addr.ip(), port)) {
+            warn!(
+                "{addr} is in use, bound to {} instead",
+                socket.local_addr()?
+            );
+            return Ok(socket);
+        }
+    }
+    Err(error)
 }
 
 impl Server {
     pub fn start(addr: SocketAddr, max_clients: usize) -> anyhow::Result<Self> {
-        let (send_tx, send_rx) = crossbeam_channel::unbounded();
-        let (recv_tx, recv_rx) = crossbeam_channel::unbounded();
-
-        thread::spawn(
-            move || match ServerProcess::bind(addr, max_clients, send_tx, recv_rx) {
-                Ok(mut process) => {
-                    if let Err(e) = process.start() {
-                        error!("error while running starting: {}", e)
-                    }
-                }
-                Err(e) => error!("error while binding process: {}", e),
+        Self::start_with_config(
+            addr,
+            ServerConfig {
+                max_clients,
+                ..Default::default()
             },
-        );
+        )
+    }
+
+    pub fn start_with_config(addr: SocketAddr, config: ServerConfig) -> anyhow::Result<Self> {
+        let socket = bind(addr, config.port_fallback)
+            .with_context(|| format!("failed binding the server to {addr}"))?;
+        Self::start_with_socket(socket, config)
+    }
+
+    //listens on all of the addresses with one process, like an ipv4 and an ipv6 one or several ports.
+    //a client is answered from the address it sent to
+    pub fn start_multi(addrs: &[SocketAddr], max_clients: usize) -> anyhow::Result<Self> {
+        Self::start_multi_with_config(
+            addrs,
+            ServerConfig {
+                max_clients,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn start_multi_with_config(
+        addrs: &[SocketAddr],
+        config: ServerConfig,
+    ) -> anyhow::Result<Self> {
+        let sockets = addrs
+            .iter()
+            .map(|&addr| {
+                bind(addr, config.port_fallback)
+                    .with_context(|| format!("failed binding the server to {addr}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Self::start_with_sockets(sockets, config)
+    }
+
+    //runs on a socket that is already bound, options like the buffer sizes set on it are kept
+    pub fn start_with_socket(socket: UdpSocket, config: ServerConfig) -> anyhow::Result<Self> {
+        Self::start_with_sockets(vec![socket], config)
+    }
+
+    pub fn start_with_sockets(
+        sockets: Vec<UdpSocket>,
+        config: ServerConfig,
+    ) -> anyhow::Result<Self> {
+        if sockets.is_empty() {
+            bail!("a server needs at least one address to listen on");
+        }
+        let id_shards = config.workers.max(1) * config.id_space.parts.max(1) as usize;
+        if config.id_space.part >= config.id_space.parts.max(1) {
+            bail!("the part of the id space has to be below its parts");
+        }
+        if config.max_clients * id_shards > MAX_CONNECTION_SLOTS {
+            bail!("max_clients of every worker together can't be above {MAX_CONNECTION_SLOTS}");
+        }
+        let local_addrs = sockets
+            .iter()
+            .map(UdpSocket::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (send_tx, send_rx) = queue::channel(queue::EVENT_CAPACITY, OnFull::Block);
+        let (recv_tx, recv_rx) = queue::channel(queue::COMMAND_CAPACITY, OnFull::Block);
+
+        //the process is set up on its thread, an error there is handed back before the events
+        //end
+        let (failed_tx, failed_rx) = crossbeam_channel::bounded(1);
+        if config.workers > 1 {
+            thread::spawn(
+                move || match IoProcess::bind(sockets, config, send_tx, recv_rx) {
+                    Ok(mut process) => {
+                        if let Err(e) = process.start() {
+                            error!("error while running starting: {}", e)
+                        }
+                    }
+                    Err(e) => {
+                        let _ = failed_tx.send(e);
+                    }
+                },
+            );
+        } else {
+            thread::spawn(
+                move || match ServerProcess::bind(sockets, config, send_tx, recv_rx) {
+                    Ok(mut process) => {
+                        if let Err(e) = process.start() {
+                            error!("error while running starting: {}", e)
+                        }
+                    }
+                    Err(e) => {
+                        let _ = failed_tx.send(e);
+                    }
+                },
+            );
+        }
 
         //wait for the start event
-        match send_rx.recv_timeout(Duration::from_secs(50)) {
-            Ok(InternalServerEvent::ServerStarted) => {}
-            _ => panic!("failed waiting for start event"),
-        };
+        let (waker, invalid_packets, send_queue, stats, phase_samples) =
+            match send_rx.recv_timeout(START_TIMEOUT) {
+                Ok(InternalServerEvent::ServerStarted(
+                    waker,
+                    invalid_packets,
+                    send_queue,
+                    stats,
+                    phase_samples,
+                )) => (waker, invalid_packets, send_queue, stats, phase_samples),
+                Ok(_) => bail!("the server process sent an event before it started"),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(match failed_rx.recv() {
+                        Ok(e) => e.context("failed setting up the server process"),
+                        Err(_) => anyhow!("the server process stopped while starting"),
+                    })
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("the server process didn't start within {START_TIMEOUT:?}")
+                }
+            };
 
         Ok(Server {
-            in_sends: recv_tx,
+            in_sends: CommandSender::new(recv_tx, waker),
             out_events: send_rx,
+            invalid_packets,
+            send_queue,
+            stats,
+            phase_samples,
+            next_schedule_id: AtomicU32::new(0),
+            next_send_id: AtomicU32::new(0),
+            id_shards,
+            tag_queues: Mutex::new(HashMap::new()),
+            local_addrs,
         })
     }
 
+    //the address of the first socket, the one start bound
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    //the addresses of all sockets in the order they were given to start_multi
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
     pub fn send(&self, addr: SocketAddr, data: &[u8], send_type: SendType) -> anyhow::Result<()> {
         let send_event = packets::construct_send_event(data, send_type)?;
 
-        self.in_sends.send((addr, send_event))?;
+        self.in_sends
+            .send(InternalServerCommand::Send(addr, send_event))?;
+        Ok(())
+    }
+
+    //like send without copying the data, the packets hold on to it. a snapshot sent to every
+    //connection is in memory once
+    pub fn send_shared(
+        &self,
+        addr: SocketAddr,
+        data: Arc<[u8]>,
+        send_type: SendType,
+    ) -> anyhow::Result<()> {
+        let send_event = packets::construct_shared_send_event(data, send_type)?;
+
+        self.in_sends
+            .send(InternalServerCommand::Send(addr, send_event))?;
+        Ok(())
+    }
+
+    //the client only gets the newest value of the slot, it's sent unreliably and again every
+    //state_resend_interval until it's replaced. the client reads it with Client::latest
+    pub fn set_state(
+        &self,
+        connection_id: ConnectionId,
+        slot: u16,
+        value: &[u8],
+    ) -> anyhow::Result<()> {
+        self.in_sends.send(InternalServerCommand::SetState(
+            connection_id,
+            slot,
+            value.to_vec(),
+        ))?;
+        Ok(())
+    }
+
+    //holds the received payloads and the reliable sends of the connection until it's resumed, the
+    //unreliable sends are dropped, acks and keep alives continue so the connection stays alive.
+    //the payloads over the held read limits of the config are dropped or kick the connection
+    pub fn pause(&self, connection_id: ConnectionId) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::Pause(connection_id))?;
+        Ok(())
+    }
+
+    //delivers the payloads received while paused and sends the held ones
+    pub fn resume(&self, connection_id: ConnectionId) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::Resume(connection_id))?;
+        Ok(())
+    }
+
+    //sends the data to the connection on every interval until cancelled or the connection is gone,
+    //the first send happens on the next update of the server
+    pub fn send_repeated(
+        &self,
+        connection_id: ConnectionId,
+        data: &[u8],
+        interval: Duration,
+        send_type: SendType,
+    ) -> anyhow::Result<ScheduleHandle> {
+        if interval.is_zero() {
+            bail!("the interval of a repeated send can't be zero");
+        }
+        let send_event = packets::construct_send_event(data, send_type)?;
+
+        let schedule_id = self.next_schedule_id.fetch_add(1, Ordering::Relaxed);
+        self.in_sends.send(InternalServerCommand::SendRepeated(
+            schedule_id,
+            connection_id,
+            send_event,
+            interval,
+        ))?;
+        Ok(ScheduleHandle { schedule_id })
+    }
+
+    //a reliable message too large for one packet that cancel_send can take back while it's in
+    //flight, like a snapshot a newer one replaces. a channel remembers its 32 latest of them
+    pub fn send_cancellable(&self, addr: SocketAddr, data: &[u8]) -> anyhow::Result<SendHandle> {
+        if !FragmentationManager::should_fragment(data.len()) {
+            bail!("only messages sent in fragments can be cancelled");
+        }
+        let send_event = packets::construct_send_event(data, SendType::Reliable)?;
+
+        let send_id = self.next_send_id.fetch_add(1, Ordering::Relaxed);
+        self.in_sends.send(InternalServerCommand::SendCancellable(
+            addr, send_id, send_event,
+        ))?;
+        Ok(SendHandle { send_id })
+    }
+
+    //the fragments that weren't acked yet are dropped and the client drops the ones it has, a
+    //message that was already delivered stays delivered
+    pub fn cancel_send(&self, addr: SocketAddr, handle: SendHandle) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::CancelSend(addr, handle.send_id))?;
+        Ok(())
+    }
+
+    //a reliable message that replaces the previous one sent with the key, which isn't resent anymore
+    //if it wasn't acked yet. for values where only the latest one matters, like the inventory. the
+    //data has to fit in one packet and the channel can't have ordered_reliable
+    pub fn send_superseding(&self, addr: SocketAddr, key: u64, data: &[u8]) -> anyhow::Result<()> {
+        if FragmentationManager::should_fragment(data.len()) {
+            bail!("a superseding message has to fit in one packet");
+        }
+        let send_event = packets::construct_send_event(data, SendType::Reliable)?;
+
+        self.in_sends.send(InternalServerCommand::SendSuperseding(
+            addr, key, send_event,
+        ))?;
+        Ok(())
+    }
+
+    pub fn cancel_repeated(&self, handle: ScheduleHandle) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::CancelRepeated(handle.schedule_id))?;
+        Ok(())
+    }
+
+    //the keys of the filtered broadcasts the connection receives, replaces the previous ones
+    pub fn set_interest(
+        &self,
+        connection_id: ConnectionId,
+        keys: impl IntoIterator<Item = u64>,
+    ) -> anyhow::Result<()> {
+        self.in_sends.send(InternalServerCommand::SetInterest(
+            connection_id,
+            keys.into_iter().collect(),
+        ))?;
+        Ok(())
+    }
+
+    //sends the data to every connection whose interests contain the key
+    pub fn broadcast_filtered(
+        &self,
+        key: u64,
+        data: &[u8],
+        send_type: SendType,
+    ) -> anyhow::Result<()> {
+        let send_event = packets::construct_send_event(data, send_type)?;
+
+        self.in_sends
+            .send(InternalServerCommand::BroadcastFiltered(key, send_event))?;
+        Ok(())
+    }
+
+    //the response is sent reliably and passed to the handle returned by Client::request
+    pub fn reply(&self, handle: RequestHandle, data: &[u8]) -> anyhow::Result<()> {
+        let send_event = packets::construct_send_event(
+            &write_request_id(handle.request_id, data),
+            SendType::Reliable,
+        )?;
+
+        self.in_sends.send(InternalServerCommand::Reply(
+            handle.connection_id,
+            send_event,
+        ))?;
         Ok(())
     }
 
+    //the largest message that can be passed to send
+    pub fn max_message_size(&self) -> usize {
+        FragmentationManager::max_message_size()
+    }
+
+    //the ranges of data of the length to send as separate messages when it's larger than
+    //max_message_size
+    pub fn split_hint(&self, len: usize) -> Vec<Range<usize>> {
+        FragmentationManager::split_hint(len)
+    }
+
+    //send a raw packet that skips the handshake, the address doesn't have to be connected
+    pub fn send_unconnected(&self, addr: SocketAddr, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() > MAX_UNCONNECTED_SIZE {
+            bail!("unconnected packets can be at most {MAX_UNCONNECTED_SIZE} bytes long");
+        }
+
+        self.in_sends.send(InternalServerCommand::SendUnconnected(
+            addr,
+            write_unconnected(data),
+        ))?;
+        Ok(())
+    }
+
+    //the handler runs on the server thread for every unconnected packet from an address
+    //that isn't connected, the returned bytes are sent back as an unconnected packet
+    pub fn set_unconnected_handler<F>(&self, handler: F) -> anyhow::Result<()>
+    where
+        F: FnMut(SocketAddr, &[u8]) -> Option<Bytes> + Send + Sync + 'static,
+    {
+        self.in_sends
+            .send(InternalServerCommand::SetUnconnectedHandler(Some(
+                Box::new(handler),
+            )))?;
+        Ok(())
+    }
+
+    pub fn clear_unconnected_handler(&self) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::SetUnconnectedHandler(None))?;
+        Ok(())
+    }
+
+    //application data like the map name returned to server info queries
+    pub fn set_server_info_payload(&self, payload: &[u8]) -> anyhow::Result<()> {
+        if payload.len() > MAX_INFO_PAYLOAD_SIZE {
+            bail!("server info payload can be at most {MAX_INFO_PAYLOAD_SIZE} bytes long");
+        }
+
+        self.in_sends
+            .send(InternalServerCommand::SetServerInfoPayload(
+                payload.to_vec(),
+            ))?;
+        Ok(())
+    }
+
+    //sends the reason to the client and removes the connection, ConnectionLost is emitted for it too
+    pub fn disconnect(
+        &self,
+        connection_id: ConnectionId,
+        reason: DisconnectReason,
+    ) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::Disconnect(connection_id, reason))?;
+        Ok(())
+    }
+
+    //sends every client a disconnect with DisconnectCode::Shutdown and waits for the process to end,
+    //the port is free once this returns. the events that weren't read are dropped. dropping the
+    //server ends it without telling the clients, they are closed by their idle timeout
+    pub fn shutdown(self, timeout: Duration) -> anyhow::Result<()> {
+        self.in_sends.send(InternalServerCommand::Shutdown)?;
+        let deadline = Instant::now() + timeout;
+        let out_events = &self.out_events;
+        loop {
+            match out_events.recv_deadline(deadline) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => bail!("the server didn't shut down in time"),
+            }
+        }
+    }
+
+    //simulate latency, jitter and packet loss on both directions of a live connection
+    pub fn set_debug_conditions(
+        &self,
+        connection_id: ConnectionId,
+        latency: Duration,
+        jitter: Duration,
+        loss: f32,
+    ) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&loss) {
+            bail!("loss has to be between 0.0 and 1.0");
+        }
+
+        self.in_sends
+            .send(InternalServerCommand::SetDebugConditions(
+                connection_id,
+                Some(DebugConditions {
+                    latency,
+                    jitter,
+                    loss,
+                }),
+            ))?;
+        Ok(())
+    }
+
+    pub fn clear_debug_conditions(&self, connection_id: ConnectionId) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::SetDebugConditions(
+                connection_id,
+                None,
+            ))?;
+        Ok(())
+    }
+
+    //changes the config without restarting, the connected clients stay connected
+    pub fn update_config(&self, update: ServerConfigUpdate) -> anyhow::Result<()> {
+        if update
+            .max_clients
+            .is_some_and(|max_clients| max_clients * self.id_shards > MAX_CONNECTION_SLOTS)
+        {
+            bail!("max_clients of every worker together can't be above {MAX_CONNECTION_SLOTS}");
+        }
+        if let Some(Some(conditions)) = update.debug_conditions {
+            if !(0.0..=1.0).contains(&conditions.loss) {
+                bail!("loss has to be between 0.0 and 1.0");
+            }
+        }
+
+        self.in_sends
+            .send(InternalServerCommand::UpdateConfig(update))?;
+        Ok(())
+    }
+
+    //datagrams that were dropped because they didn't start with our protocol id
+    pub fn invalid_packet_stats(&self) -> InvalidPacketStats {
+        self.invalid_packets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    //the packets waiting for the socket, with the highest depth and age seen
+    pub fn send_queue_stats(&self) -> SendQueueStats {
+        *self.send_queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //the sequencing state of a connection's channel, for diagnosing stuck reliable messages
+    pub fn debug_dump(&self, connection_id: ConnectionId) -> anyhow::Result<ChannelDebugState> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        self.in_sends
+            .send(InternalServerCommand::DebugDump(connection_id, sender))?;
+
+        match receiver.recv_timeout(Duration::from_secs(5)) {
+            Ok(state) => Ok(state),
+            Err(RecvTimeoutError::Timeout) => bail!("the server didn't respond"),
+            Err(RecvTimeoutError::Disconnected) => bail!("connection {connection_id} not found"),
+        }
+    }
+
+    //the latest resends, send timeouts, malformed packets and window stalls of a connection,
+    //oldest first. how many are kept is the event_log_size of the channel config
+    pub fn recent_events(&self, connection_id: ConnectionId) -> anyhow::Result<Vec<RecentEvent>> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        self.in_sends
+            .send(InternalServerCommand::RecentEvents(connection_id, sender))?;
+
+        match receiver.recv_timeout(Duration::from_secs(5)) {
+            Ok(events) => Ok(events),
+            Err(RecvTimeoutError::Timeout) => bail!("the server didn't respond"),
+            Err(RecvTimeoutError::Disconnected) => bail!("connection {connection_id} not found"),
+        }
+    }
+
+    //the counters of a connected connection as of the last update of the server
+    pub fn connection_stats(&self, connection_id: ConnectionId) -> Option<ConnectionStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&connection_id)
+            .cloned()
+    }
+
+    //the client prints the same one, for matching the logs of both sides. None if the connection
+    //isn't connected
+    pub fn session_token(&self, connection_id: ConnectionId) -> Option<SessionToken> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&connection_id)
+            .map(|stats| stats.session_token)
+    }
+
+    //None if the connection isn't connected
+    //where the threads of the server spend their time, one profile per worker
+    pub fn process_profiles(&self) -> Vec<ProcessProfile> {
+        self.phase_samples
+            .iter()
+            .map(|samples| samples.lock().unwrap_or_else(|e| e.into_inner()).profile())
+            .collect()
+    }
+
+    pub fn recommended_send_rate(&self, connection_id: ConnectionId) -> Option<u32> {
+        self.connection_stats(connection_id)
+            .map(|stats| stats.recommended_send_rate)
+    }
+
+    //the events of the connection are read with read_tagged of the tag from now on, its
+    //NewConnection was already read with read. a connection has one tag, the previous one is
+    //replaced. the queue of a tag holds EVENT_CAPACITY events, past it the server waits for them
+    //to be read like it does for read
+    pub fn tag(&self, connection_id: ConnectionId, tag: &str) -> anyhow::Result<()> {
+        let queue = {
+            let mut tag_queues = self.tag_queues.lock().unwrap_or_else(|e| e.into_inner());
+            //the tags without connections, the events left in them are read first
+            tag_queues.retain(|_, queue| Arc::strong_count(queue) > 1 || !queue.1.is_empty());
+            tag_queue(&mut tag_queues, tag)
+        };
+        self.in_sends.send(InternalServerCommand::Tag(
+            connection_id,
+            Some((tag.to_string(), queue)),
+        ))?;
+        Ok(())
+    }
+
+    //a receiver that gets a copy of every event from now on, next to the ones read with read and
+    //read_tagged, so separate systems can each follow all of the events
+    pub fn subscribe(&self) -> anyhow::Result<EventReceiver> {
+        let (receiver, sender) = subscription();
+        self.in_sends
+            .send(InternalServerCommand::Subscribe(sender))?;
+        Ok(receiver)
+    }
+
+    //the events of the connection are read with read again
+    pub fn untag(&self, connection_id: ConnectionId) -> anyhow::Result<()> {
+        self.in_sends
+            .send(InternalServerCommand::Tag(connection_id, None))?;
+        Ok(())
+    }
+
+    //like read for the connections with the tag, every tag can be read from a thread of its own.
+    //the ConnectionLost of a tagged connection is its last event here
+    pub fn read_tagged<'a>(
+        &self,
+        tag: &str,
+        dest: &'a mut [u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Option<ServerEvent<'a>>> {
+        let queue = tag_queue(
+            &mut self.tag_queues.lock().unwrap_or_else(|e| e.into_inner()),
+            tag,
+        );
+        to_server_event(queue.1.recv_timeout(timeout), dest)
+    }
+
+    //called from the server threads whenever an event for read is queued, so a loop of the
+    //application can sleep on its own handle (an eventfd, a pipe, a waker) and call read with a zero
+    //timeout when it fires. it runs for every event so it should only signal, and another read can
+    //take the event first so the read may find nothing. the events of tag queues and subscribers
+    //don't call it
+    pub fn set_wakeup<F>(&self, wakeup: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.out_events.set_wakeup(Some(Arc::new(wakeup)));
+    }
+
+    pub fn clear_wakeup(&self) {
+        self.out_events.set_wakeup(None);
+    }
+
+    //an event can be read without waiting, or the server has stopped
+    pub(crate) fn has_events(&self) -> bool {
+        self.out_events.is_ready()
+    }
+
+    //the events have to be read, once 4096 of them are waiting the server holds its processing until
+    //the game read some
     pub fn read<'a>(
         &self,
         dest: &'a mut [u8],
         timeout: Duration,
     ) -> anyhow::Result<Option<ServerEvent<'a>>> {
-        match self.out_events.recv_timeout(timeout) {
-            Ok(InternalServerEvent::Receive(client_id, buffer)) => {
-                if dest.len() < buffer.len() {
-                    bail!("destination size is not big enough.")
-                }
-                dest[..buffer.len()].copy_from_slice(&buffer);
-                Ok(Some(ServerEvent::Receive(client_id, &dest[..buffer.len()])))
-            }
-            Ok(InternalServerEvent::ReceiveParts(client_id, parts)) => {
-                let mut bytes_offset = 0;
-                for part in parts {
-                    let part_len = part.len();
+        let event = self.out_events.recv_timeout(timeout);
+        to_server_event(event, dest)
+    }
+
+    //gathers every event that arrives before the deadline instead of returning after the first one,
+    //the payloads are appended to dest which the returned events borrow from
+    pub fn read_until<'a>(
+        &self,
+        deadline: Instant,
+        dest: &'a mut Bytes,
+        events: &mut Vec<ServerEvent<'a>>,
+    ) -> anyhow::Result<()> {
+        dest.clear();
+        events.clear();
 
-                    if bytes_offset + part_len <= dest.len() {
-                        dest[bytes_offset..bytes_offset + part_len].copy_from_slice(&part);
-                        bytes_offset += part_len;
-                    } else {
-                        bail!("destination size is not big enough.")
+        //the events can only borrow dest once all of the payloads are copied
+        let mut received = Vec::new();
+        let out_events = &self.out_events;
+        loop {
+            match out_events.recv_deadline(deadline) {
+                Ok(InternalServerEvent::Receive(client_id, buffer, received_at)) => {
+                    let start = dest.len();
+                    dest.extend_from_slice(&buffer);
+                    received.push(ReadUntilEvent::Receive(
+                        client_id,
+                        start..dest.len(),
+                        received_at,
+                    ));
+                }
+                Ok(InternalServerEvent::ReceiveParts(client_id, parts, received_at)) => {
+                    let start = dest.len();
+                    for part in parts {
+                        dest.extend_from_slice(&part);
                     }
+                    received.push(ReadUntilEvent::Receive(
+                        client_id,
+                        start..dest.len(),
+                        received_at,
+                    ));
+                }
+                Ok(InternalServerEvent::Request(client_id, request_id, data, received_at)) => {
+                    let start = dest.len();
+                    dest.extend_from_slice(&data);
+                    received.push(ReadUntilEvent::Request(
+                        RequestHandle {
+                            connection_id: client_id,
+                            request_id,
+                        },
+                        start..dest.len(),
+                        received_at,
+                    ));
+                }
+                Ok(InternalServerEvent::KeepAlivePayload(client_id, buffer, received_at)) => {
+                    let start = dest.len();
+                    dest.extend_from_slice(&buffer);
+                    received.push(ReadUntilEvent::KeepAlivePayload(
+                        client_id,
+                        start..dest.len(),
+                        received_at,
+                    ));
+                }
+                Ok(InternalServerEvent::NewConnection(client_id)) => {
+                    received.push(ReadUntilEvent::NewConnection(client_id))
+                }
+                Ok(InternalServerEvent::ConnectionLost(client_id, reason)) => {
+                    received.push(ReadUntilEvent::ConnectionLost(client_id, reason))
+                }
+                Ok(InternalServerEvent::ProtocolError(addr, count)) => {
+                    received.push(ReadUntilEvent::ProtocolError(addr, count))
+                }
+                Ok(InternalServerEvent::QualityChanged(client_id, quality)) => {
+                    received.push(ReadUntilEvent::QualityChanged(client_id, quality))
+                }
+                Ok(InternalServerEvent::SocketError(e)) => {
+                    received.push(ReadUntilEvent::SocketError(e))
+                }
+                Ok(InternalServerEvent::MalformedPacket(client_id, count)) => {
+                    received.push(ReadUntilEvent::MalformedPacket(client_id, count))
+                }
+                Ok(InternalServerEvent::Handshaking(addr)) => {
+                    received.push(ReadUntilEvent::Handshaking(addr))
+                }
+                Ok(InternalServerEvent::PayloadFlagged(client_id, verdict)) => {
+                    received.push(ReadUntilEvent::PayloadFlagged(client_id, verdict))
                 }
+                Ok(InternalServerEvent::LimitExceeded(client_id, limit, count)) => {
+                    received.push(ReadUntilEvent::LimitExceeded(client_id, limit, count))
+                }
+                Ok(InternalServerEvent::OutboundLimitExceeded(client_id, count)) => {
+                    received.push(ReadUntilEvent::OutboundLimitExceeded(client_id, count))
+                }
+                Ok(InternalServerEvent::AddressChanged(client_id, old, new)) => {
+                    received.push(ReadUntilEvent::AddressChanged(client_id, old, new))
+                }
+                Ok(InternalServerEvent::HandshakeFailed(addr, failure)) => {
+                    received.push(ReadUntilEvent::HandshakeFailed(addr, failure))
+                }
+                Ok(InternalServerEvent::ReceiveProgress(
+                    client_id,
+                    group,
+                    received_count,
+                    total,
+                )) => received.push(ReadUntilEvent::ReceiveProgress(
+                    client_id,
+                    group,
+                    received_count,
+                    total,
+                )),
+                //start takes it before the server is handed out, a second one has nothing to report
+                Ok(InternalServerEvent::ServerStarted(..)) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => bail!("channel to thread lost"),
+            }
+        }
+
+        let dest: &'a [u8] = dest;
+        events.extend(received.into_iter().map(|event| match event {
+            ReadUntilEvent::NewConnection(client_id) => ServerEvent::NewConnection(client_id),
+            ReadUntilEvent::ConnectionLost(client_id, reason) => {
+                ServerEvent::ConnectionLost(client_id, reason)
+            }
+            ReadUntilEvent::Receive(client_id, range, received_at) => {
+                ServerEvent::Receive(client_id, &dest[range], received_at)
+            }
+            ReadUntilEvent::Request(handle, range, received_at) => {
+                ServerEvent::Request(handle, &dest[range], received_at)
+            }
+            ReadUntilEvent::KeepAlivePayload(client_id, range, received_at) => {
+                ServerEvent::KeepAlivePayload(client_id, &dest[range], received_at)
+            }
+            ReadUntilEvent::ProtocolError(addr, count) => ServerEvent::ProtocolError(addr, count),
+            ReadUntilEvent::QualityChanged(client_id, quality) => {
+                ServerEvent::QualityChanged(client_id, quality)
+            }
+            ReadUntilEvent::SocketError(e) => ServerEvent::SocketError(e),
+            ReadUntilEvent::MalformedPacket(client_id, count) => {
+                ServerEvent::MalformedPacket(client_id, count)
+            }
+            ReadUntilEvent::Handshaking(addr) => ServerEvent::Handshaking(addr),
+            ReadUntilEvent::PayloadFlagged(client_id, verdict) => {
+                ServerEvent::PayloadFlagged(client_id, verdict)
+            }
+            ReadUntilEvent::LimitExceeded(client_id, limit, count) => {
+                ServerEvent::LimitExceeded(client_id, limit, count)
+            }
+            ReadUntilEvent::OutboundLimitExceeded(client_id, count) => {
+                ServerEvent::OutboundLimitExceeded(client_id, count)
+            }
+            ReadUntilEvent::AddressChanged(client_id, old, new) => {
+                ServerEvent::AddressChanged(client_id, old, new)
+            }
+            ReadUntilEvent::HandshakeFailed(addr, failure) => {
+                ServerEvent::HandshakeFailed(addr, failure)
+            }
+            ReadUntilEvent::ReceiveProgress(client_id, group, received, total) => {
+                ServerEvent::ReceiveProgress(client_id, group, received, total)
+            }
+        }));
 
-                Ok(Some(ServerEvent::Receive(client_id, &dest[..bytes_offset])))
+        Ok(())
+    }
+}
+
+//the event read from one of the queues, the payload is copied to dest
+pub fn to_server_event(
+    event: Result<InternalServerEvent, RecvTimeoutError>,
+    dest: &mut [u8],
+) -> anyhow::Result<Option<ServerEvent<'_>>> {
+    match event {
+        Ok(InternalServerEvent::Receive(client_id, buffer, received_at)) => {
+            if dest.len() < buffer.len() {
+                bail!("destination size is not big enough.")
             }
-            Ok(InternalServerEvent::NewConnection(client_id)) => {
-                Ok(Some(ServerEvent::NewConnection(client_id)))
+            dest[..buffer.len()].copy_from_slice(&buffer);
+            Ok(Some(ServerEvent::Receive(
+                client_id,
+                &dest[..buffer.len()],
+                received_at,
+            )))
+        }
+        Ok(InternalServerEvent::ReceiveParts(client_id, parts, received_at)) => {
+            let mut bytes_offset = 0;
+            for part in parts {
+                let part_len = part.len();
+
+                if bytes_offset + part_len <= dest.len() {
+                    dest[bytes_offset..bytes_offset + part_len].copy_from_slice(&part);
+                    bytes_offset += part_len;
+                } else {
+                    bail!("destination size is not big enough.")
+                }
             }
-            Ok(InternalServerEvent::ConnectionLost(client_id)) => {
-                Ok(Some(ServerEvent::ConnectionLost(client_id)))
+
+            Ok(Some(ServerEvent::Receive(
+                client_id,
+                &dest[..bytes_offset],
+                received_at,
+            )))
+        }
+        Ok(InternalServerEvent::Request(client_id, request_id, data, received_at)) => {
+            if dest.len() < data.len() {
+                bail!("destination size is not big enough.")
             }
-            Err(RecvTimeoutError::Timeout) => Ok(None),
-            _ => bail!("channel to thread lost"),
+            dest[..data.len()].copy_from_slice(&data);
+            Ok(Some(ServerEvent::Request(
+                RequestHandle {
+                    connection_id: client_id,
+                    request_id,
+                },
+                &dest[..data.len()],
+                received_at,
+            )))
         }
+        Ok(InternalServerEvent::KeepAlivePayload(client_id, buffer, received_at)) => {
+            if dest.len() < buffer.len() {
+                bail!("destination size is not big enough.")
+            }
+            dest[..buffer.len()].copy_from_slice(&buffer);
+            Ok(Some(ServerEvent::KeepAlivePayload(
+                client_id,
+                &dest[..buffer.len()],
+                received_at,
+            )))
+        }
+        Ok(InternalServerEvent::NewConnection(client_id)) => {
+            Ok(Some(ServerEvent::NewConnection(client_id)))
+        }
+        Ok(InternalServerEvent::ConnectionLost(client_id, reason)) => {
+            Ok(Some(ServerEvent::ConnectionLost(client_id, reason)))
+        }
+        Ok(InternalServerEvent::ProtocolError(addr, count)) => {
+            Ok(Some(ServerEvent::ProtocolError(addr, count)))
+        }
+        Ok(InternalServerEvent::QualityChanged(client_id, quality)) => {
+            Ok(Some(ServerEvent::QualityChanged(client_id, quality)))
+        }
+        Ok(InternalServerEvent::SocketError(e)) => Ok(Some(ServerEvent::SocketError(e))),
+        Ok(InternalServerEvent::MalformedPacket(client_id, count)) => {
+            Ok(Some(ServerEvent::MalformedPacket(client_id, count)))
+        }
+        Ok(InternalServerEvent::Handshaking(addr)) => Ok(Some(ServerEvent::Handshaking(addr))),
+        Ok(InternalServerEvent::PayloadFlagged(client_id, verdict)) => {
+            Ok(Some(ServerEvent::PayloadFlagged(client_id, verdict)))
+        }
+        Ok(InternalServerEvent::LimitExceeded(client_id, limit, count)) => {
+            Ok(Some(ServerEvent::LimitExceeded(client_id, limit, count)))
+        }
+        Ok(InternalServerEvent::OutboundLimitExceeded(client_id, count)) => {
+            Ok(Some(ServerEvent::OutboundLimitExceeded(client_id, count)))
+        }
+        Ok(InternalServerEvent::AddressChanged(client_id, old, new)) => {
+            Ok(Some(ServerEvent::AddressChanged(client_id, old, new)))
+        }
+        Ok(InternalServerEvent::HandshakeFailed(addr, failure)) => {
+            Ok(Some(ServerEvent::HandshakeFailed(addr, failure)))
+        }
+        Ok(InternalServerEvent::ReceiveProgress(client_id, group, received, total)) => Ok(Some(
+            ServerEvent::ReceiveProgress(client_id, group, received, total),
+        )),
+        Ok(InternalServerEvent::ServerStarted(..)) => Ok(None),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => bail!("channel to thread lost"),
     }
 }
+
+enum ReadUntilEvent {
+    NewConnection(ConnectionId),
+    ConnectionLost(ConnectionId, DisconnectReason),
+    Receive(ConnectionId, Range<usize>, Instant),
+    Request(RequestHandle, Range<usize>, Instant),
+    KeepAlivePayload(ConnectionId, Range<usize>, Instant),
+    ProtocolError(SocketAddr, u64),
+    ReceiveProgress(ConnectionId, u16, u8, u8),
+    QualityChanged(ConnectionId, ConnectionQuality),
+    SocketError(SocketError),
+    MalformedPacket(ConnectionId, u64),
+    Handshaking(SocketAddr),
+    HandshakeFailed(SocketAddr, HandshakeFailure),
+    PayloadFlagged(ConnectionId, Verdict),
+    LimitExceeded(ConnectionId, Limit, u64),
+    OutboundLimitExceeded(ConnectionId, u64),
+    AddressChanged(ConnectionId, SocketAddr, SocketAddr),
+}
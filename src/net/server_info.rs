@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use super::{
+    bytes_with_header, int_buffer::IntBuffer, unconnected::MAX_UNCONNECTED_SIZE, Bytes, NetError,
+    PacketType, ProtocolId, PROTOCOL_ID_SIZE,
+};
+
+//packet type, player count and max players
+pub const INFO_RESPONSE_HEADER_SIZE: usize = 9;
+pub const MAX_INFO_PAYLOAD_SIZE: usize = MAX_UNCONNECTED_SIZE - INFO_RESPONSE_HEADER_SIZE;
+//requests are padded to the largest response, a spoofed request can't be answered with more bytes
+//than it took to send it
+pub const INFO_REQUEST_SIZE: usize = INFO_RESPONSE_HEADER_SIZE + MAX_INFO_PAYLOAD_SIZE;
+
+//what a server tells launchers and server browsers without them connecting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub players: u32,
+    pub max_players: u32,
+    //application defined data like the map name or game mode
+    pub payload: Bytes,
+}
+
+impl ServerInfo {
+    //creates a response prefixed with the protocol id ready to be sent
+    pub fn write(&self) -> Bytes {
+        let mut int_buffer = IntBuffer::new_at(PROTOCOL_ID_SIZE);
+        let mut buffer = bytes_with_header!(INFO_RESPONSE_HEADER_SIZE + self.payload.len());
+
+        int_buffer.write_u8(PacketType::ServerInfoResponse as u8, &mut buffer);
+        int_buffer.write_u32(self.players, &mut buffer);
+        int_buffer.write_u32(self.max_players, &mut buffer);
+        int_buffer.write_slice(&self.payload, &mut buffer);
+
+        buffer
+    }
+
+    //reads a response with the protocol id already stripped
+    pub fn read(buffer: &[u8]) -> anyhow::Result<ServerInfo> {
+        if buffer.is_empty() {
+            bail!(NetError::EmptyPacket);
+        }
+
+        let mut int_buffer = IntBuffer::default();
+        let packet_type = PacketType::try_from(int_buffer.read_u8(buffer))?;
+        if packet_type != PacketType::ServerInfoResponse {
+            bail!(NetError::UnexpectedPacketType(packet_type));
+        }
+
+        if buffer.len() < INFO_RESPONSE_HEADER_SIZE {
+            bail!(NetError::InvalidLength {
+                packet_type,
+                expected: INFO_RESPONSE_HEADER_SIZE,
+                actual: buffer.len(),
+            });
+        }
+
+        Ok(ServerInfo {
+            players: int_buffer.read_u32(buffer),
+            max_players: int_buffer.read_u32(buffer),
+            payload: buffer[INFO_RESPONSE_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+pub fn write_info_request() -> Bytes {
+    let mut buffer = bytes_with_header!(INFO_REQUEST_SIZE);
+    buffer[PROTOCOL_ID_SIZE] = PacketType::ServerInfoRequest as u8;
+    buffer
+}
+
+//checks a request with the protocol id already stripped
+pub fn read_info_request(buffer: &[u8]) -> anyhow::Result<()> {
+    if buffer.len() != INFO_REQUEST_SIZE {
+        bail!(NetError::InvalidLength {
+            packet_type: PacketType::ServerInfoRequest,
+            expected: INFO_REQUEST_SIZE,
+            actual: buffer.len(),
+        });
+    }
+    Ok(())
+}
+
+//limits the responses to unconnected requests per address and in total, a spoofed source can't
+//turn the answers into a flood aimed at it
+pub struct ResponseLimiter {
+    //responses to the same address per interval
+    per_address: usize,
+    interval: Duration,
+    //when the interval of the address started and the responses sent to it since
+    addresses: HashMap<SocketAddr, (Instant, usize)>,
+    //total responses allowed per second
+    max_per_second: usize,
+    window_start: Instant,
+    window_responses: usize,
+}
+
+impl ResponseLimiter {
+    pub fn new(per_address: usize, interval: Duration, max_per_second: usize) -> Self {
+        Self {
+            per_address,
+            interval,
+            addresses: HashMap::new(),
+            max_per_second,
+            window_start: Instant::now(),
+            window_responses: 0,
+        }
+    }
+
+    //false if the address or the total is over the limit, true counts the response
+    pub fn allows(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_responses = 0;
+        }
+        if self.window_responses >= self.max_per_second {
+            return false;
+        }
+
+        let (start, responses) = self.addresses.entry(addr).or_insert((now, 0));
+        if now.duration_since(*start) >= self.interval {
+            *start = now;
+            *responses = 0;
+        }
+        if *responses >= self.per_address {
+            return false;
+        }
+        *responses += 1;
+        self.window_responses += 1;
+        true
+    }
+
+    //forget addresses that can be answered again
+    pub fn update(&mut self, now: Instant) {
+        let interval = self.interval;
+        self.addresses
+            .retain(|_, (start, _)| now.duration_since(*start) < interval);
+    }
+}
+
+//answers info requests from a cached response, limited per address and in total
+pub struct ServerInfoResponder {
+    payload: Bytes,
+    //the encoded response and the info it was built from
+    cached: Option<(ServerInfo, Bytes)>,
+    //one response per address every interval
+    limiter: ResponseLimiter,
+}
+
+impl ServerInfoResponder {
+    pub fn new(interval: Duration, max_per_second: usize) -> Self {
+        Self {
+            payload: Bytes::new(),
+            cached: None,
+            limiter: ResponseLimiter::new(1, interval, max_per_second),
+        }
+    }
+
+    pub fn set_payload(&mut self, payload: Bytes) -> anyhow::Result<()> {
+        if payload.len() > MAX_INFO_PAYLOAD_SIZE {
+            bail!("server info payload can be at most {MAX_INFO_PAYLOAD_SIZE} bytes long");
+        }
+
+        self.payload = payload;
+        self.cached = None;
+        Ok(())
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    //returns the response to send back, None if the address or the server is over the limit
+    pub fn respond(
+        &mut self,
+        addr: SocketAddr,
+        players: u32,
+        max_players: u32,
+        now: Instant,
+    ) -> Option<&Bytes> {
+        if !self.limiter.allows(addr, now) {
+            return None;
+        }
+
+        //rebuild the response only when any of its fields changed
+        let fresh = |info: &ServerInfo| {
+            info.players == players
+                && info.max_players == max_players
+                && info.payload == self.payload
+        };
+        if !matches!(&self.cached, Some((info, _)) if fresh(info)) {
+            let info = ServerInfo {
+                players,
+                max_players,
+                payload: self.payload.clone(),
+            };
+            let buffer = info.write();
+            self.cached = Some((info, buffer));
+        }
+
+        self.cached.as_ref().map(|(_, buffer)| buffer)
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        self.limiter.update(now);
+    }
+}
+
+//asks a server for its info without connecting to it, the id has to match the one of the server
+pub fn query_server_info(
+    local_addr: SocketAddr,
+    server_addr: SocketAddr,
+    protocol_id: ProtocolId,
+    timeout: Duration,
+) -> anyhow::Result<ServerInfo> {
+    let socket = UdpSocket::bind(local_addr)?;
+    let mut request = write_info_request();
+    protocol_id.write_into(&mut request);
+    socket.send_to(&request, server_addr)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; PROTOCOL_ID_SIZE + 1 + MAX_UNCONNECTED_SIZE];
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            bail!("server info query timed out");
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+
+        match socket.recv_from(&mut buf) {
+            Ok((size, addr)) => {
+                //ignore anything that isn't a response from the server
+                if addr != server_addr || !protocol_id.matches(&buf[..size]) {
+                    continue;
+                }
+                if let Ok(info) = ServerInfo::read(&buf[PROTOCOL_ID_SIZE..size]) {
+                    return Ok(info);
+                }
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                bail!("server info query timed out")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read_roundtrip() {
+        let info = ServerInfo {
+            players: 3,
+            max_players: 16,
+            payload: b"de_dust2".to_vec(),
+        };
+
+        let buffer = info.write();
+        assert_eq!(
+            buffer.len(),
+            PROTOCOL_ID_SIZE + INFO_RESPONSE_HEADER_SIZE + 8
+        );
+        assert_eq!(ServerInfo::read(&buffer[PROTOCOL_ID_SIZE..]).unwrap(), info);
+    }
+
+    #[test]
+    fn truncated_response() {
+        let buffer = ServerInfo {
+            players: 1,
+            max_players: 2,
+            payload: Bytes::new(),
+        }
+        .write();
+
+        let error = ServerInfo::read(&buffer[PROTOCOL_ID_SIZE..PROTOCOL_ID_SIZE + 4])
+            .unwrap_err()
+            .downcast::<NetError>()
+            .unwrap();
+        assert!(matches!(error, NetError::InvalidLength { actual: 4, .. }));
+    }
+
+    #[test]
+    fn request_has_to_be_padded() {
+        let request = write_info_request();
+        assert!(read_info_request(&request[PROTOCOL_ID_SIZE..]).is_ok());
+        assert!(read_info_request(&request[PROTOCOL_ID_SIZE..PROTOCOL_ID_SIZE + 16]).is_err());
+
+        let largest = ServerInfo {
+            players: 0,
+            max_players: 0,
+            payload: vec![0; MAX_INFO_PAYLOAD_SIZE],
+        }
+        .write();
+        assert!(largest.len() <= request.len());
+    }
+
+    #[test]
+    fn responder_rate_limits() {
+        let mut responder = ServerInfoResponder::new(Duration::from_secs(1), 2);
+        let now = Instant::now();
+        let first: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let third: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+
+        assert!(responder.respond(first, 1, 4, now).is_some());
+        //same address within the interval
+        assert!(responder.respond(first, 1, 4, now).is_none());
+        assert!(responder.respond(second, 1, 4, now).is_some());
+        //over the total limit for this second
+        assert!(responder.respond(third, 1, 4, now).is_none());
+
+        let later = now + Duration::from_secs(1);
+        responder.update(later);
+        assert!(responder.respond(first, 1, 4, later).is_some());
+    }
+
+    #[test]
+    fn responder_rebuilds_cache() {
+        let mut responder = ServerInfoResponder::new(Duration::ZERO, 100);
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let now = Instant::now();
+
+        responder.set_payload(b"map".to_vec()).unwrap();
+        let buffer = responder.respond(addr, 1, 4, now).unwrap().clone();
+        assert_eq!(
+            ServerInfo::read(&buffer[PROTOCOL_ID_SIZE..])
+                .unwrap()
+                .players,
+            1
+        );
+
+        let buffer = responder.respond(addr, 2, 4, now).unwrap().clone();
+        let info = ServerInfo::read(&buffer[PROTOCOL_ID_SIZE..]).unwrap();
+        assert_eq!(info.players, 2);
+        assert_eq!(info.payload, b"map");
+
+        //a raised max_clients with the same player count
+        let buffer = responder.respond(addr, 2, 8, now).unwrap().clone();
+        let info = ServerInfo::read(&buffer[PROTOCOL_ID_SIZE..]).unwrap();
+        assert_eq!(info.max_players, 8);
+
+        assert!(responder
+            .set_payload(vec![0; MAX_INFO_PAYLOAD_SIZE + 1])
+            .is_err());
+    }
+}
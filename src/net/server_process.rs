@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     error, io,
     net::SocketAddr,
     sync::Arc,
@@ -8,191 +8,965 @@ use std::{
 };
 
 use anyhow::bail;
-use crossbeam_channel::{select, Receiver, Sender};
-use log::{error, info};
+use crossbeam_channel::Sender;
+use log::{debug, error, info, warn};
+use mio::Waker;
 
 use super::{
     channel::ReadPayload,
-    connections::{ConnectionManager, ConnectionStatus},
+    conditioner::DebugConditions,
+    config::{ServerConfig, ServerConfigUpdate},
+    connections::{
+        ConnectionId, ConnectionManager, ConnectionStatus, ControlPacket, HandshakeFailure,
+    },
+    debug_state::ChannelDebugState,
+    disconnect::{DisconnectCode, DisconnectReason},
+    event_log::RecentEvent,
     header::SendType,
-    packets::SendEvent,
-    socket::{Socket, UdpEvent, UdpSendEvent},
-    Bytes,
+    invalid_packets::SharedInvalidPacketStats,
+    limits::{HeldReadPolicy, Limit, OutboundOverflow},
+    linger::Linger,
+    master::{read_master_challenge, write_heartbeat},
+    packets::{self, SendEvent},
+    pipeline::WorkerLink,
+    profile::{Phase, PhaseTimer, SharedPhaseSamples},
+    quality::ConnectionQuality,
+    queue::{QueueReceiver, QueueSender, TryRecvError},
+    read_scheduler::ReadScheduler,
+    schedule::Scheduler,
+    server_info::{read_info_request, ServerInfo, ServerInfoResponder},
+    socket::{Datagram, ReadBudget, Socket, SocketError, UdpEvent, UdpSendEvent},
+    stats::{SharedSendQueueStats, SharedServerStats},
+    tags::{EventSink, TagQueue},
+    ticker::Ticker,
+    unconnected::{
+        read_unconnected, write_unconnected, SharedUnconnectedHandler, UnconnectedHandler,
+        MAX_UNCONNECTED_SIZE,
+    },
+    validation::{validate, PayloadValidator, Verdict},
+    Bytes, PacketType,
 };
 
+#[derive(Clone)]
 pub enum InternalServerEvent {
-    //the sever has started
-    ServerStarted,
+    //the sever has started, the waker interrupts its poll when a command is sent
+    ServerStarted(
+        Arc<Waker>,
+        SharedInvalidPacketStats,
+        SharedSendQueueStats,
+        SharedServerStats,
+        //the phase timings of every process, the workers in order
+        Vec<SharedPhaseSamples>,
+    ),
     //new connection
-    NewConnection(u32),
+    NewConnection(ConnectionId),
     //connection disconnected
-    ConnectionLost(u32),
+    ConnectionLost(ConnectionId, DisconnectReason),
     //received a packet that fits in a single fragment
-    Receive(u32, Bytes),
+    Receive(ConnectionId, Bytes, Instant),
     //received a fragment packet
-    ReceiveParts(u32, Vec<Bytes>),
+    ReceiveParts(ConnectionId, Vec<Bytes>, Instant),
+    //received a request, by its id
+    Request(ConnectionId, u32, Bytes, Instant),
+    //a fragment of a message arrived, the group with the fragments received and the total
+    ReceiveProgress(ConnectionId, u16, u8, u8),
+    //the payload the client attached to its keep alive
+    KeepAlivePayload(ConnectionId, Bytes, Instant),
+    QualityChanged(ConnectionId, ConnectionQuality),
+    //an address is sending packets with another protocol id
+    ProtocolError(SocketAddr, u64),
+    SocketError(SocketError),
+    //the connection sent a packet that isn't part of its session, the count is every such packet
+    MalformedPacket(ConnectionId, u64),
+    //the challenge was sent to the address
+    Handshaking(SocketAddr),
+    HandshakeFailed(SocketAddr, HandshakeFailure),
+    //the payload validator flagged, rejected or kicked for a payload of the connection
+    PayloadFlagged(ConnectionId, Verdict),
+    //a message of the connection was dropped for going over the limits, the count is every such message
+    LimitExceeded(ConnectionId, Limit, u64),
+    //a send to the connection was dropped by the outbound limits, the count is every such send
+    OutboundLimitExceeded(ConnectionId, u64),
+    //the connection moved from the first address to the second
+    AddressChanged(ConnectionId, SocketAddr, SocketAddr),
+}
+
+pub enum InternalServerCommand {
+    //send a packet to the client on the address
+    Send(SocketAddr, SendEvent),
+    //simulate network conditions on a connection, None clears them
+    SetDebugConditions(ConnectionId, Option<DebugConditions>),
+    //send a raw packet to an address that doesn't need to be connected
+    SendUnconnected(SocketAddr, Bytes),
+    //handle unconnected packets, None drops them
+    SetUnconnectedHandler(Option<UnconnectedHandler>),
+    //application data attached to the server info responses
+    SetServerInfoPayload(Bytes),
+    //send the disconnect packets to a connection and remove it
+    Disconnect(ConnectionId, DisconnectReason),
+    //send the response to a request of a connection
+    Reply(ConnectionId, SendEvent),
+    //replace the interest keys of a connection
+    SetInterest(ConnectionId, HashSet<u64>),
+    //send a packet to every connection interested in the key
+    BroadcastFiltered(u64, SendEvent),
+    //hold the payloads of a connection in both directions until it's resumed
+    Pause(ConnectionId),
+    Resume(ConnectionId),
+    //send a packet to a connection on an interval, by schedule id
+    SendRepeated(u32, ConnectionId, SendEvent, Duration),
+    CancelRepeated(u32),
+    //send a reliable message in fragments that can be cancelled, by send id
+    SendCancellable(SocketAddr, u32, SendEvent),
+    CancelSend(SocketAddr, u32),
+    //send a reliable message that replaces the previous one with the key
+    SendSuperseding(SocketAddr, u64, SendEvent),
+    //a snapshot of the channel of a connection, the sender is dropped if it's not found
+    DebugDump(ConnectionId, Sender<ChannelDebugState>),
+    //the event log of the channel of a connection, the sender is dropped if it's not found
+    RecentEvents(ConnectionId, Sender<Vec<RecentEvent>>),
+    //change the config of the running server at the next update
+    UpdateConfig(ServerConfigUpdate),
+    //replace the value of a state slot of a connection
+    SetState(ConnectionId, u16, Bytes),
+    //send the events of a connection to the queue of a tag, None to the API again
+    Tag(ConnectionId, Option<TagQueue>),
+    //send a copy of every event from now on
+    Subscribe(Sender<InternalServerEvent>),
+    //disconnect every connection with DisconnectCode::Shutdown and end the process
+    Shutdown,
+}
+
+//where the process reads its datagrams from and writes its sends to
+enum Transport {
+    Socket(Box<Socket>),
+    //one of the workers of a server, the socket is owned by the io thread
+    Worker(WorkerLink),
 }
 
 pub struct ServerProcess {
-    socket: Socket,
-    //API channels
-    out_events: Sender<InternalServerEvent>,
-    in_sends: Receiver<(SocketAddr, SendEvent)>,
+    transport: Transport,
+    //the index of the worker, 0 if the server runs a single process
+    shard: usize,
+    //API channels, the events of tagged connections go to the queues of their tags
+    out_events: EventSink,
+    in_sends: QueueReceiver<InternalServerCommand>,
     //connections
     send_queue: VecDeque<UdpSendEvent>,
     connection_manager: ConnectionManager,
+    delayed_reads_buf: Vec<(SocketAddr, Bytes, Instant)>,
+    //config updates applied at the next update, in the order they were made
+    pending_config: Vec<ServerConfigUpdate>,
+    unconnected_handler: SharedUnconnectedHandler,
+    server_info: ServerInfoResponder,
+    last_heartbeat: Option<Instant>,
+    //from the latest challenge of the master server, the heartbeats only count with it
+    master_token: u64,
+    read_scheduler: ReadScheduler,
+    //of the socket, the io thread has it with several workers
+    read_budget: ReadBudget,
+    //commands of the API handled since the last update
+    max_commands_per_tick: usize,
+    commands_handled: usize,
+    ticker: Ticker,
+    stats: SharedServerStats,
+    receive_progress: bool,
+    scheduler: Scheduler,
+    timer: PhaseTimer,
+    //set by the shutdown, the process ends after the command
+    stopped: bool,
 }
 
 impl ServerProcess {
     pub fn bind(
-        addr: SocketAddr,
-        max_clients: usize,
-        out_events: Sender<InternalServerEvent>,
-        in_sends: Receiver<(SocketAddr, SendEvent)>,
+        sockets: Vec<std::net::UdpSocket>,
+        config: ServerConfig,
+        out_events: QueueSender<InternalServerEvent>,
+        in_sends: QueueReceiver<InternalServerCommand>,
     ) -> anyhow::Result<Self> {
-        let socket = Socket::bind(addr)?;
+        let mut socket = Socket::from_std_all(sockets, &config.socket)?;
+        socket.set_protocol_id(config.protocol_id);
+        socket.report_invalid_packets(config.protocol_error_interval);
 
-        out_events.send(InternalServerEvent::ServerStarted)?;
+        let stats = SharedServerStats::default();
+        let samples = SharedPhaseSamples::default();
+        out_events.send(InternalServerEvent::ServerStarted(
+            socket.waker(),
+            socket.invalid_packets(),
+            socket.send_queue_stats(),
+            stats.clone(),
+            vec![samples.clone()],
+        ))?;
 
-        Ok(Self {
-            socket,
-            connection_manager: ConnectionManager::new(max_clients),
+        let connection_manager = ConnectionManager::new(config);
+        let mut process = Self::new(
+            Transport::Socket(Box::new(socket)),
+            0,
+            connection_manager,
+            out_events,
             in_sends,
-            send_queue: VecDeque::new(),
+            stats,
+            SharedUnconnectedHandler::default(),
+        );
+        process.timer.set_samples(samples);
+        Ok(process)
+    }
+
+    //a worker of a server with more than one, the io thread hands it the datagrams of its addresses
+    //and the commands for its connections
+    pub fn worker(
+        link: WorkerLink,
+        connection_manager: ConnectionManager,
+        shard: usize,
+        out_events: QueueSender<InternalServerEvent>,
+        in_sends: QueueReceiver<InternalServerCommand>,
+        stats: SharedServerStats,
+        unconnected_handler: SharedUnconnectedHandler,
+    ) -> Self {
+        let samples = link.samples();
+        let mut process = Self::new(
+            Transport::Worker(link),
+            shard,
+            connection_manager,
             out_events,
-        })
+            in_sends,
+            stats,
+            unconnected_handler,
+        );
+        process.timer.set_samples(samples);
+        process
+    }
+
+    fn new(
+        transport: Transport,
+        shard: usize,
+        connection_manager: ConnectionManager,
+        out_events: QueueSender<InternalServerEvent>,
+        in_sends: QueueReceiver<InternalServerCommand>,
+        stats: SharedServerStats,
+        unconnected_handler: SharedUnconnectedHandler,
+    ) -> Self {
+        let config = connection_manager.config();
+        let receive_progress = config.receive_progress;
+        let max_commands_per_tick = config.max_commands_per_tick;
+        let read_scheduler = ReadScheduler::new();
+        let read_budget = ReadBudget::new(config.max_reads_per_tick);
+        let ticker = Ticker::new(config.channel.update_interval);
+        let server_info = ServerInfoResponder::new(
+            config.server_info_interval,
+            config.max_server_info_responses,
+        );
+
+        Self {
+            transport,
+            shard,
+            connection_manager,
+            in_sends,
+            send_queue: VecDeque::new(),
+            out_events: EventSink::new(out_events),
+            delayed_reads_buf: Vec::new(),
+            pending_config: Vec::new(),
+            unconnected_handler,
+            server_info,
+            last_heartbeat: None,
+            master_token: 0,
+            read_scheduler,
+            read_budget,
+            max_commands_per_tick,
+            commands_handled: 0,
+            ticker,
+            stats,
+            receive_progress,
+            scheduler: Scheduler::default(),
+            timer: PhaseTimer::default(),
+            stopped: false,
+        }
     }
 
     pub fn start(&mut self) -> anyhow::Result<()> {
-        let interval_rx = crossbeam_channel::tick(Duration::from_millis(10));
         let mut udp_events = VecDeque::new();
 
         loop {
-            select! {
-                //constant updates
-                recv(interval_rx) -> _ => {
-                    self.update();
-                }
-                //send requests coming from the API
-                recv(self.in_sends) -> msg_result => {
-                    //prioritize update
-                    if interval_rx.try_recv().is_ok() {
-                        self.update();
-                    }
-
-                    match msg_result {
-                        Ok(msg) => {
-                            if let Err(e) = self.process_send_request(
-                            msg.0,
-                            msg.1
-                        ) {
+            self.tick();
+
+            //send requests coming from the API, the tick is checked in between so a flood can't delay it
+            let mark = self.timer.start();
+            while self.commands_handled < self.max_commands_per_tick {
+                match self.in_sends.try_recv() {
+                    Ok(command) => {
+                        self.commands_handled += 1;
+                        if let Err(e) = self.process_command(command) {
                             error!("error processing send request: {e}")
                         }
-                    },
-                        Err(e) => bail!("process ending {}", e),
-                    };
+                        if self.stopped {
+                            return Ok(());
+                        }
+                        self.tick();
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(e) => bail!("process ending {}", e),
                 }
-                //incoming read packets
-                default => {
+            }
+            self.timer.end(Phase::Sends, mark);
+
+            //incoming read packets until the next update is due
+            let mark = self.timer.start();
+            match &mut self.transport {
+                Transport::Socket(socket) => {
                     if !self.send_queue.is_empty() {
-                        self.socket.enqueue_send_events(&mut self.send_queue);
+                        socket.enqueue_send_events(&mut self.send_queue);
                     }
-
-                    self.socket.process(
-                        Instant::now() + Duration::from_millis(10),
-                        None,
+                    let reads = socket.process(
+                        self.ticker.deadline(),
+                        self.read_budget.left(),
                         &mut udp_events,
                     )?;
+                    self.read_budget.spend(reads);
+                }
+                Transport::Worker(link) => {
+                    link.send(&mut self.send_queue)?;
+                    //with the budget spent the commands wait for the update instead of waking it
+                    let commands = (self.commands_handled < self.max_commands_per_tick)
+                        .then_some(&self.in_sends);
+                    link.process(self.ticker.deadline(), commands, &mut udp_events);
+                }
+            }
+            self.timer.end(Phase::Poll, mark);
 
-                    while let Some(udp_event) = udp_events.pop_back() {
-                        match udp_event {
-                            UdpEvent::Read(addr, buffer, received_at) => {
-                                if let Err(ref e) = self.process_read_request(addr, buffer, &received_at) {
-                                    error!("failed processing read request: {e}");
-                                };
-                            }
-                            UdpEvent::SentServer(addr, seq, sent_at) => {
-                                if let Some(conn) = self.connection_manager.get_client_mut(&addr) {
-                                    conn.channel.send_buffer.mark_sent(seq, sent_at);
-                                }
-                            }
-                            _ => {}
+            let mark = self.timer.start();
+
+            while let Some(udp_event) = udp_events.pop_back() {
+                match udp_event {
+                    UdpEvent::Read(addr, buffer, received_at, ingress) => {
+                        self.read_scheduler.push(addr, buffer, received_at, ingress);
+                    }
+                    UdpEvent::SentServer(addr, seq, sent_at) => {
+                        if let Some(conn) = self.connection_manager.get_client_mut(&addr) {
+                            conn.channel.send_buffer.mark_sent(seq, sent_at);
                         }
                     }
+                    UdpEvent::Invalid(addr, count) => {
+                        debug!("{count} invalid packets from {addr}");
+                        self.out_events
+                            .send(InternalServerEvent::ProtocolError(addr, count))?;
+                    }
+                    UdpEvent::Error(e) => {
+                        self.out_events.send(InternalServerEvent::SocketError(e))?;
+                    }
+                    _ => {}
                 }
             }
+
+            //share the processing fairly between the addresses
+            while let Some((addr, buffer, received_at, ingress)) = self.read_scheduler.pop() {
+                if let Err(ref e) = self.process_read_request(addr, buffer, &received_at, ingress) {
+                    error!("failed processing read request: {e}");
+                };
+                self.tick();
+            }
+            self.timer.end(Phase::Reads, mark);
         }
 
         Ok(())
     }
 
+    //runs the update if it's due
+    fn tick(&mut self) {
+        if self.ticker.is_due(Instant::now()) {
+            self.update();
+        }
+    }
+
     fn process_read_request(
         &mut self,
         addr: SocketAddr,
         buffer: Bytes,
         received_at: &Instant,
+        ingress: usize,
     ) -> anyhow::Result<()> {
-        //client exists, process the request
-        let mut disconnect_client_addr = None;
+        //the connection reads the packet at the address it has and moves once its channel took it,
+        //the simulated conditions don't hold back the packet that moves it
+        if let Some((client_id, old)) = self.connection_manager.migrating(&addr, &buffer) {
+            self.process_connection_read(old, Some(buffer.clone()), received_at)?;
+            if let Some(old) = self
+                .connection_manager
+                .migrate(client_id, &addr, &buffer, ingress)
+            {
+                info!("Client {client_id} moved from addr {old} to {addr}");
+                self.out_events
+                    .send(InternalServerEvent::AddressChanged(client_id, old, addr))?;
+            }
+            return Ok(());
+        }
 
-        if let Some(client) = self.connection_manager.get_client_mut(&addr) {
-            match client.channel.read(buffer, received_at) {
-                Ok(ReadPayload::Single(buffer)) => {
-                    self.out_events.send(InternalServerEvent::Receive(
-                        client.identity.connection_id,
-                        buffer,
-                    ))?;
-                }
-                Ok(ReadPayload::Parts(parts)) => {
-                    self.out_events.send(InternalServerEvent::ReceiveParts(
-                        client.identity.connection_id,
-                        parts,
-                    ))?;
-                }
-                Ok(ReadPayload::Disconnect) => {
-                    if let Some(client_id) = self.connection_manager.disconnect_connection(addr) {
-                        self.out_events
-                            .send(InternalServerEvent::ConnectionLost(client_id))?;
-                        info!("disconnected client {client_id}")
-                    }
-                }
-                Err(e) => {
-                    error!("failed channel read: {e}");
-                    disconnect_client_addr = Some(client.identity.addr);
-                }
-                _ => {}
+        //client exists, process the request. a handshake from its address is a client that restarted
+        //or another one that got the same mapping of a NAT, the duplicate policy decides
+        if let Some(client) = self
+            .connection_manager
+            .get_client_mut(&addr)
+            .filter(|_| !is_handshake_packet(&buffer))
+        {
+            //hold the packet back if the connection is simulating network conditions
+            if let Some(debug_link) = &mut client.debug_link {
+                debug_link.inbound.push(buffer);
+                return Ok(());
+            }
+
+            return self.process_connection_read(addr, Some(buffer), received_at);
+        }
+
+        //the disconnect handshake of a closed connection
+        let Some(buffer) = self.connection_manager.process_closed_read(
+            &addr,
+            buffer,
+            received_at,
+            &mut self.send_queue,
+        ) else {
+            return Ok(());
+        };
+
+        //unconnected packets never reach the connection manager
+        if Some(addr) == self.connection_manager.config().master_server {
+            if let Ok(token) = read_master_challenge(&buffer) {
+                self.master_token = token;
+                self.last_heartbeat = None;
+                self.send_heartbeat();
+                return Ok(());
             }
         }
+        if buffer.first() == Some(&(PacketType::ServerInfoRequest as u8)) {
+            return self.process_server_info_request(addr, &buffer, ingress);
+        }
+        if let Some(payload) = read_unconnected(&buffer) {
+            self.process_unconnected(addr, payload, ingress);
+            return Ok(());
+        }
+
+        //the client lost its connection without noticing, it has to connect again
+        if self.connection_manager.process_unknown_session(
+            &addr,
+            ingress,
+            &buffer,
+            &mut self.send_queue,
+        ) {
+            return Ok(());
+        }
+
         //client doesn't exist and theres space on the server, start the connection process
-        else {
-            match self
+        let mut status = self.connection_manager.process_connect(
+            &addr,
+            ingress,
+            buffer,
+            &mut self.send_queue,
+        )?;
+        if let ConnectionStatus::Replacing(replaced) = status {
+            for connection_id in replaced {
+                self.process_command(InternalServerCommand::Disconnect(
+                    connection_id,
+                    DisconnectReason::with_message(
+                        DisconnectCode::Kicked,
+                        "replaced by a new connection",
+                    ),
+                ))?;
+            }
+            status = self
                 .connection_manager
-                .process_connect(&addr, buffer, &mut self.send_queue)?
-            {
-                ConnectionStatus::Connected(client_id) => {
+                .accept_replacing(&addr, &mut self.send_queue);
+        }
+        match status {
+            ConnectionStatus::Connected(client_id) => {
+                //the session token can be looked up as soon as the connection is announced
+                let Some(stats) = self
+                    .connection_manager
+                    .get_client_by_id_mut(client_id)
+                    .map(|connection| connection.channel.stats())
+                else {
+                    bail!("connection {client_id} not found");
+                };
+                let session_token = stats.session_token;
+                self.stats
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(client_id, stats);
+                self.out_events
+                    .send(InternalServerEvent::NewConnection(client_id))?;
+                info!(
+                    "New client connected on addr {addr} with id {client_id}, session {session_token}"
+                )
+            }
+            ConnectionStatus::Connecting => {
+                if self.connection_manager.config().handshake_events {
                     self.out_events
-                        .send(InternalServerEvent::NewConnection(client_id))?;
-                    info!("New client connected on addr {addr} with id {client_id}")
+                        .send(InternalServerEvent::Handshaking(addr))?;
                 }
-                ConnectionStatus::Connecting => {
-                    info!("New client connecting on addr {addr}")
-                }
-                ConnectionStatus::Rejected => {
-                    info!("Client connection rejected on addr {addr}")
+                info!("New client connecting on addr {addr}")
+            }
+            ConnectionStatus::Failed(failure) => {
+                if self.connection_manager.config().handshake_events {
+                    self.out_events
+                        .send(InternalServerEvent::HandshakeFailed(addr, failure))?;
                 }
+                info!("Client handshake on addr {addr} failed: {failure:?}")
+            }
+            ConnectionStatus::Rejected => {
+                info!("Client connection rejected on addr {addr}")
+            }
+            ConnectionStatus::Replacing(_) => unreachable!("the replaced connections are closed"),
+        };
+
+        Ok(())
+    }
+
+    fn process_unconnected(&mut self, addr: SocketAddr, payload: &[u8], ingress: usize) {
+        let mut handler = self
+            .unconnected_handler
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let Some(handler) = handler.as_mut() else {
+            debug!("dropped unconnected packet from {addr}, no handler is set");
+            return;
+        };
+
+        if let Some(response) = handler(addr, payload) {
+            if response.len() > MAX_UNCONNECTED_SIZE {
+                error!("unconnected response to {addr} is too large, dropping it");
+                return;
+            }
+
+            self.send_queue.push_front(UdpSendEvent::Server(
+                Datagram::from_socket(write_unconnected(&response), ingress),
+                addr,
+            ));
+        }
+    }
+
+    fn process_server_info_request(
+        &mut self,
+        addr: SocketAddr,
+        buffer: &[u8],
+        ingress: usize,
+    ) -> anyhow::Result<()> {
+        read_info_request(buffer)?;
+
+        let players = self.connection_manager.active_clients() as u32;
+        let max_players = self.connection_manager.config().max_clients as u32;
+        match self
+            .server_info
+            .respond(addr, players, max_players, Instant::now())
+        {
+            Some(response) => self.send_queue.push_front(UdpSendEvent::Server(
+                Datagram::from_socket(response.clone(), ingress),
+                addr,
+            )),
+            None => debug!("rate limited server info request from {addr}"),
+        }
+
+        Ok(())
+    }
+
+    //registers the server with the master server, the first heartbeat goes out right away
+    fn send_heartbeat(&mut self) {
+        //the workers of a server all know the player count, the first one registers it
+        if self.shard != 0 {
+            return;
+        }
+        let config = self.connection_manager.config();
+        let Some(master_addr) = config.master_server else {
+            return;
+        };
+        if let Some(last_heartbeat) = self.last_heartbeat {
+            if last_heartbeat.elapsed() < config.master_heartbeat_interval {
+                return;
+            }
+        }
+
+        let info = ServerInfo {
+            players: self.connection_manager.active_clients() as u32,
+            max_players: config.max_clients as u32,
+            payload: self.server_info.payload().to_vec(),
+        };
+        self.send_queue.push_front(UdpSendEvent::Server(
+            write_heartbeat(self.master_token, &info).into(),
+            master_addr,
+        ));
+        self.last_heartbeat = Some(Instant::now());
+    }
+
+    //without a buffer only the messages the channel released on its own are delivered
+    fn process_connection_read(
+        &mut self,
+        addr: SocketAddr,
+        buffer: Option<Bytes>,
+        received_at: &Instant,
+    ) -> anyhow::Result<()> {
+        let malformed_packet_limit = self.connection_manager.config().malformed_packet_limit;
+        let validator = self.connection_manager.config().payload_validator.clone();
+        let violation_limit = self
+            .connection_manager
+            .config()
+            .message_limits
+            .violation_limit;
+        let held_read_limits = self.connection_manager.config().held_read_limits;
+        let mut kick = None;
+
+        if let Some(client) = self.connection_manager.get_client_mut(&addr) {
+            let malformed_packets = client.channel.malformed_packets;
+            let mut read = match buffer {
+                Some(buffer) => client.channel.read(buffer, received_at),
+                None => Ok(ReadPayload::None),
             };
+            loop {
+                //checked when it's read, a paused connection doesn't get to send more
+                if let Ok(payload) = &read {
+                    if let Some(limit) = client.limiter.check(payload, *received_at) {
+                        let connection_id = client.identity.connection_id;
+                        let count = client.limiter.violations;
+                        debug!(
+                            "message {count} of client {connection_id} over the limits: {limit:?}"
+                        );
+                        self.out_events.send(InternalServerEvent::LimitExceeded(
+                            connection_id,
+                            limit,
+                            count,
+                        ))?;
+                        if violation_limit.is_some_and(|limit| count >= limit) {
+                            kick = Some((connection_id, "too many messages over the limits"));
+                            break;
+                        }
+                        read = Ok(ReadPayload::None);
+                    }
+                }
+
+                match read {
+                    //acks and keep alives continue while paused, only the payloads are held
+                    Ok(
+                        payload @ (ReadPayload::Single(_)
+                        | ReadPayload::Parts(_)
+                        | ReadPayload::Request(..)),
+                    ) if client.paused => {
+                        let held = client.hold_read(payload, *received_at, &held_read_limits);
+                        if !held {
+                            let connection_id = client.identity.connection_id;
+                            let count = client.held_reads_dropped;
+                            debug!("payload {count} of paused client {connection_id} not held");
+                            self.out_events.send(InternalServerEvent::LimitExceeded(
+                                connection_id,
+                                Limit::HeldReads,
+                                count,
+                            ))?;
+                            if held_read_limits.policy == HeldReadPolicy::Disconnect {
+                                kick = Some((connection_id, "too many payloads held while paused"));
+                                break;
+                            }
+                        }
+                    }
+                    Ok(ReadPayload::Progress(..)) if client.paused => {}
+                    Ok(
+                        payload @ (ReadPayload::Single(_)
+                        | ReadPayload::Parts(_)
+                        | ReadPayload::Request(..)),
+                    ) => {
+                        let connection_id = client.identity.connection_id;
+                        let verdict = deliver_payload(
+                            &mut self.out_events,
+                            validator.as_ref(),
+                            connection_id,
+                            payload,
+                            *received_at,
+                        )?;
+                        if verdict == Verdict::Disconnect {
+                            kick = Some((connection_id, "payload rejected"));
+                            break;
+                        }
+                    }
+                    Ok(ReadPayload::Progress(group, received, total)) if self.receive_progress => {
+                        self.out_events.send(InternalServerEvent::ReceiveProgress(
+                            client.identity.connection_id,
+                            group,
+                            received,
+                            total,
+                        ))?;
+                    }
+                    Ok(ReadPayload::KeepAlive(payload)) => {
+                        self.out_events.send(InternalServerEvent::KeepAlivePayload(
+                            client.identity.connection_id,
+                            payload,
+                            *received_at,
+                        ))?;
+                    }
+                    Ok(ReadPayload::Disconnect(reason)) => {
+                        client.channel.send_disconnect_ack(&mut self.send_queue);
+                        let linger = Linger::acking(&client.channel.config, Instant::now());
+
+                        if let Some(client_id) =
+                            self.connection_manager.close_connection(addr, linger)
+                        {
+                            self.forget_stats(client_id);
+                            info!("disconnected client {client_id} ({reason})");
+                            self.out_events
+                                .send(InternalServerEvent::ConnectionLost(client_id, reason))?;
+                        }
+                        //the connection is closed, nothing held for it is delivered anymore
+                        break;
+                    }
+                    Err(e) if client.channel.malformed_packets > malformed_packets => {
+                        let connection_id = client.identity.connection_id;
+                        let count = client.channel.malformed_packets;
+                        debug!("malformed packet {count} of client {connection_id}: {e}");
+                        self.out_events
+                            .send(InternalServerEvent::MalformedPacket(connection_id, count))?;
+                        if malformed_packet_limit.is_some_and(|limit| count >= limit) {
+                            kick = Some((connection_id, "too many malformed packets"));
+                        }
+                    }
+                    Err(e) => error!("failed channel read: {e}"),
+                    _ => {}
+                }
+
+                //messages that waited for the one read or the jitter delay
+                match client.channel.take_released() {
+                    Some(payload) => read = Ok(payload),
+                    None => break,
+                }
+            }
         }
 
-        //disconnect the client
-        /*if let Some(addr) = disconnect_client_addr {
-            if let Some(client_id) = self.connection_manager.disconnect_connection(addr) {
+        //the packets likely come from someone tampering with the connection
+        if let Some((connection_id, message)) = kick {
+            self.kick(connection_id, message)?;
+        }
+
+        Ok(())
+    }
+
+    fn kick(&mut self, connection_id: ConnectionId, message: &str) -> anyhow::Result<()> {
+        warn!("kicking client {connection_id}, {message}");
+        self.process_command(InternalServerCommand::Disconnect(
+            connection_id,
+            DisconnectReason::with_message(DisconnectCode::Kicked, message),
+        ))
+    }
+
+    fn process_command(&mut self, command: InternalServerCommand) -> anyhow::Result<()> {
+        match command {
+            InternalServerCommand::Send(addr, send_event) => {
+                self.process_send_request(addr, send_event)
+            }
+            InternalServerCommand::SetDebugConditions(connection_id, conditions) => {
+                match self.connection_manager.get_client_by_id_mut(connection_id) {
+                    Some(connection) => {
+                        connection.set_debug_conditions(conditions);
+                        info!("set debug conditions {conditions:?} on connection {connection_id}");
+                    }
+                    None => bail!("connection {connection_id} not found"),
+                }
+                Ok(())
+            }
+            InternalServerCommand::SendUnconnected(addr, buffer) => {
+                self.send_queue
+                    .push_front(UdpSendEvent::Server(buffer.into(), addr));
+                Ok(())
+            }
+            InternalServerCommand::SetUnconnectedHandler(handler) => {
+                *self
+                    .unconnected_handler
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) = handler;
+                Ok(())
+            }
+            InternalServerCommand::SetServerInfoPayload(payload) => {
+                self.server_info.set_payload(payload)
+            }
+            InternalServerCommand::Disconnect(connection_id, reason) => {
+                let Some(connection) = self.connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    bail!("connection {connection_id} not found");
+                };
+
+                //skips the debug conditions, the held back packets would be dropped with the connection
+                let addr = connection.identity.addr;
+                connection
+                    .channel
+                    .send_event(SendEvent::Disconnect(reason.clone()), &mut self.send_queue)?;
+                let linger =
+                    Linger::closing(reason.clone(), &connection.channel.config, Instant::now());
+                self.connection_manager.close_connection(addr, linger);
+                self.forget_stats(connection_id);
+                info!("disconnected client {connection_id} ({reason})");
                 self.out_events
-                    .send(InternalServerEvent::ConnectionLost(client_id))?;
-                info!("Disconnected client {client_id}")
+                    .send(InternalServerEvent::ConnectionLost(connection_id, reason))?;
+                Ok(())
+            }
+            InternalServerCommand::SetInterest(connection_id, keys) => {
+                match self.connection_manager.get_client_by_id_mut(connection_id) {
+                    Some(connection) => connection.interests = keys,
+                    None => bail!("connection {connection_id} not found"),
+                }
+                Ok(())
+            }
+            InternalServerCommand::BroadcastFiltered(key, send_event) => {
+                let mut refused = Vec::new();
+                for connection in self.connection_manager.connections_mut() {
+                    if !connection.interests.contains(&key) {
+                        continue;
+                    }
+                    //a full send buffer of one connection doesn't stop the others
+                    let connection_id = connection.identity.connection_id;
+                    match connection.send_event(send_event.clone(), &mut self.send_queue) {
+                        Ok(()) => {}
+                        Err(e) if e.is::<OutboundOverflow>() => refused.push((connection_id, e)),
+                        Err(e) => warn!("failed broadcasting to connection {connection_id}: {e}"),
+                    }
+                }
+                for (connection_id, e) in refused {
+                    self.check_outbound(connection_id, Err(e))?;
+                }
+                Ok(())
+            }
+            InternalServerCommand::UpdateConfig(update) => {
+                self.pending_config.push(update);
+                Ok(())
+            }
+            InternalServerCommand::DebugDump(connection_id, sender) => {
+                let Some(connection) = self.connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    bail!("connection {connection_id} not found");
+                };
+                //the caller stopped waiting if this fails
+                let _ = sender.send(connection.channel.debug_state());
+                Ok(())
+            }
+            InternalServerCommand::RecentEvents(connection_id, sender) => {
+                let Some(connection) = self.connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    bail!("connection {connection_id} not found");
+                };
+                //the caller stopped waiting if this fails
+                let _ = sender.send(connection.channel.recent_events());
+                Ok(())
             }
-        }*/
+            InternalServerCommand::SetState(connection_id, slot, value) => {
+                match self.connection_manager.get_client_by_id_mut(connection_id) {
+                    Some(connection) => connection.state_slots.set(slot, value),
+                    None => bail!("connection {connection_id} not found"),
+                }
+                Ok(())
+            }
+            InternalServerCommand::Tag(connection_id, queue) => {
+                if self
+                    .connection_manager
+                    .get_client_by_id_mut(connection_id)
+                    .is_none()
+                {
+                    bail!("connection {connection_id} not found");
+                }
+                self.out_events.tag(connection_id, queue);
+                Ok(())
+            }
+            InternalServerCommand::Subscribe(sender) => {
+                self.out_events.subscribe(sender);
+                Ok(())
+            }
+            InternalServerCommand::Shutdown => self.shut_down(),
+            InternalServerCommand::Pause(connection_id) => {
+                match self.connection_manager.get_client_by_id_mut(connection_id) {
+                    Some(connection) => connection.paused = true,
+                    None => bail!("connection {connection_id} not found"),
+                }
+                Ok(())
+            }
+            InternalServerCommand::Resume(connection_id) => {
+                let Some(connection) = self.connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    bail!("connection {connection_id} not found");
+                };
 
-        Ok(())
+                for e in connection.resume(&mut self.send_queue) {
+                    //a kick for one over the outbound limits closed the connection
+                    if self
+                        .connection_manager
+                        .get_client_by_id_mut(connection_id)
+                        .is_none()
+                    {
+                        break;
+                    }
+                    if e.is::<OutboundOverflow>() {
+                        self.check_outbound(connection_id, Err(e))?;
+                    } else {
+                        warn!("failed sending a held payload to connection {connection_id}: {e}");
+                    }
+                }
+                let validator = self.connection_manager.config().payload_validator.clone();
+                let Some(connection) = self.connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    return Ok(());
+                };
+                while let Some((payload, received_at)) = connection.take_held_read() {
+                    let verdict = deliver_payload(
+                        &mut self.out_events,
+                        validator.as_ref(),
+                        connection_id,
+                        payload,
+                        received_at,
+                    )?;
+                    if verdict == Verdict::Disconnect {
+                        return self.kick(connection_id, "payload rejected");
+                    }
+                }
+                Ok(())
+            }
+            InternalServerCommand::SendRepeated(
+                schedule_id,
+                connection_id,
+                send_event,
+                interval,
+            ) => {
+                self.scheduler.add(
+                    schedule_id,
+                    connection_id,
+                    send_event,
+                    interval,
+                    Instant::now(),
+                );
+                Ok(())
+            }
+            InternalServerCommand::CancelRepeated(schedule_id) => {
+                self.scheduler.cancel(schedule_id);
+                Ok(())
+            }
+            InternalServerCommand::SendCancellable(addr, send_id, send_event) => {
+                let Some(connection) = self.connection_manager.get_client_mut(&addr) else {
+                    return Ok(());
+                };
+                let connection_id = connection.identity.connection_id;
+                let result = connection.send_cancellable(send_id, send_event, &mut self.send_queue);
+                self.check_outbound(connection_id, result)
+            }
+            InternalServerCommand::SendSuperseding(addr, key, send_event) => {
+                let Some(connection) = self.connection_manager.get_client_mut(&addr) else {
+                    return Ok(());
+                };
+                let connection_id = connection.identity.connection_id;
+                let result = connection.send_superseding(key, send_event, &mut self.send_queue);
+                self.check_outbound(connection_id, result)
+            }
+            InternalServerCommand::CancelSend(addr, send_id) => {
+                let Some(connection) = self.connection_manager.get_client_mut(&addr) else {
+                    return Ok(());
+                };
+                if !connection.cancel_send(send_id, &mut self.send_queue)? {
+                    debug!("send {send_id} to {addr} is too old to cancel");
+                }
+                Ok(())
+            }
+            InternalServerCommand::Reply(connection_id, send_event) => {
+                let result = match self.connection_manager.get_client_by_id_mut(connection_id) {
+                    Some(connection) => connection.send_response(send_event, &mut self.send_queue),
+                    None => bail!("connection {connection_id} not found"),
+                };
+                self.check_outbound(connection_id, result)
+            }
+        }
     }
 
     fn process_send_request(
@@ -200,16 +974,288 @@ impl ServerProcess {
         addr: SocketAddr,
         send_event: SendEvent,
     ) -> anyhow::Result<()> {
-        if let Some(connection) = self.connection_manager.get_client_mut(&addr) {
-            return connection
-                .channel
-                .send_event(send_event, &mut self.send_queue);
-        }
+        let Some(connection) = self.connection_manager.get_client_mut(&addr) else {
+            return Ok(());
+        };
+        let connection_id = connection.identity.connection_id;
+        let result = connection.send_event(send_event, &mut self.send_queue);
+        self.check_outbound(connection_id, result)
+    }
+
+    //reports a send the outbound limits of the connection refused, the other errors are returned
+    fn check_outbound(
+        &mut self,
+        connection_id: ConnectionId,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let Err(e) = result else {
+            return Ok(());
+        };
+        let Some(overflow) = e.downcast_ref::<OutboundOverflow>().copied() else {
+            return Err(e);
+        };
 
+        let count = self
+            .connection_manager
+            .get_client_by_id_mut(connection_id)
+            .map_or(0, |connection| connection.outbound_dropped);
+        debug!("send {count} to connection {connection_id} over the outbound limits");
+        self.out_events
+            .send(InternalServerEvent::OutboundLimitExceeded(
+                connection_id,
+                count,
+            ))?;
+        if overflow.kick {
+            self.kick(connection_id, "too much queued for the connection")?;
+        }
         Ok(())
     }
 
+    fn expire_handshakes(&mut self) {
+        let mut expired = Vec::new();
+        self.connection_manager
+            .expire_handshakes(Instant::now(), &mut expired);
+        for addr in expired {
+            info!("Client handshake on addr {addr} timed out");
+            if !self.connection_manager.config().handshake_events {
+                continue;
+            }
+            if let Err(e) = self.out_events.send(InternalServerEvent::HandshakeFailed(
+                addr,
+                HandshakeFailure::TimedOut,
+            )) {
+                error!("failed sending handshake event: {e}");
+            }
+        }
+    }
+
+    fn expire_idle(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<_> = self
+            .connection_manager
+            .connections()
+            .filter(|connection| connection.channel.is_idle(now))
+            .map(|connection| connection.identity.connection_id)
+            .collect();
+        for connection_id in idle {
+            let reason = DisconnectReason::new(DisconnectCode::Idle);
+            if let Err(e) =
+                self.process_command(InternalServerCommand::Disconnect(connection_id, reason))
+            {
+                error!("failed disconnecting idle client {connection_id}: {e}");
+            }
+        }
+    }
+
+    //every connection is sent the disconnect once, nobody is left to wait for the acks. a client
+    //that doesn't get it is closed by its idle timeout
+    fn shut_down(&mut self) -> anyhow::Result<()> {
+        self.stopped = true;
+        let reason = DisconnectReason::new(DisconnectCode::Shutdown);
+        for connection in self.connection_manager.connections_mut() {
+            connection
+                .channel
+                .send_event(SendEvent::Disconnect(reason.clone()), &mut self.send_queue)?;
+        }
+        info!("shutting down, disconnected every client");
+        match &mut self.transport {
+            Transport::Socket(socket) => {
+                socket.enqueue_send_events(&mut self.send_queue);
+                socket.flush()
+            }
+            Transport::Worker(link) => link.send(&mut self.send_queue),
+        }
+    }
+
     fn update(&mut self) {
+        let started = Instant::now();
+        self.timer.end_tick();
+        for update in std::mem::take(&mut self.pending_config) {
+            self.connection_manager.update_config(&update);
+            if let Some(max_reads_per_tick) = update.max_reads_per_tick {
+                self.read_budget.set_max(max_reads_per_tick);
+            }
+            if let Some(max_commands_per_tick) = update.max_commands_per_tick {
+                self.max_commands_per_tick = max_commands_per_tick;
+            }
+            info!("updated the server config");
+        }
+        self.send_states();
         self.connection_manager.update(&mut self.send_queue);
+        self.expire_handshakes();
+        self.expire_idle();
+        self.poll_quality();
+        self.server_info.update(Instant::now());
+        self.read_budget.reset();
+        self.commands_handled = 0;
+        self.send_heartbeat();
+
+        //process the inbound packets that were held back by the debug conditions
+        let mut delayed_reads = std::mem::take(&mut self.delayed_reads_buf);
+        self.connection_manager
+            .take_delayed_reads(&mut delayed_reads);
+        for (addr, buffer, received_at) in delayed_reads.drain(..) {
+            if let Err(ref e) = self.process_connection_read(addr, Some(buffer), &received_at) {
+                error!("failed processing read request: {e}");
+            }
+        }
+        self.delayed_reads_buf = delayed_reads;
+        self.deliver_jittered();
+
+        let connection_manager = &mut self.connection_manager;
+        let send_queue = &mut self.send_queue;
+        let mut refused = Vec::new();
+        self.scheduler
+            .send_due(Instant::now(), |connection_id, send_event| {
+                let Some(connection) = connection_manager.get_client_by_id_mut(connection_id)
+                else {
+                    return false;
+                };
+                match connection.send_event(send_event, send_queue) {
+                    Ok(()) => {}
+                    Err(e) if e.is::<OutboundOverflow>() => refused.push((connection_id, e)),
+                    Err(e) => warn!("failed repeated send to connection {connection_id}: {e}"),
+                }
+                true
+            });
+        for (connection_id, e) in refused {
+            if let Err(e) = self.check_outbound(connection_id, Err(e)) {
+                error!("failed reporting a refused send: {e}");
+            }
+        }
+
+        self.publish_stats();
+        self.timer.add_update(started.elapsed());
+    }
+
+    //the unreliable messages that became due in the jitter buffers since the last packet
+    fn deliver_jittered(&mut self) {
+        if self
+            .connection_manager
+            .config()
+            .channel
+            .jitter_delay
+            .is_none()
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        let due: Vec<SocketAddr> = self
+            .connection_manager
+            .connections_mut()
+            .filter_map(|connection| {
+                let addr = connection.channel.addr;
+                connection.channel.release_jittered(now).then_some(addr)
+            })
+            .collect();
+        for addr in due {
+            if let Err(ref e) = self.process_connection_read(addr, None, &now) {
+                error!("failed delivering jittered messages: {e}");
+            }
+        }
+    }
+
+    //the state values are sent before the channels update so they go out in the same tick
+    fn send_states(&mut self) {
+        let now = Instant::now();
+        let resend_interval = self.connection_manager.config().state_resend_interval;
+        let send_queue = &mut self.send_queue;
+        for connection in self.connection_manager.connections_mut() {
+            let mut frames = Vec::new();
+            connection
+                .state_slots
+                .due(now, resend_interval, |frame| frames.push(frame));
+            for frame in frames {
+                let result = packets::construct_send_event(&frame, SendType::Unreliable)
+                    .and_then(|send_event| connection.send_event(send_event, send_queue));
+                if let Err(e) = result {
+                    warn!(
+                        "failed sending state to connection {}: {e}",
+                        connection.identity.connection_id
+                    );
+                }
+            }
+        }
+    }
+
+    fn poll_quality(&mut self) {
+        let now = Instant::now();
+        for connection in self.connection_manager.connections_mut() {
+            if let Some(quality) = connection.channel.poll_quality(now) {
+                let connection_id = connection.identity.connection_id;
+                debug!("quality of connection {connection_id} changed to {quality:?}");
+                if let Err(e) = self
+                    .out_events
+                    .send(InternalServerEvent::QualityChanged(connection_id, quality))
+                {
+                    error!("failed sending quality event: {e}");
+                }
+            }
+        }
+    }
+
+    //the entries are added with the connections and overwritten in place, the map isn't rebuilt
+    fn publish_stats(&mut self) {
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        for connection in self.connection_manager.connections() {
+            let entry = stats.entry(connection.identity.connection_id).or_default();
+            connection.channel.write_stats(entry);
+        }
+    }
+
+    //the API only sees the connected ones
+    fn forget_stats(&self, connection_id: ConnectionId) {
+        self.stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&connection_id);
+    }
+}
+
+//the control packets have a size no channel packet of their type can have
+fn is_handshake_packet(buffer: &[u8]) -> bool {
+    matches!(
+        ControlPacket::read(buffer),
+        Ok(ControlPacket::ConnectionRequest { .. } | ControlPacket::ChallengeResponse { .. })
+    )
+}
+
+//queues the receive of a payload unless the validator drops it
+fn deliver_payload(
+    out_events: &mut EventSink,
+    validator: Option<&PayloadValidator>,
+    connection_id: ConnectionId,
+    payload: ReadPayload,
+    received_at: Instant,
+) -> anyhow::Result<Verdict> {
+    let verdict = validate(validator, connection_id, &payload);
+    if verdict != Verdict::Accept {
+        debug!("payload of client {connection_id} not accepted: {verdict:?}");
+        out_events.send(InternalServerEvent::PayloadFlagged(connection_id, verdict))?;
+    }
+    if !matches!(verdict, Verdict::Accept | Verdict::Flag) {
+        return Ok(verdict);
+    }
+
+    match payload {
+        ReadPayload::Single(buffer) => out_events.send(InternalServerEvent::Receive(
+            connection_id,
+            buffer,
+            received_at,
+        ))?,
+        ReadPayload::Parts(parts) => out_events.send(InternalServerEvent::ReceiveParts(
+            connection_id,
+            parts,
+            received_at,
+        ))?,
+        ReadPayload::Request(request_id, data) => out_events.send(InternalServerEvent::Request(
+            connection_id,
+            request_id,
+            data,
+            received_at,
+        ))?,
+        _ => {}
     }
+    Ok(verdict)
 }
@@ -1,82 +1,509 @@
+use anyhow::bail;
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
 use log::{debug, info, warn};
 use mio::net::UdpSocket;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
+use socket2::SockRef;
 use std::borrow::BorrowMut;
 use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::io;
-use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IoSlice};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Deref;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crate::net::{bytes, MAGIC_NUMBER_HEADER};
+use crate::net::{bytes, ProtocolId, PROTOCOL_ID_SIZE};
 
+use super::alloc_counters::{self, AllocPath};
+use super::buffer_pool::{recycle_buffer, ConnectionPool};
+use super::checksum::{crc32c_parts, CHECKSUM_SIZE};
+use super::config::SocketConfig;
+use super::invalid_packets::SharedInvalidPacketStats;
+use super::packets::Payload;
 use super::send_buffer::SendPayload;
+use super::stats::SharedSendQueueStats;
 use super::Bytes;
 
 const UDP_SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+//the sockets added after the first one take the tokens from this on
+const EXTRA_SOCKETS: usize = 2;
+//the delay before sending again after a failed send, doubled for every failure in a row
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+//how long the last sends of a closing socket may take
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub enum UdpEvent {
     SentServer(SocketAddr, u16, Instant),
     SentClient(u16, Instant),
-    Read(SocketAddr, Bytes, Instant),
+    //the last one is the index of the socket it was received on, 0 is the first one
+    Read(SocketAddr, Bytes, Instant, usize),
+    //an address keeps sending datagrams with another protocol id, the count of them so far
+    Invalid(SocketAddr, u64),
+    //a send or a receive failed and the socket recovered from it
+    Error(SocketError),
+}
+
+//what the socket did about a failed send or receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketRecovery {
+    //the destination couldn't be reached, only the datagram was dropped
+    Dropped,
+    //sending is paused for the duration and the datagram is sent again after it
+    Retrying(Duration),
+    //the address of the socket went away and it was bound again on the address
+    Rebound(SocketAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketError {
+    pub kind: io::ErrorKind,
+    pub message: String,
+    pub recovery: SocketRecovery,
+}
+
+impl std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "socket error ({:?}): {}", self.recovery, self.message)
+    }
+}
+
+impl std::error::Error for SocketError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    Drop,
+    Retry,
+    Rebind,
+    Fatal,
+}
+
+fn classify(e: &io::Error) -> ErrorClass {
+    match e.kind() {
+        //errors of a single destination, a connected socket also reads them from the ICMP replies
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::InvalidInput => ErrorClass::Drop,
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::OutOfMemory
+        | io::ErrorKind::NetworkUnreachable => ErrorClass::Retry,
+        //the interface of the address is gone or changed
+        io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::NetworkDown
+        | io::ErrorKind::NotConnected => ErrorClass::Rebind,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+//an error of a single destination or one that passes, the socket keeps working
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(classify(e), ErrorClass::Drop | ErrorClass::Retry)
+}
+
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    //the delay before the next attempt
+    fn fail(&mut self, now: Instant) -> Duration {
+        let delay = MIN_BACKOFF
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_BACKOFF);
+        self.failures += 1;
+        self.until = Some(now + delay);
+        delay
+    }
+
+    fn succeed(&mut self) {
+        self.failures = 0;
+        self.until = None;
+    }
+
+    fn is_waiting(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now < until)
+    }
+}
+
+//the datagrams a process reads from the socket between two updates, see max_reads_per_tick of the
+//configs
+pub struct ReadBudget {
+    max: Option<usize>,
+    spent: usize,
+}
+
+impl ReadBudget {
+    pub fn new(max: Option<usize>) -> Self {
+        Self { max, spent: 0 }
+    }
+
+    //the max_reads of the next process of the socket
+    pub fn left(&self) -> Option<usize> {
+        self.max.map(|max| max.saturating_sub(self.spent))
+    }
+
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+    }
+
+    pub fn spend(&mut self, reads: usize) {
+        self.spent += reads;
+    }
+
+    pub fn reset(&mut self) {
+        self.spent = 0;
+    }
 }
 
 pub enum UdpSendEvent {
-    ServerTracking(Bytes, SocketAddr, u16),
-    Server(Bytes, SocketAddr),
-    ClientTracking(Bytes, u16),
-    Client(Bytes),
+    ServerTracking(Datagram, SocketAddr, u16),
+    Server(Datagram, SocketAddr),
+    ClientTracking(Datagram, u16),
+    Client(Datagram),
+}
+
+impl UdpSendEvent {
+    pub fn datagram(&self) -> &Datagram {
+        match self {
+            UdpSendEvent::ServerTracking(datagram, _, _)
+            | UdpSendEvent::Server(datagram, _)
+            | UdpSendEvent::ClientTracking(datagram, _)
+            | UdpSendEvent::Client(datagram) => datagram,
+        }
+    }
+
+    pub fn datagram_mut(&mut self) -> &mut Datagram {
+        match self {
+            UdpSendEvent::ServerTracking(datagram, _, _)
+            | UdpSendEvent::Server(datagram, _)
+            | UdpSendEvent::ClientTracking(datagram, _)
+            | UdpSendEvent::Client(datagram) => datagram,
+        }
+    }
+
+    pub fn into_datagram(self) -> Datagram {
+        match self {
+            UdpSendEvent::ServerTracking(datagram, _, _)
+            | UdpSendEvent::Server(datagram, _)
+            | UdpSendEvent::ClientTracking(datagram, _)
+            | UdpSendEvent::Client(datagram) => datagram,
+        }
+    }
+}
+
+//an outgoing packet kept in parts, the payload is shared with the send buffer
+//and only gathered behind the header when the packet is written to the socket
+pub struct Datagram {
+    //protocol id and the header, or the whole packet if there is no payload
+    pub head: Bytes,
+    pub payload: Option<Payload>,
+    pub checksum: Option<[u8; CHECKSUM_SIZE]>,
+    //the pool of the connection the head was taken from, the pool of the thread otherwise
+    pub pool: Option<ConnectionPool>,
+    //an unreliable packet is dropped instead of sent late after this if the sends back up
+    pub expires_at: Option<Instant>,
+    //set when the socket queues it
+    pub queued_at: Option<Instant>,
+    //the index of the socket it's sent from, the one the peer sends to. None takes the first socket
+    //of the ip version of the address
+    pub socket: Option<usize>,
+}
+
+impl Datagram {
+    pub fn new(head: Bytes, payload: Option<Payload>) -> Self {
+        Self {
+            head,
+            payload,
+            checksum: None,
+            pool: None,
+            expires_at: None,
+            queued_at: None,
+            socket: None,
+        }
+    }
+
+    //sent from the socket with the index, like a reply to a datagram read on it
+    pub fn from_socket(head: Bytes, socket: usize) -> Self {
+        Self {
+            socket: Some(socket),
+            ..Self::new(head, None)
+        }
+    }
+
+    //hands the head back to the pool it was taken from
+    pub fn recycle(self) {
+        match self.pool {
+            Some(pool) => pool.recycle(self.head),
+            None => recycle_buffer(self.head),
+        }
+    }
+
+    //checksum of everything after the protocol id
+    pub fn append_checksum(&mut self) {
+        let payload = self.payload.as_deref().unwrap_or_default();
+        self.checksum =
+            Some(crc32c_parts(&[&self.head[PROTOCOL_ID_SIZE..], payload]).to_le_bytes());
+    }
+
+    pub fn len(&self) -> usize {
+        self.head.len()
+            + self.payload.as_ref().map_or(0, |p| p.len())
+            + self.checksum.map_or(0, |c| c.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    //the continuous packet, the scratch buffer is only used if there is more than the head
+    pub fn gather<'a>(&'a self, scratch: &'a mut Bytes) -> &'a [u8] {
+        if self.payload.is_none() && self.checksum.is_none() {
+            return &self.head;
+        }
+
+        scratch.clear();
+        scratch.extend_from_slice(&self.head);
+        if let Some(payload) = &self.payload {
+            scratch.extend_from_slice(payload);
+        }
+        if let Some(checksum) = &self.checksum {
+            scratch.extend_from_slice(checksum);
+        }
+        scratch
+    }
+
+    //the head, the payload and the checksum as they follow each other on the wire
+    fn io_slices(&self) -> ([IoSlice<'_>; 3], usize) {
+        let mut slices = [IoSlice::new(&[]); 3];
+        let parts = [
+            Some(&self.head[..]),
+            self.payload.as_deref(),
+            self.checksum.as_ref().map(|checksum| &checksum[..]),
+        ];
+        let mut len = 0;
+        for part in parts.into_iter().flatten() {
+            slices[len] = IoSlice::new(part);
+            len += 1;
+        }
+        (slices, len)
+    }
+
+    pub fn to_vec(&self) -> Bytes {
+        let mut buffer = Vec::with_capacity(self.len());
+        self.gather(&mut buffer).to_vec()
+    }
+}
+
+impl From<Bytes> for Datagram {
+    fn from(head: Bytes) -> Self {
+        Self::new(head, None)
+    }
 }
 
 pub struct Socket {
     addr: SocketAddr,
     poll: Poll,
     events: Events,
+    //interrupts the poll when there is a command to process
+    waker: Arc<Waker>,
+    //written in front of every datagram sent, datagrams received with another id are dropped
+    protocol_id: ProtocolId,
+    invalid_packets: SharedInvalidPacketStats,
+    //how often an address sending invalid packets is reported, None never reports it
+    invalid_report_interval: Option<Duration>,
     socket: UdpSocket,
+    //more addresses a server listens on and their local address
+    extra: Vec<(UdpSocket, SocketAddr)>,
+    //the tokens and the readiness of the polled events
+    ready: Vec<(Token, bool, bool)>,
+    //the sockets that were readable when the reads ran out, the poll doesn't report them again
+    deferred: Vec<usize>,
     client_mode: bool,
+    //kept to bind the socket again after its address went away
+    bind_addr: SocketAddr,
+    config: SocketConfig,
+    remote_addr: Option<SocketAddr>,
+    //the error that made the socket rebind, set until binding succeeds
+    rebind_error: Option<io::Error>,
+    backoff: Backoff,
     send_queue: VecDeque<UdpSendEvent>,
+    //a send didn't go through, the queue backs up until it's empty again
+    send_blocked: bool,
+    send_queue_stats: SharedSendQueueStats,
     buf: [u8; 1 << 16],
+    //reused to gather datagrams that are kept in parts
+    send_buf: Bytes,
 }
 
 impl Socket {
-    pub fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+    pub fn bind(addr: SocketAddr, config: &SocketConfig) -> anyhow::Result<Self> {
+        Self::from_std(std::net::UdpSocket::bind(addr)?, config)
+    }
+
+    //takes over a socket that is already bound, only the options set in the config are changed
+    pub fn from_std(socket: std::net::UdpSocket, config: &SocketConfig) -> anyhow::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        apply_options(&socket, addr, config)?;
+
         let mut poll = Poll::new()?;
-        let mut socket = UdpSocket::bind(addr)?;
+        let mut socket = UdpSocket::from_std(socket);
         poll.registry()
             .register(&mut socket, UDP_SOCKET, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
 
         Ok(Self {
             addr,
             poll,
             socket,
-            events: Events::with_capacity(1),
+            extra: Vec::new(),
+            ready: Vec::new(),
+            waker,
+            protocol_id: ProtocolId::DEFAULT,
+            invalid_packets: SharedInvalidPacketStats::default(),
+            invalid_report_interval: None,
+            events: Events::with_capacity(2),
             client_mode: false,
+            bind_addr: addr,
+            config: config.clone(),
+            remote_addr: None,
+            rebind_error: None,
+            backoff: Backoff::default(),
             send_queue: VecDeque::new(),
+            send_blocked: false,
+            send_queue_stats: SharedSendQueueStats::default(),
             buf: [0; 1 << 16],
+            send_buf: Vec::new(),
+            deferred: Vec::new(),
         })
     }
 
-    pub fn connect(addr: SocketAddr, remote_addr: SocketAddr) -> anyhow::Result<Self> {
-        let mut socket = Socket::bind(addr)?;
+    //listens on all of the sockets, replies go out of the socket the address sent to
+    pub fn from_std_all(
+        sockets: Vec<std::net::UdpSocket>,
+        config: &SocketConfig,
+    ) -> anyhow::Result<Self> {
+        let mut sockets = sockets.into_iter();
+        let Some(first) = sockets.next() else {
+            bail!("no socket to listen on");
+        };
+
+        let mut socket = Self::from_std(first, config)?;
+        for extra in sockets {
+            socket.add_socket(extra)?;
+        }
+        Ok(socket)
+    }
+
+    //another address to listen on, it isn't bound again if its address goes away
+    pub fn add_socket(&mut self, socket: std::net::UdpSocket) -> anyhow::Result<SocketAddr> {
+        if self.client_mode {
+            bail!("a connected socket can't listen on more addresses");
+        }
+
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        apply_options(&socket, addr, &self.config)?;
+
+        let mut socket = UdpSocket::from_std(socket);
+        self.poll.registry().register(
+            &mut socket,
+            Token(EXTRA_SOCKETS + self.extra.len()),
+            Interest::READABLE,
+        )?;
+        self.extra.push((socket, addr));
+        self.events = Events::with_capacity(2 + self.extra.len());
+
+        Ok(addr)
+    }
+
+    pub fn connect(
+        socket: std::net::UdpSocket,
+        remote_addr: SocketAddr,
+        config: &SocketConfig,
+    ) -> anyhow::Result<Self> {
+        let mut socket = Socket::from_std(socket, config)?;
         socket.socket.connect(remote_addr)?;
+        //a socket bound to the unspecified address now has the one of the route to the remote
+        socket.addr = socket.socket.local_addr()?;
         socket.client_mode = true;
+        socket.remote_addr = Some(remote_addr);
 
         Ok(socket)
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    //the server of a client socket
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.addr)
+            .chain(self.extra.iter().map(|(_, addr)| *addr))
+            .collect()
+    }
+
+    pub fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    pub fn invalid_packets(&self) -> SharedInvalidPacketStats {
+        self.invalid_packets.clone()
+    }
+
+    pub fn set_protocol_id(&mut self, protocol_id: ProtocolId) {
+        self.protocol_id = protocol_id;
+    }
+
+    pub fn report_invalid_packets(&mut self, interval: Option<Duration>) {
+        self.invalid_report_interval = interval;
+    }
+
+    pub fn send_queue_stats(&self) -> SharedSendQueueStats {
+        self.send_queue_stats.clone()
+    }
+
     pub fn empty_send_events(&mut self) {
         self.send_queue.clear();
     }
 
-    pub fn enqueue_send_event(&mut self, send_event: UdpSendEvent) {
+    pub fn has_pending_sends(&self) -> bool {
+        !self.send_queue.is_empty()
+    }
+
+    //sends what is queued before the socket is dropped, nothing is read
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        let mut events = VecDeque::new();
+        while self.has_pending_sends() && Instant::now() < deadline {
+            self.process(Instant::now(), Some(0), &mut events)?;
+        }
+        Ok(())
+    }
+
+    pub fn enqueue_send_event(&mut self, mut send_event: UdpSendEvent) {
+        send_event.datagram_mut().queued_at = Some(Instant::now());
         self.send_queue.push_front(send_event);
     }
 
     pub fn enqueue_send_events(&mut self, send_events: &mut VecDeque<UdpSendEvent>) {
+        let now = Instant::now();
+        for packet in send_events.iter_mut() {
+            packet.datagram_mut().queued_at = Some(now);
+        }
+
         if self.send_queue.is_empty() {
             std::mem::swap(send_events, &mut self.send_queue);
         } else {
@@ -86,24 +513,52 @@ impl Socket {
         }
     }
 
+    //polls until the deadline or a wakeup, returns early once max_reads datagrams were read. what's
+    //left in the sockets is read by the next call, returns the datagrams read
     pub fn process(
         &mut self,
         deadline: Instant,
-        max_events: Option<usize>,
+        max_reads: Option<usize>,
         events: &mut VecDeque<UdpEvent>,
-    ) -> anyhow::Result<()> {
-        let max_events = max_events.unwrap_or(usize::MAX);
+    ) -> anyhow::Result<usize> {
+        let max_reads = max_reads.unwrap_or(usize::MAX);
+        let mut reads = 0;
+
+        if max_reads > 0 && !self.deferred.is_empty() {
+            let mut rebind = None;
+            for index in std::mem::take(&mut self.deferred) {
+                if rebind.is_none() {
+                    self.receive(index, max_reads, &mut reads, events, &mut rebind)?;
+                }
+            }
+            if let Some(e) = rebind {
+                self.rebind_error = Some(e);
+                self.try_rebind(events);
+            }
+            if !self.deferred.is_empty() {
+                return Ok(reads);
+            }
+        }
 
-        while Instant::now() < deadline {
-            let timeout = deadline - Instant::now();
+        //always poll at least once so queued sends go out even if the deadline already passed
+        let mut first_poll = true;
+        while first_poll || Instant::now() < deadline {
+            first_poll = false;
+            if self.rebind_error.is_some() && !self.backoff.is_waiting(Instant::now()) {
+                self.try_rebind(events);
+            }
+            let now = Instant::now();
+            let mut timeout = deadline.saturating_duration_since(now);
+            if self.send_blocked {
+                self.drop_expired_sends(now);
+            }
+            self.record_send_queue(now);
 
-            //check if there are and send requests
-            if !self.send_queue.is_empty() {
-                self.poll.registry().reregister(
-                    &mut self.socket,
-                    UDP_SOCKET,
-                    Interest::READABLE | Interest::WRITABLE,
-                )?;
+            //check if there are and send requests, nothing is sent while backing off from an error
+            if let Some(until) = self.backoff.until.filter(|&until| now < until) {
+                timeout = timeout.min(until - now);
+            } else if !self.send_queue.is_empty() && self.rebind_error.is_none() {
+                self.register_all(Interest::READABLE | Interest::WRITABLE)?;
             }
 
             // Poll to check if we have events waiting for us.
@@ -114,117 +569,731 @@ impl Socket {
                 return Err(err.into());
             }
 
+            //copied out so the sockets can be used while going through them
+            self.ready.clear();
+            self.ready.extend(
+                self.events
+                    .iter()
+                    .map(|event| (event.token(), event.is_writable(), event.is_readable())),
+            );
+
             // Process each event.
-            for event in self.events.iter() {
-                match event.token() {
-                    UDP_SOCKET => {
-                        if event.is_writable() {
-                            let mut send_finished = true;
-
-                            while let Some(packet) = self.send_queue.pop_back() {
-                                let send_result = match packet {
-                                    UdpSendEvent::ServerTracking(ref data, addr, _) => {
-                                        self.socket.send_to(data, addr)
-                                    }
-                                    UdpSendEvent::ClientTracking(ref data, _) => {
-                                        self.socket.send(data)
-                                    }
-                                    UdpSendEvent::Server(ref data, addr) => {
-                                        self.socket.send_to(data, addr)
-                                    }
-                                    UdpSendEvent::Client(ref data) => self.socket.send(data),
-                                };
-
-                                match send_result {
-                                    Ok(length) => {
-                                        debug!("sent packet of size {length} on {}", self.addr);
-
-                                        match packet {
-                                            UdpSendEvent::ServerTracking(_, addr, seq) => {
-                                                events.push_front(UdpEvent::SentServer(
-                                                    addr,
-                                                    seq,
-                                                    Instant::now(),
-                                                ));
-                                            }
-                                            UdpSendEvent::ClientTracking(_, seq) => {
-                                                events.push_front(UdpEvent::SentClient(
-                                                    seq,
-                                                    Instant::now(),
-                                                ));
-                                            }
-                                            _ => {}
-                                        };
-                                    }
-                                    Err(ref e) if would_block(e) => {
-                                        //set the message back in the queue
-                                        self.send_queue.push_back(packet);
-
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        return Err(e.into());
-                                    }
-                                    _ => {}
-                                };
-                            }
-
-                            //if we sent all of the packets in the channel we can switch back to readable events
-                            if self.send_queue.is_empty() {
-                                self.poll.registry().reregister(
-                                    &mut self.socket,
-                                    UDP_SOCKET,
-                                    Interest::READABLE,
-                                )?;
-                            }
-                        } else if event.is_readable() {
-                            // In this loop we receive all packets queued for the socket.
-                            loop {
-                                match self.socket.recv_from(&mut self.buf) {
-                                    Ok((packet_size, source_address)) => {
-                                        if packet_size >= 4 && self.buf[..4] == MAGIC_NUMBER_HEADER
-                                        {
-                                            debug!(
-                                                "received packet of size {packet_size} on {}",
-                                                self.addr
-                                            );
-                                            let data_size = packet_size - 4;
-                                            let mut buffer = bytes!(data_size);
-
-                                            //copy the data
-                                            buffer[..data_size]
-                                                .copy_from_slice(&self.buf[4..packet_size]);
-
-                                            events.push_front(UdpEvent::Read(
-                                                source_address,
-                                                buffer,
-                                                Instant::now(),
-                                            ));
-
-                                            if max_events <= events.len() {
-                                                return Ok(());
-                                            }
-                                        }
-                                    }
-                                    Err(ref e) if would_block(e) => break,
-                                    Err(e) => {
-                                        return Err(e.into());
-                                    }
-                                }
-                            }
-                        }
+            let mut woken = false;
+            let mut rebind = None;
+            for i in 0..self.ready.len() {
+                let (token, writable, readable) = self.ready[i];
+                let index = match token {
+                    //return so the caller can process the commands before the deadline
+                    WAKER => {
+                        woken = true;
+                        continue;
+                    }
+                    UDP_SOCKET => 0,
+                    Token(token)
+                        if token >= EXTRA_SOCKETS && token - EXTRA_SOCKETS < self.extra.len() =>
+                    {
+                        token - EXTRA_SOCKETS + 1
                     }
                     _ => {
-                        warn!("Got event for unexpected token: {:?}", event);
+                        warn!("Got event for unexpected token: {:?}", token);
+                        continue;
                     }
+                };
+
+                //the queue is shared, any of the sockets being writable sends what it can
+                if writable {
+                    self.send_queued(events, &mut rebind)?;
                 }
+
+                //the event can be both writable and readable, reads would be lost otherwise. without
+                //any reads left the socket waits for the next call
+                if readable
+                    && rebind.is_none()
+                    && self.receive(index, max_reads, &mut reads, events, &mut rebind)?
+                    && max_reads > 0
+                {
+                    return Ok(reads);
+                }
+            }
+
+            if let Some(e) = rebind {
+                self.rebind_error = Some(e);
+                self.try_rebind(events);
+            }
+
+            if woken {
+                return Ok(reads);
             }
         }
 
+        Ok(reads)
+    }
+
+    fn send_queued(
+        &mut self,
+        events: &mut VecDeque<UdpEvent>,
+        rebind: &mut Option<io::Error>,
+    ) -> anyhow::Result<()> {
+        while let Some(mut packet) = self.send_queue.pop_back() {
+            //the packets are written with the default id
+            self.protocol_id.write_into(&mut packet.datagram_mut().head);
+            let index = match packet {
+                UdpSendEvent::ServerTracking(ref datagram, addr, _)
+                | UdpSendEvent::Server(ref datagram, addr) => self.route(addr, datagram.socket),
+                UdpSendEvent::ClientTracking(_, _) | UdpSendEvent::Client(_) => 0,
+            };
+            let (socket, local_addr) = match index {
+                0 => (&self.socket, self.addr),
+                _ => (&self.extra[index - 1].0, self.extra[index - 1].1),
+            };
+            let addr = match packet {
+                UdpSendEvent::ServerTracking(_, addr, _) | UdpSendEvent::Server(_, addr) => {
+                    Some(addr)
+                }
+                UdpSendEvent::ClientTracking(_, _) | UdpSendEvent::Client(_) => None,
+            };
+            //taken right before the syscall, the rtt is measured from it
+            let sent_at = Instant::now();
+            let send_result = send_datagram(
+                socket,
+                packet.datagram(),
+                addr,
+                self.config.gather_sends,
+                &mut self.send_buf,
+            );
+
+            match send_result {
+                Ok(length) => {
+                    debug!("sent packet of size {length} on {local_addr}");
+                    self.backoff.succeed();
+
+                    match packet {
+                        UdpSendEvent::ServerTracking(_, addr, seq) => {
+                            events.push_front(UdpEvent::SentServer(addr, seq, sent_at));
+                        }
+                        UdpSendEvent::ClientTracking(_, seq) => {
+                            events.push_front(UdpEvent::SentClient(seq, sent_at));
+                        }
+                        _ => {}
+                    };
+
+                    packet.into_datagram().recycle();
+                }
+                Err(ref e) if would_block(e) => {
+                    //set the message back in the queue
+                    self.send_queue.push_back(packet);
+                    self.send_blocked = true;
+
+                    break;
+                }
+                Err(e) => match classify_on(index, &e) {
+                    ErrorClass::Drop => {
+                        warn!("dropped a packet on {local_addr}: {e}");
+                        events.push_front(socket_error(&e, SocketRecovery::Dropped));
+                        packet.into_datagram().recycle();
+                    }
+                    ErrorClass::Retry => {
+                        let delay = self.backoff.fail(Instant::now());
+                        warn!("send failed on {local_addr}, retrying in {delay:?}: {e}");
+                        events.push_front(socket_error(&e, SocketRecovery::Retrying(delay)));
+                        self.send_queue.push_back(packet);
+                        self.send_blocked = true;
+                        break;
+                    }
+                    ErrorClass::Rebind => {
+                        self.send_queue.push_back(packet);
+                        self.send_blocked = true;
+                        *rebind = Some(e);
+                        break;
+                    }
+                    ErrorClass::Fatal => return Err(e.into()),
+                },
+            };
+        }
+
+        if self.send_queue.is_empty() {
+            self.send_blocked = false;
+        }
+        self.record_send_queue(Instant::now());
+
+        //if we sent all of the packets in the channel or have to wait we can switch back to readable events
+        if self.send_queue.is_empty() || self.backoff.until.is_some() || rebind.is_some() {
+            self.register_all(Interest::READABLE)?;
+        }
+
         Ok(())
     }
+
+    //receives all datagrams queued for the socket, true once the reads ran out and the socket was
+    //deferred
+    fn receive(
+        &mut self,
+        index: usize,
+        max_reads: usize,
+        reads: &mut usize,
+        events: &mut VecDeque<UdpEvent>,
+        rebind: &mut Option<io::Error>,
+    ) -> anyhow::Result<bool> {
+        let (socket, local_addr) = match index {
+            0 => (&self.socket, self.addr),
+            _ => (&self.extra[index - 1].0, self.extra[index - 1].1),
+        };
+
+        loop {
+            if *reads >= max_reads {
+                if !self.deferred.contains(&index) {
+                    self.deferred.push(index);
+                }
+                return Ok(true);
+            }
+            match socket.recv_from(&mut self.buf) {
+                Ok((packet_size, source_address)) => {
+                    //the datagrams of another protocol take their time too
+                    *reads += 1;
+                    if self.protocol_id.matches(&self.buf[..packet_size]) {
+                        debug!("received packet of size {packet_size} on {local_addr}");
+                        let buffer = {
+                            let _path = alloc_counters::enter(AllocPath::Receive);
+                            self.buf[PROTOCOL_ID_SIZE..packet_size].to_vec()
+                        };
+                        events.push_front(UdpEvent::Read(
+                            source_address,
+                            buffer,
+                            Instant::now(),
+                            index,
+                        ));
+                    } else {
+                        let report = self
+                            .invalid_packets
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .record(source_address, Instant::now(), self.invalid_report_interval);
+                        if let Some(count) = report {
+                            events.push_front(UdpEvent::Invalid(source_address, count));
+                        }
+                    }
+                }
+                Err(ref e) if would_block(e) => break,
+                Err(e) => match classify_on(index, &e) {
+                    //the error was consumed, the next datagram can be read
+                    ErrorClass::Drop => {
+                        debug!("receive failed on {local_addr}: {e}");
+                        events.push_front(socket_error(&e, SocketRecovery::Dropped));
+                        *reads += 1;
+                    }
+                    //read again on the next poll
+                    ErrorClass::Retry => break,
+                    ErrorClass::Rebind => {
+                        *rebind = Some(e);
+                        break;
+                    }
+                    ErrorClass::Fatal => return Err(e.into()),
+                },
+            }
+        }
+
+        Ok(false)
+    }
+
+    //the socket a datagram to the address is sent from, 0 is the first one
+    fn route(&self, addr: SocketAddr, socket: Option<usize>) -> usize {
+        match socket {
+            Some(index) if index <= self.extra.len() => index,
+            _ => default_route(self.addr, &self.extra, addr),
+        }
+    }
+
+    fn register_all(&mut self, interest: Interest) -> io::Result<()> {
+        self.poll
+            .registry()
+            .reregister(&mut self.socket, UDP_SOCKET, interest)?;
+        for (i, (socket, _)) in self.extra.iter_mut().enumerate() {
+            self.poll
+                .registry()
+                .reregister(socket, Token(EXTRA_SOCKETS + i), interest)?;
+        }
+        Ok(())
+    }
+
+    //sending an unreliable packet late is worse than not sending it, the reliable and the control
+    //packets don't expire
+    fn drop_expired_sends(&mut self, now: Instant) {
+        let mut expired = 0;
+        for packet in std::mem::take(&mut self.send_queue) {
+            if packet
+                .datagram()
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                packet.into_datagram().recycle();
+                expired += 1;
+            } else {
+                self.send_queue.push_back(packet);
+            }
+        }
+
+        if expired > 0 {
+            debug!(
+                "dropped {expired} expired unreliable packets on {}",
+                self.addr
+            );
+            self.send_queue_stats
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .expired += expired;
+        }
+    }
+
+    //the packets are sent from the back, it holds the oldest one
+    fn record_send_queue(&self, now: Instant) {
+        let oldest_age = self
+            .send_queue
+            .back()
+            .and_then(|packet| packet.datagram().queued_at)
+            .map_or(Duration::ZERO, |queued_at| {
+                now.saturating_duration_since(queued_at)
+            });
+        self.send_queue_stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(self.send_queue.len(), oldest_age);
+    }
+
+    //reports the error that made the socket rebind, a failed attempt is retried after the backoff
+    fn try_rebind(&mut self, events: &mut VecDeque<UdpEvent>) {
+        let Some(e) = self.rebind_error.take() else {
+            return;
+        };
+
+        let recovery = match self.rebind() {
+            Ok(addr) => {
+                info!("socket bound again on {addr} after: {e}");
+                self.backoff.succeed();
+                SocketRecovery::Rebound(addr)
+            }
+            Err(rebind_error) => {
+                let delay = self.backoff.fail(Instant::now());
+                warn!("failed binding the socket again, retrying in {delay:?}: {rebind_error}");
+                SocketRecovery::Retrying(delay)
+            }
+        };
+        events.push_front(socket_error(&e, recovery));
+        if !matches!(recovery, SocketRecovery::Rebound(_)) {
+            self.rebind_error = Some(e);
+        }
+    }
+
+    //binds a new socket on the original address only, while the ip is gone it fails and the
+    //socket errors keep reporting the retries instead of listening on more addresses
+    fn rebind(&mut self) -> anyhow::Result<SocketAddr> {
+        //the old socket is closed first to free its address, the placeholder is never bound or used
+        let _ = self.poll.registry().deregister(&mut self.socket);
+        let placeholder = socket2::Socket::new(
+            socket2::Domain::for_address(self.bind_addr),
+            socket2::Type::DGRAM,
+            None,
+        )?;
+        self.socket = UdpSocket::from_std(placeholder.into());
+
+        let socket = std::net::UdpSocket::bind(self.bind_addr)?;
+        socket.set_nonblocking(true)?;
+        apply_options(&socket, self.bind_addr, &self.config)?;
+
+        let mut socket = UdpSocket::from_std(socket);
+        if let Some(remote_addr) = self.remote_addr {
+            socket.connect(remote_addr)?;
+        }
+        self.poll
+            .registry()
+            .register(&mut socket, UDP_SOCKET, Interest::READABLE)?;
+        self.socket = socket;
+        self.addr = self.socket.local_addr()?;
+
+        Ok(self.addr)
+    }
+}
+
+fn socket_error(e: &io::Error, recovery: SocketRecovery) -> UdpEvent {
+    UdpEvent::Error(SocketError {
+        kind: e.kind(),
+        message: e.to_string(),
+        recovery,
+    })
+}
+
+//only the first socket is bound again, an extra one that lost its address drops what it can't send
+fn classify_on(index: usize, e: &io::Error) -> ErrorClass {
+    match classify(e) {
+        ErrorClass::Rebind if index > 0 => ErrorClass::Drop,
+        class => class,
+    }
+}
+
+//the first socket of the ip version of the address, the first one if none has it
+fn default_route(first: SocketAddr, extra: &[(UdpSocket, SocketAddr)], addr: SocketAddr) -> usize {
+    if first.is_ipv4() == addr.is_ipv4() {
+        return 0;
+    }
+    extra
+        .iter()
+        .position(|(_, local)| local.is_ipv4() == addr.is_ipv4())
+        .map_or(0, |i| i + 1)
+}
+
+//sends to the address or to the connected peer without one. the parts of the datagram go out with
+//one vectored call unless gather is set, then they're copied into the scratch buffer first
+fn send_datagram(
+    socket: &UdpSocket,
+    datagram: &Datagram,
+    addr: Option<SocketAddr>,
+    gather: bool,
+    scratch: &mut Bytes,
+) -> io::Result<usize> {
+    #[cfg(unix)]
+    if !gather && (datagram.payload.is_some() || datagram.checksum.is_some()) {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+
+        //mio only hands out the raw descriptor, the socket outlives the borrow
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+        let socket = SockRef::from(&fd);
+        let (slices, len) = datagram.io_slices();
+        return match addr {
+            Some(addr) => socket.send_to_vectored(&slices[..len], &addr.into()),
+            None => socket.send_vectored(&slices[..len]),
+        };
+    }
+    #[cfg(not(unix))]
+    let _ = gather;
+
+    let data = datagram.gather(scratch);
+    match addr {
+        Some(addr) => socket.send_to(data, addr),
+        None => socket.send(data),
+    }
 }
 
 fn would_block(e: &io::Error) -> bool {
     e.kind() == io::ErrorKind::WouldBlock
 }
+
+fn apply_options(
+    socket: &std::net::UdpSocket,
+    addr: SocketAddr,
+    config: &SocketConfig,
+) -> anyhow::Result<()> {
+    let socket = SockRef::from(socket);
+
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(dscp) = config.dscp {
+        if dscp > 63 {
+            bail!("DSCP has to be between 0 and 63, got {dscp}");
+        }
+        //the DSCP is the upper 6 bits of the TOS byte and of the traffic class, the rest is left
+        //for ECN
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos((dscp as u32) << 2)?,
+            SocketAddr::V6(_) => set_traffic_class(&socket, (dscp as u32) << 2)?,
+        }
+    }
+    if let Some(ttl) = config.ttl {
+        match addr {
+            SocketAddr::V4(_) => socket.set_ttl(ttl)?,
+            SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_traffic_class(socket: &SockRef, traffic_class: u32) -> anyhow::Result<()> {
+    Ok(socket.set_tclass_v6(traffic_class)?)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_traffic_class(_: &SockRef, _: u32) -> anyhow::Result<()> {
+    bail!("DSCP marking of IPv6 sockets isn't supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_applied() {
+        let config = SocketConfig {
+            recv_buffer_size: Some(4096),
+            send_buffer_size: Some(4096),
+            dscp: Some(46),
+            ttl: Some(32),
+            ..Default::default()
+        };
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        apply_options(&socket, socket.local_addr().unwrap(), &config).unwrap();
+
+        let socket = SockRef::from(&socket);
+        //the kernel is free to round the buffer sizes, linux doubles them for its bookkeeping. the
+        //defaults are far larger
+        if cfg!(target_os = "linux") {
+            assert_eq!(socket.recv_buffer_size().unwrap(), 2 * 4096);
+            assert_eq!(socket.send_buffer_size().unwrap(), 2 * 4096);
+        }
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+        assert_eq!(socket.ttl().unwrap(), 32);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dscp_is_applied_to_ipv6_sockets() {
+        let socket = std::net::UdpSocket::bind("[::1]:0").unwrap();
+        let config = SocketConfig {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        apply_options(&socket, socket.local_addr().unwrap(), &config).unwrap();
+        assert_eq!(SockRef::from(&socket).tclass_v6().unwrap(), 46 << 2);
+    }
+
+    #[test]
+    fn invalid_dscp() {
+        let config = SocketConfig {
+            dscp: Some(64),
+            ..Default::default()
+        };
+        assert!(Socket::bind("127.0.0.1:0".parse().unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let class = |kind| classify(&io::Error::from(kind));
+
+        assert_eq!(class(io::ErrorKind::ConnectionRefused), ErrorClass::Drop);
+        assert_eq!(class(io::ErrorKind::NetworkUnreachable), ErrorClass::Retry);
+        assert_eq!(class(io::ErrorKind::AddrNotAvailable), ErrorClass::Rebind);
+        assert_eq!(class(io::ErrorKind::Unsupported), ErrorClass::Fatal);
+    }
+
+    #[test]
+    fn backoff_doubles_until_success() {
+        let mut backoff = Backoff::default();
+        let now = Instant::now();
+
+        assert_eq!(backoff.fail(now), MIN_BACKOFF);
+        assert_eq!(backoff.fail(now), MIN_BACKOFF * 2);
+        assert!(backoff.is_waiting(now));
+        assert!(!backoff.is_waiting(now + MIN_BACKOFF * 2));
+        for _ in 0..20 {
+            backoff.fail(now);
+        }
+        assert_eq!(backoff.fail(now), MAX_BACKOFF);
+
+        backoff.succeed();
+        assert!(!backoff.is_waiting(now));
+        assert_eq!(backoff.fail(now), MIN_BACKOFF);
+    }
+
+    #[test]
+    fn rebind_fails_while_the_address_is_gone() {
+        let mut socket =
+            Socket::bind("127.0.0.1:0".parse().unwrap(), &SocketConfig::default()).unwrap();
+        let port = socket.local_addr().port();
+        //an address of the documentation range that isn't on any interface
+        socket.bind_addr = SocketAddr::new([192, 0, 2, 1].into(), port);
+
+        assert!(socket.rebind().is_err());
+        //the port wasn't taken on every interface instead
+        std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)).unwrap();
+    }
+
+    #[test]
+    fn rebound_socket_keeps_its_address() {
+        let mut socket =
+            Socket::bind("127.0.0.1:0".parse().unwrap(), &SocketConfig::default()).unwrap();
+        let addr = socket.local_addr();
+        assert_eq!(socket.rebind().unwrap(), addr);
+
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut datagram = vec![0; PROTOCOL_ID_SIZE + 1];
+        ProtocolId::DEFAULT.write_into(&mut datagram);
+        datagram[PROTOCOL_ID_SIZE] = 7;
+        sender.send_to(&datagram, addr).unwrap();
+
+        let mut events = VecDeque::new();
+        socket
+            .process(
+                Instant::now() + Duration::from_secs(2),
+                Some(1),
+                &mut events,
+            )
+            .unwrap();
+        assert!(matches!(events.pop_back(), Some(UdpEvent::Read(_, buffer, ..)) if buffer == [7]));
+    }
+
+    #[test]
+    fn reads_over_the_budget_wait_for_the_next_process() {
+        let mut socket =
+            Socket::bind("127.0.0.1:0".parse().unwrap(), &SocketConfig::default()).unwrap();
+        let addr = socket.local_addr();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for i in 0..3 {
+            let mut datagram = vec![0; PROTOCOL_ID_SIZE + 1];
+            ProtocolId::DEFAULT.write_into(&mut datagram);
+            datagram[PROTOCOL_ID_SIZE] = i;
+            sender.send_to(&datagram, addr).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        let mut budget = ReadBudget::new(Some(2));
+        let mut events = VecDeque::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let reads = socket
+            .process(deadline, budget.left(), &mut events)
+            .unwrap();
+        budget.spend(reads);
+        assert_eq!((reads, events.len(), budget.left()), (2, 2, Some(0)));
+
+        //the poll doesn't report the socket again, the last datagram is read once there's budget
+        let reads = socket
+            .process(
+                Instant::now() + Duration::from_millis(20),
+                budget.left(),
+                &mut events,
+            )
+            .unwrap();
+        assert_eq!(reads, 0);
+        budget.reset();
+        let reads = socket
+            .process(
+                Instant::now() + Duration::from_millis(20),
+                budget.left(),
+                &mut events,
+            )
+            .unwrap();
+        assert_eq!(reads, 1);
+        let read: Vec<_> = events
+            .into_iter()
+            .rev()
+            .filter_map(|event| match event {
+                UdpEvent::Read(_, buffer, ..) => Some(buffer[0]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(read, [0, 1, 2]);
+    }
+
+    #[test]
+    fn replies_leave_from_the_socket_they_came_to() {
+        let mut socket = Socket::from_std_all(
+            vec![
+                std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+                std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+            ],
+            &SocketConfig::default(),
+        )
+        .unwrap();
+        let extra_addr = socket.local_addrs()[1];
+
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut datagram = vec![0; PROTOCOL_ID_SIZE + 1];
+        ProtocolId::DEFAULT.write_into(&mut datagram);
+        sender.send_to(&datagram, extra_addr).unwrap();
+
+        let mut events = VecDeque::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        socket.process(deadline, Some(1), &mut events).unwrap();
+        let Some(UdpEvent::Read(source, _, _, ingress)) = events.pop_back() else {
+            panic!("expected a read");
+        };
+        assert_eq!(ingress, 1);
+
+        socket.enqueue_send_event(UdpSendEvent::Server(
+            Datagram::from_socket(vec![0; PROTOCOL_ID_SIZE], ingress),
+            source,
+        ));
+        socket.process(Instant::now(), None, &mut events).unwrap();
+        sender
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let (_, from) = sender.recv_from(&mut datagram).unwrap();
+        assert_eq!(from, extra_addr);
+    }
+
+    #[test]
+    fn datagrams_in_parts_arrive_whole() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        for gather_sends in [false, true] {
+            let config = SocketConfig {
+                gather_sends,
+                ..Default::default()
+            };
+            let mut socket = Socket::bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+            let mut head = vec![0; PROTOCOL_ID_SIZE];
+            head.extend_from_slice(&[1, 2]);
+            let mut datagram = Datagram::new(head, Some(Payload::new(&[3, 4, 5])));
+            datagram.checksum = Some([6; CHECKSUM_SIZE]);
+            socket.enqueue_send_event(UdpSendEvent::Server(datagram, addr));
+            socket
+                .process(Instant::now(), None, &mut VecDeque::new())
+                .unwrap();
+
+            let mut buf = [0; 64];
+            let (size, _) = receiver.recv_from(&mut buf).unwrap();
+            let mut expected = vec![1, 2, 3, 4, 5];
+            expected.extend_from_slice(&[6; CHECKSUM_SIZE]);
+            assert_eq!(&buf[PROTOCOL_ID_SIZE..size], &expected[..]);
+        }
+    }
+
+    #[test]
+    fn expired_unreliable_sends_are_dropped() {
+        let mut socket =
+            Socket::bind("127.0.0.1:0".parse().unwrap(), &SocketConfig::default()).unwrap();
+        let addr = socket.local_addr();
+        let now = Instant::now();
+
+        let mut stale = Datagram::from(vec![1]);
+        stale.expires_at = Some(now);
+        let mut fresh = Datagram::from(vec![2]);
+        fresh.expires_at = Some(now + Duration::from_secs(1));
+        socket.enqueue_send_event(UdpSendEvent::Server(stale, addr));
+        socket.enqueue_send_event(UdpSendEvent::Server(fresh, addr));
+        socket.enqueue_send_event(UdpSendEvent::ServerTracking(vec![3].into(), addr, 0));
+
+        socket.drop_expired_sends(now);
+        assert_eq!(socket.send_queue_stats.lock().unwrap().expired, 1);
+        let heads: Vec<_> = socket
+            .send_queue
+            .iter()
+            .map(|packet| packet.datagram().head.clone())
+            .collect();
+        assert_eq!(heads, [vec![3], vec![2]]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::Bytes;
+
+//state values are unreliable messages with a marker, the slot and the version in front, games must
+//not start their own messages with the marker
+pub const STATE_MARKER: [u8; 4] = *b"STAT";
+//the marker, the slot and the version
+pub const STATE_FRAME_SIZE: usize = 10;
+
+pub fn write_state(slot: u16, version: u32, data: &[u8]) -> Bytes {
+    let mut buffer = Vec::with_capacity(STATE_FRAME_SIZE + data.len());
+    buffer.extend_from_slice(&STATE_MARKER);
+    buffer.extend_from_slice(&slot.to_le_bytes());
+    buffer.extend_from_slice(&version.to_le_bytes());
+    buffer.extend_from_slice(data);
+    buffer
+}
+
+//the slot, the version and the value if the message is a state value
+pub fn read_state(message: &[u8]) -> Option<(u16, u32, &[u8])> {
+    if message.len() < STATE_FRAME_SIZE || message[..4] != STATE_MARKER {
+        return None;
+    }
+
+    let slot = u16::from_le_bytes(message[4..6].try_into().unwrap());
+    let version = u32::from_le_bytes(message[6..STATE_FRAME_SIZE].try_into().unwrap());
+    Some((slot, version, &message[STATE_FRAME_SIZE..]))
+}
+
+struct OutgoingState {
+    version: u32,
+    value: Bytes,
+    //None until it's sent, a changed value is sent at the next update
+    sent_at: Option<Instant>,
+}
+
+//the values the server keeps sending to a connection, only the newest one of every slot
+pub struct StateSlots {
+    slots: HashMap<u16, OutgoingState>,
+}
+
+impl StateSlots {
+    pub fn new() -> Self {
+        Self {
+            slots: HashMap::new(),
+        }
+    }
+
+    //replaces the value of the slot, the one before it is never sent again
+    pub fn set(&mut self, slot: u16, value: Bytes) {
+        let state = self.slots.entry(slot).or_insert(OutgoingState {
+            version: 0,
+            value: Vec::new(),
+            sent_at: None,
+        });
+        state.version = state.version.wrapping_add(1);
+        state.value = value;
+        state.sent_at = None;
+    }
+
+    //the frames of the changed values and of the ones not sent for the interval, a lost value is
+    //repaired by the next resend
+    pub fn due(&mut self, now: Instant, resend_interval: Duration, mut send: impl FnMut(Bytes)) {
+        for (slot, state) in &mut self.slots {
+            let due = state
+                .sent_at
+                .is_none_or(|sent_at| now.saturating_duration_since(sent_at) >= resend_interval);
+            if due {
+                send(write_state(*slot, state.version, &state.value));
+                state.sent_at = Some(now);
+            }
+        }
+    }
+}
+
+//the newest value received for every slot by slot, with its version
+pub type SharedLatestStates = Arc<Mutex<HashMap<u16, (u32, Bytes)>>>;
+
+//false if the message isn't a state value. an older version than the one kept is dropped
+pub fn store_state(states: &SharedLatestStates, message: &[u8]) -> bool {
+    let Some((slot, version, value)) = read_state(message) else {
+        return false;
+    };
+
+    let mut states = states.lock().unwrap_or_else(|e| e.into_inner());
+    let newer = match states.get(&slot) {
+        Some((latest, _)) => version != *latest && version.wrapping_sub(*latest) < u32::MAX / 2,
+        None => true,
+    };
+    if newer {
+        states.insert(slot, (version, value.to_vec()));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_newest_value_is_sent_and_kept() {
+        let mut slots = StateSlots::new();
+        let now = Instant::now();
+        let interval = Duration::from_millis(100);
+        slots.set(1, vec![1]);
+        slots.set(1, vec![2]);
+
+        let mut frames = Vec::new();
+        slots.due(now, interval, |frame| frames.push(frame));
+        assert_eq!(frames, vec![write_state(1, 2, &[2])]);
+        slots.due(now, interval, |frame| frames.push(frame));
+        assert_eq!(frames.len(), 1);
+        slots.due(now + interval, interval, |frame| frames.push(frame));
+        assert_eq!(frames.len(), 2);
+
+        let states = SharedLatestStates::default();
+        assert!(store_state(&states, &frames[0]));
+        assert!(store_state(&states, &write_state(1, 1, &[1])));
+        assert_eq!(states.lock().unwrap()[&1], (2, vec![2]));
+        assert!(!store_state(&states, &[1, 2, 3]));
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{connections::ConnectionId, fragmentation_manager::FragmentStats, SessionToken};
+
+//counters of a single connection, the process thread publishes a snapshot on every update
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    //the same on both sides of the connection, for finding the logs of a session
+    pub session_token: SessionToken,
+    //both directions, reliable and unreliable messages together
+    pub fragments: FragmentStats,
+    //packets dropped because of a checksum mismatch
+    pub corrupted_packets: u64,
+    //packets with another session key or a header that doesn't decode
+    pub malformed_packets: u64,
+    //unreliable packets and keep alives dropped as duplicates or as too old, likely replayed
+    pub replayed_packets: u64,
+    //unreliable messages the jitter buffer dropped because they arrived too late
+    pub late_unreliable: u64,
+    //reliable packets held back because the window the remote advertised is used up
+    pub queued_reliable: usize,
+    //unreliable sends per second recommended for the current quality of the connection
+    pub recommended_send_rate: u32,
+    //None until the first reliable packet was acked
+    pub smoothed_rtt: Option<Duration>,
+    pub rtt_variance: Duration,
+    //how long a reliable packet currently waits for its ack before it's resent
+    pub retransmit_timeout: Duration,
+    //the latest rtt samples of the config's sample history, oldest first
+    pub rtt_samples: Vec<Duration>,
+}
+
+//written by the process thread, read by the API
+pub type SharedConnectionStats = Arc<Mutex<ConnectionStats>>;
+//the stats of every connection of a server by connection id
+pub type SharedServerStats = Arc<Mutex<HashMap<ConnectionId, ConnectionStats>>>;
+
+//the packets waiting for the socket to be writable. a deep queue with young packets means the game
+//sends more than the socket takes, old packets mean the socket itself is backed up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendQueueStats {
+    pub depth: usize,
+    //how long the oldest queued packet has been waiting
+    pub oldest_age: Duration,
+    //the highest values seen since the socket was created
+    pub max_depth: usize,
+    pub max_age: Duration,
+    //unreliable packets dropped because they waited too long
+    pub expired: u64,
+}
+
+impl SendQueueStats {
+    pub fn record(&mut self, depth: usize, oldest_age: Duration) {
+        self.depth = depth;
+        self.oldest_age = oldest_age;
+        self.max_depth = self.max_depth.max(depth);
+        self.max_age = self.max_age.max(oldest_age);
+    }
+}
+
+//written by the socket, read by the API
+pub type SharedSendQueueStats = Arc<Mutex<SendQueueStats>>;
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::debug;
+
+use super::{
+    queue::EVENT_CAPACITY,
+    server::{to_server_event, ServerEvent},
+    server_process::InternalServerEvent,
+};
+
+//returned by Server::subscribe, gets a copy of every event of the server from the time it
+//subscribed, the tagged ones included. it unsubscribes when it's dropped
+pub struct EventReceiver {
+    events: Receiver<InternalServerEvent>,
+}
+
+impl EventReceiver {
+    pub fn read<'a>(
+        &self,
+        dest: &'a mut [u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Option<ServerEvent<'a>>> {
+        to_server_event(self.events.recv_timeout(timeout), dest)
+    }
+}
+
+//the subscriber end and the sender the process keeps for it
+pub fn subscription() -> (EventReceiver, Sender<InternalServerEvent>) {
+    let (sender, events) = crossbeam_channel::bounded(EVENT_CAPACITY);
+    (EventReceiver { events }, sender)
+}
+
+//the process side of the subscriptions, a subscriber that falls this far behind misses the new
+//events until it catches up so it can't hold the memory of the server
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<Sender<InternalServerEvent>>,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, sender: Sender<InternalServerEvent>) {
+        self.senders.push(sender);
+    }
+
+    //the start of the server isn't one of the events the API reads
+    pub fn publish(&mut self, event: &InternalServerEvent) {
+        if self.senders.is_empty() || matches!(event, InternalServerEvent::ServerStarted(..)) {
+            return;
+        }
+
+        self.senders
+            .retain(|sender| match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!("a subscriber is behind, dropped an event for it");
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::net::connections::ConnectionId;
+
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_every_event() {
+        let mut subscribers = Subscribers::default();
+        let (first, sender) = subscription();
+        subscribers.add(sender);
+        let (second, sender) = subscription();
+        subscribers.add(sender);
+
+        let connection_id = ConnectionId::from_bits(1);
+        subscribers.publish(&InternalServerEvent::Receive(
+            connection_id,
+            vec![1, 2],
+            Instant::now(),
+        ));
+        let mut buf = [0; 4];
+        for receiver in [&first, &second] {
+            assert!(matches!(
+                receiver.read(&mut buf, Duration::ZERO),
+                Ok(Some(ServerEvent::Receive(id, [1, 2], _))) if id == connection_id
+            ));
+        }
+
+        //a dropped receiver unsubscribes
+        drop(second);
+        subscribers.publish(&InternalServerEvent::NewConnection(connection_id));
+        assert_eq!(subscribers.senders.len(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use super::{
+    connections::ConnectionId,
+    queue::{QueueSender, SendError},
+    server_process::InternalServerEvent,
+    subscription::Subscribers,
+};
+
+//both ends of the queue of a tag, the server counts the connections with the tag by its clones
+pub type TagQueueEnds = Arc<(Sender<InternalServerEvent>, Receiver<InternalServerEvent>)>;
+
+//the queue of a tag and the name of it, handed to the process with the connection tagged
+pub type TagQueue = (String, TagQueueEnds);
+
+//the events of a tagged connection go to the queue of its tag, read with Server::read_tagged, the
+//rest go to the one read with Server::read. a connection is tagged after its NewConnection so
+//that one always goes to Server::read. the subscribers get a copy of every event either way
+pub struct EventSink {
+    out_events: QueueSender<InternalServerEvent>,
+    tags: HashMap<ConnectionId, TagQueue>,
+    subscribers: Subscribers,
+}
+
+impl EventSink {
+    pub fn new(out_events: QueueSender<InternalServerEvent>) -> Self {
+        Self {
+            out_events,
+            tags: HashMap::new(),
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    pub fn subscribe(&mut self, sender: Sender<InternalServerEvent>) {
+        self.subscribers.add(sender);
+    }
+
+    //replaces the tag of the connection, None sends its events to Server::read again
+    pub fn tag(&mut self, connection_id: ConnectionId, queue: Option<TagQueue>) {
+        match queue {
+            Some(queue) => self.tags.insert(connection_id, queue),
+            None => self.tags.remove(&connection_id),
+        };
+    }
+
+    pub fn tag_of(&self, connection_id: ConnectionId) -> Option<&str> {
+        self.tags.get(&connection_id).map(|(tag, _)| tag.as_str())
+    }
+
+    //the tag is forgotten with the ConnectionLost of the connection, which still goes to its queue
+    pub fn send(
+        &mut self,
+        event: InternalServerEvent,
+    ) -> Result<(), SendError<InternalServerEvent>> {
+        self.subscribers.publish(&event);
+        let Some(connection_id) = connection_of(&event) else {
+            return self.out_events.send(event);
+        };
+        let lost = matches!(event, InternalServerEvent::ConnectionLost(..));
+        let result = match self.tags.get(&connection_id) {
+            //a full queue holds up the process like the one of Server::read, the events of a
+            //connection are never split between two readers
+            Some((_, queue)) => queue.0.send(event),
+            None => self.out_events.send(event),
+        };
+        if lost {
+            self.tags.remove(&connection_id);
+        }
+        result
+    }
+}
+
+fn connection_of(event: &InternalServerEvent) -> Option<ConnectionId> {
+    match event {
+        InternalServerEvent::NewConnection(connection_id)
+        | InternalServerEvent::ConnectionLost(connection_id, _)
+        | InternalServerEvent::Receive(connection_id, _, _)
+        | InternalServerEvent::ReceiveParts(connection_id, _, _)
+        | InternalServerEvent::Request(connection_id, _, _, _)
+        | InternalServerEvent::ReceiveProgress(connection_id, _, _, _)
+        | InternalServerEvent::KeepAlivePayload(connection_id, _, _)
+        | InternalServerEvent::QualityChanged(connection_id, _)
+        | InternalServerEvent::MalformedPacket(connection_id, _)
+        | InternalServerEvent::PayloadFlagged(connection_id, _)
+        | InternalServerEvent::LimitExceeded(connection_id, _, _)
+        | InternalServerEvent::OutboundLimitExceeded(connection_id, _)
+        | InternalServerEvent::AddressChanged(connection_id, _, _) => Some(*connection_id),
+        InternalServerEvent::ServerStarted(..)
+        | InternalServerEvent::ProtocolError(..)
+        | InternalServerEvent::SocketError(_)
+        | InternalServerEvent::Handshaking(_)
+        | InternalServerEvent::HandshakeFailed(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::net::{
+        disconnect::{DisconnectCode, DisconnectReason},
+        queue::{self, OnFull},
+    };
+
+    use super::*;
+
+    #[test]
+    fn events_of_tagged_connections_go_to_their_queue() {
+        let (out_events, out) = queue::channel(16, OnFull::Grow);
+        let queue = Arc::new(crossbeam_channel::bounded(2));
+        let tagged = queue.1.clone();
+        let mut sink = EventSink::new(out_events);
+        let lobby = ConnectionId::from_bits(1);
+        let other = ConnectionId::from_bits(2);
+
+        sink.tag(lobby, Some(("lobby:12".to_string(), queue.clone())));
+        assert_eq!(sink.tag_of(lobby), Some("lobby:12"));
+        sink.send(InternalServerEvent::Receive(lobby, vec![1], Instant::now()))
+            .unwrap();
+        sink.send(InternalServerEvent::Receive(other, vec![2], Instant::now()))
+            .unwrap();
+        assert!(matches!(
+            tagged.try_recv(),
+            Ok(InternalServerEvent::Receive(id, buffer, _)) if id == lobby && buffer == [1]
+        ));
+        assert!(matches!(
+            out.try_recv(),
+            Ok(InternalServerEvent::Receive(id, _, _)) if id == other
+        ));
+
+        //the lost connection is the last event of the tag
+        let reason = DisconnectReason::new(DisconnectCode::Idle);
+        sink.send(InternalServerEvent::ConnectionLost(lobby, reason))
+            .unwrap();
+        assert!(tagged.try_recv().is_ok());
+        assert_eq!(sink.tag_of(lobby), None);
+        //the server can forget the queue
+        assert_eq!(Arc::strong_count(&queue), 1);
+    }
+
+    #[test]
+    fn full_tag_queues_wait_for_their_reader() {
+        let (out_events, out) = queue::channel(16, OnFull::Block);
+        let mut sink = EventSink::new(out_events);
+        let lobby = ConnectionId::from_bits(1);
+        let queue: TagQueueEnds = Arc::new(crossbeam_channel::bounded(1));
+        sink.tag(lobby, Some(("lobby".to_string(), queue.clone())));
+
+        let reader = std::thread::spawn(move || {
+            (0..3)
+                .map(|_| match queue.1.recv_timeout(Duration::from_secs(5)) {
+                    Ok(InternalServerEvent::Receive(_, buffer, _)) => buffer[0],
+                    _ => panic!("expected the events of the tag in order"),
+                })
+                .collect::<Vec<_>>()
+        });
+        for i in 0..3 {
+            sink.send(InternalServerEvent::Receive(lobby, vec![i], Instant::now()))
+                .unwrap();
+        }
+        assert_eq!(reader.join().unwrap(), [0, 1, 2]);
+        assert!(out.try_recv().is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+//keeps the process loops updating at a fixed cadence, checked in between all other work
+pub struct Ticker {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Ticker {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Instant::now() + interval,
+        }
+    }
+
+    //returns true once per interval, ticks missed while the loop was busy are skipped instead of bursting
+    pub fn is_due(&mut self, now: Instant) -> bool {
+        if now < self.next {
+            return false;
+        }
+
+        self.next += self.interval;
+        if self.next <= now {
+            self.next = now + self.interval;
+        }
+        true
+    }
+
+    //when the next update has to run, blocking work should return by then
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_once_per_interval() {
+        let interval = Duration::from_millis(10);
+        let mut ticker = Ticker::new(interval);
+        let start = ticker.deadline();
+
+        assert!(!ticker.is_due(start - Duration::from_millis(1)));
+        assert!(ticker.is_due(start));
+        assert!(!ticker.is_due(start));
+        assert_eq!(ticker.deadline(), start + interval);
+
+        //stays on the original cadence when an update is a bit late
+        assert!(ticker.is_due(start + Duration::from_millis(13)));
+        assert_eq!(ticker.deadline(), start + interval * 2);
+    }
+
+    #[test]
+    fn skips_missed_ticks() {
+        let interval = Duration::from_millis(10);
+        let mut ticker = Ticker::new(interval);
+        let late = ticker.deadline() + Duration::from_millis(55);
+
+        assert!(ticker.is_due(late));
+        assert!(!ticker.is_due(late));
+        assert_eq!(ticker.deadline(), late + interval);
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+//slots per level, a timer goes to the level where its tick and the current tick first differ
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+//with millisecond ticks the last level reaches past 4 hours, later timers are parked in it
+const LEVELS: usize = 4;
+
+struct Timer<T> {
+    tick: u64,
+    item: T,
+}
+
+//hierarchical timer wheel, a timer is moved down a level once its slot comes up so advancing only
+//touches the timers that expire and the slots passed on the way
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick: Duration,
+    //the next tick to expire
+    current: u64,
+    levels: Vec<Vec<Vec<Timer<T>>>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            tick,
+            current: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    //a deadline that already passed expires on the next advance
+    pub fn insert(&mut self, deadline: Instant, item: T) {
+        let tick = self.tick_of(deadline).max(self.current);
+        self.len += 1;
+        self.place(Timer { tick, item });
+    }
+
+    //pushes the items of every timer up to now
+    pub fn advance(&mut self, now: Instant, expired: &mut Vec<T>) {
+        //the rounding down of now keeps a timer from expiring before its deadline
+        let now_tick = now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos();
+        let now_tick = now_tick as u64;
+
+        while self.current <= now_tick {
+            if self.len == 0 {
+                self.current = now_tick + 1;
+                return;
+            }
+
+            //nothing expires before the next slot of the first level with timers
+            let empty_levels = self
+                .levels
+                .iter()
+                .take_while(|level| level.iter().all(Vec::is_empty))
+                .count() as u32;
+            if empty_levels > 0 {
+                let next = self
+                    .current
+                    .next_multiple_of(1 << (SLOT_BITS * empty_levels));
+                self.current = next.min(now_tick + 1);
+                if self.current > now_tick {
+                    return;
+                }
+            }
+
+            self.cascade();
+            let slot = (self.current as usize) & (SLOTS - 1);
+            //the first level only has timers of the current pass
+            for timer in std::mem::take(&mut self.levels[0][slot]) {
+                self.len -= 1;
+                expired.push(timer.item);
+            }
+            self.current += 1;
+        }
+    }
+
+    //moves the timers of the higher levels whose slot starts at the current tick down
+    fn cascade(&mut self) {
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.current & ((1 << shift) - 1) != 0 {
+                break;
+            }
+
+            let slot = ((self.current >> shift) as usize) & (SLOTS - 1);
+            for timer in std::mem::take(&mut self.levels[level][slot]) {
+                self.place(timer);
+            }
+        }
+    }
+
+    fn place(&mut self, timer: Timer<T>) {
+        let differing = timer.tick ^ self.current;
+        let level = if differing == 0 {
+            0
+        } else {
+            ((63 - differing.leading_zeros()) / SLOT_BITS) as usize
+        };
+
+        let (level, tick) = if level < LEVELS {
+            (level, timer.tick)
+        } else {
+            //the last slot before the current one comes up again, the timer is placed again then
+            let last = LEVELS - 1;
+            let span = 1u64 << (SLOT_BITS * LEVELS as u32);
+            (last, self.current + span - 1)
+        };
+        let slot = ((tick >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1);
+        self.levels[level][slot].push(timer);
+    }
+
+    //rounded up so a timer never expires early
+    fn tick_of(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.start).as_nanos();
+        let tick = self.tick.as_nanos();
+        nanos.div_ceil(tick) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(1);
+
+    fn advance(wheel: &mut TimerWheel<u32>, now: Instant) -> Vec<u32> {
+        let mut expired = Vec::new();
+        wheel.advance(now, &mut expired);
+        expired.sort();
+        expired
+    }
+
+    #[test]
+    fn timers_expire_at_their_deadline() {
+        let mut wheel = TimerWheel::new(TICK);
+        let start = wheel.start;
+        wheel.insert(start + Duration::from_millis(5), 1);
+        wheel.insert(start + Duration::from_millis(5), 2);
+        //past the first level
+        wheel.insert(start + Duration::from_millis(300), 3);
+        //past the second level
+        wheel.insert(start + Duration::from_secs(10), 4);
+        assert_eq!(wheel.len(), 4);
+
+        assert!(advance(&mut wheel, start + Duration::from_millis(4)).is_empty());
+        assert_eq!(
+            advance(&mut wheel, start + Duration::from_millis(5)),
+            [1, 2]
+        );
+        assert!(advance(&mut wheel, start + Duration::from_millis(299)).is_empty());
+        assert_eq!(advance(&mut wheel, start + Duration::from_millis(300)), [3]);
+        assert!(advance(&mut wheel, start + Duration::from_millis(9999)).is_empty());
+        assert_eq!(advance(&mut wheel, start + Duration::from_secs(10)), [4]);
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn late_and_distant_timers() {
+        let mut wheel = TimerWheel::new(TICK);
+        let start = wheel.start;
+        assert!(advance(&mut wheel, start + Duration::from_millis(100)).is_empty());
+
+        //already passed
+        wheel.insert(start, 1);
+        assert_eq!(advance(&mut wheel, start + Duration::from_millis(101)), [1]);
+
+        //beyond the last level
+        let distant = start + Duration::from_secs(6 * 60 * 60);
+        wheel.insert(distant, 2);
+        assert!(advance(&mut wheel, distant - TICK).is_empty());
+        assert_eq!(advance(&mut wheel, distant), [2]);
+    }
+}
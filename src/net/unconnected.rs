@@ -0,0 +1,62 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use super::{bytes_with_header, Bytes, PacketType, FRAGMENT_SIZE, PROTOCOL_ID_SIZE};
+
+//largest payload an unconnected packet can carry, they are never fragmented
+pub const MAX_UNCONNECTED_SIZE: usize = FRAGMENT_SIZE;
+
+//called on the server process thread for every unconnected packet, the returned bytes are sent back to the sender
+pub type UnconnectedHandler = Box<dyn FnMut(SocketAddr, &[u8]) -> Option<Bytes> + Send + Sync>;
+//the workers of a server share its handler
+pub type SharedUnconnectedHandler = Arc<Mutex<Option<UnconnectedHandler>>>;
+
+//creates a buffer prefixed with the protocol id and the unconnected packet type ready to be sent
+pub fn write_unconnected(data: &[u8]) -> Bytes {
+    let mut buffer = bytes_with_header!(1 + data.len());
+    buffer[PROTOCOL_ID_SIZE] = PacketType::Unconnected as u8;
+    buffer[PROTOCOL_ID_SIZE + 1..].copy_from_slice(data);
+    buffer
+}
+
+//returns the payload if the buffer (with the protocol id already stripped) is an unconnected packet
+pub fn read_unconnected(buffer: &[u8]) -> Option<&[u8]> {
+    match buffer.first() {
+        Some(packet_type) if *packet_type == PacketType::Unconnected as u8 => Some(&buffer[1..]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::connections::ControlPacket;
+
+    use super::*;
+
+    #[test]
+    fn write_read_roundtrip() {
+        let buffer = write_unconnected(&[1, 2, 3]);
+        assert_eq!(buffer.len(), PROTOCOL_ID_SIZE + 1 + 3);
+        assert_eq!(
+            read_unconnected(&buffer[PROTOCOL_ID_SIZE..]),
+            Some(&[1_u8, 2, 3][..])
+        );
+
+        let empty = write_unconnected(&[]);
+        assert_eq!(read_unconnected(&empty[PROTOCOL_ID_SIZE..]), Some(&[][..]));
+    }
+
+    #[test]
+    fn control_packets_are_not_unconnected() {
+        let buffer = ControlPacket::ConnectionRequest {
+            client_salt: 1,
+            flags: 0,
+        }
+        .write();
+
+        assert_eq!(read_unconnected(&buffer[PROTOCOL_ID_SIZE..]), None);
+        assert_eq!(read_unconnected(&[]), None);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{fmt, sync::Arc};
+
+use super::{channel::ReadPayload, connections::ConnectionId};
+
+//what the server does with a payload its validator looked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    //delivered, a PayloadFlagged event comes before its Receive
+    Flag,
+    //dropped, only the PayloadFlagged event is emitted
+    Reject,
+    //dropped and the connection is kicked
+    Disconnect,
+}
+
+//called on the process thread with every reassembled payload before its Receive is queued, for
+//anti-cheat or schema checks. a server with several workers calls it from all of them
+#[derive(Clone)]
+pub struct PayloadValidator(Arc<ValidatorFn>);
+
+type ValidatorFn = dyn Fn(ConnectionId, &[u8]) -> Verdict + Send + Sync;
+
+impl PayloadValidator {
+    pub fn new(validator: impl Fn(ConnectionId, &[u8]) -> Verdict + Send + Sync + 'static) -> Self {
+        Self(Arc::new(validator))
+    }
+}
+
+impl fmt::Debug for PayloadValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PayloadValidator")
+    }
+}
+
+pub fn validate(
+    validator: Option<&PayloadValidator>,
+    connection_id: ConnectionId,
+    payload: &ReadPayload,
+) -> Verdict {
+    let Some(validator) = validator else {
+        return Verdict::Accept;
+    };
+
+    match payload {
+        ReadPayload::Single(buffer) => (validator.0)(connection_id, buffer),
+        //the fragments are only joined for the validator, the event keeps them apart
+        ReadPayload::Parts(parts) => (validator.0)(connection_id, &parts.concat()),
+        ReadPayload::Request(_, data) => (validator.0)(connection_id, data),
+        _ => Verdict::Accept,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_are_validated_as_one_payload() {
+        let validator = PayloadValidator::new(|_, payload| match payload {
+            [1, 2, 3] => Verdict::Accept,
+            _ => Verdict::Reject,
+        });
+        let connection_id = ConnectionId::from_bits(0);
+
+        let parts = ReadPayload::Parts(vec![vec![1], vec![2, 3]]);
+        assert_eq!(
+            validate(Some(&validator), connection_id, &parts),
+            Verdict::Accept
+        );
+        let single = ReadPayload::Single(vec![4]);
+        assert_eq!(
+            validate(Some(&validator), connection_id, &single),
+            Verdict::Reject
+        );
+        assert_eq!(validate(None, connection_id, &single), Verdict::Accept);
+    }
+}
@@ -0,0 +1,487 @@
+use super::{
+    channel::{ACK_DELAY_SIZE, ACK_DELAY_UNIT, WINDOW_SIZE},
+    checksum::CHECKSUM_SIZE,
+    connections::{
+        ControlPacket, FLAG_ACK_DELAY, FLAG_CHECKSUM, FLAG_COMPACT_HEADER, FLAG_FLOW_CONTROL,
+        FLAG_INTERLEAVE,
+    },
+    header::{FRAG_HEADER_SIZE, HEADER_SIZE},
+    PacketType, PROTOCOL_ID_SIZE,
+};
+
+//a description of the packets for dissectors and clients in other languages, built from the
+//types and sizes the crate itself uses so it can't drift from them. serializable with the serde
+//feature, e.g. written as json by a build step of the other project
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WireFormat {
+    //every datagram starts with the protocol id, the packet type byte follows it
+    pub protocol_id_size: usize,
+    pub packet_types: Vec<PacketTypeInfo>,
+    //the layouts of the channel packets, after the protocol id
+    pub headers: Vec<Layout>,
+    //the handshake packets and the reconnect request, after the protocol id
+    pub control_packets: Vec<Layout>,
+    //the features negotiated in the flags of the handshake
+    pub flags: Vec<FlagInfo>,
+    pub handshake: Vec<HandshakeStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PacketTypeInfo {
+    pub name: String,
+    pub id: u8,
+    //sent on the channel of a connection with one of the headers
+    pub session: bool,
+    //the header has the fragment fields
+    pub fragment: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Layout {
+    pub name: String,
+    //the ids of the packet types it's used for, the type byte is the discriminator. a channel
+    //header starts with the seq so its type is at the offset of the packet_type field
+    pub packet_types: Vec<u8>,
+    //the flag it's only used with, the flags are known from the handshake of the connection
+    pub flag: Option<&'static str>,
+    pub fields: Vec<Field>,
+    //None if a field has a variable size
+    pub size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Field {
+    pub name: &'static str,
+    //None after a field of variable size
+    pub offset: Option<usize>,
+    //None for varints
+    pub size: Option<usize>,
+    pub encoding: &'static str,
+    pub description: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FlagInfo {
+    pub name: &'static str,
+    pub bit: u8,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HandshakeStep {
+    pub from: &'static str,
+    pub packet_type: String,
+    pub description: &'static str,
+}
+
+//the fields of a layout without the offsets, they are added up from the sizes
+type FieldSpec = (&'static str, Option<usize>, &'static str, &'static str);
+
+pub fn wire_format() -> WireFormat {
+    WireFormat {
+        protocol_id_size: PROTOCOL_ID_SIZE,
+        packet_types: PacketType::ALL
+            .iter()
+            .map(|packet_type| PacketTypeInfo {
+                name: format!("{packet_type:?}"),
+                id: *packet_type as u8,
+                session: packet_type.is_session_variant(),
+                fragment: packet_type.is_frag_variant(),
+            })
+            .collect(),
+        headers: headers(),
+        control_packets: control_packets(),
+        flags: flags(),
+        handshake: handshake(),
+    }
+}
+
+fn layout(
+    name: impl Into<String>,
+    packet_types: Vec<u8>,
+    flag: Option<&'static str>,
+    specs: &[FieldSpec],
+) -> Layout {
+    let mut offset = Some(0);
+    let fields = specs
+        .iter()
+        .map(|&(name, size, encoding, description)| {
+            let field = Field {
+                name,
+                offset,
+                size,
+                encoding,
+                description,
+            };
+            offset = offset.zip(size).map(|(offset, size)| offset + size);
+            field
+        })
+        .collect();
+    Layout {
+        name: name.into(),
+        packet_types,
+        flag,
+        fields,
+        size: offset,
+    }
+}
+
+const FRAGMENT_FIELDS: [FieldSpec; 3] = [
+    (
+        "fragment_group_id",
+        Some(2),
+        "u16 le",
+        "the message the fragment belongs to",
+    ),
+    (
+        "fragment_id",
+        Some(1),
+        "u8",
+        "the index of the fragment in its message",
+    ),
+    (
+        "fragment_size",
+        Some(1),
+        "u8",
+        "the fragments of the message, 0 cancels the message of the group",
+    ),
+];
+
+fn headers() -> Vec<Layout> {
+    let full: [FieldSpec; 5] = [
+        ("seq", Some(2), "u16 le", "the sequence of the packet"),
+        ("packet_type", Some(1), "u8", "the id of the packet type"),
+        (
+            "session_key",
+            Some(8),
+            "u64 le",
+            "derived by both sides in the handshake, never sent in it",
+        ),
+        (
+            "ack",
+            Some(2),
+            "u16 le",
+            "the newest reliable sequence received",
+        ),
+        (
+            "ack_bits",
+            Some(4),
+            "u32 le",
+            "bit n is set if the sequence ack - 1 - n was received",
+        ),
+    ];
+    let compact: [FieldSpec; 4] = [
+        ("packet_type", Some(1), "u8", "the id of the packet type"),
+        ("seq", Some(2), "u16 le", "the sequence of the packet"),
+        (
+            "ack_distance",
+            None,
+            "zigzag varint",
+            "seq minus ack as an i16",
+        ),
+        (
+            "missing_bits",
+            None,
+            "varint",
+            "the ack bits inverted, the packets that are missing",
+        ),
+    ];
+    let with_fragment = |fields: &[FieldSpec]| [fields, &FRAGMENT_FIELDS[..]].concat();
+    let types = |filter: fn(&PacketType) -> bool| -> Vec<u8> {
+        PacketType::ALL
+            .into_iter()
+            .filter(filter)
+            .map(|ty| ty as u8)
+            .collect()
+    };
+    let single = types(|ty| ty.is_session_variant() && !ty.is_frag_variant());
+    let fragment = types(PacketType::is_frag_variant);
+    let session = types(PacketType::is_session_variant);
+
+    vec![
+        layout("header", single.clone(), None, &full),
+        layout(
+            "fragment header",
+            fragment.clone(),
+            None,
+            &with_fragment(&full),
+        ),
+        layout("compact header", single, Some("compact_header"), &compact),
+        layout(
+            "compact fragment header",
+            fragment,
+            Some("compact_header"),
+            &with_fragment(&compact),
+        ),
+        //written after the header in this order if negotiated, the payload follows them
+        layout(
+            "ack delay",
+            session.clone(),
+            Some("ack_delay"),
+            &[(
+                "ack_delay",
+                Some(ACK_DELAY_SIZE),
+                "u16 le",
+                "how long the ack was held, see the ack_delay flag",
+            )],
+        ),
+        layout(
+            "window",
+            session.clone(),
+            Some("flow_control"),
+            &[(
+                "window",
+                Some(WINDOW_SIZE),
+                "u16 le",
+                "reliable packets the remote may send past the ack",
+            )],
+        ),
+        layout(
+            "checksum trailer",
+            session,
+            Some("checksum"),
+            &[(
+                "checksum",
+                Some(CHECKSUM_SIZE),
+                "u32 le",
+                "CRC32C of the packet after the protocol id",
+            )],
+        ),
+    ]
+}
+
+fn control_packets() -> Vec<Layout> {
+    let packet_type: FieldSpec = ("packet_type", Some(1), "u8", "the id of the packet type");
+    let packets: [(PacketType, &[FieldSpec]); 5] = [
+        (
+            PacketType::ConnectionRequest,
+            &[
+                (
+                    "client_salt",
+                    Some(8),
+                    "u64 le",
+                    "random, part of the session key",
+                ),
+                ("flags", Some(1), "u8", "the features the client wants"),
+            ],
+        ),
+        (
+            PacketType::Challenge,
+            &[
+                (
+                    "client_tag",
+                    Some(8),
+                    "u64 le",
+                    "matches the challenge to the request",
+                ),
+                (
+                    "server_salt",
+                    Some(8),
+                    "u64 le",
+                    "random, part of the session key",
+                ),
+            ],
+        ),
+        (
+            PacketType::ChallengeResponse,
+            &[(
+                "response",
+                Some(8),
+                "u64 le",
+                "proof that the client derived the session key",
+            )],
+        ),
+        (
+            PacketType::ConnectionAccepted,
+            &[
+                (
+                    "connection_id",
+                    Some(4),
+                    "u32 le",
+                    "the id of the connection",
+                ),
+                ("flags", Some(1), "u8", "the features both sides agreed on"),
+            ],
+        ),
+        (
+            PacketType::ReconnectRequired,
+            &[(
+                "session_key",
+                Some(8),
+                "u64 le",
+                "the session key of the packet the server didn't know",
+            )],
+        ),
+    ];
+
+    packets
+        .into_iter()
+        .map(|(ty, fields)| {
+            layout(
+                format!("{ty:?}"),
+                vec![ty as u8],
+                None,
+                &[&[packet_type], fields].concat(),
+            )
+        })
+        .collect()
+}
+
+fn flags() -> Vec<FlagInfo> {
+    vec![
+        FlagInfo {
+            name: "checksum",
+            bit: FLAG_CHECKSUM,
+            description: format!("every packet ends with a {CHECKSUM_SIZE} byte checksum"),
+        },
+        FlagInfo {
+            name: "compact_header",
+            bit: FLAG_COMPACT_HEADER,
+            description: "the channel packets use the compact header".to_string(),
+        },
+        FlagInfo {
+            name: "ack_delay",
+            bit: FLAG_ACK_DELAY,
+            description: format!(
+                "the header is followed by the ack delay in units of {}us",
+                ACK_DELAY_UNIT.as_micros()
+            ),
+        },
+        FlagInfo {
+            name: "flow_control",
+            bit: FLAG_FLOW_CONTROL,
+            description: "the header is followed by the receive window".to_string(),
+        },
+        FlagInfo {
+            name: "interleave",
+            bit: FLAG_INTERLEAVE,
+            description: "the fragments of reliable messages can be sent in turns".to_string(),
+        },
+    ]
+}
+
+fn handshake() -> Vec<HandshakeStep> {
+    let step = |from, packet_type: PacketType, description| HandshakeStep {
+        from,
+        packet_type: format!("{packet_type:?}"),
+        description,
+    };
+    vec![
+        step(
+            "client",
+            PacketType::ConnectionRequest,
+            "resent until the challenge arrives",
+        ),
+        step(
+            "server",
+            PacketType::Challenge,
+            "the server keeps no state for the address yet",
+        ),
+        step(
+            "client",
+            PacketType::ChallengeResponse,
+            "both sides derive the session key from the salts",
+        ),
+        step(
+            "server",
+            PacketType::ConnectionAccepted,
+            "the channel packets follow with the agreed flags",
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::header::{Header, SendType};
+
+    use super::*;
+
+    fn field<'a>(layout: &'a Layout, name: &str) -> &'a Field {
+        layout
+            .fields
+            .iter()
+            .find(|field| field.name == name)
+            .unwrap()
+    }
+
+    fn read_at(buffer: &[u8], field: &Field) -> u64 {
+        let offset = field.offset.unwrap();
+        let mut bytes = [0; 8];
+        bytes[..field.size.unwrap()].copy_from_slice(&buffer[offset..offset + field.size.unwrap()]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn every_packet_type_is_described() {
+        let format = wire_format();
+        let known: Vec<u8> = (0..=u8::MAX)
+            .filter(|id| PacketType::try_from(*id).is_ok())
+            .collect();
+        let described: Vec<u8> = format.packet_types.iter().map(|info| info.id).collect();
+        assert_eq!(known, described);
+    }
+
+    #[test]
+    fn header_layouts_match_the_written_headers() {
+        let format = wire_format();
+        let mut header = Header::new(0x1234, 0x0102_0304_0506_0708, SendType::Reliable, true);
+        header.ack = 0x4321;
+        header.ack_bits = 0xdead_beef;
+        header.fragment_group_id = 0x0a0b;
+        header.fragment_id = 3;
+        header.fragment_size = 7;
+        let mut buffer = Vec::new();
+        header.write_into(&mut buffer);
+
+        let layout = &format.headers[1];
+        assert_eq!(layout.size, Some(FRAG_HEADER_SIZE));
+        assert_eq!(format.headers[0].size, Some(HEADER_SIZE));
+        for (name, value) in [
+            ("seq", 0x1234),
+            ("packet_type", PacketType::PayloadReliableFrag as u64),
+            ("session_key", 0x0102_0304_0506_0708),
+            ("ack", 0x4321),
+            ("ack_bits", 0xdead_beef),
+            ("fragment_group_id", 0x0a0b),
+            ("fragment_id", 3),
+            ("fragment_size", 7),
+        ] {
+            assert_eq!(read_at(&buffer, field(layout, name)), value, "{name}");
+        }
+
+        //the fixed start of the compact header
+        let mut compact = Vec::new();
+        header.write_compact_into(&mut compact);
+        let layout = &format.headers[2];
+        assert_eq!(read_at(&compact, field(layout, "seq")), 0x1234);
+        assert_eq!(field(layout, "missing_bits").offset, None);
+    }
+
+    #[test]
+    fn control_layouts_match_the_written_packets() {
+        let format = wire_format();
+        for layout in &format.control_packets {
+            let packet_type = PacketType::ALL
+                .into_iter()
+                .find(|ty| format!("{ty:?}") == layout.name)
+                .unwrap();
+            assert_eq!(layout.size, ControlPacket::size_of(packet_type));
+        }
+
+        let buffer = ControlPacket::Challenge {
+            client_tag: 11,
+            server_salt: 22,
+        }
+        .write();
+        let layout = &format.control_packets[1];
+        let packet = &buffer[format.protocol_id_size..];
+        assert_eq!(read_at(packet, field(layout, "client_tag")), 11);
+        assert_eq!(read_at(packet, field(layout, "server_salt")), 22);
+    }
+}
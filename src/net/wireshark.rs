@@ -0,0 +1,318 @@
+use std::fmt::Write;
+
+use super::wire_format::{Field, Layout, WireFormat};
+
+//the layouts the dissector looks up by name, the rest are taken from the packet types
+const HEADER: &str = "header";
+const FRAGMENT_HEADER: &str = "fragment header";
+const COMPACT_HEADER: &str = "compact header";
+const COMPACT_FRAGMENT_HEADER: &str = "compact fragment header";
+const CHECKSUM_TRAILER: &str = "checksum trailer";
+
+impl WireFormat {
+    //a Wireshark dissector in Lua for the packets sent to or from the port, dropped into the
+    //plugins folder of Wireshark. the negotiated flags can't be seen in a single packet so they
+    //are preferences of the protocol. regenerate it whenever the crate is updated
+    pub fn wireshark_dissector(&self, udp_port: u16) -> String {
+        let mut lua = String::new();
+        self.write_dissector(&mut lua, udp_port)
+            .expect("writing to a string doesn't fail");
+        lua
+    }
+
+    fn write_dissector(&self, lua: &mut String, udp_port: u16) -> std::fmt::Result {
+        writeln!(lua, "-- generated from the wire format of game-networking")?;
+        writeln!(lua, "local proto = Proto(\"gamenet\", \"game-networking\")")?;
+        writeln!(lua, "local PROTOCOL_ID_SIZE = {}", self.protocol_id_size)?;
+        writeln!(lua)?;
+
+        for flag in &self.flags {
+            writeln!(
+                lua,
+                "proto.prefs.{} = Pref.bool({:?}, false, {:?})",
+                flag.name, flag.name, flag.description
+            )?;
+        }
+        writeln!(lua)?;
+
+        writeln!(lua, "local packet_types = {{")?;
+        for info in &self.packet_types {
+            writeln!(lua, "  [{}] = {:?},", info.id, info.name)?;
+        }
+        writeln!(lua, "}}")?;
+        writeln!(lua, "local session_types = {{")?;
+        for info in self.packet_types.iter().filter(|info| info.session) {
+            writeln!(lua, "  [{}] = {},", info.id, info.fragment)?;
+        }
+        writeln!(lua, "}}")?;
+        writeln!(lua)?;
+
+        self.write_fields(lua)?;
+        self.write_layouts(lua)?;
+        lua.push_str(DISSECT);
+        writeln!(
+            lua,
+            "DissectorTable.get(\"udp.port\"):add({udp_port}, proto)"
+        )
+    }
+
+    //one field per name, the layouts sharing a name share the field
+    fn write_fields(&self, lua: &mut String) -> std::fmt::Result {
+        writeln!(lua, "local fields = {{")?;
+        writeln!(
+            lua,
+            "  protocol_id = ProtoField.bytes(\"gamenet.protocol_id\", \"protocol_id\"),"
+        )?;
+        writeln!(
+            lua,
+            "  payload = ProtoField.bytes(\"gamenet.payload\", \"payload\"),"
+        )?;
+        let mut names = Vec::new();
+        for field in self.layouts().flat_map(|layout| &layout.fields) {
+            if names.contains(&field.name) || field.name == "protocol_id" {
+                continue;
+            }
+            names.push(field.name);
+            let value_strings = match field.name {
+                "packet_type" => "packet_types",
+                _ => "nil",
+            };
+            writeln!(
+                lua,
+                "  {name} = ProtoField.{kind}(\"gamenet.{name}\", \"{name}\", base.DEC, {value_strings}, nil, {description:?}),",
+                name = field.name,
+                kind = proto_field_kind(field),
+                description = field.description,
+            )?;
+        }
+        writeln!(lua, "}}")?;
+        writeln!(lua, "proto.fields = fields")?;
+        writeln!(lua)
+    }
+
+    fn write_layouts(&self, lua: &mut String) -> std::fmt::Result {
+        writeln!(lua, "local layouts = {{")?;
+        for layout in self.layouts() {
+            writeln!(lua, "  [{:?}] = {{", layout.name)?;
+            for field in &layout.fields {
+                match field.size {
+                    Some(size) => {
+                        writeln!(lua, "    {{ name = {:?}, size = {size} }},", field.name)?
+                    }
+                    None => writeln!(lua, "    {{ name = {:?} }},", field.name)?,
+                }
+            }
+            writeln!(lua, "  }},")?;
+        }
+        writeln!(lua, "}}")?;
+
+        //the control packets have a fixed size, it tells them apart from the channel packets
+        writeln!(lua, "local control_layouts = {{")?;
+        for layout in &self.control_packets {
+            if let (Some(size), [packet_type]) = (layout.size, layout.packet_types.as_slice()) {
+                writeln!(
+                    lua,
+                    "  [{packet_type}] = {{ name = {:?}, size = {size} }},",
+                    layout.name
+                )?;
+            }
+        }
+        writeln!(lua, "}}")?;
+
+        //the extensions follow the header in this order when their flag is set
+        writeln!(lua, "local extensions = {{")?;
+        for layout in &self.headers {
+            let is_header = [
+                HEADER,
+                FRAGMENT_HEADER,
+                COMPACT_HEADER,
+                COMPACT_FRAGMENT_HEADER,
+                CHECKSUM_TRAILER,
+            ]
+            .contains(&layout.name.as_str());
+            if let (false, Some(flag)) = (is_header, layout.flag) {
+                writeln!(lua, "  {{ layout = {:?}, flag = {flag:?} }},", layout.name)?;
+            }
+        }
+        writeln!(lua, "}}")?;
+        writeln!(
+            lua,
+            "local CHECKSUM_SIZE = {}",
+            self.layout_size(CHECKSUM_TRAILER)
+        )?;
+        writeln!(lua, "local HEADER_SIZE = {}", self.layout_size(HEADER))?;
+        writeln!(
+            lua,
+            "local HEADER_TYPE_OFFSET = {}",
+            self.type_offset(HEADER)
+        )?;
+        writeln!(lua)
+    }
+
+    fn layouts(&self) -> impl Iterator<Item = &Layout> {
+        self.headers.iter().chain(&self.control_packets)
+    }
+
+    fn layout(&self, name: &str) -> &Layout {
+        self.layouts()
+            .find(|layout| layout.name == name)
+            .expect("the wire format has the layout")
+    }
+
+    fn layout_size(&self, name: &str) -> usize {
+        self.layout(name).size.expect("the layout has a fixed size")
+    }
+
+    fn type_offset(&self, name: &str) -> usize {
+        self.layout(name)
+            .fields
+            .iter()
+            .find(|field| field.name == "packet_type")
+            .and_then(|field| field.offset)
+            .expect("the layout has the packet type at a fixed offset")
+    }
+}
+
+//every field is a little endian integer, the varints are shown with their value
+fn proto_field_kind(field: &Field) -> &'static str {
+    match field.size {
+        Some(1) => "uint8",
+        Some(2) => "uint16",
+        Some(8) => "uint64",
+        _ => "uint32",
+    }
+}
+
+//the part of the dissector that doesn't depend on the layouts
+const DISSECT: &str = r#"
+local function read_varint(tvb, offset)
+  local value, scale, start = 0, 1, offset
+  while offset < tvb:len() and offset - start < 5 do
+    local byte = tvb(offset, 1):uint()
+    offset = offset + 1
+    value = value + (byte % 128) * scale
+    if byte < 128 then
+      return value, offset - start
+    end
+    scale = scale * 128
+  end
+  return nil
+end
+
+-- adds the fields of the layout, nil if the packet is too short for it
+local function add_layout(tvb, tree, name, offset)
+  if offset >= tvb:len() then
+    return nil
+  end
+  local subtree = tree:add(proto, tvb(offset), name)
+  for _, field in ipairs(layouts[name]) do
+    if field.size then
+      if offset + field.size > tvb:len() then
+        return nil
+      end
+      subtree:add_le(fields[field.name], tvb(offset, field.size))
+      offset = offset + field.size
+    else
+      local value, len = read_varint(tvb, offset)
+      if not value then
+        return nil
+      end
+      subtree:add(fields[field.name], tvb(offset, len), value)
+      offset = offset + len
+    end
+  end
+  return offset
+end
+
+function proto.dissector(tvb, pinfo, tree)
+  local len = tvb:len()
+  if len <= PROTOCOL_ID_SIZE then
+    return 0
+  end
+  local offset = PROTOCOL_ID_SIZE
+  local first = tvb(offset, 1):uint()
+
+  -- the control packets have the type first and a fixed size, the channel packets have it
+  -- after the seq unless they use the compact header
+  local packet_type, layout
+  local control = control_layouts[first]
+  if control and control.size == len - offset then
+    packet_type, layout = first, control.name
+  elseif proto.prefs.compact_header and session_types[first] ~= nil then
+    packet_type = first
+    layout = session_types[first] and "compact fragment header" or "compact header"
+  elseif len - offset >= HEADER_SIZE and session_types[tvb(offset + HEADER_TYPE_OFFSET, 1):uint()] ~= nil then
+    packet_type = tvb(offset + HEADER_TYPE_OFFSET, 1):uint()
+    layout = session_types[packet_type] and "fragment header" or "header"
+  elseif packet_types[first] then
+    packet_type = first
+  else
+    return 0
+  end
+
+  pinfo.cols.protocol = "GAMENET"
+  pinfo.cols.info = packet_types[packet_type]
+  local root = tree:add(proto, tvb(), "game-networking, " .. packet_types[packet_type])
+  root:add(fields.protocol_id, tvb(0, PROTOCOL_ID_SIZE))
+
+  local trailer = 0
+  if layout then
+    offset = add_layout(tvb, root, layout, offset)
+    if not offset then
+      return len
+    end
+    if session_types[packet_type] ~= nil then
+      for _, extension in ipairs(extensions) do
+        if proto.prefs[extension.flag] then
+          offset = add_layout(tvb, root, extension.layout, offset)
+          if not offset then
+            return len
+          end
+        end
+      end
+      if proto.prefs.checksum then
+        trailer = CHECKSUM_SIZE
+      end
+    end
+  else
+    -- the unconnected packets only have the type before their payload
+    root:add(fields.packet_type, tvb(offset, 1))
+    offset = offset + 1
+  end
+
+  if len - trailer > offset then
+    root:add(fields.payload, tvb(offset, len - trailer - offset))
+  end
+  if trailer > 0 and len - trailer >= offset then
+    add_layout(tvb, root, "checksum trailer", len - trailer)
+  end
+  return len
+end
+
+"#;
+
+#[cfg(test)]
+mod tests {
+    use crate::net::wire_format::wire_format;
+
+    #[test]
+    fn dissector_has_every_packet_type_and_field() {
+        let format = wire_format();
+        let lua = format.wireshark_dissector(9000);
+
+        for info in &format.packet_types {
+            assert!(lua.contains(&format!("[{}] = {:?}", info.id, info.name)));
+        }
+        for layout in format.headers.iter().chain(&format.control_packets) {
+            assert!(lua.contains(&format!("[{:?}] = {{", layout.name)));
+            for field in &layout.fields {
+                assert!(lua.contains(&format!("\"gamenet.{}\"", field.name)));
+            }
+        }
+        for flag in &format.flags {
+            assert!(lua.contains(&format!("proto.prefs.{} =", flag.name)));
+        }
+        assert!(lua.contains("local HEADER_TYPE_OFFSET = 2"));
+        assert!(lua.contains(":add(9000, proto)"));
+    }
+}
@@ -0,0 +1,39 @@
+use bit_field::BitField;
+
+//least significant bit is the remote_seq - 1 value
+pub fn generate_ack_bits(remote_seq: u16, is_received: impl Fn(u16) -> bool) -> u32 {
+    let mut ack_bitfield = 0;
+
+    let mut seq = remote_seq.wrapping_sub(1);
+    for pos in 0..32 {
+        if is_received(seq) {
+            ack_bitfield.set_bit(pos, true);
+        }
+        seq = seq.wrapping_sub(1);
+    }
+    ack_bitfield
+}
+
+//the sequences acked by the bitfield, not including the ack itself
+pub fn acked_by_bits(ack: u16, ack_bitfield: u32) -> impl Iterator<Item = u16> {
+    (0..32_u16)
+        .filter(move |bit_pos| ack_bitfield.get_bit(*bit_pos as usize))
+        .map(move |bit_pos| ack.wrapping_sub(bit_pos).wrapping_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn bits_roundtrip() {
+        let received = [4_u16, 3, 65535, 65509];
+        let ack_bits = generate_ack_bits(5, |seq| received.contains(&seq));
+        assert_eq!(ack_bits, 0x8000_0023);
+
+        let acked: Vec<u16> = acked_by_bits(5, ack_bits).collect();
+        assert_eq!(acked, received);
+    }
+}
@@ -0,0 +1,112 @@
+use core::fmt;
+
+//derives the values exchanged in the handshake from the salts, both sides have to use the same scheme
+pub trait ChallengeScheme: fmt::Debug + Send + Sync {
+    //sent back in the challenge so the client can match it with its request without the salt being echoed
+    fn client_tag(&self, client_salt: u64) -> u64;
+    //never sent over the wire, both sides derive it from the two salts
+    fn session_key(&self, client_salt: u64, server_salt: u64) -> u64;
+    //sent in the challenge response to prove the client derived the same session key
+    fn response(&self, session_key: u64) -> u64;
+}
+
+//the default scheme, every value is a SipHash-2-4 with its own key so an observer that only sees
+//one direction of the handshake is missing either the client salt or the server salt.
+//it doesn't replace a key exchange, seeing both directions is still enough to derive the key
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SipHashChallenge;
+
+const CLIENT_TAG_KEY: (u64, u64) = (0x6368_616c_6c65_6e67, 0x652d_636c_6965_6e74);
+const SESSION_KEY_KEY: (u64, u64) = (0x7365_7373_696f_6e2d, 0x6b65_792d_7369_7031);
+const RESPONSE_KEY: (u64, u64) = (0x7265_7370_6f6e_7365, 0x2d70_726f_6f66_2d31);
+
+impl ChallengeScheme for SipHashChallenge {
+    fn client_tag(&self, client_salt: u64) -> u64 {
+        siphash24(CLIENT_TAG_KEY, &client_salt.to_le_bytes())
+    }
+
+    fn session_key(&self, client_salt: u64, server_salt: u64) -> u64 {
+        let mut data = [0_u8; 16];
+        data[..8].copy_from_slice(&client_salt.to_le_bytes());
+        data[8..].copy_from_slice(&server_salt.to_le_bytes());
+        siphash24(SESSION_KEY_KEY, &data)
+    }
+
+    fn response(&self, session_key: u64) -> u64 {
+        siphash24(RESPONSE_KEY, &session_key.to_le_bytes())
+    }
+}
+
+pub fn siphash24(key: (u64, u64), data: &[u8]) -> u64 {
+    let mut v = [
+        key.0 ^ 0x736f_6d65_7073_6575,
+        key.1 ^ 0x646f_7261_6e64_6f6d,
+        key.0 ^ 0x6c79_6765_6e65_7261,
+        key.1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0_u8; 8];
+        word.copy_from_slice(chunk);
+        compress(&mut v, u64::from_le_bytes(word));
+    }
+
+    //the last word holds the remaining bytes and the length in the top byte
+    let mut last = [0_u8; 8];
+    let remainder = chunks.remainder();
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= m;
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_vector() {
+        //from the SipHash paper, key 00..0f and the message 00..0e
+        let key = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+        let data: [u8; 15] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash24(key, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn handshake_values_differ() {
+        let scheme = SipHashChallenge;
+        let session_key = scheme.session_key(1, 2);
+
+        assert_ne!(session_key, scheme.session_key(2, 1));
+        assert_ne!(session_key, 1 ^ 2);
+        assert_ne!(scheme.response(session_key), session_key);
+        assert_ne!(scheme.client_tag(1), 1);
+    }
+}
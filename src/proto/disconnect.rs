@@ -0,0 +1,200 @@
+use alloc::string::String;
+use core::fmt;
+
+use super::Bytes;
+
+//longer messages are cut at the last character that fits
+pub const MAX_DISCONNECT_MESSAGE_SIZE: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectCode {
+    //the client closed the connection
+    UserQuit,
+    //the server removed the client
+    Kicked,
+    //the server is shutting down
+    Shutdown,
+    //the connection didn't send anything for too long
+    Idle,
+    //nothing listens on the port of the remote anymore, only reported locally from the ICMP replies
+    Refused,
+    //the server doesn't know the session anymore, it restarted or dropped the connection without
+    //telling the client
+    ServerRestarted,
+    //a code this version doesn't know about
+    Other(u8),
+}
+
+impl DisconnectCode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            DisconnectCode::UserQuit => 0,
+            DisconnectCode::Kicked => 1,
+            DisconnectCode::Shutdown => 2,
+            DisconnectCode::Idle => 3,
+            DisconnectCode::Refused => 4,
+            DisconnectCode::ServerRestarted => 5,
+            DisconnectCode::Other(code) => code,
+        }
+    }
+
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            0 => DisconnectCode::UserQuit,
+            1 => DisconnectCode::Kicked,
+            2 => DisconnectCode::Shutdown,
+            3 => DisconnectCode::Idle,
+            4 => DisconnectCode::Refused,
+            5 => DisconnectCode::ServerRestarted,
+            code => DisconnectCode::Other(code),
+        }
+    }
+}
+
+//sent after the header of the disconnect packets as the code, the message length and the message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectReason {
+    pub code: DisconnectCode,
+    pub message: Option<String>,
+}
+
+impl DisconnectReason {
+    pub fn new(code: DisconnectCode) -> Self {
+        Self {
+            code,
+            message: None,
+        }
+    }
+
+    pub fn with_message(code: DisconnectCode, message: &str) -> Self {
+        Self {
+            code,
+            message: Some(String::from(message)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        2 + self.message_len()
+    }
+
+    pub fn write_into(&self, buffer: &mut Bytes) {
+        let message_len = self.message_len();
+        buffer.push(self.code.to_u8());
+        buffer.push(message_len as u8);
+        if let Some(message) = &self.message {
+            buffer.extend_from_slice(&message.as_bytes()[..message_len]);
+        }
+    }
+
+    //never fails, a packet without a body comes from a peer that only quits
+    //and a message that isn't valid UTF-8 is left out
+    pub fn read(buffer: &[u8]) -> Self {
+        let Some(&code) = buffer.first() else {
+            return Self::new(DisconnectCode::UserQuit);
+        };
+
+        let message = buffer.get(1).and_then(|&len| {
+            let bytes = buffer.get(2..2 + len as usize)?;
+            match core::str::from_utf8(bytes) {
+                Ok(message) if !message.is_empty() => Some(String::from(message)),
+                _ => None,
+            }
+        });
+
+        Self {
+            code: DisconnectCode::from_u8(code),
+            message,
+        }
+    }
+
+    fn message_len(&self) -> usize {
+        let Some(message) = &self.message else {
+            return 0;
+        };
+
+        let mut len = message.len().min(MAX_DISCONNECT_MESSAGE_SIZE);
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        len
+    }
+}
+
+impl Default for DisconnectReason {
+    fn default() -> Self {
+        Self::new(DisconnectCode::UserQuit)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            DisconnectCode::UserQuit => write!(f, "user quit")?,
+            DisconnectCode::Kicked => write!(f, "kicked")?,
+            DisconnectCode::Shutdown => write!(f, "shutdown")?,
+            DisconnectCode::Idle => write!(f, "idle")?,
+            DisconnectCode::Refused => write!(f, "refused")?,
+            DisconnectCode::ServerRestarted => write!(f, "server restarted")?,
+            DisconnectCode::Other(code) => write!(f, "code {code}")?,
+        }
+
+        match &self.message {
+            Some(message) => write!(f, ": {message}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn reason_roundtrip() {
+        for reason in [
+            DisconnectReason::new(DisconnectCode::Idle),
+            DisconnectReason::new(DisconnectCode::Refused),
+            DisconnectReason::new(DisconnectCode::ServerRestarted),
+            DisconnectReason::with_message(DisconnectCode::Kicked, "cheating"),
+            DisconnectReason::new(DisconnectCode::Other(200)),
+        ] {
+            let mut buffer = Vec::new();
+            reason.write_into(&mut buffer);
+            assert_eq!(buffer.len(), reason.size());
+            assert_eq!(DisconnectReason::read(&buffer), reason);
+        }
+    }
+
+    #[test]
+    fn long_messages_are_cut_on_a_char_boundary() {
+        let message = "é".repeat(200);
+        let reason = DisconnectReason::with_message(DisconnectCode::Shutdown, &message);
+
+        let mut buffer = Vec::new();
+        reason.write_into(&mut buffer);
+        let read = DisconnectReason::read(&buffer);
+
+        assert_eq!(read.message.unwrap(), "é".repeat(127));
+        assert_eq!(reason.size(), 2 + 254);
+    }
+
+    #[test]
+    fn malformed_bodies_are_tolerated() {
+        assert_eq!(DisconnectReason::read(&[]), DisconnectReason::default());
+        //the message length runs past the packet
+        assert_eq!(
+            DisconnectReason::read(&[1, 10, b'a']),
+            DisconnectReason::new(DisconnectCode::Kicked)
+        );
+        assert_eq!(
+            DisconnectReason::read(&[2, 2, 0xff, 0xfe]),
+            DisconnectReason::new(DisconnectCode::Shutdown)
+        );
+        assert_eq!(
+            DisconnectReason::with_message(DisconnectCode::Kicked, "afk").to_string(),
+            "kicked: afk"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use core::fmt;
+
+use super::PacketType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetError {
+    //the packet didn't contain any data
+    EmptyPacket,
+    //the packet type byte doesn't map to any known packet type
+    UnknownPacketType(u8),
+    //a known packet type arrived where it isn't expected
+    UnexpectedPacketType(PacketType),
+    //the packet length doesn't match the length of its packet type
+    InvalidLength {
+        packet_type: PacketType,
+        expected: usize,
+        actual: usize,
+    },
+    //a reliable send found the send window and the queue behind it full
+    WindowFull,
+    //the message doesn't fit in the fragments of one message, split_hint tells where to cut it
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::EmptyPacket => write!(f, "packet is empty"),
+            NetError::UnknownPacketType(value) => {
+                write!(f, "couldn't parse value '{value}' to packet type")
+            }
+            NetError::UnexpectedPacketType(packet_type) => {
+                write!(f, "unexpected packet type {packet_type:?}")
+            }
+            NetError::InvalidLength {
+                packet_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{packet_type:?} packet has to be {expected} bytes long, got {actual}"
+            ),
+            NetError::WindowFull => write!(f, "the reliable send window is full"),
+            NetError::PayloadTooLarge { size, max } => {
+                write!(f, "payload of {size} bytes is larger than the max of {max}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for NetError {}
@@ -0,0 +1,728 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    ops::{Add, Range},
+    time::Duration,
+};
+
+use anyhow::bail;
+
+use super::{
+    header::Header,
+    payload::Payload,
+    sequence::{Sequence, SequenceBuffer, WindowSequenceBuffer},
+    Bytes, BUFFER_SIZE, BUFFER_WINDOW_SIZE,
+};
+
+pub const FRAGMENT_SIZE: usize = 1024;
+//the fragment id and count are a single byte in the header
+pub const MAX_FRAGMENT_COUNT: usize = u8::MAX as usize;
+pub const MAX_FRAGMENT_SIZE: usize = FRAGMENT_SIZE * MAX_FRAGMENT_COUNT;
+pub const GROUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+//the times passed in are durations since any fixed point the caller picks,
+//so the fragments can expire without depending on a clock
+
+//counters to see how much the message sizes in use rely on fragmentation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragmentStats {
+    //fragments created for sending, resends aren't counted
+    pub fragments_sent: u64,
+    //fragments received for the first time
+    pub fragments_received: u64,
+    pub groups_completed: u64,
+    //groups that were dropped before all of their fragments arrived
+    pub groups_timed_out: u64,
+    //groups the sender cancelled before they were complete
+    pub groups_cancelled: u64,
+    //from the first fragment of a group to the assembly, summed over the completed groups
+    pub total_reassembly_time: Duration,
+}
+
+impl FragmentStats {
+    pub fn average_reassembly_latency(&self) -> Option<Duration> {
+        if self.groups_completed == 0 {
+            return None;
+        }
+        //the count can be past u32, dividing the nanoseconds keeps it whole
+        let average = self.total_reassembly_time.as_nanos() / self.groups_completed as u128;
+        Some(Duration::from_nanos(average as u64))
+    }
+}
+
+impl Add for FragmentStats {
+    type Output = FragmentStats;
+
+    fn add(self, other: FragmentStats) -> FragmentStats {
+        FragmentStats {
+            fragments_sent: self.fragments_sent + other.fragments_sent,
+            fragments_received: self.fragments_received + other.fragments_received,
+            groups_completed: self.groups_completed + other.groups_completed,
+            groups_timed_out: self.groups_timed_out + other.groups_timed_out,
+            groups_cancelled: self.groups_cancelled + other.groups_cancelled,
+            total_reassembly_time: self.total_reassembly_time + other.total_reassembly_time,
+        }
+    }
+}
+
+//a message that is still being reassembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FragmentGroupState {
+    pub group_id: u16,
+    pub received: u8,
+    pub total: u8,
+    pub bytes: usize,
+    //since the first fragment arrived
+    pub age: Duration,
+}
+
+pub struct FragmentationManager {
+    group_seq: u16,
+    fragments: WindowSequenceBuffer<ReceiveFragments>,
+    //the groups in the order they were started, checked for expiry
+    pending_groups: VecDeque<(u16, Duration)>,
+    //a group that isn't complete after this is dropped
+    timeout: Duration,
+    //groups reassembled at the same time, the oldest is dropped to make room for a new one
+    max_groups: Option<usize>,
+    pub stats: FragmentStats,
+}
+
+impl FragmentationManager {
+    pub fn new() -> Self {
+        Self::with_limits(GROUP_TIMEOUT, None)
+    }
+
+    pub fn with_limits(timeout: Duration, max_groups: Option<usize>) -> Self {
+        Self {
+            group_seq: 0,
+            fragments: WindowSequenceBuffer::with_size(BUFFER_SIZE, BUFFER_WINDOW_SIZE),
+            pending_groups: VecDeque::new(),
+            timeout,
+            max_groups,
+            stats: FragmentStats::default(),
+        }
+    }
+
+    pub fn should_fragment(length: usize) -> bool {
+        length > FRAGMENT_SIZE
+    }
+
+    pub fn split_fragments(&mut self, chunks: Vec<Payload>) -> anyhow::Result<Fragments> {
+        if chunks.is_empty() {
+            bail!("cannot create a fragmented message with 0 chunks");
+        }
+
+        if chunks.len() > MAX_FRAGMENT_COUNT {
+            bail!("cannot create a fragmented message from more than 255 chunks");
+        }
+
+        let chunk_count = chunks.len() as u8;
+
+        let mut fragments = Fragments {
+            chunk_count,
+            group_id: self.group_seq,
+            chunks: Vec::with_capacity(chunks.len()),
+        };
+
+        for (fragment_id, chunk) in (0_u8..u8::MAX).zip(chunks) {
+            fragments.chunks.push(FragmentChunk {
+                buffer: chunk,
+                fragment_id,
+            });
+        }
+
+        Sequence::increment(&mut self.group_seq);
+        self.stats.fragments_sent += chunk_count as u64;
+
+        Ok(fragments)
+    }
+
+    //the group id split_fragments gives the next message
+    pub fn next_group_id(&self) -> u16 {
+        self.group_seq
+    }
+
+    //the groups count up from here, the receiver takes whichever group arrives first
+    pub fn start_groups_at(&mut self, group_id: u16) {
+        self.group_seq = group_id;
+    }
+
+    pub fn insert_fragment(
+        &mut self,
+        header: &Header,
+        buffer: Bytes,
+        now: Duration,
+    ) -> anyhow::Result<bool> {
+        if header.fragment_size == 0 {
+            bail!("empty fragment with size 0")
+        }
+
+        if header.fragment_id >= header.fragment_size {
+            bail!(
+                "fragment id ({}) cannot be larger than the fragment size ({})",
+                header.fragment_id,
+                header.fragment_size
+            )
+        }
+
+        //insert the fragment buffer if it doesn't exist yet
+        if self.fragments.is_none(header.fragment_group_id) {
+            self.make_room(now);
+            self.fragments.insert(
+                header.fragment_group_id,
+                ReceiveFragments {
+                    group_id: header.fragment_group_id,
+                    chunks: (0..header.fragment_size).map(|_| None).collect(),
+                    size: header.fragment_size,
+                    current_size: 0,
+                    current_bytes: 0,
+                    created_on: now,
+                },
+            );
+            self.pending_groups
+                .push_back((header.fragment_group_id, now));
+        }
+
+        if !self.validate_group(header.fragment_group_id, now) {
+            self.remove_fragment_group(header.fragment_group_id);
+            self.stats.groups_timed_out += 1;
+            bail!("fragment has timed out")
+        }
+
+        //we can safely expect because we inserted the entry above
+        let mut fragment = self
+            .fragments
+            .get_mut(header.fragment_group_id)
+            .expect("fragment not set in the buffer");
+
+        if header.fragment_size != fragment.size {
+            bail!("fragment sizes do not match")
+        }
+
+        if fragment.chunks[header.fragment_id as usize].is_none() {
+            let buffer_len = buffer.len();
+
+            fragment.chunks[header.fragment_id as usize] = Some(buffer);
+            fragment.current_size += 1;
+            fragment.current_bytes += buffer_len;
+            self.stats.fragments_received += 1;
+        }
+
+        Ok(fragment.is_done())
+    }
+
+    //fragments received and the total of a group that is still being reassembled
+    pub fn progress(&self, group_id: u16) -> Option<(u8, u8)> {
+        self.fragments
+            .get(group_id)
+            .map(|fragment| (fragment.current_size, fragment.size))
+    }
+
+    //the groups that are still waiting for fragments, oldest first
+    pub fn pending_groups(&self, now: Duration) -> Vec<FragmentGroupState> {
+        self.pending_groups
+            .iter()
+            .filter_map(|&(group_id, created_on)| {
+                let fragment = self.fragments.get(group_id)?;
+                (fragment.created_on == created_on).then(|| FragmentGroupState {
+                    group_id,
+                    received: fragment.current_size,
+                    total: fragment.size,
+                    bytes: fragment.current_bytes,
+                    age: now.saturating_sub(created_on),
+                })
+            })
+            .collect()
+    }
+
+    pub fn assemble(&mut self, group_id: u16, now: Duration) -> anyhow::Result<Vec<Bytes>> {
+        if !self.validate_group(group_id, now) {
+            self.remove_fragment_group(group_id);
+            self.stats.groups_timed_out += 1;
+            bail!("fragment group has expired");
+        }
+
+        if let Some(fragment) = self.fragments.get(group_id) {
+            if !fragment.is_done() {
+                bail!("fragment is not done yet");
+            }
+        } else {
+            bail!("fragment not found");
+        }
+
+        let mut fragment = self.fragments.take(group_id).unwrap();
+
+        let mut parts = Vec::with_capacity(fragment.current_size as usize);
+        for i in 0..fragment.size {
+            if let Some(chunk) = fragment.chunks.pop_front().unwrap() {
+                parts.push(chunk);
+            } else {
+                bail!("missing chunk on index {i}");
+            }
+        }
+
+        self.stats.groups_completed += 1;
+        self.stats.total_reassembly_time += now.saturating_sub(fragment.created_on);
+
+        Ok(parts)
+    }
+
+    //drops the fragments of a group the sender cancelled, false if none of them arrived
+    pub fn discard(&mut self, group_id: u16) -> bool {
+        if self.fragments.take(group_id).is_none() {
+            return false;
+        }
+        self.stats.groups_cancelled += 1;
+        true
+    }
+
+    //drops the groups that can't be completed anymore, without it a group that lost a fragment
+    //is only noticed when another fragment of it arrives
+    pub fn expire_groups(&mut self, now: Duration) {
+        while let Some(&(_, created_on)) = self.pending_groups.front() {
+            if now.saturating_sub(created_on) < self.timeout {
+                break;
+            }
+            self.drop_oldest_group();
+        }
+    }
+
+    //drops the oldest groups until a new one fits under the limit
+    fn make_room(&mut self, now: Duration) {
+        let Some(max_groups) = self.max_groups else {
+            return;
+        };
+
+        self.expire_groups(now);
+        while self.active_groups() >= max_groups.max(1) {
+            self.drop_oldest_group();
+        }
+    }
+
+    fn drop_oldest_group(&mut self) {
+        let Some((group_id, created_on)) = self.pending_groups.pop_front() else {
+            return;
+        };
+
+        //the group was assembled or already dropped, the id can belong to a newer group
+        if self.is_active(group_id, created_on) {
+            self.remove_fragment_group(group_id);
+            self.stats.groups_timed_out += 1;
+        }
+    }
+
+    fn active_groups(&self) -> usize {
+        self.pending_groups
+            .iter()
+            .filter(|&&(group_id, created_on)| self.is_active(group_id, created_on))
+            .count()
+    }
+
+    fn is_active(&self, group_id: u16, created_on: Duration) -> bool {
+        self.fragments
+            .get(group_id)
+            .is_some_and(|fragment| fragment.created_on == created_on)
+    }
+
+    fn validate_group(&self, group_id: u16, now: Duration) -> bool {
+        if let Some(fragment) = self.fragments.get(group_id) {
+            return now.saturating_sub(fragment.created_on) < self.timeout;
+        }
+        false
+    }
+
+    fn remove_fragment_group(&mut self, group_id: u16) {
+        self.fragments.remove(group_id);
+    }
+
+    pub fn exceeds_max_length(length: usize) -> bool {
+        Self::max_message_size() < length
+    }
+
+    //the largest message that can be split into fragments with the fragment size in use
+    pub fn max_message_size() -> usize {
+        FRAGMENT_SIZE * MAX_FRAGMENT_COUNT
+    }
+
+    //where to cut data too large for one message, as few messages as possible of about the same
+    //size. the cuts are on fragment boundaries so only the last message has a fragment that isn't
+    //full
+    pub fn split_hint(length: usize) -> Vec<Range<usize>> {
+        let messages = length.div_ceil(Self::max_message_size()).max(1);
+        let fragments = length.div_ceil(FRAGMENT_SIZE);
+        let step = fragments.div_ceil(messages) * FRAGMENT_SIZE;
+        (0..length)
+            .step_by(step.max(1))
+            .map(|start| start..(start + step).min(length))
+            .collect()
+    }
+}
+
+impl Default for FragmentationManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ReceiveFragments {
+    pub group_id: u16,
+    pub chunks: VecDeque<Option<Bytes>>,
+    pub size: u8,
+    pub current_size: u8,
+    pub current_bytes: usize,
+    pub created_on: Duration,
+}
+
+impl ReceiveFragments {
+    fn is_done(&self) -> bool {
+        self.current_size == self.size
+    }
+}
+
+pub struct Fragments {
+    pub chunks: Vec<FragmentChunk>,
+    pub chunk_count: u8,
+    pub group_id: u16,
+}
+
+pub struct FragmentChunk {
+    pub buffer: Payload,
+    pub fragment_id: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::Deref;
+
+    use crate::proto::{bytes, PacketType};
+
+    use super::*;
+
+    #[test]
+    fn split_hint_cuts_on_fragment_boundaries() {
+        let max = FragmentationManager::max_message_size();
+        assert!(FragmentationManager::split_hint(0).is_empty());
+        assert_eq!(
+            FragmentationManager::split_hint(100),
+            [Range { start: 0, end: 100 }]
+        );
+        assert_eq!(
+            FragmentationManager::split_hint(max),
+            [Range { start: 0, end: max }]
+        );
+
+        //two messages of about the same size instead of a full one and a tiny one
+        let ranges = FragmentationManager::split_hint(max + 1);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].end, ranges[1].start);
+        assert_eq!(ranges[0].len() % FRAGMENT_SIZE, 0);
+        assert!(ranges[0].len() - ranges[1].len() <= FRAGMENT_SIZE);
+
+        let length = max * 5 + 17;
+        let ranges = FragmentationManager::split_hint(length);
+        assert_eq!(ranges.len(), 6);
+        assert_eq!(ranges.last().map(|range| range.end), Some(length));
+        for range in &ranges {
+            assert!(range.len() <= max);
+        }
+    }
+
+    #[test]
+    fn valid_chunk_sequence() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 5,
+        };
+
+        let mut seq = 0;
+        for i in 0..5 {
+            let mut data = bytes!(3);
+            data[0] = i;
+            data[1] = i;
+            data[2] = i;
+
+            let status = fragment_manager
+                .insert_fragment(&header, data, Duration::ZERO)
+                .unwrap();
+            header.fragment_id += 1;
+        }
+
+        let frag_data = fragment_manager
+            .assemble(header.fragment_group_id, Duration::ZERO)
+            .unwrap();
+
+        for i in 0..5_u8 {
+            assert_eq!(frag_data[i as usize].deref(), &[i, i, i]);
+        }
+    }
+
+    #[test]
+    fn full_fragment_insert_and_build() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: u8::MAX,
+        };
+
+        for i in 0..u8::MAX {
+            let data = bytes!(3);
+
+            let status = fragment_manager
+                .insert_fragment(&header, data, Duration::ZERO)
+                .unwrap();
+            header.fragment_id += 1;
+            assert_eq!(status, i == u8::MAX - 1);
+        }
+
+        let frag_data = fragment_manager
+            .assemble(header.fragment_group_id, Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(
+            frag_data.into_iter().map(|x| x.len()).sum::<usize>(),
+            3 * u8::MAX as usize
+        )
+    }
+
+    #[test]
+    fn fragment_group_timeout() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 2,
+        };
+
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+        header.fragment_id += 1;
+
+        //insert the next one after the group timed out
+        assert!(fragment_manager
+            .insert_fragment(&header, bytes!(3), GROUP_TIMEOUT)
+            .is_err());
+        //check the fragment group was removed
+        assert!(fragment_manager.fragments.is_none(header.fragment_group_id));
+    }
+
+    #[test]
+    fn pending_groups_are_listed() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 3,
+            fragment_id: 0,
+            fragment_size: 2,
+        };
+
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            fragment_manager.pending_groups(Duration::from_secs(1)),
+            [FragmentGroupState {
+                group_id: 3,
+                received: 1,
+                total: 2,
+                bytes: 3,
+                age: Duration::from_secs(1),
+            }]
+        );
+
+        header.fragment_id += 1;
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+        fragment_manager.assemble(3, Duration::ZERO).unwrap();
+        assert!(fragment_manager.pending_groups(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn insert_duplicate_packet() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: u8::MAX,
+        };
+
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+
+        let frag = fragment_manager
+            .fragments
+            .get(header.fragment_group_id)
+            .unwrap();
+        assert_eq!(frag.current_bytes, 3);
+        assert_eq!(frag.current_size, 1);
+    }
+
+    #[test]
+    fn insert_different_fragment_sizes() {
+        let mut fragment_manager = FragmentationManager::new();
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliable,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: u8::MAX,
+        };
+
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .unwrap();
+        header.fragment_id += 1;
+
+        //change the fragment size, this should throw an error
+        header.fragment_size -= 1;
+
+        assert!(fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let mut fragment_manager: FragmentationManager = FragmentationManager::new();
+
+        let frags = Payload::new(&bytes!(MAX_FRAGMENT_SIZE)).chunks(FRAGMENT_SIZE);
+
+        let frags_result = fragment_manager.split_fragments(frags);
+        assert!(frags_result.is_ok());
+
+        assert_eq!(frags_result.unwrap().chunk_count, u8::MAX);
+    }
+
+    #[test]
+    fn packet_too_large() {
+        let mut fragment_manager = FragmentationManager::new();
+        let frags = Payload::new(&bytes!(MAX_FRAGMENT_SIZE + 1)).chunks(FRAGMENT_SIZE);
+
+        assert!(fragment_manager.split_fragments(frags).is_err());
+    }
+
+    #[test]
+    fn stats_are_counted() {
+        let mut fragment_manager = FragmentationManager::new();
+        let frags = Payload::new(&bytes!(FRAGMENT_SIZE * 3)).chunks(FRAGMENT_SIZE);
+        fragment_manager.split_fragments(frags).unwrap();
+
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadReliableFrag,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 2,
+        };
+        for (fragment_id, now) in [(0, 10), (0, 20), (1, 30)] {
+            header.fragment_id = fragment_id;
+            fragment_manager
+                .insert_fragment(&header, bytes!(3), Duration::from_millis(now))
+                .unwrap();
+        }
+        fragment_manager
+            .assemble(0, Duration::from_millis(40))
+            .unwrap();
+
+        //a group that never gets its second fragment
+        header.fragment_group_id = 1;
+        header.fragment_id = 0;
+        fragment_manager
+            .insert_fragment(&header, bytes!(3), Duration::from_millis(50))
+            .unwrap();
+        fragment_manager.expire_groups(Duration::from_millis(50) + GROUP_TIMEOUT);
+
+        let stats = fragment_manager.stats;
+        assert_eq!(stats.fragments_sent, 3);
+        assert_eq!(stats.fragments_received, 3);
+        assert_eq!(stats.groups_completed, 1);
+        assert_eq!(stats.groups_timed_out, 1);
+        assert_eq!(
+            stats.average_reassembly_latency(),
+            Some(Duration::from_millis(30))
+        );
+        assert!(fragment_manager.pending_groups.is_empty());
+        assert!(fragment_manager.fragments.is_none(1));
+
+        //a count past u32 isn't truncated
+        let stats = FragmentStats {
+            groups_completed: 1 << 32,
+            total_reassembly_time: Duration::from_millis(1 << 32),
+            ..Default::default()
+        };
+        assert_eq!(
+            stats.average_reassembly_latency(),
+            Some(Duration::from_millis(1))
+        );
+    }
+
+    #[test]
+    fn limits_drop_stale_groups() {
+        let mut fragment_manager =
+            FragmentationManager::with_limits(Duration::from_millis(100), Some(2));
+        let mut header = Header {
+            seq: 0,
+            packet_type: PacketType::PayloadUnreliableFrag,
+            session_key: 0,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 2,
+        };
+
+        //a third group pushes out the oldest one
+        for group_id in 0..3 {
+            header.fragment_group_id = group_id;
+            fragment_manager
+                .insert_fragment(&header, bytes!(3), Duration::from_millis(10))
+                .unwrap();
+        }
+        assert!(fragment_manager.fragments.is_none(0));
+        assert_eq!(fragment_manager.stats.groups_timed_out, 1);
+
+        //the rest expire long before the reliable timeout
+        fragment_manager.expire_groups(Duration::from_millis(110));
+        assert!(fragment_manager.pending_groups(Duration::ZERO).is_empty());
+        assert_eq!(fragment_manager.stats.groups_timed_out, 3);
+    }
+}
@@ -1,13 +1,11 @@
-use std::sync::Arc;
-
 use anyhow::{anyhow, bail};
 
-use crate::net::PacketType;
-
-use super::{int_buffer::IntBuffer, MAGIC_NUMBER_HEADER};
+use super::{int_buffer::IntBuffer, Bytes, PacketType};
 
 pub const HEADER_SIZE: usize = 17;
 pub const FRAG_HEADER_SIZE: usize = 21;
+//type, seq and the longest varints of the ack and the ack bits
+pub const MAX_COMPACT_HEADER_SIZE: usize = 1 + 2 + 3 + 5;
 
 #[derive(PartialEq, Eq)]
 pub enum SendType {
@@ -71,6 +69,32 @@ impl Header {
         }
     }
 
+    pub fn new_disconnect_ack(seq: u16, session_key: u64) -> Self {
+        Self {
+            seq,
+            session_key,
+            packet_type: PacketType::DisconnectAck,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 0,
+        }
+    }
+
+    pub fn new_keep_alive(seq: u16, session_key: u64) -> Self {
+        Self {
+            seq,
+            session_key,
+            packet_type: PacketType::KeepAlive,
+            ack: 0,
+            ack_bits: 0,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 0,
+        }
+    }
+
     pub fn write(&self, data: &mut [u8], int_buffer: &mut IntBuffer) -> anyhow::Result<()> {
         if data.len() - int_buffer.index < HEADER_SIZE {
             bail!("data length needs to be at least bytes {HEADER_SIZE} long.");
@@ -95,6 +119,73 @@ impl Header {
         Ok(())
     }
 
+    //appends the header to the end of the buffer instead of writing into reserved space
+    pub fn write_into(&self, buffer: &mut Bytes) {
+        buffer.extend_from_slice(&self.seq.to_le_bytes());
+        buffer.push(self.packet_type as u8);
+        buffer.extend_from_slice(&self.session_key.to_le_bytes());
+        buffer.extend_from_slice(&self.ack.to_le_bytes());
+        buffer.extend_from_slice(&self.ack_bits.to_le_bytes());
+
+        if self.packet_type.is_frag_variant() {
+            buffer.extend_from_slice(&self.fragment_group_id.to_le_bytes());
+            buffer.push(self.fragment_id);
+            buffer.push(self.fragment_size);
+        }
+    }
+
+    //used by channels that negotiated it, the session is implied by the address of the connection,
+    //the ack is sent as its distance to the seq and the ack bits as the packets that are missing
+    //so both usually fit in a byte
+    pub fn write_compact_into(&self, buffer: &mut Bytes) {
+        buffer.push(self.packet_type as u8);
+        buffer.extend_from_slice(&self.seq.to_le_bytes());
+        write_varint(zigzag(self.seq.wrapping_sub(self.ack)), buffer);
+        write_varint(!self.ack_bits, buffer);
+
+        if self.packet_type.is_frag_variant() {
+            buffer.extend_from_slice(&self.fragment_group_id.to_le_bytes());
+            buffer.push(self.fragment_id);
+            buffer.push(self.fragment_size);
+        }
+    }
+
+    //the session key isn't sent so the one of the channel is filled in, returns the header and its size
+    pub fn read_compact(data: &[u8], session_key: u64) -> anyhow::Result<(Header, usize)> {
+        if data.len() < 3 {
+            bail!("compact header needs to be at least 3 bytes long.");
+        }
+
+        let packet_type = PacketType::try_from(data[0])?;
+        let seq = u16::from_le_bytes([data[1], data[2]]);
+        let mut index = 3;
+        let ack_delta = read_varint(data, &mut index)?;
+        let ack_bits = !read_varint(data, &mut index)?;
+
+        let mut header = Header {
+            seq,
+            packet_type,
+            session_key,
+            ack: seq.wrapping_sub(unzigzag(ack_delta)?),
+            ack_bits,
+            fragment_group_id: 0,
+            fragment_id: 0,
+            fragment_size: 0,
+        };
+
+        if packet_type.is_frag_variant() {
+            let Some(fragment) = data.get(index..index + 4) else {
+                bail!("compact fragment header is missing the fragment fields.");
+            };
+            header.fragment_group_id = u16::from_le_bytes([fragment[0], fragment[1]]);
+            header.fragment_id = fragment[2];
+            header.fragment_size = fragment[3];
+            index += 4;
+        }
+
+        Ok((header, index))
+    }
+
     pub fn read(data: &[u8]) -> anyhow::Result<Header> {
         if data.len() < HEADER_SIZE {
             bail!("data length needs to be at least bytes {HEADER_SIZE} long.");
@@ -148,11 +239,53 @@ impl Header {
     }
 }
 
+//small distances in either direction become small numbers
+fn zigzag(delta: u16) -> u32 {
+    let delta = delta as i16;
+    ((delta << 1) ^ (delta >> 15)) as u16 as u32
+}
+
+fn unzigzag(value: u32) -> anyhow::Result<u16> {
+    let Ok(value) = u16::try_from(value) else {
+        bail!("ack distance {value} is out of range.");
+    };
+    Ok((value >> 1) ^ (value & 1).wrapping_neg())
+}
+
+//7 bits per byte, the high bit is set if another byte follows
+fn write_varint(mut value: u32, buffer: &mut Bytes) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(data: &[u8], index: &mut usize) -> anyhow::Result<u32> {
+    let mut value = 0_u32;
+    for shift in (0..32).step_by(7) {
+        let Some(&byte) = data.get(*index) else {
+            bail!("compact header is truncated.");
+        };
+        *index += 1;
+
+        //the fifth byte only has room for the top 4 bits
+        if shift == 28 && byte > 0x0F {
+            bail!("varint doesn't fit in 32 bits.");
+        }
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    unreachable!()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{ops::Deref, thread};
+    use alloc::vec;
 
-    use crate::net::bytes;
+    use crate::proto::bytes;
 
     use super::*;
 
@@ -277,4 +410,84 @@ mod tests {
         assert_eq!(header.fragment_id, new_header.fragment_id);
         assert_eq!(header.fragment_size, new_header.fragment_size);
     }
+
+    #[test]
+    fn write_into_matches_write() {
+        for frag in [false, true] {
+            let mut header = Header::new(1, 2, SendType::Reliable, frag);
+            header.ack = 3;
+            header.ack_bits = 4;
+            header.fragment_group_id = 5;
+            header.fragment_id = 6;
+            header.fragment_size = 7;
+
+            let mut written = vec![0_u8; header.get_header_size()];
+            header
+                .write(&mut written, &mut IntBuffer::default())
+                .unwrap();
+
+            let mut appended = vec![9];
+            header.write_into(&mut appended);
+            assert_eq!(appended[1..], written[..]);
+        }
+    }
+
+    #[test]
+    fn compact_header_roundtrip() {
+        for (frag, seq, ack, ack_bits) in [
+            (false, 100, 98, u32::MAX),
+            (true, 2, 65530, 0),
+            (false, 0, 32768, 0x8000_0023),
+        ] {
+            let mut header = Header::new(seq, 7, SendType::Reliable, frag);
+            header.ack = ack;
+            header.ack_bits = ack_bits;
+            header.fragment_group_id = 5;
+            header.fragment_id = 6;
+            header.fragment_size = 7;
+
+            let mut buffer = vec![];
+            header.write_compact_into(&mut buffer);
+            buffer.extend_from_slice(&[1, 2, 3]);
+
+            let (read, size) = Header::read_compact(&buffer, 7).unwrap();
+            assert_eq!(size, buffer.len() - 3);
+            assert_eq!(read.seq, seq);
+            assert_eq!(read.packet_type, header.packet_type);
+            assert_eq!(read.session_key, 7);
+            assert_eq!(read.ack, ack);
+            assert_eq!(read.ack_bits, ack_bits);
+            if frag {
+                assert_eq!(read.fragment_group_id, 5);
+                assert_eq!(read.fragment_id, 6);
+                assert_eq!(read.fragment_size, 7);
+            }
+        }
+    }
+
+    #[test]
+    fn compact_header_is_small_without_losses() {
+        let mut header = Header::new(1000, 7, SendType::Unreliable, false);
+        header.ack = 1001;
+        header.ack_bits = u32::MAX;
+
+        let mut buffer = vec![];
+        header.write_compact_into(&mut buffer);
+        assert_eq!(buffer.len(), 5);
+    }
+
+    #[test]
+    fn compact_header_read_truncated() {
+        let mut header = Header::new(1, 2, SendType::Reliable, true);
+        header.ack_bits = 1;
+
+        let mut buffer = vec![];
+        header.write_compact_into(&mut buffer);
+        for len in 0..buffer.len() {
+            assert!(Header::read_compact(&buffer[..len], 2).is_err());
+        }
+
+        //a varint running past 32 bits
+        assert!(Header::read_compact(&[6, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0], 2).is_err());
+    }
 }
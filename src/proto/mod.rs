@@ -0,0 +1,207 @@
+//the wire format and the reliability logic without sockets, threads or clocks,
+//it builds without std so platforms with their own sockets can reuse it
+use anyhow::bail;
+
+pub mod ack;
+pub mod challenge;
+pub mod disconnect;
+pub mod error;
+pub mod fragmentation_manager;
+pub mod header;
+pub mod int_buffer;
+pub mod payload;
+pub mod protocol_id;
+pub mod sequence;
+pub mod session_token;
+
+pub use disconnect::{DisconnectCode, DisconnectReason};
+pub use error::NetError;
+pub use protocol_id::{ProtocolId, PROTOCOL_ID_SIZE};
+pub use session_token::{InvalidSessionToken, SessionToken};
+
+pub const BUFFER_SIZE: u16 = 1024;
+//always has to be less than BUFFER SIZE
+pub const BUFFER_WINDOW_SIZE: u16 = 256;
+
+pub type Bytes = alloc::vec::Vec<u8>;
+macro_rules! bytes {
+    ($size:expr) => {{
+        alloc::vec![0_u8; $size]
+    }};
+}
+
+macro_rules! bytes_with_header {
+    ($payload_size:expr) => {{
+        //the default id is replaced with the configured one when the socket sends the packet
+        let mut buffer = alloc::vec![0_u8; $payload_size + crate::proto::PROTOCOL_ID_SIZE];
+        crate::proto::ProtocolId::DEFAULT.write_into(&mut buffer);
+        buffer
+    }};
+}
+pub(crate) use {bytes, bytes_with_header};
+
+//what a reliable message is for, requests and responses have their own packet types so no
+//message of the game is mistaken for one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Data,
+    Request,
+    Response,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    ConnectionRequest = 1,
+    Challenge = 2,
+    ChallengeResponse = 3,
+    ConnectionAccepted = 4,
+    PayloadReliableFrag = 5,
+    PayloadReliable = 6,
+    PayloadUnreliableFrag = 7,
+    PayloadUnreliable = 8,
+    Disconnect = 9,
+    KeepAlive = 10,
+    //raw packet outside of any connection, only carries the type byte before the payload
+    Unconnected = 11,
+    //unconnected query for the server info
+    ServerInfoRequest = 12,
+    ServerInfoResponse = 13,
+    //servers registering with a master server and clients asking it for the list
+    MasterHeartbeat = 14,
+    MasterListRequest = 15,
+    MasterListResponse = 16,
+    //confirms a disconnect so the closing side can stop resending it
+    DisconnectAck = 17,
+    //tells a client that the server doesn't know its session
+    ReconnectRequired = 18,
+    //the token a server has to put in its heartbeats to be listed by the master server
+    MasterChallenge = 19,
+    //reliable messages that are requests and their responses, sequenced with the other reliable ones
+    PayloadRequest = 20,
+    PayloadRequestFrag = 21,
+    PayloadResponse = 22,
+    PayloadResponseFrag = 23,
+}
+
+impl PacketType {
+    //every type in the order of its id
+    pub const ALL: [PacketType; 23] = [
+        PacketType::ConnectionRequest,
+        PacketType::Challenge,
+        PacketType::ChallengeResponse,
+        PacketType::ConnectionAccepted,
+        PacketType::PayloadReliableFrag,
+        PacketType::PayloadReliable,
+        PacketType::PayloadUnreliableFrag,
+        PacketType::PayloadUnreliable,
+        PacketType::Disconnect,
+        PacketType::KeepAlive,
+        PacketType::Unconnected,
+        PacketType::ServerInfoRequest,
+        PacketType::ServerInfoResponse,
+        PacketType::MasterHeartbeat,
+        PacketType::MasterListRequest,
+        PacketType::MasterListResponse,
+        PacketType::DisconnectAck,
+        PacketType::ReconnectRequired,
+        PacketType::MasterChallenge,
+        PacketType::PayloadRequest,
+        PacketType::PayloadRequestFrag,
+        PacketType::PayloadResponse,
+        PacketType::PayloadResponseFrag,
+    ];
+
+    //the type of a reliable packet of the message kind
+    pub fn reliable(kind: MessageKind, frag: bool) -> PacketType {
+        match (kind, frag) {
+            (MessageKind::Data, false) => PacketType::PayloadReliable,
+            (MessageKind::Data, true) => PacketType::PayloadReliableFrag,
+            (MessageKind::Request, false) => PacketType::PayloadRequest,
+            (MessageKind::Request, true) => PacketType::PayloadRequestFrag,
+            (MessageKind::Response, false) => PacketType::PayloadResponse,
+            (MessageKind::Response, true) => PacketType::PayloadResponseFrag,
+        }
+    }
+
+    pub fn is_frag_variant(&self) -> bool {
+        matches!(
+            self,
+            PacketType::PayloadReliableFrag
+                | PacketType::PayloadUnreliableFrag
+                | PacketType::PayloadRequestFrag
+                | PacketType::PayloadResponseFrag
+        )
+    }
+
+    //the packets that take a reliable sequence and are acked
+    pub fn is_reliable_variant(&self) -> bool {
+        self.message_kind().is_some()
+    }
+
+    //the kind of the message a reliable packet carries
+    pub fn message_kind(&self) -> Option<MessageKind> {
+        match self {
+            PacketType::PayloadReliable | PacketType::PayloadReliableFrag => {
+                Some(MessageKind::Data)
+            }
+            PacketType::PayloadRequest | PacketType::PayloadRequestFrag => {
+                Some(MessageKind::Request)
+            }
+            PacketType::PayloadResponse | PacketType::PayloadResponseFrag => {
+                Some(MessageKind::Response)
+            }
+            _ => None,
+        }
+    }
+
+    //the packets sent on the channel of an established connection
+    pub fn is_session_variant(&self) -> bool {
+        matches!(
+            self,
+            PacketType::PayloadReliableFrag
+                | PacketType::PayloadReliable
+                | PacketType::PayloadUnreliableFrag
+                | PacketType::PayloadUnreliable
+                | PacketType::Disconnect
+                | PacketType::KeepAlive
+                | PacketType::DisconnectAck
+                | PacketType::PayloadRequest
+                | PacketType::PayloadRequestFrag
+                | PacketType::PayloadResponse
+                | PacketType::PayloadResponseFrag
+        )
+    }
+}
+impl TryFrom<u8> for PacketType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PacketType::ConnectionRequest),
+            2 => Ok(PacketType::Challenge),
+            3 => Ok(PacketType::ChallengeResponse),
+            4 => Ok(PacketType::ConnectionAccepted),
+            5 => Ok(PacketType::PayloadReliableFrag),
+            6 => Ok(PacketType::PayloadReliable),
+            7 => Ok(PacketType::PayloadUnreliableFrag),
+            8 => Ok(PacketType::PayloadUnreliable),
+            9 => Ok(PacketType::Disconnect),
+            10 => Ok(PacketType::KeepAlive),
+            11 => Ok(PacketType::Unconnected),
+            12 => Ok(PacketType::ServerInfoRequest),
+            13 => Ok(PacketType::ServerInfoResponse),
+            14 => Ok(PacketType::MasterHeartbeat),
+            15 => Ok(PacketType::MasterListRequest),
+            16 => Ok(PacketType::MasterListResponse),
+            17 => Ok(PacketType::DisconnectAck),
+            18 => Ok(PacketType::ReconnectRequired),
+            19 => Ok(PacketType::MasterChallenge),
+            20 => Ok(PacketType::PayloadRequest),
+            21 => Ok(PacketType::PayloadRequestFrag),
+            22 => Ok(PacketType::PayloadResponse),
+            23 => Ok(PacketType::PayloadResponseFrag),
+            _ => bail!(NetError::UnknownPacketType(value)),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Deref;
+
+//a range of user data that is shared between all packets of a message and the send buffer,
+//the data is copied once when the message is sent and the headers are written separately
+#[derive(Clone)]
+pub struct Payload {
+    data: Arc<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl Payload {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            data: Arc::from(data),
+            start: 0,
+            end: data.len(),
+        }
+    }
+
+    //takes the data without copying it, the caller can send the same data again
+    pub fn shared(data: Arc<[u8]>) -> Self {
+        Self {
+            end: data.len(),
+            data,
+            start: 0,
+        }
+    }
+
+    //splits the data into chunks of at most the given size without copying it
+    pub fn chunks(&self, size: usize) -> Vec<Payload> {
+        (self.start..self.end)
+            .step_by(size)
+            .map(|start| Payload {
+                data: self.data.clone(),
+                start,
+                end: (start + size).min(self.end),
+            })
+            .collect()
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{bytes, fragmentation_manager::FRAGMENT_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn chunks_share_the_data() {
+        let payload = Payload::new(&bytes!(FRAGMENT_SIZE * 2 + 1));
+        let chunks = payload.chunks(FRAGMENT_SIZE);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].len(), 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| Arc::ptr_eq(&chunk.data, &payload.data)));
+    }
+}
@@ -0,0 +1,65 @@
+use super::challenge::siphash24;
+
+pub const PROTOCOL_ID_SIZE: usize = 8;
+
+const PROTOCOL_ID_KEY: (u64, u64) = (0x7072_6f74_6f63_6f6c, 0x2d69_642d_6761_6d65);
+
+//sent in front of every datagram, packets with another id are dropped before they are parsed
+//so different games sharing a LAN or a port range ignore each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProtocolId(pub [u8; PROTOCOL_ID_SIZE]);
+
+impl ProtocolId {
+    //used when the game doesn't pick its own id, every such game accepts the others' packets
+    pub const DEFAULT: ProtocolId = ProtocolId([1, 27, 25, 14, 0, 0, 0, 0]);
+
+    //the name and the version are hashed separately so ("ab", "c") and ("a", "bc") differ
+    pub fn from_game(name: &str, version: &str) -> Self {
+        let name = siphash24(PROTOCOL_ID_KEY, name.as_bytes());
+        let id = siphash24((name, PROTOCOL_ID_KEY.1), version.as_bytes());
+        ProtocolId(id.to_le_bytes())
+    }
+
+    //overwrites the start of a datagram that was written with another id
+    pub fn write_into(&self, datagram: &mut [u8]) {
+        datagram[..PROTOCOL_ID_SIZE].copy_from_slice(&self.0);
+    }
+
+    pub fn matches(&self, datagram: &[u8]) -> bool {
+        datagram.len() >= PROTOCOL_ID_SIZE && datagram[..PROTOCOL_ID_SIZE] == self.0
+    }
+}
+
+impl Default for ProtocolId {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_differ_per_game_and_version() {
+        let id = ProtocolId::from_game("arena", "1.0");
+
+        assert_eq!(id, ProtocolId::from_game("arena", "1.0"));
+        assert_ne!(id, ProtocolId::from_game("arena", "1.1"));
+        assert_ne!(id, ProtocolId::from_game("arena1", ".0"));
+        assert_ne!(id, ProtocolId::DEFAULT);
+    }
+
+    #[test]
+    fn write_and_match() {
+        let id = ProtocolId::from_game("arena", "1.0");
+        let mut datagram = [ProtocolId::DEFAULT.0.as_slice(), &[6, 1, 2]].concat();
+
+        assert!(ProtocolId::DEFAULT.matches(&datagram));
+        id.write_into(&mut datagram);
+        assert!(id.matches(&datagram));
+        assert!(!ProtocolId::DEFAULT.matches(&datagram));
+        assert_eq!(datagram[PROTOCOL_ID_SIZE..], [6, 1, 2]);
+        assert!(!id.matches(&datagram[..4]));
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 pub struct SequenceBuffer<T> {
     values: Vec<Option<T>>,
     pub partition_by: u16,
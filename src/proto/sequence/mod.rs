@@ -1,7 +1,9 @@
 mod buffer;
+mod replay_window;
 mod window_buffer;
 
 pub use buffer::SequenceBuffer;
+pub use replay_window::{ReplayWindow, REPLAY_WINDOW_SIZE};
 pub use window_buffer::WindowSequenceBuffer;
 
 pub struct Sequence {}
@@ -27,16 +29,25 @@ impl Sequence {
     }
 
     pub fn next_sequence(sequence: u16) -> u16 {
-        if sequence >= std::u16::MAX - 1 {
+        if sequence >= u16::MAX - 1 {
             0
         } else {
             sequence + 1
         }
     }
 
+    //increments from one sequence to the other, u16::MAX is skipped like the increment does
+    pub fn distance(from: u16, to: u16) -> u16 {
+        if to >= from {
+            to - from
+        } else {
+            to + (u16::MAX - from)
+        }
+    }
+
     pub fn previous_sequence(sequence: u16) -> u16 {
         if sequence == 0 {
-            std::u16::MAX - 1
+            u16::MAX - 1
         } else {
             sequence - 1
         }
@@ -59,6 +70,10 @@ mod tests {
 
         assert!(Sequence::is_greater_then(0, 65534));
 
+        assert_eq!(Sequence::distance(65533, 65534), 1);
+        assert_eq!(Sequence::distance(65533, 1), 3);
+        assert_eq!(Sequence::distance(5, 5), 0);
+
         let mut seq = 10_u16;
         Sequence::increment(&mut seq);
         assert!(seq == 11)
@@ -0,0 +1,83 @@
+use super::Sequence;
+
+//how far behind the newest sequence a packet is still accepted
+pub const REPLAY_WINDOW_SIZE: u16 = 128;
+
+//the sequences received lately, a packet older than the window or received before is a replay
+pub struct ReplayWindow {
+    newest: Option<u16>,
+    //bit n is set if newest - n was received
+    received: u128,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            newest: None,
+            received: 0,
+        }
+    }
+
+    //false if the packet has to be dropped
+    pub fn accept(&mut self, sequence: u16) -> bool {
+        let Some(newest) = self.newest else {
+            self.newest = Some(sequence);
+            self.received = 1;
+            return true;
+        };
+
+        if Sequence::is_greater_then(sequence, newest) {
+            let shift = sequence.wrapping_sub(newest) as u32;
+            self.received = self.received.checked_shl(shift).unwrap_or(0) | 1;
+            self.newest = Some(sequence);
+            return true;
+        }
+
+        let age = newest.wrapping_sub(sequence);
+        if age >= REPLAY_WINDOW_SIZE || self.received & (1 << age) != 0 {
+            return false;
+        }
+        self.received |= 1 << age;
+        true
+    }
+
+    pub fn newest(&self) -> Option<u16> {
+        self.newest
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_and_old_sequences_are_rejected() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+
+        //late but inside the window
+        assert!(window.accept(12));
+        assert!(window.accept(11));
+        assert!(!window.accept(11));
+
+        assert!(window.accept(12 + REPLAY_WINDOW_SIZE));
+        assert!(!window.accept(12));
+        assert!(window.accept(13));
+    }
+
+    #[test]
+    fn sequences_wrap_around() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(u16::MAX - 1));
+        assert!(window.accept(1));
+        assert!(window.accept(u16::MAX));
+        assert!(!window.accept(u16::MAX - 1));
+    }
+}
@@ -0,0 +1,101 @@
+use core::{fmt, str::FromStr};
+
+use super::challenge::siphash24;
+
+const SESSION_TOKEN_KEY: (u64, u64) = (0x7365_7373_696f_6e2d, 0x746f_6b65_6e2d_7631);
+//crockford's base32, no I, L, O or U so it survives being read out over the phone
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const DIGITS: usize = 8;
+
+//a short name of a session for the logs of both sides, printed like 3F7K-QW2M. it's derived from
+//the session key so the client and the server agree on it without sending it, and the key can't
+//be recovered from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionToken(pub u64);
+
+impl SessionToken {
+    pub fn of(session_key: u64) -> Self {
+        let hash = siphash24(SESSION_TOKEN_KEY, &session_key.to_le_bytes());
+        SessionToken(hash & ((1 << (DIGITS * 5)) - 1))
+    }
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..DIGITS {
+            if i == DIGITS / 2 {
+                write!(f, "-")?;
+            }
+            let digit = (self.0 >> ((DIGITS - 1 - i) * 5)) & 31;
+            write!(f, "{}", ALPHABET[digit as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSessionToken;
+
+impl fmt::Display for InvalidSessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a session token is {DIGITS} base32 digits")
+    }
+}
+
+impl core::error::Error for InvalidSessionToken {}
+
+//the way a player reads it off the screen, any case and with or without the dash. the letters
+//crockford's base32 leaves out are read as the digits they look like
+impl FromStr for SessionToken {
+    type Err = InvalidSessionToken;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut value = 0;
+        let mut digits = 0;
+        for c in s.chars().filter(|c| *c != '-') {
+            let c = match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            };
+            let Some(digit) = ALPHABET.iter().position(|a| *a as char == c) else {
+                return Err(InvalidSessionToken);
+            };
+            value = value << 5 | digit as u64;
+            digits += 1;
+        }
+        if digits != DIGITS {
+            return Err(InvalidSessionToken);
+        }
+        Ok(SessionToken(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn token_is_printed_and_read_back() {
+        let token = SessionToken::of(0x1234_5678_9abc_def0);
+        assert_eq!(token, SessionToken::of(0x1234_5678_9abc_def0));
+        assert_ne!(token, SessionToken::of(0x1234_5678_9abc_def1));
+
+        let printed = token.to_string();
+        assert_eq!(printed.len(), DIGITS + 1);
+        assert_eq!(printed.as_bytes()[DIGITS / 2], b'-');
+        assert_eq!(printed.parse(), Ok(token));
+        assert_eq!(printed.to_lowercase().replace('-', "").parse(), Ok(token));
+
+        assert_eq!(SessionToken(0).to_string(), "0000-0000");
+        assert_eq!("oooo-ooo1".parse(), Ok(SessionToken(1)));
+        assert_eq!("0000-000".parse::<SessionToken>(), Err(InvalidSessionToken));
+        assert_eq!(
+            "0000-000U".parse::<SessionToken>(),
+            Err(InvalidSessionToken)
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//typed remote calls on top of the requests, a trait marked with #[net_rpc] gets a client stub named
+//<Trait>Client and a server wrapper named <Trait>Server that is registered with an RpcServer:
+//
+//  #[net_rpc]
+//  pub trait Lobby {
+//      fn join(&self, name: String) -> JoinResult;
+//  }
+//
+//the arguments and the results are encoded with bincode so they have to implement serde's traits
+use std::{collections::HashMap, marker::PhantomData, time::Duration};
+
+use anyhow::{anyhow, bail};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use game_networking_macros::net_rpc;
+
+use crate::{Client, RequestHandle, ResponseHandle, Server};
+
+pub type Result<T> = anyhow::Result<T>;
+
+//the first byte of a response
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+//implemented by the <Trait>Server wrappers the attribute generates
+pub trait RpcService {
+    //the name of the trait, calls are routed to the service by it
+    fn name(&self) -> &'static str;
+    //runs the method with the encoded arguments and returns its encoded result
+    fn dispatch(&self, method: &str, args: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(data)?)
+}
+
+pub fn unknown_method(service: &str, method: &str) -> Result<Vec<u8>> {
+    bail!("{service} has no method {method}")
+}
+
+//the service and the method names are prefixed with their length in front of the arguments
+pub fn write_call(service: &str, method: &str, args: &[u8]) -> Result<Vec<u8>> {
+    let mut call = Vec::with_capacity(2 + service.len() + method.len() + args.len());
+    for name in [service, method] {
+        let Ok(length) = u8::try_from(name.len()) else {
+            bail!("rpc names can't be longer than {} bytes", u8::MAX);
+        };
+        call.push(length);
+        call.extend_from_slice(name.as_bytes());
+    }
+    call.extend_from_slice(args);
+    Ok(call)
+}
+
+//the service, the method and the encoded arguments
+pub fn read_call(call: &[u8]) -> Result<(&str, &str, &[u8])> {
+    let mut rest = call;
+    let mut names = [""; 2];
+    for name in &mut names {
+        let Some((&length, tail)) = rest.split_first() else {
+            bail!("rpc call is too short");
+        };
+        if tail.len() < length as usize {
+            bail!("rpc call is too short");
+        }
+        *name = std::str::from_utf8(&tail[..length as usize])?;
+        rest = &tail[length as usize..];
+    }
+    Ok((names[0], names[1], rest))
+}
+
+//sends the call as a request, used by the generated client stubs
+pub fn call<T: DeserializeOwned, A: Serialize>(
+    client: &Client,
+    service: &str,
+    method: &str,
+    args: &A,
+) -> Result<RpcResponse<T>> {
+    let call = write_call(service, method, &encode(args)?)?;
+    Ok(RpcResponse {
+        handle: client.request(&call)?,
+        result: PhantomData,
+    })
+}
+
+//the result of a call, the error of a call that failed on the server is returned by wait
+pub struct RpcResponse<T> {
+    handle: ResponseHandle,
+    result: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> RpcResponse<T> {
+    pub fn request_id(&self) -> u32 {
+        self.handle.request_id()
+    }
+
+    //None if the server didn't reply before the timeout
+    pub fn wait(&self, timeout: Duration) -> Result<Option<T>> {
+        self.handle
+            .wait(timeout)?
+            .map(|response| read_response(&response))
+            .transpose()
+    }
+
+    pub fn try_get(&self) -> Result<Option<T>> {
+        self.handle
+            .try_get()?
+            .map(|response| read_response(&response))
+            .transpose()
+    }
+}
+
+fn read_response<T: DeserializeOwned>(response: &[u8]) -> Result<T> {
+    match response.split_first() {
+        Some((&STATUS_OK, result)) => decode(result),
+        Some((&STATUS_ERROR, message)) => {
+            bail!(
+                "rpc failed on the server: {}",
+                String::from_utf8_lossy(message)
+            )
+        }
+        _ => bail!("invalid rpc response"),
+    }
+}
+
+//routes the requests of the clients to the registered services
+#[derive(Default)]
+pub struct RpcServer {
+    services: HashMap<&'static str, Box<dyn RpcService>>,
+}
+
+impl RpcServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //a service registered under the same name replaces the previous one
+    pub fn register(&mut self, service: impl RpcService + 'static) {
+        self.services.insert(service.name(), Box::new(service));
+    }
+
+    //runs the call and returns the response, a failed call is encoded as an error for the caller
+    pub fn dispatch(&self, call: &[u8]) -> Vec<u8> {
+        let result = read_call(call).and_then(|(service, method, args)| {
+            self.services
+                .get(service)
+                .ok_or_else(|| anyhow!("no rpc service named {service}"))?
+                .dispatch(method, args)
+        });
+
+        match result {
+            Ok(encoded) => [&[STATUS_OK], encoded.as_slice()].concat(),
+            Err(e) => [&[STATUS_ERROR], e.to_string().as_bytes()].concat(),
+        }
+    }
+
+    //replies to a request read from the server with ServerEvent::Request
+    pub fn handle(&self, server: &Server, handle: RequestHandle, call: &[u8]) -> Result<()> {
+        server.reply(handle, &self.dispatch(call))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub enum JoinResult {
+        Joined(u32),
+        Full,
+    }
+
+    #[net_rpc]
+    pub trait Lobby {
+        fn join(&self, name: String, team: u8) -> JoinResult;
+        fn players(&self) -> Vec<String>;
+    }
+
+    struct TestLobby {
+        joined: Cell<u32>,
+    }
+
+    impl Lobby for TestLobby {
+        fn join(&self, name: String, team: u8) -> JoinResult {
+            if name.is_empty() {
+                return JoinResult::Full;
+            }
+            self.joined.set(self.joined.get() + 1);
+            JoinResult::Joined(team as u32)
+        }
+
+        fn players(&self) -> Vec<String> {
+            vec!["a".to_owned(); self.joined.get() as usize]
+        }
+    }
+
+    #[test]
+    fn calls_are_dispatched() {
+        let mut rpc = RpcServer::new();
+        rpc.register(LobbyServer(TestLobby {
+            joined: Cell::new(0),
+        }));
+
+        let args = encode(&("player".to_owned(), 2_u8)).unwrap();
+        let call = write_call("Lobby", "join", &args).unwrap();
+        assert_eq!(
+            read_response::<JoinResult>(&rpc.dispatch(&call)).unwrap(),
+            JoinResult::Joined(2)
+        );
+
+        let call = write_call("Lobby", "players", &encode(&()).unwrap()).unwrap();
+        assert_eq!(
+            read_response::<Vec<String>>(&rpc.dispatch(&call)).unwrap(),
+            ["a"]
+        );
+    }
+
+    #[test]
+    fn failed_calls_return_an_error() {
+        let mut rpc = RpcServer::new();
+        rpc.register(LobbyServer(TestLobby {
+            joined: Cell::new(0),
+        }));
+
+        let unknown = write_call("Lobby", "leave", &[]).unwrap();
+        assert!(read_response::<()>(&rpc.dispatch(&unknown)).is_err());
+        let service = write_call("Shop", "buy", &[]).unwrap();
+        assert!(read_response::<()>(&rpc.dispatch(&service)).is_err());
+        //the arguments don't decode as (String, u8)
+        let args = write_call("Lobby", "join", &[1]).unwrap();
+        assert!(read_response::<JoinResult>(&rpc.dispatch(&args)).is_err());
+        assert!(read_call(&[5, 1]).is_err());
+    }
+}
@@ -0,0 +1,628 @@
+//chunked transfers of files and assets over the reliable channel, the types only build and read
+//the messages so the same code runs on the client and on the server: the game sends every message
+//they return with SendType::Reliable and passes the received ones starting with TRANSFER_MARKER
+//back to the transfer with the same id
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::bail;
+
+use crate::proto::{
+    fragmentation_manager::{FRAGMENT_SIZE, MAX_FRAGMENT_SIZE},
+    int_buffer::IntBuffer,
+    Bytes,
+};
+
+//starts every transfer message, games must not start their own messages with it
+pub const TRANSFER_MARKER: [u8; 4] = *b"XFER";
+pub const HASH_SIZE: usize = 32;
+//marker, message type and transfer id
+const MESSAGE_HEADER_SIZE: usize = 9;
+//the offset in front of the chunk data
+const CHUNK_HEADER_SIZE: usize = MESSAGE_HEADER_SIZE + 8;
+pub const MAX_CHUNK_SIZE: usize = MAX_FRAGMENT_SIZE - CHUNK_HEADER_SIZE;
+//so a chunk with its header fills its last fragment
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * FRAGMENT_SIZE - CHUNK_HEADER_SIZE;
+//chunks sent ahead of the last one the receiver confirmed
+pub const MAX_CHUNKS_IN_FLIGHT: usize = 8;
+//bytes past the received ones a chunk may reach, the sender stops at MAX_CHUNKS_IN_FLIGHT and can
+//have started one more chunk before it did
+const RECEIVE_WINDOW: u64 = (MAX_CHUNK_SIZE * (MAX_CHUNKS_IN_FLIGHT + 1)) as u64;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    Offer = 1,
+    Accept = 2,
+    Chunk = 3,
+    Ack = 4,
+    Complete = 5,
+    Cancel = 6,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(MessageType::Offer),
+            2 => Ok(MessageType::Accept),
+            3 => Ok(MessageType::Chunk),
+            4 => Ok(MessageType::Ack),
+            5 => Ok(MessageType::Complete),
+            6 => Ok(MessageType::Cancel),
+            _ => bail!("unknown transfer message type {value}"),
+        }
+    }
+}
+
+//what the receiver learns about a transfer before accepting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferOffer {
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+    //blake3 hash of the whole file, checked once every chunk arrived
+    pub hash: [u8; HASH_SIZE],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferMessage {
+    //sender to receiver
+    Offer(TransferOffer),
+    Chunk { id: u32, offset: u64, data: Bytes },
+    //receiver to sender, the offset is where the sender starts so a partial file can be resumed
+    Accept { id: u32, offset: u64 },
+    //every byte before the offset arrived
+    Ack { id: u32, offset: u64 },
+    //every byte arrived and the hash matches
+    Complete { id: u32 },
+    //either side gave up, also sent by the receiver if the hash doesn't match
+    Cancel { id: u32 },
+}
+
+impl TransferMessage {
+    pub fn id(&self) -> u32 {
+        match self {
+            TransferMessage::Offer(offer) => offer.id,
+            TransferMessage::Chunk { id, .. }
+            | TransferMessage::Accept { id, .. }
+            | TransferMessage::Ack { id, .. }
+            | TransferMessage::Complete { id }
+            | TransferMessage::Cancel { id } => *id,
+        }
+    }
+
+    pub fn write(&self) -> Bytes {
+        let (message_type, body_size) = match self {
+            TransferMessage::Offer(offer) => (MessageType::Offer, 8 + HASH_SIZE + offer.name.len()),
+            TransferMessage::Chunk { data, .. } => (MessageType::Chunk, 8 + data.len()),
+            TransferMessage::Accept { .. } => (MessageType::Accept, 8),
+            TransferMessage::Ack { .. } => (MessageType::Ack, 8),
+            TransferMessage::Complete { .. } => (MessageType::Complete, 0),
+            TransferMessage::Cancel { .. } => (MessageType::Cancel, 0),
+        };
+
+        let mut int_buffer = IntBuffer::default();
+        let mut buffer = vec![0; MESSAGE_HEADER_SIZE + body_size];
+        int_buffer.write_slice(&TRANSFER_MARKER, &mut buffer);
+        int_buffer.write_u8(message_type as u8, &mut buffer);
+        int_buffer.write_u32(self.id(), &mut buffer);
+
+        match self {
+            TransferMessage::Offer(offer) => {
+                int_buffer.write_u64(offer.size, &mut buffer);
+                int_buffer.write_slice(&offer.hash, &mut buffer);
+                int_buffer.write_slice(offer.name.as_bytes(), &mut buffer);
+            }
+            TransferMessage::Chunk { offset, data, .. } => {
+                int_buffer.write_u64(*offset, &mut buffer);
+                int_buffer.write_slice(data, &mut buffer);
+            }
+            TransferMessage::Accept { offset, .. } | TransferMessage::Ack { offset, .. } => {
+                int_buffer.write_u64(*offset, &mut buffer)
+            }
+            TransferMessage::Complete { .. } | TransferMessage::Cancel { .. } => {}
+        }
+
+        buffer
+    }
+
+    pub fn read(buffer: &[u8]) -> anyhow::Result<TransferMessage> {
+        if !is_transfer_message(buffer) || buffer.len() < MESSAGE_HEADER_SIZE {
+            bail!("not a transfer message");
+        }
+
+        let mut int_buffer = IntBuffer::new_at(TRANSFER_MARKER.len());
+        let message_type = MessageType::try_from(int_buffer.read_u8(buffer))?;
+        let id = int_buffer.read_u32(buffer);
+
+        let body_size = match message_type {
+            MessageType::Offer => 8 + HASH_SIZE,
+            MessageType::Chunk | MessageType::Accept | MessageType::Ack => 8,
+            MessageType::Complete | MessageType::Cancel => 0,
+        };
+        if buffer.len() < MESSAGE_HEADER_SIZE + body_size {
+            bail!(
+                "{message_type:?} transfer message has to be at least {} bytes long, got {}",
+                MESSAGE_HEADER_SIZE + body_size,
+                buffer.len()
+            );
+        }
+
+        Ok(match message_type {
+            MessageType::Offer => {
+                let size = int_buffer.read_u64(buffer);
+                let mut hash = [0; HASH_SIZE];
+                hash.copy_from_slice(&buffer[int_buffer.index..int_buffer.index + HASH_SIZE]);
+                int_buffer.jump(HASH_SIZE);
+
+                let Ok(name) = String::from_utf8(buffer[int_buffer.index..].to_vec()) else {
+                    bail!("transfer name is not valid utf-8");
+                };
+                TransferMessage::Offer(TransferOffer {
+                    id,
+                    name,
+                    size,
+                    hash,
+                })
+            }
+            MessageType::Chunk => TransferMessage::Chunk {
+                id,
+                offset: int_buffer.read_u64(buffer),
+                data: buffer[CHUNK_HEADER_SIZE..].to_vec(),
+            },
+            MessageType::Accept => TransferMessage::Accept {
+                id,
+                offset: int_buffer.read_u64(buffer),
+            },
+            MessageType::Ack => TransferMessage::Ack {
+                id,
+                offset: int_buffer.read_u64(buffer),
+            },
+            MessageType::Complete => TransferMessage::Complete { id },
+            MessageType::Cancel => TransferMessage::Cancel { id },
+        })
+    }
+}
+
+pub fn is_transfer_message(buffer: &[u8]) -> bool {
+    buffer.starts_with(&TRANSFER_MARKER)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    //waiting for the receiver to accept
+    Offered,
+    Transferring,
+    Completed,
+    Cancelled,
+    //the received file doesn't match the hash of the offer
+    Failed,
+}
+
+pub struct OutgoingTransfer {
+    offer: TransferOffer,
+    data: Bytes,
+    chunk_size: usize,
+    //the next byte to send and the bytes the receiver confirmed
+    sent: u64,
+    acked: u64,
+    state: TransferState,
+}
+
+impl OutgoingTransfer {
+    pub fn new(id: u32, name: &str, data: Bytes, chunk_size: usize) -> anyhow::Result<Self> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            bail!("chunk size has to be between 1 and {MAX_CHUNK_SIZE}, got {chunk_size}");
+        }
+
+        Ok(Self {
+            offer: TransferOffer {
+                id,
+                name: name.to_owned(),
+                size: data.len() as u64,
+                hash: *blake3::hash(&data).as_bytes(),
+            },
+            data,
+            chunk_size,
+            sent: 0,
+            acked: 0,
+            state: TransferState::Offered,
+        })
+    }
+
+    //the file name without the directories is used as the name of the transfer
+    pub fn from_file(id: u32, path: &Path, chunk_size: usize) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Self::new(id, &name, fs::read(path)?, chunk_size)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.offer.id
+    }
+
+    pub fn state(&self) -> TransferState {
+        self.state
+    }
+
+    //bytes the receiver confirmed and the size of the file
+    pub fn progress(&self) -> (u64, u64) {
+        (self.acked, self.offer.size)
+    }
+
+    //the first message to send, again after a reconnect to resume the transfer
+    pub fn offer(&mut self) -> TransferMessage {
+        self.state = TransferState::Offered;
+        TransferMessage::Offer(self.offer.clone())
+    }
+
+    //the next chunk to send, None until the receiver confirms enough of the chunks in flight
+    pub fn next_chunk(&mut self) -> Option<TransferMessage> {
+        let in_flight = self.sent - self.acked;
+        if self.state != TransferState::Transferring
+            || self.sent == self.offer.size
+            || in_flight >= (self.chunk_size * MAX_CHUNKS_IN_FLIGHT) as u64
+        {
+            return None;
+        }
+
+        let start = self.sent as usize;
+        let end = (start + self.chunk_size).min(self.data.len());
+        self.sent = end as u64;
+
+        Some(TransferMessage::Chunk {
+            id: self.offer.id,
+            offset: start as u64,
+            data: self.data[start..end].to_vec(),
+        })
+    }
+
+    pub fn handle(&mut self, message: TransferMessage) -> anyhow::Result<()> {
+        match message {
+            TransferMessage::Accept { offset, .. } => {
+                if offset > self.offer.size {
+                    bail!("accepted at offset {offset} past the end of the transfer");
+                }
+                self.sent = offset;
+                self.acked = offset;
+                self.state = TransferState::Transferring;
+            }
+            TransferMessage::Ack { offset, .. } => {
+                if offset > self.sent {
+                    bail!("acked offset {offset} wasn't sent yet");
+                }
+                self.acked = self.acked.max(offset);
+            }
+            TransferMessage::Complete { .. } => {
+                self.acked = self.offer.size;
+                self.state = TransferState::Completed;
+            }
+            TransferMessage::Cancel { .. } => self.state = TransferState::Cancelled,
+            message => bail!("unexpected transfer message {message:?}"),
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel(&mut self) -> TransferMessage {
+        self.state = TransferState::Cancelled;
+        TransferMessage::Cancel { id: self.offer.id }
+    }
+}
+
+pub struct IncomingTransfer {
+    offer: TransferOffer,
+    data: Bytes,
+    //chunks that arrived before the ones in front of them, reliable messages aren't ordered. they
+    //don't overlap and all of them are within the receive window
+    pending: BTreeMap<u64, Bytes>,
+    state: TransferState,
+}
+
+impl IncomingTransfer {
+    pub fn new(offer: TransferOffer) -> Self {
+        Self::resume(offer, Bytes::new())
+    }
+
+    //continues from the data received before, for example saved to disk before a reconnect
+    pub fn resume(offer: TransferOffer, mut partial: Bytes) -> Self {
+        partial.truncate(offer.size as usize);
+        Self {
+            offer,
+            data: partial,
+            pending: BTreeMap::new(),
+            state: TransferState::Offered,
+        }
+    }
+
+    pub fn offer(&self) -> &TransferOffer {
+        &self.offer
+    }
+
+    pub fn state(&self) -> TransferState {
+        self.state
+    }
+
+    //bytes received in order and the size of the file
+    pub fn progress(&self) -> (u64, u64) {
+        (self.data.len() as u64, self.offer.size)
+    }
+
+    //the bytes received in order so far, the whole file once completed
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+
+    //with nothing left to receive, an empty file or one resumed in full, the transfer ends right away
+    pub fn accept(&mut self) -> TransferMessage {
+        if self.data.len() as u64 == self.offer.size {
+            return self.finish();
+        }
+        self.state = TransferState::Transferring;
+        TransferMessage::Accept {
+            id: self.offer.id,
+            offset: self.data.len() as u64,
+        }
+    }
+
+    //returns the message to send back to the sender
+    pub fn handle(&mut self, message: TransferMessage) -> anyhow::Result<Option<TransferMessage>> {
+        let (offset, chunk) = match message {
+            TransferMessage::Chunk { offset, data, .. } => (offset, data),
+            TransferMessage::Cancel { .. } => {
+                self.state = TransferState::Cancelled;
+                return Ok(None);
+            }
+            message => bail!("unexpected transfer message {message:?}"),
+        };
+
+        if self.state != TransferState::Transferring {
+            return Ok(None);
+        }
+        let received = self.data.len() as u64;
+        let Some(end) = offset
+            .checked_add(chunk.len() as u64)
+            .filter(|&end| end <= self.offer.size)
+        else {
+            bail!("chunk at offset {offset} is past the end of the transfer");
+        };
+        if end > received + RECEIVE_WINDOW {
+            bail!("chunk at offset {offset} is past the receive window");
+        }
+
+        //a chunk sent again after a resume can overlap the data we already have
+        if offset >= received && !self.overlaps_pending(offset, end) {
+            self.pending.insert(offset, chunk);
+        }
+        while let Some(chunk) = self.pending.remove(&(self.data.len() as u64)) {
+            self.data.extend_from_slice(&chunk);
+        }
+
+        if self.data.len() as u64 != self.offer.size {
+            return Ok(Some(TransferMessage::Ack {
+                id: self.offer.id,
+                offset: self.data.len() as u64,
+            }));
+        }
+        Ok(Some(self.finish()))
+    }
+
+    //the whole file is there, it's checked against the hash of the offer
+    fn finish(&mut self) -> TransferMessage {
+        if *blake3::hash(&self.data).as_bytes() != self.offer.hash {
+            self.state = TransferState::Failed;
+            return TransferMessage::Cancel { id: self.offer.id };
+        }
+        self.state = TransferState::Completed;
+        TransferMessage::Complete { id: self.offer.id }
+    }
+
+    pub fn cancel(&mut self) -> TransferMessage {
+        self.state = TransferState::Cancelled;
+        TransferMessage::Cancel { id: self.offer.id }
+    }
+
+    fn overlaps_pending(&self, offset: u64, end: u64) -> bool {
+        let before = self.pending.range(..=offset).next_back();
+        let after = self.pending.range(offset..).next();
+        before.is_some_and(|(&start, chunk)| start + chunk.len() as u64 > offset)
+            || after.is_some_and(|(&start, _)| start < end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //passes the messages through their encoding like the real channel would
+    fn roundtrip(message: TransferMessage) -> TransferMessage {
+        TransferMessage::read(&message.write()).unwrap()
+    }
+
+    fn data(size: usize) -> Bytes {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let mut outgoing = OutgoingTransfer::new(3, "map.bin", data(10), 4).unwrap();
+        let offer = outgoing.offer();
+
+        assert_eq!(roundtrip(offer.clone()), offer);
+        let chunk = TransferMessage::Chunk {
+            id: 3,
+            offset: 4,
+            data: vec![1, 2],
+        };
+        assert_eq!(roundtrip(chunk.clone()), chunk);
+        assert_eq!(
+            roundtrip(TransferMessage::Ack { id: 3, offset: 8 }),
+            TransferMessage::Ack { id: 3, offset: 8 }
+        );
+        assert!(TransferMessage::read(&[1, 2, 3]).is_err());
+        assert!(TransferMessage::read(&offer.write()[..MESSAGE_HEADER_SIZE + 4]).is_err());
+    }
+
+    #[test]
+    fn file_is_transferred_out_of_order() {
+        let file = data(100);
+        let mut outgoing = OutgoingTransfer::new(1, "asset", file.clone(), 16).unwrap();
+        let TransferMessage::Offer(offer) = roundtrip(outgoing.offer()) else {
+            panic!("expected an offer");
+        };
+
+        let mut incoming = IncomingTransfer::new(offer);
+        outgoing.handle(roundtrip(incoming.accept())).unwrap();
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = outgoing.next_chunk() {
+            chunks.push(roundtrip(chunk));
+        }
+        assert_eq!(chunks.len(), 7);
+
+        for chunk in chunks.into_iter().rev() {
+            let reply = incoming.handle(chunk).unwrap().unwrap();
+            outgoing.handle(roundtrip(reply)).unwrap();
+        }
+
+        assert_eq!(incoming.state(), TransferState::Completed);
+        assert_eq!(outgoing.state(), TransferState::Completed);
+        assert_eq!(outgoing.progress(), (100, 100));
+        assert_eq!(incoming.into_data(), file);
+    }
+
+    #[test]
+    fn chunks_in_flight_are_limited() {
+        let size = 4 * (MAX_CHUNKS_IN_FLIGHT + 2);
+        let mut outgoing = OutgoingTransfer::new(1, "asset", data(size), 4).unwrap();
+        outgoing
+            .handle(TransferMessage::Accept { id: 1, offset: 0 })
+            .unwrap();
+
+        for _ in 0..MAX_CHUNKS_IN_FLIGHT {
+            assert!(outgoing.next_chunk().is_some());
+        }
+        assert!(outgoing.next_chunk().is_none());
+
+        outgoing
+            .handle(TransferMessage::Ack { id: 1, offset: 4 })
+            .unwrap();
+        assert!(outgoing.next_chunk().is_some());
+        assert!(outgoing.next_chunk().is_none());
+    }
+
+    #[test]
+    fn partial_file_is_resumed() {
+        let file = data(50);
+        let mut outgoing = OutgoingTransfer::new(1, "asset", file.clone(), 16).unwrap();
+        let TransferMessage::Offer(offer) = outgoing.offer() else {
+            panic!("expected an offer");
+        };
+
+        let mut incoming = IncomingTransfer::resume(offer, file[..20].to_vec());
+        outgoing.handle(incoming.accept()).unwrap();
+        assert_eq!(outgoing.progress(), (20, 50));
+
+        let mut chunks = 0;
+        while let Some(chunk) = outgoing.next_chunk() {
+            chunks += 1;
+            if let Some(reply) = incoming.handle(chunk).unwrap() {
+                outgoing.handle(reply).unwrap();
+            }
+        }
+
+        assert_eq!(chunks, 2);
+        assert_eq!(incoming.state(), TransferState::Completed);
+        assert_eq!(incoming.data(), file);
+    }
+
+    #[test]
+    fn transfers_with_nothing_left_complete_on_accept() {
+        let mut outgoing = OutgoingTransfer::new(1, "empty", Bytes::new(), 16).unwrap();
+        let TransferMessage::Offer(offer) = outgoing.offer() else {
+            panic!("expected an offer");
+        };
+        let mut incoming = IncomingTransfer::new(offer);
+        outgoing.handle(roundtrip(incoming.accept())).unwrap();
+        assert_eq!(incoming.state(), TransferState::Completed);
+        assert_eq!(outgoing.state(), TransferState::Completed);
+        assert_eq!(outgoing.next_chunk(), None);
+
+        //a file resumed in full is still checked against the hash
+        let file = data(50);
+        let mut outgoing = OutgoingTransfer::new(2, "asset", file.clone(), 16).unwrap();
+        let TransferMessage::Offer(offer) = outgoing.offer() else {
+            panic!("expected an offer");
+        };
+        let mut incoming = IncomingTransfer::resume(offer.clone(), file);
+        outgoing.handle(incoming.accept()).unwrap();
+        assert_eq!(incoming.state(), TransferState::Completed);
+        assert_eq!(outgoing.progress(), (50, 50));
+
+        let mut corrupted = IncomingTransfer::resume(offer, vec![0; 50]);
+        assert_eq!(corrupted.accept(), TransferMessage::Cancel { id: 2 });
+        assert_eq!(corrupted.state(), TransferState::Failed);
+    }
+
+    #[test]
+    fn chunks_outside_the_window_are_refused() {
+        let size = 4 * RECEIVE_WINDOW;
+        let mut outgoing = OutgoingTransfer::new(1, "asset", data(size as usize), 16).unwrap();
+        let TransferMessage::Offer(offer) = outgoing.offer() else {
+            panic!("expected an offer");
+        };
+        let mut incoming = IncomingTransfer::new(offer);
+        incoming.accept();
+
+        let chunk = |offset, len| TransferMessage::Chunk {
+            id: 1,
+            offset,
+            data: vec![0; len],
+        };
+        assert!(incoming.handle(chunk(u64::MAX - 1, 4)).is_err());
+        assert!(incoming.handle(chunk(RECEIVE_WINDOW, 4)).is_err());
+
+        //the chunks overlapping a pending one are dropped
+        incoming.handle(chunk(16, 16)).unwrap();
+        incoming.handle(chunk(8, 16)).unwrap();
+        incoming.handle(chunk(24, 16)).unwrap();
+        incoming.handle(chunk(16, 16)).unwrap();
+        assert_eq!(incoming.pending.len(), 1);
+    }
+
+    #[test]
+    fn mismatching_hash_fails_the_transfer() {
+        let mut outgoing = OutgoingTransfer::new(1, "asset", data(8), 8).unwrap();
+        let TransferMessage::Offer(offer) = outgoing.offer() else {
+            panic!("expected an offer");
+        };
+
+        let mut incoming = IncomingTransfer::resume(offer, vec![9; 4]);
+        outgoing.handle(incoming.accept()).unwrap();
+        let reply = incoming.handle(outgoing.next_chunk().unwrap()).unwrap();
+
+        assert_eq!(reply, Some(TransferMessage::Cancel { id: 1 }));
+        assert_eq!(incoming.state(), TransferState::Failed);
+        outgoing.handle(reply.unwrap()).unwrap();
+        assert_eq!(outgoing.state(), TransferState::Cancelled);
+    }
+
+    #[test]
+    fn cancelled_transfer_stops_sending() {
+        let mut outgoing = OutgoingTransfer::new(1, "asset", data(64), 8).unwrap();
+        outgoing
+            .handle(TransferMessage::Accept { id: 1, offset: 0 })
+            .unwrap();
+
+        assert_eq!(outgoing.cancel(), TransferMessage::Cancel { id: 1 });
+        assert_eq!(outgoing.next_chunk(), None);
+        assert!(OutgoingTransfer::new(1, "asset", data(8), MAX_CHUNK_SIZE + 1).is_err());
+    }
+}